@@ -1,14 +1,85 @@
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, Stream};
+use nalgebra::Point3;
+use point_viewer::attributes::{AttrStats, AttributeData};
 use point_viewer::data_provider::{DataProvider, DataProviderFactory};
 use point_viewer::errors::*;
 use point_viewer::geometry::Aabb;
-use point_viewer::iterator::{ParallelIterator, PointCloud, PointQuery};
+use point_viewer::iterator::{
+    scalar_values, OwnedPointQuery, PointCloud, PointQuery, PooledIterator, QueryEstimate,
+    QuerySummary, ReservoirSampler, WindowedSorter, ALL_ATTRIBUTES, EXCLUDED_ATTRIBUTE_PREFIX,
+};
+use point_viewer::math::KdTree;
 use point_viewer::octree::Octree;
 use point_viewer::s2_cells::S2Cells;
 use point_viewer::{PointsBatch, NUM_POINTS_PER_BATCH};
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// The `feature_id` assigned by `annotate_nearest` to points without a feature in range.
+pub const NO_FEATURE_ID: u64 = u64::MAX;
+
+/// A point returned by `top_n`.
+#[derive(Clone, Debug)]
+pub struct PointWithData {
+    pub position: Point3<f64>,
+    /// The value of the attribute the points were ranked by.
+    pub value: f64,
+    /// The attributes requested by the query, with the value of this point only.
+    pub attributes: BTreeMap<String, AttributeData>,
+}
+
+/// An entry of the heap of `top_n`. The key is the value, negated for ascending order, so that
+/// the heap always holds the points with the largest keys.
+struct RankedPoint {
+    key: f64,
+    point: PointWithData,
+}
+
+impl PartialEq for RankedPoint {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedPoint {}
+
+impl PartialOrd for RankedPoint {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RankedPoint {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // NaN values are never ranked.
+        self.key.partial_cmp(&other.key).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// The query for `attributes` instead of the attributes of the query. The filter attributes are
+/// still requested, since filtering needs their values.
+fn with_attributes<'b>(point_query: &PointQuery<'b>, attributes: &[&'b str]) -> PointQuery<'b> {
+    let mut filter_attributes: Vec<&str> = point_query
+        .filter_intervals
+        .keys()
+        .copied()
+        .filter(|attribute| !attributes.contains(attribute))
+        .collect();
+    filter_attributes.sort_unstable();
+    PointQuery {
+        attributes: [attributes, &filter_attributes[..]].concat(),
+        ..point_query.clone()
+    }
+}
 
 enum PointClouds {
-    Octrees(Vec<Octree>),
-    S2Cells(Vec<S2Cells>),
+    Octrees(Arc<[Octree]>),
+    S2Cells(Arc<[S2Cells]>),
 }
 
 pub struct PointCloudClient {
@@ -17,6 +88,9 @@ pub struct PointCloudClient {
     num_points_per_batch: usize,
     num_threads: usize,
     buffer_size: usize,
+    default_attributes: Vec<String>,
+    /// Created once and shared by all queries of this client.
+    thread_pool: Arc<rayon::ThreadPool>,
 }
 
 impl PointCloudClient {
@@ -24,30 +98,344 @@ impl PointCloudClient {
         &self.aabb
     }
 
-    fn for_each<C, F>(&self, point_cloud: &[C], point_query: &PointQuery, mut func: F) -> Result<()>
+    /// The query with the default attributes of the client if it lists none, see
+    /// `PointCloudClientBuilder::default_attributes`.
+    fn with_default_attributes<'b>(
+        &'b self,
+        point_query: &'b PointQuery<'b>,
+    ) -> Cow<'b, PointQuery<'b>> {
+        if !point_query.attributes.is_empty() || self.default_attributes.is_empty() {
+            return Cow::Borrowed(point_query);
+        }
+        Cow::Owned(PointQuery {
+            attributes: self.default_attributes.iter().map(String::as_str).collect(),
+            ..point_query.clone()
+        })
+    }
+
+    fn for_each<C, F>(
+        &self,
+        point_cloud: &Arc<[C]>,
+        point_query: &PointQuery,
+        mut func: F,
+    ) -> Result<QuerySummary>
     where
-        C: PointCloud,
+        C: PointCloud + Send + 'static,
         F: FnMut(PointsBatch) -> Result<()>,
     {
-        let mut parallel_iterator = ParallelIterator::new(
-            point_cloud,
+        let mut pooled_iterator = PooledIterator::new(
+            &self.thread_pool,
+            Arc::clone(point_cloud),
             point_query,
             self.num_points_per_batch,
             self.num_threads,
             self.buffer_size,
         );
-        parallel_iterator.try_for_each_batch(&mut func)
+        pooled_iterator.try_for_each_batch(&mut func)
+    }
+
+    /// Estimates the cost of the query over all point clouds without reading any points, see
+    /// `PointCloud::estimate_query`, e.g. to decide whether to run it in the background.
+    pub fn estimate(&self, point_query: &PointQuery) -> Result<QueryEstimate> {
+        let point_query = &*self.with_default_attributes(point_query);
+        fn sum<C: PointCloud>(
+            point_clouds: &[C],
+            point_query: &PointQuery,
+        ) -> Result<QueryEstimate> {
+            let mut estimate = QueryEstimate::default();
+            for point_cloud in point_clouds {
+                estimate += point_cloud.estimate_query(point_query)?;
+            }
+            Ok(estimate)
+        }
+        match &self.point_clouds {
+            PointClouds::Octrees(octrees) => sum(octrees, point_query),
+            PointClouds::S2Cells(s2_cells) => sum(s2_cells, point_query),
+        }
+    }
+
+    fn stream_each<C>(
+        &self,
+        point_clouds: &Arc<[C]>,
+        point_query: &PointQuery,
+    ) -> mpsc::Receiver<Result<PointsBatch>>
+    where
+        C: PointCloud + Clone + Send + Sync + 'static,
+        C::Id: 'static,
+    {
+        let (tx, rx) = mpsc::channel(self.buffer_size);
+        for (index, point_cloud) in point_clouds.iter().enumerate() {
+            let point_cloud: Arc<[C]> = Arc::from(vec![point_cloud.clone()]);
+            let point_query = OwnedPointQuery::new(point_query);
+            let thread_pool = Arc::clone(&self.thread_pool);
+            let (batch_size, num_threads, buffer_size) = (
+                self.num_points_per_batch,
+                self.num_threads,
+                self.buffer_size,
+            );
+            let mut tx = tx.clone();
+            // The points are received on this thread, which is not one of the pool.
+            std::thread::spawn(move || {
+                let point_query = point_query.as_point_query();
+                let pooled_iterator = PooledIterator::new(
+                    &thread_pool,
+                    point_cloud,
+                    &point_query,
+                    batch_size,
+                    num_threads,
+                    buffer_size,
+                );
+                for batch in pooled_iterator.batches() {
+                    let batch =
+                        batch.chain_err(|| format!("The query of point cloud {} failed.", index));
+                    // Dropping the batches cancels the query if the stream was dropped.
+                    if block_on(tx.send(batch)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        rx
+    }
+
+    /// Runs the query on every point cloud of the client concurrently and merges their points
+    /// into one stream, e.g. for a server answering a request from many tiles. Each point cloud
+    /// is queried on its own, so that an error only ends its own points: It becomes an item of
+    /// the stream, and the points of the other point clouds still follow. Dropping the stream
+    /// cancels the remaining queries. The stream does not need to be polled on any particular
+    /// executor.
+    pub fn stream_point_data(
+        &self,
+        point_query: &PointQuery,
+    ) -> impl Stream<Item = Result<PointsBatch>> + Send + Unpin + 'static {
+        let point_query = &*self.with_default_attributes(point_query);
+        match &self.point_clouds {
+            PointClouds::Octrees(octrees) => self.stream_each(octrees, point_query),
+            PointClouds::S2Cells(s2_cells) => self.stream_each(s2_cells, point_query),
+        }
     }
 
-    pub fn for_each_point_data<F>(&self, point_query: &PointQuery, func: F) -> Result<()>
+    /// Streams the points matching the query to `func`. On success, the returned summary tells
+    /// how many points matched, which may be none. The query runs on the thread pool of the
+    /// client, and `func` on the calling thread, which must not be one of the pool.
+    pub fn for_each_point_data<F>(&self, point_query: &PointQuery, func: F) -> Result<QuerySummary>
     where
         F: FnMut(PointsBatch) -> Result<()>,
     {
+        let point_query = &*self.with_default_attributes(point_query);
         match &self.point_clouds {
             PointClouds::Octrees(octrees) => self.for_each(octrees, point_query, func),
             PointClouds::S2Cells(s2_cells) => self.for_each(s2_cells, point_query, func),
         }
     }
+
+    /// Like `for_each_point_data`, but passes on the points in ascending order of `time_attribute`,
+    /// in windows of `window` length, holding back at most about `max_buffered_points` points.
+    /// See `WindowedSorter` for the guarantees. The query needs to request the time attribute.
+    pub fn for_each_time_window<F>(
+        &self,
+        point_query: &PointQuery,
+        time_attribute: &str,
+        window: f64,
+        max_buffered_points: usize,
+        func: F,
+    ) -> Result<QuerySummary>
+    where
+        F: FnMut(PointsBatch) -> Result<()>,
+    {
+        let mut sorter = WindowedSorter::new(time_attribute, window, max_buffered_points, func);
+        let summary = self.for_each_point_data(point_query, |batch| sorter.push(batch))?;
+        sorter.finish()?;
+        Ok(summary)
+    }
+
+    /// Like `for_each_point_data`, but adds a `feature_id` attribute to every batch, which holds
+    /// the id of the nearest feature within `radius` of each point, or `NO_FEATURE_ID`.
+    pub fn annotate_nearest<F>(
+        &self,
+        point_query: &PointQuery,
+        features: &KdTree,
+        radius: f64,
+        mut func: F,
+    ) -> Result<QuerySummary>
+    where
+        F: FnMut(PointsBatch) -> Result<()>,
+    {
+        self.for_each_point_data(point_query, |mut batch| {
+            let feature_ids = batch
+                .position
+                .iter()
+                .map(|pos| {
+                    features
+                        .nearest_within(pos, radius)
+                        .map_or(NO_FEATURE_ID, |(id, _)| id)
+                })
+                .collect();
+            batch
+                .attributes
+                .insert("feature_id".to_string(), AttributeData::U64(feature_ids));
+            func(batch)
+        })
+    }
+
+    /// Returns the `n` points matching the query with the largest values of the scalar
+    /// `attribute`, or the smallest if not `descending`, ordered from the first to the last.
+    /// Points whose value is NaN are left out, and ties are broken arbitrarily. Only `n` points
+    /// are held in memory. The attribute is requested in addition to those of the query.
+    pub fn top_n(
+        &self,
+        point_query: &PointQuery,
+        attribute: &str,
+        n: usize,
+        descending: bool,
+    ) -> Result<Vec<PointWithData>> {
+        let point_query = &*self.with_default_attributes(point_query);
+        let mut attributes = point_query.attributes.clone();
+        if !point_query.requests_attribute(attribute) {
+            // The attribute is either excluded from all attributes, or not listed.
+            let num_attributes = attributes.len();
+            attributes.retain(|a| a.strip_prefix(EXCLUDED_ATTRIBUTE_PREFIX) != Some(attribute));
+            if attributes.len() == num_attributes {
+                attributes.push(attribute);
+            } else if attributes.is_empty() {
+                attributes.push(ALL_ATTRIBUTES);
+            }
+        }
+        let ranking_query = PointQuery {
+            attributes,
+            ..point_query.clone()
+        };
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        let mut heap: BinaryHeap<Reverse<RankedPoint>> = BinaryHeap::with_capacity(n + 1);
+        self.for_each_point_data(&ranking_query, |batch| {
+            let values = scalar_values(&batch, attribute)?;
+            for (index, value) in values.into_iter().enumerate() {
+                let key = if descending { value } else { -value };
+                let is_ranked = match heap.peek() {
+                    _ if key.is_nan() => false,
+                    Some(Reverse(last)) if heap.len() == n => key > last.key,
+                    _ => true,
+                };
+                if !is_ranked {
+                    continue;
+                }
+                let point = PointWithData {
+                    position: batch.position[index],
+                    value,
+                    attributes: batch
+                        .attributes
+                        .iter()
+                        .map(|(name, data)| (name.clone(), data.select(&[index])))
+                        .collect(),
+                };
+                heap.push(Reverse(RankedPoint { key, point }));
+                if heap.len() > n {
+                    heap.pop();
+                }
+            }
+            Ok(())
+        })?;
+        // Sorting the reversed entries puts the largest keys first.
+        Ok(heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse(ranked)| ranked.point)
+            .collect())
+    }
+
+    /// Returns exactly `num_samples` points drawn uniformly at random from all points matching
+    /// the query, or all of them if there are fewer, in a single pass over the points. The same
+    /// seed gives the same sample of the same point cloud. See `ReservoirSampler`.
+    pub fn sample(
+        &self,
+        point_query: &PointQuery,
+        num_samples: usize,
+        seed: u64,
+    ) -> Result<PointsBatch> {
+        let mut sampler = ReservoirSampler::new(num_samples, seed);
+        self.for_each_point_data(point_query, |batch| sampler.push(batch))?;
+        Ok(sampler.finish())
+    }
+
+    /// Collects all points matching the query into a single batch, e.g. for small regions or in
+    /// tests. Fails as soon as more than `max_points` points are found, so that an accidentally
+    /// large query does not exhaust the memory.
+    pub fn collect(&self, point_query: &PointQuery, max_points: usize) -> Result<PointsBatch> {
+        let mut points = PointsBatch {
+            position: Vec::new(),
+            attributes: BTreeMap::new(),
+            bounding_box: None,
+        };
+        self.for_each_point_data(point_query, |mut batch| {
+            if points.position.len() + batch.position.len() > max_points {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "The query matches more than {} points.",
+                    max_points
+                ))
+                .into());
+            }
+            points.append(&mut batch).map_err(ErrorKind::InvalidInput)?;
+            Ok(())
+        })?;
+        Ok(points)
+    }
+
+    /// The values of the attribute of all points matching the query, e.g. the intensities of a
+    /// region as `Vec<f32>` for a histogram. Only this attribute and those of the filters are
+    /// read, instead of the attributes of the query. The values arrive in no particular order.
+    /// Fails if the attribute does not have the type `T`.
+    pub fn collect_attribute<T>(&self, point_query: &PointQuery, attribute: &str) -> Result<Vec<T>>
+    where
+        Vec<T>: TryFrom<AttributeData, Error = String>,
+    {
+        let attribute_query = with_attributes(point_query, &[attribute]);
+        let mut values = Vec::new();
+        self.for_each_point_data(&attribute_query, |mut batch| {
+            let mut batch_values = batch
+                .remove_attribute_vec::<T>(attribute)
+                .map_err(ErrorKind::InvalidInput)?;
+            values.append(&mut batch_values);
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    /// Computes statistics of `attributes` over the points matching the query, which replace the
+    /// attributes requested by the query. The points are not buffered. Fails with `InvalidInput`
+    /// if one of `attributes` is not a scalar, e.g. color.
+    pub fn attribute_stats(
+        &self,
+        point_query: &PointQuery,
+        attributes: &[&str],
+    ) -> Result<HashMap<String, AttrStats>> {
+        let stats_query = with_attributes(point_query, attributes);
+        let mut stats: HashMap<String, AttrStats> = HashMap::new();
+        self.for_each_point_data(&stats_query, |batch| {
+            // The filter attributes are only requested for filtering.
+            for (name, data) in batch
+                .attributes
+                .iter()
+                .filter(|(name, _)| attributes.contains(&name.as_str()))
+            {
+                if data.dim() != 1 {
+                    return Err(ErrorKind::InvalidInput(format!(
+                        "Attribute '{}' is not a scalar.",
+                        name
+                    ))
+                    .into());
+                }
+                stats
+                    .entry(name.clone())
+                    .or_default()
+                    .add_data(data)
+                    .map_err(ErrorKind::InvalidInput)?;
+            }
+            Ok(())
+        })?;
+        Ok(stats)
+    }
 }
 
 pub struct PointCloudClientBuilder<'a> {
@@ -56,6 +444,7 @@ pub struct PointCloudClientBuilder<'a> {
     num_points_per_batch: usize,
     num_threads: usize,
     buffer_size: usize,
+    default_attributes: Vec<String>,
 }
 
 impl<'a> PointCloudClientBuilder<'a> {
@@ -66,6 +455,7 @@ impl<'a> PointCloudClientBuilder<'a> {
             num_points_per_batch: NUM_POINTS_PER_BATCH,
             num_threads: std::cmp::max(1, num_cpus::get() - 1),
             buffer_size: 4,
+            default_attributes: Vec::new(),
         }
     }
 
@@ -79,6 +469,7 @@ impl<'a> PointCloudClientBuilder<'a> {
         self
     }
 
+    /// The size of the thread pool that all queries of the client share.
     pub fn num_threads(mut self, num_threads: usize) -> Self {
         self.num_threads = num_threads;
         self
@@ -89,6 +480,19 @@ impl<'a> PointCloudClientBuilder<'a> {
         self
     }
 
+    /// The attributes to return for queries that list none, e.g. `&["color"]`, so that queries
+    /// return colors unless they ask for other attributes. "position" may be listed, but is left
+    /// out, since positions are always returned. Without default attributes, such queries return
+    /// positions only.
+    pub fn default_attributes(mut self, attributes: &[&str]) -> Self {
+        self.default_attributes = attributes
+            .iter()
+            .filter(|a| **a != "position")
+            .map(|a| (*a).to_string())
+            .collect();
+        self
+    }
+
     pub fn build(self) -> Result<PointCloudClient> {
         if self.locations.is_empty() {
             return Err("No locations specified for point cloud client.".into());
@@ -115,7 +519,8 @@ impl<'a> PointCloudClientBuilder<'a> {
                             octree
                         })
                     })
-                    .collect::<Result<Vec<Octree>>>()?,
+                    .collect::<Result<Vec<Octree>>>()?
+                    .into(),
             )
         } else {
             PointClouds::S2Cells(
@@ -127,16 +532,25 @@ impl<'a> PointCloudClientBuilder<'a> {
                             s2_cells
                         })
                     })
-                    .collect::<Result<Vec<S2Cells>>>()?,
+                    .collect::<Result<Vec<S2Cells>>>()?
+                    .into(),
             )
         };
 
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.num_threads)
+            .thread_name(|index| format!("point_cloud_client_{}", index))
+            .build()
+            .chain_err(|| "Could not create the thread pool of the point cloud client.")?;
+
         Ok(PointCloudClient {
             point_clouds,
             aabb: aabb.unwrap_or_else(Aabb::zero),
             num_points_per_batch: self.num_points_per_batch,
             num_threads: self.num_threads,
             buffer_size: self.buffer_size,
+            default_attributes: self.default_attributes,
+            thread_pool: Arc::new(thread_pool),
         })
     }
 }
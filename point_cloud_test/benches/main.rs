@@ -1,10 +1,23 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use nalgebra::Vector3;
 use point_cloud_client::PointCloudClient;
 use point_cloud_test_lib::queries::*;
 use point_cloud_test_lib::{
-    make_octree, make_s2_cells, setup_octree_client, setup_s2_client, Arguments, SyntheticData,
+    get_s2_and_octree_path, make_octree, make_s2_cells, setup_octree_client, setup_pointcloud,
+    setup_s2_client, Arguments, SyntheticData,
 };
-use point_viewer::iterator::{PointLocation, PointQuery};
+use point_viewer::data_provider::{DataProvider, OnDiskDataProvider};
+use point_viewer::errors::Result;
+use point_viewer::geometry::Aabb;
+use point_viewer::iterator::{
+    ParallelIterator, PointCloud, PointLocation, PointQuery, PooledIterator, ALL_ATTRIBUTES,
+};
+use point_viewer::octree::Octree;
+use point_viewer::proto;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
 use tempdir::TempDir;
 
 fn bench_octree_building_multithreaded(c: &mut Criterion) {
@@ -64,6 +77,16 @@ fn frustum_query_octree(b: &mut Criterion) {
     )
 }
 
+/// Only selects the nodes of the frustum query, which culls them by their bounding spheres first.
+fn frustum_nodes_octree(c: &mut Criterion) {
+    let args = Arguments::default();
+    let (_, octree, data) = setup_pointcloud(&args);
+    let location = get_frustum_query(data);
+    c.bench_function("frustum_nodes_octree", |b| {
+        b.iter(|| black_box(octree.nodes_in_location(&location)))
+    });
+}
+
 fn frustum_query_s2(b: &mut Criterion) {
     run_bench("frustum_query_s2", setup_s2_client, get_frustum_query, b)
 }
@@ -94,6 +117,168 @@ fn cell_union_query_s2(b: &mut Criterion) {
     )
 }
 
+/// The number of threads of the small box query benchmarks.
+const NUM_THREADS: usize = 4;
+
+/// A grid of 10 x 10 small boxes, each covering a hundredth of the extent of the data in x and y.
+fn small_box_query_grid(data: &SyntheticData) -> Vec<PointQuery<'static>> {
+    let diag = data.bbox().diag();
+    let cell = Vector3::new(0.1 * diag.x, 0.1 * diag.y, diag.z);
+    let size = Vector3::new(0.01 * diag.x, 0.01 * diag.y, diag.z);
+    (0..100)
+        .map(|i| {
+            let min = data.bbox().min()
+                + Vector3::new((i % 10) as f64 * cell.x, (i / 10) as f64 * cell.y, 0.0);
+            PointQuery {
+                attributes: vec!["color"],
+                location: PointLocation::Aabb(Aabb::new(min, min + size)),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/// Compares spawning threads for every query with running all queries on a shared thread pool.
+fn small_box_queries(c: &mut Criterion) {
+    let args = Arguments::default();
+    let (_, octree, data) = setup_pointcloud(&args);
+    let queries = small_box_query_grid(&data);
+    let octrees: Arc<[Octree]> = Arc::from(vec![octree]);
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(NUM_THREADS)
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("small_box_queries");
+    group.sample_size(10);
+    group.bench_function("spawning_threads", |b| {
+        b.iter(|| {
+            for query in &queries {
+                let mut parallel_iterator =
+                    ParallelIterator::new(&octrees, query, args.batch_size, NUM_THREADS, 4);
+                let res = parallel_iterator.try_for_each_batch(|batch| {
+                    black_box(batch);
+                    Ok(())
+                });
+                assert!(res.is_ok());
+            }
+        })
+    });
+    group.bench_function("shared_pool", |b| {
+        b.iter(|| {
+            for query in &queries {
+                let mut pooled_iterator = PooledIterator::new(
+                    &thread_pool,
+                    Arc::clone(&octrees),
+                    query,
+                    args.batch_size,
+                    NUM_THREADS,
+                    4,
+                );
+                let res = pooled_iterator.try_for_each_batch(|batch| {
+                    black_box(batch);
+                    Ok(())
+                });
+                assert!(res.is_ok());
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Compares reading only the positions of all points with reading all of their attributes, which
+/// are stored separately per node, so that the positions are read on their own.
+fn attribute_reads_octree(c: &mut Criterion) {
+    let args = Arguments::default();
+    let (_, octree, _) = setup_pointcloud(&args);
+    let octrees = std::slice::from_ref(&octree);
+
+    let mut group = c.benchmark_group("attribute_reads_octree");
+    for (name, attributes) in &[
+        ("positions_only", Vec::new()),
+        ("all_attributes", vec![ALL_ATTRIBUTES]),
+    ] {
+        let query = PointQuery {
+            attributes: attributes.clone(),
+            ..Default::default()
+        };
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut parallel_iterator =
+                    ParallelIterator::new(octrees, &query, args.batch_size, NUM_THREADS, 4);
+                let res = parallel_iterator.try_for_each_batch(|batch| {
+                    black_box(batch);
+                    Ok(())
+                });
+                assert!(res.is_ok());
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Takes `delay` to start reading every node, like a data provider with a high latency.
+struct DelayedDataProvider {
+    data_provider: OnDiskDataProvider,
+    delay: Duration,
+}
+
+impl DataProvider for DelayedDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        self.data_provider.meta_proto()
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        std::thread::sleep(self.delay);
+        self.data_provider.data(node_id, node_attributes)
+    }
+}
+
+/// Compares decoding threads that wait for every read in turn with IO threads reading ahead of
+/// them, on a data provider with a high latency.
+fn io_threads_slow_data_provider(c: &mut Criterion) {
+    let args = Arguments::default();
+    let (_, octree_path, _) = get_s2_and_octree_path(&args);
+    let octree = Octree::from_data_provider(Box::new(DelayedDataProvider {
+        data_provider: OnDiskDataProvider {
+            directory: octree_path,
+        },
+        delay: Duration::from_millis(5),
+    }))
+    .unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+
+    let mut group = c.benchmark_group("io_threads_slow_data_provider");
+    group.sample_size(10);
+    for num_io_threads in &[0, 16] {
+        group.bench_function(format!("{}_io_threads", num_io_threads), |b| {
+            b.iter(|| {
+                let res = ParallelIterator::new(
+                    std::slice::from_ref(&octree),
+                    &query,
+                    args.batch_size,
+                    2,
+                    4,
+                )
+                .io_threads(*num_io_threads)
+                .try_for_each_batch(|batch| {
+                    black_box(batch);
+                    Ok(())
+                });
+                assert!(res.is_ok());
+            })
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_octree_building_multithreaded,
@@ -103,11 +288,15 @@ criterion_group!(
     box_query_octree,
     box_query_s2,
     frustum_query_octree,
+    frustum_nodes_octree,
     frustum_query_s2,
     obb_query_octree,
     obb_query_s2,
     cell_union_query_octree,
     cell_union_query_s2,
+    small_box_queries,
+    attribute_reads_octree,
+    io_threads_slow_data_provider,
 );
 criterion_main!(benches);
 
@@ -0,0 +1,55 @@
+// Runs queries repeatedly and measures them, e.g. to track the query performance of revisions
+// in CI, without the benchmark harness of criterion.
+use point_cloud_client::PointCloudClient;
+use point_viewer::errors::*;
+use point_viewer::iterator::PointQuery;
+use std::time::{Duration, Instant};
+
+/// The timings of `run_query_benchmark`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchResult {
+    /// The number of times the query was run.
+    pub iterations: usize,
+    /// The number of points returned by every run of the query.
+    pub num_points: usize,
+    pub min: Duration,
+    pub median: Duration,
+    /// The points per second of the median run.
+    pub points_per_second: f64,
+}
+
+/// Runs the query `iterations` times, consuming its points without copying them, and returns how
+/// long the runs took. Fails if a run fails or returns a different number of points than the
+/// first one, since the timings would not be comparable.
+pub fn run_query_benchmark(
+    client: &PointCloudClient,
+    point_query: &PointQuery,
+    iterations: usize,
+) -> Result<BenchResult> {
+    assert!(iterations > 0, "At least one iteration is needed.");
+    let mut durations = Vec::with_capacity(iterations);
+    let mut num_points = None;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let summary = client.for_each_point_data(point_query, |_| Ok(()))?;
+        durations.push(start.elapsed());
+        if *num_points.get_or_insert(summary.points) != summary.points {
+            return Err(ErrorKind::InvalidInput(format!(
+                "The query returned {} points, but {} points before.",
+                summary.points,
+                num_points.unwrap()
+            ))
+            .into());
+        }
+    }
+    durations.sort();
+    let num_points = num_points.unwrap();
+    let median = durations[iterations / 2];
+    Ok(BenchResult {
+        iterations,
+        num_points,
+        min: durations[0],
+        median,
+        points_per_second: num_points as f64 / median.as_secs_f64(),
+    })
+}
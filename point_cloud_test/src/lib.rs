@@ -13,6 +13,9 @@ use std::path::{Path, PathBuf};
 use std::sync::Once;
 use tempdir::TempDir;
 
+pub mod benchmark;
+pub use benchmark::{run_query_benchmark, BenchResult};
+
 pub mod synthetic_data;
 pub use synthetic_data::{Batched, SyntheticData};
 
@@ -33,7 +36,8 @@ pub struct Arguments {
     pub num_points: usize,
     // The batch size used for building the S2 point cloud.
     pub batch_size: usize,
-    // The seed used for generating point clouds
+    // The seed used for generating point clouds. The same seed always gives the same points, so
+    // benchmarks of different revisions run on the same data.
     pub seed: u64,
 }
 
@@ -104,6 +104,7 @@ where
         PointsBatch {
             position: Vec::with_capacity(batch_size),
             attributes: attrs,
+            bounding_box: None,
         }
     }
 
@@ -1,11 +1,32 @@
+use futures::executor::block_on_stream;
 use nalgebra::{Point3, Vector3};
 use num_integer::div_ceil;
+use point_cloud_client::{PointCloudClientBuilder, NO_FEATURE_ID};
 use point_cloud_test_lib::queries::*;
-use point_cloud_test_lib::{setup_pointcloud, Arguments, SyntheticData};
+use point_cloud_test_lib::{
+    get_s2_and_octree_path, make_octree, run_query_benchmark, setup_octree_client,
+    setup_pointcloud, setup_s2_client, Arguments, SyntheticData, S2_LEVEL,
+};
+use point_viewer::attributes::AttributeData;
+use point_viewer::conversion::{build_octree_from_point_clouds, octree_to_s2_cells};
+use point_viewer::data_provider::OnDiskDataProvider;
+use point_viewer::errors::ErrorKind;
+use point_viewer::export::{export_sharded, ExportFormat, ShardManifest, MANIFEST_FILENAME};
+use point_viewer::geometry::{Aabb, CellUnion};
 use point_viewer::iterator::PointCloud;
 use point_viewer::iterator::{PointLocation, PointQuery};
-use point_viewer::math::{sat, ConvexPolyhedron, PointCulling};
+use point_viewer::math::{sat, ClosedInterval, ConvexPolyhedron, FromPoint3, KdTree, PointCulling};
+use point_viewer::octree::{
+    build_octree_from_file, build_octree_from_file_with_options, BuildOptions, Octree,
+};
+use point_viewer::read_write::{Encoding, NodeWriter, OpenMode, PlyIterator, PlyNodeWriter};
+use point_viewer::s2_cells::S2Cells;
+use point_viewer::{attribute_extension, PointsBatch};
+use s2::cellid::CellID;
 use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::path::Path;
+use tempdir::TempDir;
 
 #[test]
 fn num_points_in_octree_meta() {
@@ -32,6 +53,37 @@ fn num_points_in_s2_meta() {
     assert_eq!(num_points, Arguments::default().num_points as u64);
 }
 
+#[test]
+fn synthetic_data_is_reproducible() {
+    let mut args = Arguments::default();
+    args.num_points = 10_000;
+    let generate = |seed| {
+        let data = SyntheticData::new(args.width, args.height, args.num_points, seed);
+        data.map(|p| (p.position, p.color)).collect::<Vec<_>>()
+    };
+    assert!(generate(args.seed) == generate(args.seed));
+    assert!(generate(args.seed) != generate(args.seed + 1));
+
+    // This carries over to the octrees built from the data.
+    let content_hash = |seed| {
+        let dir = TempDir::new("octree").unwrap();
+        make_octree(
+            &Arguments {
+                seed,
+                ..args.clone()
+            },
+            dir.path(),
+        );
+        Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: dir.path().to_owned(),
+        }))
+        .unwrap()
+        .content_hash()
+        .unwrap()
+    };
+    assert_eq!(content_hash(args.seed), content_hash(args.seed));
+}
+
 #[test]
 fn check_all_query_equality() {
     check_equality(|_| PointLocation::AllPoints)
@@ -57,6 +109,53 @@ fn check_cell_union_query_equality() {
     check_equality(get_cell_union_query)
 }
 
+#[test]
+fn check_mixed_level_cell_union_query() {
+    let args = Arguments::default();
+    let (s2, oct, data) = setup_pointcloud(&args);
+    let local_cell = |x, y, level| {
+        let local = Point3::new(x, y, 0.0);
+        CellID::from_point(&data.ecef_from_local().transform_point(&local)).parent(level)
+    };
+    let coarse = local_cell(0.0, 0.0, S2_LEVEL - 3);
+    // A cell within the coarse one, which comes first when sorted, and one outside of it.
+    let fine_inside = coarse.child_begin_at_level(S2_LEVEL + 2);
+    let fine_outside = local_cell(-80.0, 80.0, S2_LEVEL + 1);
+    assert!(!coarse.contains(&fine_outside));
+    // Neither sorted nor normalized.
+    let cell_union = CellUnion(vec![fine_outside, coarse, fine_inside]);
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: PointLocation::S2Cells(cell_union.clone()),
+        ..Default::default()
+    };
+    let all_points_query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let check = |point_cloud: &dyn Fn(&PointQuery) -> Vec<IndexedPoint>| {
+        let expected: Vec<usize> = point_cloud(&all_points_query)
+            .iter()
+            .filter(|p| {
+                let point_cell = CellID::from_point(&p.pos);
+                cell_union.0.iter().any(|cell| cell.contains(&point_cell))
+            })
+            .map(|p| p.idx)
+            .collect();
+        let points = point_cloud(&query);
+        // Every point once, from both the coarse and the fine cell.
+        assert_eq!(points.iter().map(|p| p.idx).collect::<Vec<_>>(), expected);
+        assert!(points
+            .iter()
+            .any(|p| fine_outside.contains(&CellID::from_point(&p.pos))));
+        assert!(points
+            .iter()
+            .any(|p| coarse.contains(&CellID::from_point(&p.pos))));
+    };
+    check(&|query| query_and_sort(&oct, query, args.batch_size));
+    check(&|query| query_and_sort(&s2, query, args.batch_size));
+}
+
 #[test]
 fn check_web_mercator_rect_query_equality() {
     check_equality(get_web_mercator_rect_query)
@@ -82,6 +181,723 @@ fn check_web_mercator_rect_point_culling_equality() {
     check_point_culling_equality(get_web_mercator_rect);
 }
 
+#[test]
+fn check_all_points_in_depth_range() {
+    let args = Arguments::default();
+    let (_, oct, data) = setup_pointcloud(&args);
+    let location = PointLocation::AllPointsInDepthRange(1);
+    let node_ids = oct.nodes_in_location(&location);
+    assert!(node_ids.iter().all(|id| id.level() <= 1));
+    assert!(node_ids.len() < oct.nodes_in_location(&PointLocation::AllPoints).len());
+
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location,
+        ..Default::default()
+    };
+    let points = query_and_sort(&oct, &query, args.batch_size);
+    assert!(points.len() < args.num_points);
+    // The coarse sampling still spans the whole point cloud.
+    let mut bounding_box = Aabb::new(points[0].pos, points[0].pos);
+    points.iter().for_each(|p| bounding_box.grow(p.pos));
+    let coverage = bounding_box.diag().component_div(&data.bbox().diag());
+    assert!(coverage.iter().all(|c| *c > 0.9));
+}
+
+#[test]
+fn check_octree_to_s2_conversion() {
+    let args = Arguments::default();
+    let (_, oct, _) = setup_pointcloud(&args);
+    let s2_dir = TempDir::new("converted_s2").unwrap();
+    octree_to_s2_cells(&oct, s2_dir.path(), S2_LEVEL, &["color"]).unwrap();
+    let converted = S2Cells::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: s2_dir.path().to_owned(),
+    }))
+    .unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let points_oct = query_and_sort(&oct, &query, args.batch_size);
+    let points_s2 = query_and_sort(&converted, &query, args.batch_size);
+    assert_eq!(points_oct.len(), points_s2.len());
+    assert_points_equal(&points_s2, &points_oct, args.resolution);
+}
+
+#[test]
+fn check_octree_rebuild_with_different_capacity() {
+    let args = Arguments::default();
+    let (_, oct, _) = setup_pointcloud(&args);
+    let oct_dir = TempDir::new("rebuilt_octree").unwrap();
+    let options = BuildOptions {
+        max_points_per_node: 20_000,
+        ..Default::default()
+    };
+    build_octree_from_point_clouds(
+        std::slice::from_ref(&oct),
+        oct_dir.path(),
+        args.resolution,
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let rebuilt = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: oct_dir.path().to_owned(),
+    }))
+    .unwrap();
+    let num_nodes = |octree: &Octree| octree.to_meta_proto().get_octree().get_nodes().len();
+    assert!(num_nodes(&rebuilt) > num_nodes(&oct));
+
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let points_oct = query_and_sort(&oct, &query, args.batch_size);
+    let points_rebuilt = query_and_sort(&rebuilt, &query, args.batch_size);
+    assert!(points_oct
+        .iter()
+        .map(|p| p.idx)
+        .eq(points_rebuilt.iter().map(|p| p.idx)));
+    assert_points_equal(&points_rebuilt, &points_oct, args.resolution);
+}
+
+#[test]
+fn check_conversions_fail_on_unreadable_nodes() {
+    let tmp_dir = TempDir::new("unreadable_node").unwrap();
+    build_intensity_octree(tmp_dir.path());
+    let octree_dir = tmp_dir.path().join("octree");
+    let color_file = std::fs::read_dir(&octree_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.extension()
+                .map_or(false, |extension| extension == attribute_extension("color"))
+        })
+        .unwrap();
+    std::fs::remove_file(color_file).unwrap();
+    let oct = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: octree_dir,
+    }))
+    .unwrap();
+
+    assert!(octree_to_s2_cells(&oct, tmp_dir.path().join("s2"), S2_LEVEL, &["color"]).is_err());
+    assert!(build_octree_from_point_clouds(
+        std::slice::from_ref(&oct),
+        tmp_dir.path().join("rebuilt"),
+        0.01,
+        &["color"],
+        &BuildOptions::default(),
+    )
+    .is_err());
+}
+
+#[test]
+fn check_empty_query_summary() {
+    let args = Arguments::default();
+    let (octree_client, data) = setup_octree_client(&args);
+    let (s2_client, _) = setup_s2_client(&args);
+    let offset = 2.0 * data.bbox().diag();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: PointLocation::Aabb(Aabb::new(
+            data.bbox().min() + offset,
+            data.bbox().max() + offset,
+        )),
+        ..Default::default()
+    };
+    for client in &[octree_client, s2_client] {
+        let summary = client
+            .for_each_point_data(&query, |_| panic!("The query should not return points."))
+            .unwrap();
+        // The threads still report how long they ran.
+        assert_eq!((summary.points, summary.nodes_visited), (0, 0));
+        assert!(summary
+            .threads
+            .iter()
+            .all(|thread| thread.points == 0 && thread.nodes_visited == 0));
+    }
+}
+
+#[test]
+fn check_annotate_nearest() {
+    let args = Arguments::default();
+    let (client, data) = setup_octree_client(&args);
+    let ecef_from_local = *data.ecef_from_local();
+    let offset = 0.5 * data.half_width;
+    let features = KdTree::new(vec![
+        (ecef_from_local * Point3::new(-offset, 0.0, 0.0), 1),
+        (ecef_from_local * Point3::new(offset, 0.0, 0.0), 2),
+    ]);
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: get_aabb_query(data),
+        ..Default::default()
+    };
+    let mut num_points_per_feature = [0, 0];
+    client
+        .annotate_nearest(&query, &features, 4.0 * offset, |batch| {
+            let feature_ids: &Vec<u64> = batch.get_attribute_vec("feature_id")?;
+            for (pos, id) in batch.position.iter().zip(feature_ids) {
+                let local_x = ecef_from_local.inverse_transform_point(pos).x;
+                // Points close to the plane between the features could go either way.
+                if local_x < -1.0 {
+                    assert_eq!(*id, 1);
+                    num_points_per_feature[0] += 1;
+                } else if local_x > 1.0 {
+                    assert_eq!(*id, 2);
+                    num_points_per_feature[1] += 1;
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+    assert!(num_points_per_feature.iter().all(|n| *n > 0));
+
+    // No feature is within a tiny radius of any point.
+    client
+        .annotate_nearest(&query, &features, 1e-9, |batch| {
+            let feature_ids: &Vec<u64> = batch.get_attribute_vec("feature_id")?;
+            assert!(feature_ids.iter().all(|id| *id == NO_FEATURE_ID));
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn check_collect() {
+    let args = Arguments::default();
+    let (client, data) = setup_octree_client(&args);
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: get_aabb_query(data),
+        ..Default::default()
+    };
+    let mut streamed = Vec::new();
+    client
+        .for_each_point_data(&query, |batch| {
+            let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color")?;
+            streamed.extend(batch.position.iter().copied().zip(color.iter().copied()));
+            Ok(())
+        })
+        .unwrap();
+    assert!(!streamed.is_empty());
+
+    let points = client.collect(&query, streamed.len()).unwrap();
+    let color: &Vec<Vector3<u8>> = points.get_attribute_vec("color").unwrap();
+    let mut collected: Vec<_> = points
+        .position
+        .iter()
+        .copied()
+        .zip(color.iter().copied())
+        .collect();
+    // The batches arrive in no particular order.
+    let by_position = |a: &(Point3<f64>, Vector3<u8>), b: &(Point3<f64>, Vector3<u8>)| {
+        a.0.coords
+            .iter()
+            .partial_cmp(b.0.coords.iter())
+            .unwrap_or(Ordering::Equal)
+    };
+    streamed.sort_by(by_position);
+    collected.sort_by(by_position);
+    assert_eq!(collected, streamed);
+
+    assert!(client.collect(&query, streamed.len() - 1).is_err());
+}
+
+#[test]
+fn check_collect_attribute() {
+    let args = Arguments::default();
+    let (client, data) = setup_octree_client(&args);
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: get_aabb_query(data),
+        ..Default::default()
+    };
+    let mut streamed: Vec<Vector3<u8>> = Vec::new();
+    client
+        .for_each_point_data(&query, |batch| {
+            streamed.extend(batch.get_attribute_vec::<Vector3<u8>>("color")?);
+            Ok(())
+        })
+        .unwrap();
+    assert!(!streamed.is_empty());
+
+    let mut collected = client
+        .collect_attribute::<Vector3<u8>>(&query, "color")
+        .unwrap();
+    // The batches arrive in no particular order.
+    let key = |c: &Vector3<u8>| (c.x, c.y, c.z);
+    streamed.sort_by_key(key);
+    collected.sort_by_key(key);
+    assert_eq!(collected, streamed);
+
+    // The color is U8Vec3.
+    assert!(client.collect_attribute::<f32>(&query, "color").is_err());
+}
+
+#[test]
+fn check_default_attributes() {
+    let args = Arguments::default();
+    let (_, octree_path, data) = get_s2_and_octree_path(&args);
+    let locations = &[octree_path.to_str().unwrap().to_owned()];
+    let query = PointQuery {
+        location: get_aabb_query(data),
+        ..Default::default()
+    };
+    assert!(query.attributes.is_empty());
+    let attribute_names = |client: &point_cloud_client::PointCloudClient, query: &PointQuery| {
+        let mut names = Vec::new();
+        client
+            .for_each_point_data(query, |batch| {
+                assert!(!batch.position.is_empty());
+                names.push(batch.attributes.keys().cloned().collect::<Vec<_>>());
+                Ok(())
+            })
+            .unwrap();
+        names.dedup();
+        names
+    };
+
+    // By default, there are only positions.
+    let client = PointCloudClientBuilder::new(locations).build().unwrap();
+    assert_eq!(attribute_names(&client, &query), vec![Vec::<String>::new()]);
+
+    let client = PointCloudClientBuilder::new(locations)
+        .default_attributes(&["position", "color"])
+        .build()
+        .unwrap();
+    assert_eq!(attribute_names(&client, &query), vec![vec!["color"]]);
+}
+
+#[test]
+fn check_run_query_benchmark() {
+    let args = Arguments::default();
+    let (client, data) = setup_octree_client(&args);
+    let query = PointQuery {
+        location: get_aabb_query(data),
+        ..Default::default()
+    };
+    let num_points = client
+        .for_each_point_data(&query, |_| Ok(()))
+        .unwrap()
+        .points;
+    assert!(num_points > 0);
+
+    let result = run_query_benchmark(&client, &query, 5).unwrap();
+    assert_eq!(result.iterations, 5);
+    assert_eq!(result.num_points, num_points);
+    assert!(result.min > std::time::Duration::from_secs(0));
+    assert!(result.min <= result.median);
+    assert!(result.points_per_second.is_finite() && result.points_per_second > 0.0);
+}
+
+/// Builds an octree of 5000 blue points in the directory, whose intensities are 0 to 999, each
+/// occurring five times, and returns the intensities in input order.
+fn build_intensity_octree(directory: &Path) -> Vec<f32> {
+    let ply_path = directory.join("points.ply");
+    let num_points: u32 = 5000;
+    let intensities: Vec<f32> = (0..num_points)
+        .map(|i| ((i * 7919) % 1000) as f32)
+        .collect();
+    {
+        let mut writer = PlyNodeWriter::new(&ply_path, Encoding::Plain, OpenMode::Truncate);
+        let batch = PointsBatch {
+            position: (0..num_points)
+                .map(|i| Point3::new(f64::from(i % 100), f64::from(i / 100), 0.0))
+                .collect(),
+            attributes: vec![
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(0, 0, 255); num_points as usize]),
+                ),
+                (
+                    "intensity".to_string(),
+                    AttributeData::F32(intensities.clone()),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        };
+        writer.write(&batch).unwrap();
+    }
+    build_octree_from_file(
+        directory.join("octree"),
+        0.01,
+        &ply_path,
+        &["color", "intensity"],
+    );
+    intensities
+}
+
+#[test]
+fn check_top_n() {
+    let tmp_dir = TempDir::new("top_n").unwrap();
+    let intensities = build_intensity_octree(tmp_dir.path());
+    let num_points = intensities.len();
+    let locations = [tmp_dir.path().join("octree").to_string_lossy().into_owned()];
+    let client = PointCloudClientBuilder::new(&locations).build().unwrap();
+
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let mut sorted = intensities;
+    sorted.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    for (n, descending) in &[(10, true), (10, false), (7, true), (num_points + 1, true)] {
+        let top = client.top_n(&query, "intensity", *n, *descending).unwrap();
+        let values: Vec<f32> = top.iter().map(|point| point.value as f32).collect();
+        let expected: Vec<f32> = if *descending {
+            sorted.iter().take(*n).copied().collect()
+        } else {
+            sorted.iter().rev().take(*n).copied().collect()
+        };
+        assert_eq!(values, expected);
+        for point in &top {
+            let intensity = <&Vec<f32>>::try_from(&point.attributes["intensity"]).unwrap();
+            assert_eq!(f64::from(intensity[0]), point.value);
+            assert!(point.attributes.contains_key("color"));
+        }
+    }
+    assert!(client.top_n(&query, "color", 10, true).is_err());
+
+    // Excluding the attribute from all attributes still ranks by it.
+    for attributes in &[vec!["-intensity"], vec!["*", "-intensity"]] {
+        let query = PointQuery {
+            attributes: attributes.clone(),
+            ..Default::default()
+        };
+        let top = client.top_n(&query, "intensity", 3, true).unwrap();
+        let values: Vec<f32> = top.iter().map(|point| point.value as f32).collect();
+        assert_eq!(values, sorted[..3].to_vec());
+        assert!(top
+            .iter()
+            .all(|point| point.attributes.contains_key("color")));
+    }
+}
+
+#[test]
+fn check_attribute_queries_keep_filters() {
+    let tmp_dir = TempDir::new("filtered_attributes").unwrap();
+    let intensities = build_intensity_octree(tmp_dir.path());
+    let locations = [tmp_dir.path().join("octree").to_string_lossy().into_owned()];
+    let client = PointCloudClientBuilder::new(&locations).build().unwrap();
+    let query = PointQuery {
+        attributes: vec!["color", "intensity"],
+        filter_intervals: vec![("intensity", ClosedInterval::new(0.0, 499.0))]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+    let num_filtered = intensities.iter().filter(|i| **i <= 499.0).count();
+
+    // Only the color is requested, but the points are still filtered by their intensity.
+    let colors = client
+        .collect_attribute::<Vector3<u8>>(&query, "color")
+        .unwrap();
+    assert_eq!(colors.len(), num_filtered);
+
+    let stats = client.attribute_stats(&query, &["intensity"]).unwrap();
+    assert_eq!(stats["intensity"].count(), num_filtered as u64);
+    assert_eq!(stats["intensity"].max(), 499.0);
+    // Colors have no statistics.
+    let err = client
+        .attribute_stats(&query, &["intensity", "color"])
+        .unwrap_err();
+    match err.kind() {
+        ErrorKind::InvalidInput(msg) => assert!(msg.contains("'color'")),
+        kind => panic!("Expected InvalidInput, got {:?}", kind),
+    }
+}
+
+#[test]
+fn check_sample() {
+    let tmp_dir = TempDir::new("sample").unwrap();
+    let ply_path = tmp_dir.path().join("points.ply");
+    let num_points: u32 = 20_000;
+    {
+        let mut writer = PlyNodeWriter::new(&ply_path, Encoding::Plain, OpenMode::Truncate);
+        let batch = PointsBatch {
+            position: (0..num_points)
+                .map(|i| Point3::new(f64::from(i % 200), f64::from(i / 200), 0.0))
+                .collect(),
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(0, 0, 255); num_points as usize]),
+            )]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        };
+        writer.write(&batch).unwrap();
+    }
+    let octree_dir = tmp_dir.path().join("octree");
+    build_octree_from_file(&octree_dir, 0.01, &ply_path, &["color"]);
+    let locations = [octree_dir.to_string_lossy().into_owned()];
+    let client = PointCloudClientBuilder::new(&locations)
+        .num_points_per_batch(100)
+        .build()
+        .unwrap();
+
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let sample = client.sample(&query, 1000, 7).unwrap();
+    assert_eq!(sample.position.len(), 1000);
+    assert_eq!(
+        <&Vec<Vector3<u8>>>::try_from(&sample.attributes["color"])
+            .unwrap()
+            .len(),
+        1000
+    );
+    // About a quarter of the points are in each quadrant.
+    for (x, y) in &[(0.0, 0.0), (100.0, 0.0), (0.0, 50.0), (100.0, 50.0)] {
+        let count = sample
+            .position
+            .iter()
+            .filter(|p| p.x >= *x && p.x < x + 100.0 && p.y >= *y && p.y < y + 50.0)
+            .count();
+        assert!(count > 180 && count < 320, "{} points in quadrant", count);
+    }
+    // The same seed gives the same sample.
+    assert_eq!(
+        client.sample(&query, 1000, 7).unwrap().position,
+        sample.position
+    );
+    assert_eq!(
+        client
+            .sample(&query, 2 * num_points as usize, 7)
+            .unwrap()
+            .position
+            .len(),
+        num_points as usize
+    );
+}
+
+#[test]
+fn check_sharded_export() {
+    let args = Arguments::default();
+    let (_, oct, data) = setup_pointcloud(&args);
+    let export_dir = TempDir::new("sharded_export").unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: get_aabb_query(data),
+        ..Default::default()
+    };
+    let manifest = export_sharded(
+        std::slice::from_ref(&oct),
+        &query,
+        export_dir.path(),
+        ExportFormat::Ply,
+        4,
+        args.batch_size,
+    )
+    .unwrap();
+    assert!(!manifest.shards.is_empty() && manifest.shards.len() <= 4);
+    assert_eq!(
+        ShardManifest::from_file(export_dir.path().join(MANIFEST_FILENAME)).unwrap(),
+        manifest
+    );
+
+    let mut points_exported = Vec::new();
+    for shard in &manifest.shards {
+        let mut num_points = 0;
+        for batch in PlyIterator::from_file(export_dir.path().join(&shard.file_name), 1000).unwrap()
+        {
+            num_points += batch.position.len();
+            points_exported.extend(indexed_points(&batch));
+        }
+        assert_eq!(num_points, shard.num_points);
+    }
+    points_exported.sort_unstable_by(|p1, p2| p1.idx.cmp(&p2.idx));
+    let points_oct = query_and_sort(&oct, &query, args.batch_size);
+    assert_eq!(points_exported.len(), points_oct.len());
+    // The shards store the positions as floats relative to the bounding box.
+    assert!(points_exported
+        .iter()
+        .zip(&points_oct)
+        .all(|(p_exported, p_oct)| p_exported.idx == p_oct.idx
+            && (p_exported.pos - p_oct.pos).norm() < 1e-4));
+}
+
+#[test]
+fn check_sharded_export_far_from_origin() {
+    // Like ECEF coordinates, where floats have a precision of meters.
+    let origin = Vector3::new(4_100_000.0, 500_000.0, 4_800_000.0);
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let ply_path = tmp_dir.path().join("points.ply");
+    let num_points: u32 = 1000;
+    let position: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| {
+            Point3::new(
+                f64::from(i % 10),
+                f64::from(i / 10 % 10),
+                f64::from(i / 100),
+            ) * 0.123
+                + origin
+        })
+        .collect();
+    {
+        let mut writer = PlyNodeWriter::new(&ply_path, Encoding::Plain, OpenMode::Truncate);
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(
+                    (0..num_points)
+                        .map(|i| Vector3::new(0, (i >> 8) as u8, i as u8))
+                        .collect(),
+                ),
+            )]
+            .into_iter()
+            .collect(),
+            position: position.clone(),
+            bounding_box: None,
+        };
+        writer.write(&batch).unwrap();
+    }
+    let octree_dir = tmp_dir.path().join("octree");
+    build_octree_from_file(&octree_dir, 0.0001, &ply_path, &["color"]);
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: octree_dir,
+    }))
+    .unwrap();
+
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let export_dir = TempDir::new("sharded_export").unwrap();
+    let manifest = export_sharded(
+        std::slice::from_ref(&octree),
+        &query,
+        export_dir.path(),
+        ExportFormat::Ply,
+        1,
+        100,
+    )
+    .unwrap();
+    let shard_path = export_dir.path().join(&manifest.shards[0].file_name);
+    let header = String::from_utf8_lossy(&std::fs::read(&shard_path).unwrap()).into_owned();
+    let offset = octree.bounding_box().min();
+    assert!(header.contains(&format!(
+        "comment offset: {} {} {}\n",
+        offset.x, offset.y, offset.z
+    )));
+    assert!(header.contains("property float x\n"));
+
+    let mut points_exported: Vec<IndexedPoint> = PlyIterator::from_file(&shard_path, 100)
+        .unwrap()
+        .flat_map(|batch| indexed_points(&batch).collect::<Vec<_>>())
+        .collect();
+    points_exported.sort_unstable_by(|p1, p2| p1.idx.cmp(&p2.idx));
+    assert_eq!(points_exported.len(), position.len());
+    for (exported, original) in points_exported.iter().zip(&position) {
+        assert!((exported.pos - original).norm() < 1e-3);
+    }
+}
+
+#[test]
+fn check_sharded_export_with_boundary_epsilon() {
+    // Most points lie just beyond x = 4, where the children of the root begin, so they end up in
+    // nodes that do not intersect the box, but do intersect the expanded box.
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let ply_path = tmp_dir.path().join("points.ply");
+    let num_points: u32 = 1000;
+    let mut position: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new(4.001, f64::from(i % 40) * 0.1, f64::from(i / 40) * 0.1))
+        .collect();
+    position.push(Point3::origin());
+    position.push(Point3::new(8.0, 8.0, 8.0));
+    {
+        let mut writer = PlyNodeWriter::new(&ply_path, Encoding::Plain, OpenMode::Truncate);
+        let batch = PointsBatch {
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(0, 0, 255); position.len()]),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+            bounding_box: None,
+        };
+        writer.write(&batch).unwrap();
+    }
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        ..Default::default()
+    };
+    let octree_dir = tmp_dir.path().join("octree");
+    build_octree_from_file_with_options(&octree_dir, 0.001, &ply_path, &["color"], &options)
+        .unwrap();
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: octree_dir,
+    }))
+    .unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: PointLocation::Aabb(Aabb::new(Point3::origin(), Point3::new(3.999, 8.0, 8.0))),
+        boundary_epsilon: 0.01,
+        ..Default::default()
+    };
+    let export_dir = TempDir::new("sharded_export").unwrap();
+    let manifest = export_sharded(
+        std::slice::from_ref(&octree),
+        &query,
+        export_dir.path(),
+        ExportFormat::Ply,
+        2,
+        100,
+    )
+    .unwrap();
+    // The cluster and the point at the origin.
+    assert_eq!(manifest.num_points(), num_points as usize + 1);
+}
+
+#[test]
+fn check_stream_point_data() {
+    let tmp_dir = TempDir::new("fan_out").unwrap();
+    let args = Arguments {
+        num_points: 20_000,
+        ..Default::default()
+    };
+    let locations: Vec<String> = (0..3)
+        .map(|seed| {
+            let dir = tmp_dir.path().join(format!("octree_{}", seed));
+            make_octree(&Arguments { seed, ..args }, &dir);
+            dir.to_string_lossy().into_owned()
+        })
+        .collect();
+    let client = PointCloudClientBuilder::new(&locations)
+        .num_points_per_batch(1000)
+        .build()
+        .unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let stream_points = || {
+        let (mut num_points, mut num_errors) = (0, 0);
+        for batch in block_on_stream(client.stream_point_data(&query)) {
+            match batch {
+                Ok(batch) => {
+                    assert!(batch.attributes.contains_key("color"));
+                    num_points += batch.position.len();
+                }
+                Err(_) => num_errors += 1,
+            }
+        }
+        (num_points, num_errors)
+    };
+    assert_eq!(stream_points(), (3 * args.num_points, 0));
+
+    // The root of the second octree can no longer be read, which only ends its own stream.
+    std::fs::remove_file(Path::new(&locations[1]).join("r.xyz")).unwrap();
+    let (num_points, num_errors) = stream_points();
+    assert_eq!(num_errors, 1);
+    assert!(num_points >= 2 * args.num_points && num_points < 3 * args.num_points);
+}
+
 fn check_equality<F>(gen_location: F)
 where
     F: FnOnce(SyntheticData) -> PointLocation,
@@ -134,13 +950,7 @@ where
     for node_id in point_cloud.nodes_in_location(&query.location).into_iter() {
         point_cloud
             .stream_points_for_query_in_node(query, node_id, batch_size, |batch| {
-                let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color")?;
-                let indexed_point_iter = color.iter().zip(batch.position.iter()).map(|(c, p)| {
-                    // Decode the index we encoded in the color
-                    let idx = ((c.x as usize) << 16) + ((c.y as usize) << 8) + c.z as usize;
-                    IndexedPoint { idx, pos: *p }
-                });
-                points.extend(indexed_point_iter);
+                points.extend(indexed_points(&batch));
                 Ok(())
             })
             .unwrap();
@@ -153,6 +963,17 @@ where
     points
 }
 
+fn indexed_points(batch: &PointsBatch) -> impl Iterator<Item = IndexedPoint> + '_ {
+    let color: &Vec<Vector3<u8>> = batch
+        .get_attribute_vec("color")
+        .expect("The batch needs to contain colors.");
+    color.iter().zip(batch.position.iter()).map(|(c, p)| {
+        // Decode the index we encoded in the color
+        let idx = ((c.x as usize) << 16) + ((c.y as usize) << 8) + c.z as usize;
+        IndexedPoint { idx, pos: *p }
+    })
+}
+
 struct IndexedPoint {
     idx: usize,
     pos: Point3<f64>,
@@ -82,6 +82,7 @@ impl Iterator for Points {
         Some(PointsBatch {
             position,
             attributes,
+            bounding_box: None,
         })
     }
 
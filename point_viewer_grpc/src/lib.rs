@@ -25,7 +25,7 @@ use point_viewer::Point;
 pub use point_viewer_grpc_proto_rust::proto;
 pub use point_viewer_grpc_proto_rust::proto_grpc;
 use std::collections::HashMap;
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Write};
 use std::sync::Arc;
 
 pub mod service;
@@ -49,13 +49,46 @@ impl GrpcOctreeDataProvider {
         Ok(GrpcOctreeDataProvider { client, octree_id })
     }
 
+    /// The content hash of the octree on the server, see `Octree::content_hash`. Cached query
+    /// results are still valid as long as it doesn't change.
+    pub fn get_content_hash(&self) -> Result<u64> {
+        let mut req = proto::GetContentHashRequest::new();
+        req.set_octree_id(self.octree_id.clone());
+        let reply = self
+            .client
+            .get_content_hash(&req)
+            .chain_err(|| ErrorKind::Grpc)?;
+        Ok(reply.content_hash)
+    }
+
     pub fn get_points_in_box(
         &self,
         bounding_box: &Aabb,
         mut func: impl FnMut(&[Point]) -> bool,
+    ) -> Result<()> {
+        self.stream_points_in_box(bounding_box, false, |_, points| func(points))
+    }
+
+    /// Like `get_points_in_box`, but the server sends the points level by level, coarse nodes
+    /// first. `func` is called with the level of the node the points are from, which never
+    /// decreases, e.g. to render a coarse version of the points before refining it.
+    pub fn get_points_in_box_refined(
+        &self,
+        bounding_box: &Aabb,
+        func: impl FnMut(u8, &[Point]) -> bool,
+    ) -> Result<()> {
+        self.stream_points_in_box(bounding_box, true, func)
+    }
+
+    fn stream_points_in_box(
+        &self,
+        bounding_box: &Aabb,
+        refine: bool,
+        mut func: impl FnMut(u8, &[Point]) -> bool,
     ) -> Result<()> {
         let mut req = proto::GetPointsInBoxRequest::new();
         req.set_octree_id(self.octree_id.clone());
+        req.set_refine(refine);
         req.mut_bounding_box().mut_min().set_x(bounding_box.min().x);
         req.mut_bounding_box().mut_min().set_y(bounding_box.min().y);
         req.mut_bounding_box().mut_min().set_z(bounding_box.min().z);
@@ -65,34 +98,61 @@ impl GrpcOctreeDataProvider {
         let replies = self
             .client
             .get_points_in_box(&req)
-            .map_err(|_| point_viewer::errors::ErrorKind::Grpc)?;
+            .chain_err(|| ErrorKind::Grpc)?;
 
         let mut points = Vec::new();
         let mut interrupted = false;
         let result = replies
             .for_each(|reply| {
-                let last_num_points = points.len();
-                for (p, color) in reply.positions.iter().zip(reply.colors.iter()) {
-                    points.push(Point {
-                        position: Point3::from(p),
-                        color: Color {
-                            red: color.red,
-                            green: color.green,
-                            blue: color.blue,
-                            alpha: color.alpha,
-                        }
-                        .to_u8(),
-                        intensity: None,
-                    });
+                // The server ends the stream with an empty reply, which has no level.
+                if reply.positions.is_empty() {
+                    return Ok(());
                 }
-
-                if reply.intensities.len() == reply.positions.len() {
-                    for (i, p) in reply.intensities.iter().zip(&mut points[last_num_points..]) {
-                        p.intensity = Some(*i);
-                    }
+                push_points_from_reply(&reply, &mut points);
+                if !func(reply.level as u8, &points) {
+                    interrupted = true;
+                    return Err(grpcio::Error::QueueShutdown);
                 }
+                points.clear();
+                Ok(())
+            })
+            .wait()
+            .chain_err(|| ErrorKind::Grpc);
+        if result.is_err() && !interrupted {
+            result?;
+        }
+        Ok(())
+    }
 
-                if !func(&points) {
+    /// Like `get_points_in_box`, but runs the queries for all bounding boxes in one request. `func`
+    /// is called with the index of the bounding box the points belong to; all points of a box
+    /// are passed before those of the next one.
+    pub fn get_points_in_boxes(
+        &self,
+        bounding_boxes: &[Aabb],
+        mut func: impl FnMut(usize, &[Point]) -> bool,
+    ) -> Result<()> {
+        let mut req = proto::GetPointsBatchedRequest::new();
+        req.set_octree_id(self.octree_id.clone());
+        for bounding_box in bounding_boxes {
+            let mut query = proto::PointsQuery::new();
+            query.set_bounding_box(bounding_box.into());
+            req.mut_queries().push(query);
+        }
+        let replies = self
+            .client
+            .get_points_batched(&req)
+            .chain_err(|| ErrorKind::Grpc)?;
+
+        let mut points = Vec::new();
+        let mut interrupted = false;
+        let result = replies
+            .for_each(|reply| {
+                if reply.last_for_query {
+                    return Ok(());
+                }
+                push_points_from_reply(reply.get_points(), &mut points);
+                if !func(reply.query_index as usize, &points) {
                     interrupted = true;
                     return Err(grpcio::Error::QueueShutdown);
                 }
@@ -100,22 +160,64 @@ impl GrpcOctreeDataProvider {
                 Ok(())
             })
             .wait()
-            .map_err(|_| point_viewer::errors::ErrorKind::Grpc);
+            .chain_err(|| ErrorKind::Grpc);
         if result.is_err() && !interrupted {
             result?;
         }
         Ok(())
     }
+
+    /// Has the server export the points in the bounding box and writes the file to `writer`. No
+    /// bytes are written if there are no points in the box.
+    pub fn export_points_in_box(
+        &self,
+        bounding_box: &Aabb,
+        format: proto::ExportFormat,
+        writer: &mut dyn Write,
+    ) -> Result<()> {
+        let mut req = proto::ExportPointsRequest::new();
+        req.set_octree_id(self.octree_id.clone());
+        req.mut_query().set_bounding_box(bounding_box.into());
+        req.set_format(format);
+        let replies = self
+            .client
+            .export_points(&req)
+            .chain_err(|| ErrorKind::Grpc)?;
+        for reply in replies.wait() {
+            writer.write_all(&reply.chain_err(|| ErrorKind::Grpc)?.chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn push_points_from_reply(reply: &proto::PointsReply, points: &mut Vec<Point>) {
+    let last_num_points = points.len();
+    for (p, color) in reply.positions.iter().zip(reply.colors.iter()) {
+        points.push(Point {
+            position: Point3::from(p),
+            color: Color {
+                red: color.red,
+                green: color.green,
+                blue: color.blue,
+                alpha: color.alpha,
+            }
+            .to_u8(),
+            intensity: None,
+        });
+    }
+
+    if reply.intensities.len() == reply.positions.len() {
+        for (i, p) in reply.intensities.iter().zip(&mut points[last_num_points..]) {
+            p.intensity = Some(*i);
+        }
+    }
 }
 
 impl DataProvider for GrpcOctreeDataProvider {
     fn meta_proto(&self) -> Result<Meta> {
         let mut req = proto::GetMetaRequest::new();
         req.set_octree_id(self.octree_id.clone());
-        let reply = self
-            .client
-            .get_meta(&req)
-            .map_err(|_| point_viewer::errors::ErrorKind::Grpc)?;
+        let reply = self.client.get_meta(&req).chain_err(|| ErrorKind::Grpc)?;
         Ok(reply.meta.unwrap())
     }
 
@@ -130,7 +232,7 @@ impl DataProvider for GrpcOctreeDataProvider {
         let reply = self
             .client
             .get_node_data(&req)
-            .map_err(|_| point_viewer::errors::ErrorKind::Grpc)?;
+            .chain_err(|| ErrorKind::Grpc)?;
         let mut readers = HashMap::<String, Box<dyn Read + Send>>::new();
         for node_attribute in node_attributes {
             let reader: Box<dyn Read + Send> = match *node_attribute {
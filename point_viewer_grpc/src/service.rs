@@ -25,15 +25,19 @@ use nalgebra::{Isometry3, Perspective3, Point3, Quaternion, UnitQuaternion, Vect
 use point_viewer::attributes::AttributeData;
 use point_viewer::data_provider::DataProviderFactory;
 use point_viewer::errors::*;
+use point_viewer::export::{export_sharded, ExportFormat};
 use point_viewer::geometry::{Aabb, Frustum};
-use point_viewer::iterator::{ParallelIterator, PointLocation, PointQuery};
+use point_viewer::iterator::{ParallelIterator, PointCloud, PointLocation, PointQuery};
 use point_viewer::octree::{NodeId, Octree};
-use point_viewer::PointsBatch;
+use point_viewer::{PointsBatch, NUM_POINTS_PER_BATCH};
 use protobuf::Message;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
+use tempdir::TempDir;
 
 struct OctreeServiceData {
     octree: Octree,
@@ -110,6 +114,28 @@ impl proto_grpc::Octree for OctreeService {
         ctx.spawn(f)
     }
 
+    fn get_content_hash(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::GetContentHashRequest,
+        sink: UnarySink<proto::GetContentHashReply>,
+    ) {
+        let service_data = match self.get_service_data(&req.octree_id) {
+            Ok(service_data) => service_data,
+            Err(e) => return send_fail(&ctx, sink, e.to_string()),
+        };
+        let content_hash = match service_data.octree.content_hash() {
+            Ok(content_hash) => content_hash,
+            Err(e) => return send_fail(&ctx, sink, e.to_string()),
+        };
+        let mut resp = proto::GetContentHashReply::new();
+        resp.set_content_hash(content_hash);
+        let f = sink
+            .success(resp)
+            .map_err(move |e| eprintln!("failed to reply {:?}: {:?}", req, e));
+        ctx.spawn(f)
+    }
+
     fn get_points_in_frustum(
         &mut self,
         ctx: RpcContext,
@@ -130,7 +156,7 @@ impl proto_grpc::Octree for OctreeService {
         let view_transform = Isometry3::from_parts(translation.into(), rotation);
         let frustum = Frustum::new(view_transform, perspective.into());
         let location = PointLocation::Frustum(frustum);
-        self.stream_points_back_to_sink(location, &req.octree_id, &ctx, resp)
+        self.stream_points_back_to_sink(location, &req.octree_id, req.refine, &ctx, resp)
     }
 
     fn get_points_in_box(
@@ -149,7 +175,7 @@ impl proto_grpc::Octree for OctreeService {
             )
         };
         let location = PointLocation::Aabb(bounding_box);
-        self.stream_points_back_to_sink(location, &req.octree_id, &ctx, resp)
+        self.stream_points_back_to_sink(location, &req.octree_id, req.refine, &ctx, resp)
     }
 
     fn get_all_points(
@@ -159,155 +185,293 @@ impl proto_grpc::Octree for OctreeService {
         resp: ServerStreamingSink<proto::PointsReply>,
     ) {
         let location = PointLocation::AllPoints;
-        self.stream_points_back_to_sink(location, &req.octree_id, &ctx, resp)
+        self.stream_points_back_to_sink(location, &req.octree_id, req.refine, &ctx, resp)
     }
-}
 
-impl OctreeService {
-    fn stream_points_back_to_sink(
-        &self,
-        location: PointLocation,
-        octree_id: &str,
-        ctx: &RpcContext,
-        resp: ServerStreamingSink<proto::PointsReply>,
+    fn get_points_batched(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::GetPointsBatchedRequest,
+        resp: ServerStreamingSink<proto::BatchedPointsReply>,
     ) {
-        use std::thread;
-
-        let service_data = match self.get_service_data(octree_id) {
+        let service_data = match self.get_service_data(&req.octree_id) {
             Ok(service_data) => service_data,
             Err(e) => return send_fail_stream(&ctx, resp, e.to_string()),
         };
+        let mut locations = Vec::with_capacity(req.queries.len());
+        for query in req.queries.iter() {
+            match query.bounding_box.as_ref() {
+                Some(bounding_box) => locations.push(PointLocation::Aabb(bounding_box.into())),
+                None => {
+                    return send_fail_stream(
+                        &ctx,
+                        resp,
+                        "Every query needs a bounding box.".to_string(),
+                    )
+                }
+            }
+        }
+        stream_back_to_sink(&ctx, resp, move |send| {
+            for (query_index, location) in locations.into_iter().enumerate() {
+                let query_index = query_index as u32;
+                send_points_replies(&service_data.octree, location, false, &mut |points| {
+                    let mut reply = proto::BatchedPointsReply::new();
+                    reply.set_query_index(query_index);
+                    reply.set_points(points);
+                    send(reply);
+                })?;
+                let mut reply = proto::BatchedPointsReply::new();
+                reply.set_query_index(query_index);
+                reply.set_last_for_query(true);
+                send(reply);
+            }
+            Ok(())
+        })
+    }
 
-        // This creates a async-aware (tx, rx) pair that can wake up the event loop when new data
-        // is piped through it.
-
-        // We create a channel with a small buffer, which yields better performance than
-        // fully blocking without requiring a ton of memory. This has not been carefully benchmarked
-        // for best performance though.
-        let buffer_size = 4;
-        let (tx, rx) = mpsc::channel(buffer_size);
-        thread::spawn(move || {
-            // This is the secret sauce connecting an OS thread to a event-based receiver. Calling
-            // wait() on this turns the event aware, i.e. async 'tx' into a blocking 'tx' that will
-            // make this thread block when the event loop is not quick enough with piping out data.
-            let mut tx = tx.wait();
-
-            let mut reply = proto::PointsReply::new();
-            let bytes_per_point = {
-                let initial_proto_size = reply.compute_size();
-                let mut v = point_viewer::proto::Vector3d::new();
-                v.set_x(1.);
-                v.set_y(1.);
-                v.set_z(1.);
-                reply.mut_positions().push(v);
-
-                let mut v = point_viewer::proto::Color::new();
-                v.set_red(1.);
-                v.set_green(1.);
-                v.set_blue(1.);
-                v.set_alpha(1.);
-                reply.mut_colors().push(v);
-
-                reply.mut_intensities().push(1.);
-
-                let final_proto_size = reply.compute_size();
-                reply.mut_positions().clear();
-                reply.mut_colors().clear();
-                reply.mut_intensities().clear();
-                final_proto_size - initial_proto_size
+    fn export_points(
+        &mut self,
+        ctx: RpcContext,
+        req: proto::ExportPointsRequest,
+        resp: ServerStreamingSink<proto::ExportPointsReply>,
+    ) {
+        let service_data = match self.get_service_data(&req.octree_id) {
+            Ok(service_data) => service_data,
+            Err(e) => return send_fail_stream(&ctx, resp, e.to_string()),
+        };
+        let bounding_box = match req.get_query().bounding_box.as_ref() {
+            Some(bounding_box) => Aabb::from(bounding_box),
+            None => {
+                return send_fail_stream(&ctx, resp, "The query needs a bounding box.".to_string())
+            }
+        };
+        let format = match req.format {
+            proto::ExportFormat::PLY => ExportFormat::Ply,
+            proto::ExportFormat::LAS => ExportFormat::Las,
+        };
+        stream_back_to_sink(&ctx, resp, move |send| {
+            let point_query = PointQuery {
+                attributes: vec!["color", "intensity"],
+                location: PointLocation::Aabb(bounding_box),
+                ..Default::default()
             };
+            // The writers need to seek back to the header, so the file is written in full before
+            // it is streamed.
+            let export_dir = TempDir::new("export")?;
+            let manifest = export_sharded(
+                std::slice::from_ref(&service_data.octree),
+                &point_query,
+                export_dir.path(),
+                format,
+                1,
+                NUM_POINTS_PER_BATCH,
+            )?;
+            for shard in &manifest.shards {
+                let mut file = File::open(export_dir.path().join(&shard.file_name))?;
+                let mut chunk = vec![0; EXPORT_CHUNK_SIZE];
+                loop {
+                    let num_bytes = file.read(&mut chunk)?;
+                    if num_bytes == 0 {
+                        break;
+                    }
+                    let mut reply = proto::ExportPointsReply::new();
+                    reply.set_chunk(chunk[..num_bytes].to_vec());
+                    send(reply);
+                }
+            }
+            Ok(())
+        })
+    }
+}
 
-            // Proto message must be below 4 MB.
-            let max_message_size = 4 * 1024 * 1024;
-            let num_points_per_batch: usize = max_message_size / bytes_per_point as usize;
-
-            {
-                // Extra scope to make sure that 'func' does not outlive 'reply'.
-                // this function is currently not efficiently implemented
-                let func = |p_data: PointsBatch| {
-                    reply.positions = p_data
-                        .position
-                        .iter()
-                        .map(|p| {
-                            let mut v = point_viewer::proto::Vector3d::new();
-                            v.set_x(p.x);
-                            v.set_y(p.y);
-                            v.set_z(p.z);
-                            v
-                        })
-                        .collect();
-
-                    reply.colors = match p_data.attributes.get(&"color".to_string()) {
-                        Some(AttributeData::U8Vec3(data)) => data
-                            .iter()
-                            .map(|p| {
-                                let rgb8: Color<u8> = crate::Color {
-                                    red: p.x,
-                                    green: p.y,
-                                    blue: p.z,
-                                    alpha: 255,
-                                };
-                                let rgb32: Color<f32> = crate::Color::to_f32(rgb8);
-                                let mut v = point_viewer::proto::Color::new();
-                                v.set_red(rgb32.red);
-                                v.set_green(rgb32.green);
-                                v.set_blue(rgb32.blue);
-                                v.set_alpha(rgb32.alpha);
-                                v
-                            })
-                            .collect(),
-                        _ => {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                "Color format is not u8",
-                            )
-                            .into());
-                        }
-                    };
+/// We create channels with a small buffer, which yields better performance than fully blocking
+/// without requiring a ton of memory. This has not been carefully benchmarked for best
+/// performance though.
+const BUFFER_SIZE: usize = 4;
+
+/// The number of bytes of an exported file per reply, well below the maximum message size.
+const EXPORT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Calls `produce` on a new thread and streams the replies it sends back to the sink. If it fails,
+/// the call is cancelled after the replies sent so far.
+fn stream_back_to_sink<T, F>(ctx: &RpcContext, resp: ServerStreamingSink<T>, produce: F)
+where
+    T: Send + 'static,
+    F: FnOnce(&mut dyn FnMut(T)) -> Result<()> + Send + 'static,
+{
+    // This creates a async-aware (tx, rx) pair that can wake up the event loop when new data
+    // is piped through it.
+    let (tx, rx) = mpsc::channel(BUFFER_SIZE);
+    std::thread::spawn(move || {
+        // This is the secret sauce connecting an OS thread to a event-based receiver. Calling
+        // wait() on this turns the event aware, i.e. async 'tx' into a blocking 'tx' that will
+        // make this thread block when the event loop is not quick enough with piping out data.
+        let mut tx = tx.wait();
+        let result = produce(&mut |reply| tx.send(Ok((reply, WriteFlags::default()))).unwrap());
+        if let Err(e) = result {
+            let status = RpcStatus::new(RpcStatusCode::Internal, Some(e.to_string()));
+            tx.send(Err(grpcio::Error::RpcFailure(status))).unwrap();
+        }
+    });
+
+    let rx = rx
+        .map_err(|_| grpcio::Error::RemoteStopped)
+        .and_then(|reply| reply);
+    let f = resp
+        .send_all(rx)
+        .map(|_| {})
+        .map_err(|e| eprintln!("failed to reply: {:?}", e));
+    ctx.spawn(f)
+}
+
+/// Sends the color and intensity of the points of the octree in `location` in replies that stay
+/// below the maximum message size. With `refine`, the nodes are read one after the other in order
+/// of their level, and the replies carry it, instead of reading them in parallel. An error stops
+/// the replies and is returned, so that the call fails.
+fn send_points_replies(
+    octree: &Octree,
+    location: PointLocation,
+    refine: bool,
+    send: &mut dyn FnMut(proto::PointsReply),
+) -> Result<()> {
+    let mut reply = proto::PointsReply::new();
+    let bytes_per_point = {
+        let initial_proto_size = reply.compute_size();
+        let mut v = point_viewer::proto::Vector3d::new();
+        v.set_x(1.);
+        v.set_y(1.);
+        v.set_z(1.);
+        reply.mut_positions().push(v);
+
+        let mut v = point_viewer::proto::Color::new();
+        v.set_red(1.);
+        v.set_green(1.);
+        v.set_blue(1.);
+        v.set_alpha(1.);
+        reply.mut_colors().push(v);
+
+        reply.mut_intensities().push(1.);
+
+        let final_proto_size = reply.compute_size();
+        reply.mut_positions().clear();
+        reply.mut_colors().clear();
+        reply.mut_intensities().clear();
+        final_proto_size - initial_proto_size
+    };
 
-                    reply.intensities = match p_data.attributes.get(&"intensity".to_string()) {
-                        Some(AttributeData::F32(data)) => data.clone(),
-                        _ => {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                "Intensity format is not f32",
-                            )
-                            .into());
-                        }
+    // Proto message must be below 4 MB.
+    let max_message_size = 4 * 1024 * 1024;
+    let num_points_per_batch: usize = max_message_size / bytes_per_point as usize;
+
+    // this function is currently not efficiently implemented
+    let mut func = |level: u32, p_data: PointsBatch| {
+        reply.positions = p_data
+            .position
+            .iter()
+            .map(|p| {
+                let mut v = point_viewer::proto::Vector3d::new();
+                v.set_x(p.x);
+                v.set_y(p.y);
+                v.set_z(p.z);
+                v
+            })
+            .collect();
+
+        reply.colors = match p_data.attributes.get(&"color".to_string()) {
+            Some(AttributeData::U8Vec3(data)) => data
+                .iter()
+                .map(|p| {
+                    let rgb8: Color<u8> = crate::Color {
+                        red: p.x,
+                        green: p.y,
+                        blue: p.z,
+                        alpha: 255,
                     };
+                    let rgb32: Color<f32> = crate::Color::to_f32(rgb8);
+                    let mut v = point_viewer::proto::Color::new();
+                    v.set_red(rgb32.red);
+                    v.set_green(rgb32.green);
+                    v.set_blue(rgb32.blue);
+                    v.set_alpha(rgb32.alpha);
+                    v
+                })
+                .collect(),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Color format is not u8",
+                )
+                .into());
+            }
+        };
 
-                    tx.send((reply.clone(), WriteFlags::default())).unwrap();
-                    reply.mut_positions().clear();
-                    reply.mut_colors().clear();
-                    reply.mut_intensities().clear();
-                    Ok(())
-                };
-
-                let octree_slice: &[Octree] = std::slice::from_ref(&service_data.octree);
-                let point_query = PointQuery {
-                    attributes: vec!["color", "intensity"],
-                    location,
-                    ..Default::default()
-                };
-                let mut parallel_iterator = ParallelIterator::new(
-                    octree_slice,
-                    &point_query,
-                    num_points_per_batch,
-                    std::cmp::max(1, num_cpus::get() - 1),
-                    buffer_size,
-                );
-                // TODO(catevita): missing error handling for the thread
-                let _result = parallel_iterator.try_for_each_batch(func);
+        reply.intensities = match p_data.attributes.get(&"intensity".to_string()) {
+            Some(AttributeData::F32(data)) => data.clone(),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Intensity format is not f32",
+                )
+                .into());
             }
-            tx.send((reply, WriteFlags::default())).unwrap();
+        };
+
+        reply.set_level(level);
+        send(reply.clone());
+        reply.mut_positions().clear();
+        reply.mut_colors().clear();
+        reply.mut_intensities().clear();
+        Ok(())
+    };
+
+    let octree_slice: &[Octree] = std::slice::from_ref(octree);
+    let point_query = PointQuery {
+        attributes: vec!["color", "intensity"],
+        location,
+        ..Default::default()
+    };
+    if refine {
+        let mut node_ids = octree.nodes_in_location(&point_query.culling_location());
+        node_ids.sort_by_key(|node_id| node_id.level());
+        return node_ids.into_iter().try_for_each(|node_id| {
+            let level = u32::from(node_id.level());
+            octree.stream_points_for_query_in_node(
+                &point_query,
+                node_id,
+                num_points_per_batch,
+                |batch| func(level, batch),
+            )
         });
+    }
+    let mut parallel_iterator = ParallelIterator::new(
+        octree_slice,
+        &point_query,
+        num_points_per_batch,
+        std::cmp::max(1, num_cpus::get() - 1),
+        BUFFER_SIZE,
+    );
+    parallel_iterator.try_for_each_batch(|batch| func(0, batch))?;
+    Ok(())
+}
 
-        let rx = rx.map_err(|_| grpcio::Error::RemoteStopped);
-        let f = resp
-            .send_all(rx)
-            .map(|_| {})
-            .map_err(|e| eprintln!("failed to reply: {:?}", e));
-        ctx.spawn(f)
+impl OctreeService {
+    fn stream_points_back_to_sink(
+        &self,
+        location: PointLocation,
+        octree_id: &str,
+        refine: bool,
+        ctx: &RpcContext,
+        resp: ServerStreamingSink<proto::PointsReply>,
+    ) {
+        let service_data = match self.get_service_data(octree_id) {
+            Ok(service_data) => service_data,
+            Err(e) => return send_fail_stream(&ctx, resp, e.to_string()),
+        };
+        stream_back_to_sink(ctx, resp, move |send| {
+            send_points_replies(&service_data.octree, location, refine, send)?;
+            send(proto::PointsReply::new());
+            Ok(())
+        })
     }
 
     fn get_service_data(&self, octree_id: &str) -> Result<Arc<OctreeServiceData>> {
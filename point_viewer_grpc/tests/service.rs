@@ -0,0 +1,221 @@
+use grpcio::Server;
+use nalgebra::{Point3, Vector3};
+use point_viewer::attributes::AttributeData;
+use point_viewer::data_provider::DataProviderFactory;
+use point_viewer::geometry::Aabb;
+use point_viewer::octree::{build_octree_with_options, BuildOptions};
+use point_viewer::read_write::PlyIterator;
+use point_viewer::{NumberOfPoints, PointsBatch};
+use point_viewer_grpc::proto::ExportFormat;
+use point_viewer_grpc::service::start_grpc_server;
+use point_viewer_grpc::GrpcOctreeDataProvider;
+use std::convert::TryInto;
+use tempdir::TempDir;
+
+struct SingleBatch(Option<PointsBatch>);
+
+impl Iterator for SingleBatch {
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        self.0.take()
+    }
+}
+
+impl NumberOfPoints for SingleBatch {
+    fn num_points(&self) -> usize {
+        self.0.as_ref().map_or(0, |batch| batch.position.len())
+    }
+}
+
+/// Builds an octree with points at x = 0, ..., num_points - 1 as octree "points" in `directory`.
+fn build_line_octree(directory: &std::path::Path, num_points: usize) {
+    build_line_octree_with_options(directory, num_points, &BuildOptions::default());
+}
+
+fn build_line_octree_with_options(
+    directory: &std::path::Path,
+    num_points: usize,
+    options: &BuildOptions,
+) {
+    let batch = PointsBatch {
+        position: (0..num_points)
+            .map(|i| Point3::new(i as f64, 0.0, 0.0))
+            .collect(),
+        attributes: vec![
+            (
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+            ),
+            (
+                "intensity".to_string(),
+                AttributeData::F32(vec![1.0; num_points]),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        bounding_box: None,
+    };
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(num_points as f64, 1.0, 1.0));
+    build_octree_with_options(
+        directory.join("points"),
+        1.0,
+        bounding_box,
+        SingleBatch(Some(batch)),
+        &["color", "intensity"],
+        options,
+    )
+    .unwrap();
+}
+
+/// Starts a server for the octree "points" in `directory` and connects a client to it.
+fn start_server(directory: &std::path::Path) -> (Server, GrpcOctreeDataProvider) {
+    let mut server = start_grpc_server("127.0.0.1", 0, directory, DataProviderFactory::new());
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let client =
+        GrpcOctreeDataProvider::from_address(&format!("127.0.0.1:{}/points", port)).unwrap();
+    (server, client)
+}
+
+#[test]
+fn test_get_points_batched() {
+    let tmp_dir = TempDir::new("batched_queries").unwrap();
+    build_line_octree(tmp_dir.path(), 100);
+    let (_server, client) = start_server(tmp_dir.path());
+    let boxes = [
+        Aabb::new(Point3::new(-0.5, -1.0, -1.0), Point3::new(9.5, 1.0, 1.0)),
+        Aabb::new(Point3::new(59.5, -1.0, -1.0), Point3::new(79.5, 1.0, 1.0)),
+    ];
+    let mut num_points = vec![0; boxes.len()];
+    let mut last_query_index = 0;
+    client
+        .get_points_in_boxes(&boxes, |query_index, points| {
+            assert!(query_index >= last_query_index);
+            last_query_index = query_index;
+            for point in points {
+                assert!(boxes[query_index].contains(&point.position));
+            }
+            num_points[query_index] += points.len();
+            true
+        })
+        .unwrap();
+    assert_eq!(num_points, vec![10, 20]);
+}
+
+#[test]
+fn test_export_points() {
+    let tmp_dir = TempDir::new("export_points").unwrap();
+    build_line_octree(tmp_dir.path(), 100);
+    let (_server, client) = start_server(tmp_dir.path());
+
+    let bounding_box = Aabb::new(Point3::new(19.5, -1.0, -1.0), Point3::new(29.5, 1.0, 1.0));
+    let mut ply = Vec::new();
+    client
+        .export_points_in_box(&bounding_box, ExportFormat::PLY, &mut ply)
+        .unwrap();
+    let ply_path = tmp_dir.path().join("export.ply");
+    std::fs::write(&ply_path, ply).unwrap();
+
+    let mut xs = Vec::new();
+    for batch in PlyIterator::from_file(&ply_path, 100).unwrap() {
+        let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+        assert!(color.iter().all(|c| *c == Vector3::new(255, 0, 0)));
+        xs.extend(batch.position.iter().map(|p| p.x.round() as i64));
+    }
+    xs.sort_unstable();
+    assert_eq!(xs, (20..30).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_export_points_las() {
+    let tmp_dir = TempDir::new("export_points_las").unwrap();
+    build_line_octree(tmp_dir.path(), 100);
+    let (_server, client) = start_server(tmp_dir.path());
+
+    let bounding_box = Aabb::new(Point3::new(19.5, -1.0, -1.0), Point3::new(29.5, 1.0, 1.0));
+    let mut las = Vec::new();
+    client
+        .export_points_in_box(&bounding_box, ExportFormat::LAS, &mut las)
+        .unwrap();
+
+    let read_f64 = |offset: usize| f64::from_le_bytes(las[offset..offset + 8].try_into().unwrap());
+    let read_u32 = |offset: usize| u32::from_le_bytes(las[offset..offset + 4].try_into().unwrap());
+    assert_eq!(&las[..4], b"LASF");
+    let points_start = read_u32(96) as usize;
+    let record_length = usize::from(u16::from_le_bytes([las[105], las[106]]));
+    let num_points = read_u32(107) as usize;
+    assert_eq!(las.len(), points_start + num_points * record_length);
+    let (scale_x, offset_x) = (read_f64(131), read_f64(155));
+    let mut xs: Vec<i64> = (0..num_points)
+        .map(|i| {
+            let x = read_u32(points_start + i * record_length) as i32;
+            (f64::from(x) * scale_x + offset_x).round() as i64
+        })
+        .collect();
+    xs.sort_unstable();
+    assert_eq!(xs, (20..30).collect::<Vec<_>>());
+    // The red of the first point, scaled to 16 bit.
+    let red = &las[points_start + 20..points_start + 22];
+    assert_eq!(u16::from_le_bytes([red[0], red[1]]), u16::MAX);
+}
+
+#[test]
+fn test_get_points_in_box_refined() {
+    let tmp_dir = TempDir::new("refined_query").unwrap();
+    let options = BuildOptions {
+        max_points_per_node: 20,
+        ..Default::default()
+    };
+    build_line_octree_with_options(tmp_dir.path(), 1000, &options);
+    let (_server, client) = start_server(tmp_dir.path());
+
+    let bounding_box = Aabb::new(Point3::new(99.5, -1.0, -1.0), Point3::new(699.5, 1.0, 1.0));
+    let mut levels = Vec::new();
+    let mut xs = Vec::new();
+    client
+        .get_points_in_box_refined(&bounding_box, |level, points| {
+            levels.push(level);
+            xs.extend(points.iter().map(|point| point.position.x.round() as i64));
+            true
+        })
+        .unwrap();
+    assert!(levels.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(levels.first() < levels.last());
+    xs.sort_unstable();
+    assert_eq!(xs, (100..700).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_get_points_in_box_refined_fails_on_errors() {
+    let tmp_dir = TempDir::new("refined_query_error").unwrap();
+    // The replies need intensities, which this octree does not have.
+    let num_points = 100;
+    let batch = PointsBatch {
+        position: (0..num_points)
+            .map(|i| Point3::new(i as f64, 0.0, 0.0))
+            .collect(),
+        attributes: vec![(
+            "color".to_string(),
+            AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+        )]
+        .into_iter()
+        .collect(),
+        bounding_box: None,
+    };
+    build_octree_with_options(
+        tmp_dir.path().join("points"),
+        1.0,
+        Aabb::new(Point3::origin(), Point3::new(num_points as f64, 1.0, 1.0)),
+        SingleBatch(Some(batch)),
+        &["color"],
+        &BuildOptions::default(),
+    )
+    .unwrap();
+    let (_server, client) = start_server(tmp_dir.path());
+
+    let bounding_box = Aabb::new(Point3::new(-0.5, -1.0, -1.0), Point3::new(9.5, 1.0, 1.0));
+    assert!(client
+        .get_points_in_box_refined(&bounding_box, |_, _| true)
+        .is_err());
+}
@@ -0,0 +1,176 @@
+//! Conversion of points into Apache Arrow record batches, e.g. to analyze query results with
+//! DataFusion or Polars.
+
+use crate::errors::*;
+use crate::{AttributeData, AttributeDataType, PointsBatch};
+use arrow::array::{
+    ArrayRef, FixedSizeListArray, Float32Array, Float64Array, Int16Array, Int32Array, Int64Array,
+    Int8Array, UInt16Array, UInt32Array, UInt64Array, UInt8Array,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The names of the columns of the position coordinates, which come first.
+pub const POSITION_COLUMNS: [&str; 3] = ["x", "y", "z"];
+
+fn list_item(data_type: DataType) -> Arc<Field> {
+    Arc::new(Field::new("item", data_type, false))
+}
+
+/// The Arrow type of the column of an attribute. The vector attributes, like color, become fixed
+/// size lists of 3 elements.
+pub fn arrow_data_type(data_type: AttributeDataType) -> DataType {
+    match data_type {
+        AttributeDataType::U8 => DataType::UInt8,
+        AttributeDataType::U16 => DataType::UInt16,
+        AttributeDataType::U32 => DataType::UInt32,
+        AttributeDataType::U64 => DataType::UInt64,
+        AttributeDataType::I8 => DataType::Int8,
+        AttributeDataType::I16 => DataType::Int16,
+        AttributeDataType::I32 => DataType::Int32,
+        AttributeDataType::I64 => DataType::Int64,
+        AttributeDataType::F32 => DataType::Float32,
+        AttributeDataType::F64 => DataType::Float64,
+        AttributeDataType::U8Vec3 => DataType::FixedSizeList(list_item(DataType::UInt8), 3),
+        AttributeDataType::U16Vec3 => DataType::FixedSizeList(list_item(DataType::UInt16), 3),
+        AttributeDataType::F64Vec3 => DataType::FixedSizeList(list_item(DataType::Float64), 3),
+    }
+}
+
+/// The schema of the record batches of points with these attributes: The `POSITION_COLUMNS` as
+/// Float64, followed by a column per attribute, named like the attribute, in alphabetical order.
+pub fn arrow_schema(
+    attribute_data_types: &HashMap<String, AttributeDataType>,
+) -> Result<SchemaRef> {
+    let mut attributes: Vec<(&String, &AttributeDataType)> = attribute_data_types.iter().collect();
+    attributes.sort_unstable_by_key(|(name, _)| *name);
+    let mut fields: Vec<Field> = POSITION_COLUMNS
+        .iter()
+        .map(|name| Field::new(*name, DataType::Float64, false))
+        .collect();
+    for (name, data_type) in attributes {
+        if POSITION_COLUMNS.contains(&name.as_str()) {
+            return Err(ErrorKind::InvalidInput(format!(
+                "The attribute '{}' has the name of a position column.",
+                name
+            ))
+            .into());
+        }
+        fields.push(Field::new(name, arrow_data_type(*data_type), false));
+    }
+    Ok(Arc::new(Schema::new(fields)))
+}
+
+fn vec3_column(item: DataType, values: ArrayRef) -> ArrayRef {
+    Arc::new(FixedSizeListArray::new(list_item(item), 3, values, None))
+}
+
+fn to_column(data: &AttributeData) -> ArrayRef {
+    match data {
+        AttributeData::U8(d) => Arc::new(UInt8Array::from(d.clone())),
+        AttributeData::U16(d) => Arc::new(UInt16Array::from(d.clone())),
+        AttributeData::U32(d) => Arc::new(UInt32Array::from(d.clone())),
+        AttributeData::U64(d) => Arc::new(UInt64Array::from(d.clone())),
+        AttributeData::I8(d) => Arc::new(Int8Array::from(d.clone())),
+        AttributeData::I16(d) => Arc::new(Int16Array::from(d.clone())),
+        AttributeData::I32(d) => Arc::new(Int32Array::from(d.clone())),
+        AttributeData::I64(d) => Arc::new(Int64Array::from(d.clone())),
+        AttributeData::F32(d) => Arc::new(Float32Array::from(d.clone())),
+        AttributeData::F64(d) => Arc::new(Float64Array::from(d.clone())),
+        AttributeData::U8Vec3(d) => {
+            let values: Vec<u8> = d.iter().flat_map(|v| v.iter().copied()).collect();
+            vec3_column(DataType::UInt8, Arc::new(UInt8Array::from(values)))
+        }
+        AttributeData::U16Vec3(d) => {
+            let values: Vec<u16> = d.iter().flat_map(|v| v.iter().copied()).collect();
+            vec3_column(DataType::UInt16, Arc::new(UInt16Array::from(values)))
+        }
+        AttributeData::F64Vec3(d) => {
+            let values: Vec<f64> = d.iter().flat_map(|v| v.iter().copied()).collect();
+            vec3_column(DataType::Float64, Arc::new(Float64Array::from(values)))
+        }
+    }
+}
+
+/// Converts the points into a record batch with the `arrow_schema` of their attributes, e.g. for
+/// every batch of a query. Fails if an attribute does not have a value for every point.
+pub fn to_record_batch(batch: &PointsBatch) -> Result<RecordBatch> {
+    let attribute_data_types = batch
+        .attributes
+        .iter()
+        .map(|(name, data)| (name.clone(), data.data_type()))
+        .collect();
+    let schema = arrow_schema(&attribute_data_types)?;
+    let mut columns: Vec<ArrayRef> = (0..3)
+        .map(|axis| {
+            let coords: Vec<f64> = batch.position.iter().map(|p| p[axis]).collect();
+            Arc::new(Float64Array::from(coords)) as ArrayRef
+        })
+        .collect();
+    // `arrow_schema` sorts the attributes by name, like the map does.
+    columns.extend(batch.attributes.values().map(to_column));
+    RecordBatch::try_new(schema, columns).chain_err(|| "Could not create record batch.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Array;
+    use nalgebra::{Point3, Vector3};
+
+    #[test]
+    fn test_to_record_batch() {
+        let batch = PointsBatch {
+            position: vec![Point3::new(1.0, 2.0, 3.0), Point3::new(-4.0, 5.5, 6.0)],
+            attributes: vec![
+                ("intensity".to_string(), AttributeData::F32(vec![0.5, 7.0])),
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(1, 2, 3), Vector3::new(4, 5, 6)]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        };
+        let record_batch = to_record_batch(&batch).unwrap();
+        assert_eq!(record_batch.num_rows(), 2);
+        let schema = record_batch.schema();
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, vec!["x", "y", "z", "color", "intensity"]);
+        assert_eq!(
+            schema.field(3).data_type(),
+            &arrow_data_type(AttributeDataType::U8Vec3)
+        );
+        assert_eq!(schema.field(4).data_type(), &DataType::Float32);
+
+        let y = record_batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(y.values().to_vec(), vec![2.0, 5.5]);
+        let color = record_batch
+            .column(3)
+            .as_any()
+            .downcast_ref::<FixedSizeListArray>()
+            .unwrap();
+        let second_color = color.value(1);
+        let second_color = second_color.as_any().downcast_ref::<UInt8Array>().unwrap();
+        assert_eq!(second_color.values().to_vec(), vec![4, 5, 6]);
+        let intensity = record_batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<Float32Array>()
+            .unwrap();
+        assert_eq!(intensity.values().to_vec(), vec![0.5, 7.0]);
+
+        let mut clashing = batch;
+        clashing
+            .attributes
+            .insert("x".to_string(), AttributeData::F64(vec![0.0, 1.0]));
+        assert!(to_record_batch(&clashing).is_err());
+    }
+}
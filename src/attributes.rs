@@ -1,10 +1,11 @@
 use crate::errors::{ErrorKind, Result};
 use nalgebra::Vector3;
-use std::convert::TryFrom;
+use serde::{Deserialize, Serialize};
+use std::convert::{TryFrom, TryInto};
 
 pub use point_viewer_proto_rust::proto;
 
-#[derive(Copy, Debug, Clone, PartialEq, Eq)]
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AttributeDataType {
     U8,
     U16,
@@ -17,6 +18,7 @@ pub enum AttributeDataType {
     F32,
     F64,
     U8Vec3,
+    U16Vec3,
     F64Vec3,
 }
 
@@ -34,6 +36,7 @@ impl AttributeDataType {
             AttributeDataType::F32 => proto::AttributeDataType::F32,
             AttributeDataType::F64 => proto::AttributeDataType::F64,
             AttributeDataType::U8Vec3 => proto::AttributeDataType::U8Vec3,
+            AttributeDataType::U16Vec3 => proto::AttributeDataType::U16Vec3,
             AttributeDataType::F64Vec3 => proto::AttributeDataType::F64Vec3,
         }
     }
@@ -51,6 +54,7 @@ impl AttributeDataType {
             proto::AttributeDataType::F32 => AttributeDataType::F32,
             proto::AttributeDataType::F64 => AttributeDataType::F64,
             proto::AttributeDataType::U8Vec3 => AttributeDataType::U8Vec3,
+            proto::AttributeDataType::U16Vec3 => AttributeDataType::U16Vec3,
             proto::AttributeDataType::F64Vec3 => AttributeDataType::F64Vec3,
             proto::AttributeDataType::INVALID_DATA_TYPE => {
                 return Err(
@@ -68,11 +72,52 @@ impl AttributeDataType {
             AttributeDataType::U32 | AttributeDataType::I32 | AttributeDataType::F32 => 4,
             AttributeDataType::U64 | AttributeDataType::I64 | AttributeDataType::F64 => 8,
             AttributeDataType::U8Vec3 => 3,
+            AttributeDataType::U16Vec3 => 3 * 2,
             AttributeDataType::F64Vec3 => 3 * 8,
         }
     }
 }
 
+/// How clients should interpret the values of an attribute, e.g. to label axes or convert times.
+/// This is only informational; the data is stored and queried the same way.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttributeAnnotation {
+    /// E.g. "seconds since GPS epoch".
+    pub unit: Option<String>,
+    /// E.g. "normalized" for an intensity in [0, 1].
+    pub semantic: Option<String>,
+}
+
+impl AttributeAnnotation {
+    pub fn to_proto(&self, name: &str) -> proto::AttributeAnnotation {
+        let mut annotation = proto::AttributeAnnotation::new();
+        annotation.set_name(name.to_string());
+        annotation.set_unit(self.unit.clone().unwrap_or_default());
+        annotation.set_semantic(self.semantic.clone().unwrap_or_default());
+        annotation
+    }
+
+    /// Returns the attribute name and its annotation.
+    pub fn from_proto(annotation: &proto::AttributeAnnotation) -> (String, Self) {
+        let non_empty = |s: &str| Some(s.to_string()).filter(|s| !s.is_empty());
+        (
+            annotation.name.clone(),
+            AttributeAnnotation {
+                unit: non_empty(&annotation.unit),
+                semantic: non_empty(&annotation.semantic),
+            },
+        )
+    }
+}
+
+/// An attribute of a point cloud, as returned by `Octree::attributes`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AttributeDescription {
+    pub name: String,
+    pub data_type: AttributeDataType,
+    pub annotation: AttributeAnnotation,
+}
+
 /// General field to describe point feature attributes such as color, intensity, ...
 #[derive(Debug, Clone)]
 pub enum AttributeData {
@@ -87,9 +132,68 @@ pub enum AttributeData {
     F32(Vec<f32>),
     F64(Vec<f64>),
     U8Vec3(Vec<Vector3<u8>>),
+    U16Vec3(Vec<Vector3<u16>>),
     F64Vec3(Vec<Vector3<f64>>),
 }
 
+/// The values of an attribute as a slice of their type, see `PointsBatch::column`. Unlike the
+/// `TryFrom` conversions of `AttributeData`, matching on this covers every data type at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttributeColumn<'a> {
+    U8(&'a [u8]),
+    U16(&'a [u16]),
+    U32(&'a [u32]),
+    U64(&'a [u64]),
+    I8(&'a [i8]),
+    I16(&'a [i16]),
+    I32(&'a [i32]),
+    I64(&'a [i64]),
+    F32(&'a [f32]),
+    F64(&'a [f64]),
+    U8Vec3(&'a [Vector3<u8>]),
+    U16Vec3(&'a [Vector3<u16>]),
+    F64Vec3(&'a [Vector3<f64>]),
+}
+
+/// A value of an attribute that is stored as little endian bytes.
+trait LeBytes: Sized {
+    fn extend_le_bytes(&self, bytes: &mut Vec<u8>);
+
+    /// `bytes` has the size of the value.
+    fn from_le_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_le_bytes {
+    ($($scalar:ty),*) => {
+        $(impl LeBytes for $scalar {
+            fn extend_le_bytes(&self, bytes: &mut Vec<u8>) {
+                bytes.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn from_le_bytes(bytes: &[u8]) -> Self {
+                <$scalar>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        })*
+    };
+}
+
+impl_le_bytes!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl<T: LeBytes + nalgebra::Scalar> LeBytes for Vector3<T> {
+    fn extend_le_bytes(&self, bytes: &mut Vec<u8>) {
+        self.iter().for_each(|c| c.extend_le_bytes(bytes));
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> Self {
+        let size = bytes.len() / 3;
+        Vector3::new(
+            T::from_le_bytes(&bytes[..size]),
+            T::from_le_bytes(&bytes[size..2 * size]),
+            T::from_le_bytes(&bytes[2 * size..]),
+        )
+    }
+}
+
 // Convenience macro if you want to operate on the Vec inside an AttributeData
 #[macro_export]
 macro_rules! match_attr_data {
@@ -106,6 +210,7 @@ macro_rules! match_attr_data {
             AttributeData::F32(_d) => $match_rhs!(F32, _d $(, $arg )* ),
             AttributeData::F64(_d) => $match_rhs!(F64, _d $(, $arg )* ),
             AttributeData::U8Vec3(_d) => $match_rhs!(U8Vec3, _d $(, $arg )* ),
+            AttributeData::U16Vec3(_d) => $match_rhs!(U16Vec3, _d $(, $arg )* ),
             AttributeData::F64Vec3(_d) => $match_rhs!(F64Vec3, _d $(, $arg )* ),
         }
     };
@@ -126,6 +231,7 @@ macro_rules! match_1d_attr_data {
             AttributeData::F32(_d) => $match_rhs!(F32, _d $(, $arg )* ),
             AttributeData::F64(_d) => $match_rhs!(F64, _d $(, $arg )* ),
             AttributeData::U8Vec3(_d) => unimplemented!(),
+            AttributeData::U16Vec3(_d) => unimplemented!(),
             AttributeData::F64Vec3(_d) => unimplemented!(),
         }
     };
@@ -157,7 +263,7 @@ impl AttributeData {
             | AttributeData::I64(_)
             | AttributeData::F32(_)
             | AttributeData::F64(_) => 1,
-            AttributeData::U8Vec3(_) | AttributeData::F64Vec3(_) => 3,
+            AttributeData::U8Vec3(_) | AttributeData::U16Vec3(_) | AttributeData::F64Vec3(_) => 3,
         }
     }
 
@@ -183,6 +289,7 @@ impl AttributeData {
             (AttributeData::F32(s), AttributeData::F32(o)) => s.append(o),
             (AttributeData::F64(s), AttributeData::F64(o)) => s.append(o),
             (AttributeData::U8Vec3(s), AttributeData::U8Vec3(o)) => s.append(o),
+            (AttributeData::U16Vec3(s), AttributeData::U16Vec3(o)) => s.append(o),
             (AttributeData::F64Vec3(s), AttributeData::F64Vec3(o)) => s.append(o),
             (s, o) => {
                 return Err(format!(
@@ -204,6 +311,71 @@ impl AttributeData {
         match_attr_data!(self, rhs, at)
     }
 
+    /// Returns the elements at the given indices, in that order.
+    pub fn select(&self, indices: &[usize]) -> Self {
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident, $indices:expr) => {
+                AttributeData::$dtype($indices.iter().map(|i| $data[*i]).collect())
+            };
+        }
+        match_attr_data!(self, rhs, indices)
+    }
+
+    pub fn column(&self) -> AttributeColumn<'_> {
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident) => {
+                AttributeColumn::$dtype($data)
+            };
+        }
+        match_attr_data!(self, rhs)
+    }
+
+    /// The values as little endian bytes, with the components of vector types one after the
+    /// other, like in the node files.
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len() * self.data_type().size_of());
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident, $bytes:ident) => {
+                $data.iter().for_each(|v| v.extend_le_bytes(&mut $bytes))
+            };
+        }
+        match_attr_data!(self, rhs, bytes);
+        bytes
+    }
+
+    /// Reverses `to_le_bytes`. Fails if the bytes are not a whole number of values.
+    pub fn from_le_bytes(data_type: AttributeDataType, bytes: &[u8]) -> Result<Self> {
+        let size = data_type.size_of();
+        if bytes.len() % size != 0 {
+            return Err(ErrorKind::Decode(format!(
+                "{} bytes are not a whole number of values of type {:?}.",
+                bytes.len(),
+                data_type
+            ))
+            .into());
+        }
+        macro_rules! values {
+            ($dtype:ident) => {
+                AttributeData::$dtype(bytes.chunks(size).map(LeBytes::from_le_bytes).collect())
+            };
+        }
+        Ok(match data_type {
+            AttributeDataType::U8 => values!(U8),
+            AttributeDataType::U16 => values!(U16),
+            AttributeDataType::U32 => values!(U32),
+            AttributeDataType::U64 => values!(U64),
+            AttributeDataType::I8 => values!(I8),
+            AttributeDataType::I16 => values!(I16),
+            AttributeDataType::I32 => values!(I32),
+            AttributeDataType::I64 => values!(I64),
+            AttributeDataType::F32 => values!(F32),
+            AttributeDataType::F64 => values!(F64),
+            AttributeDataType::U8Vec3 => values!(U8Vec3),
+            AttributeDataType::U16Vec3 => values!(U16Vec3),
+            AttributeDataType::F64Vec3 => values!(F64Vec3),
+        })
+    }
+
     pub fn get(&self, idx: usize) -> Self {
         macro_rules! rhs {
             ($dtype:ident, $data:ident, $idx:expr) => {
@@ -214,6 +386,87 @@ impl AttributeData {
     }
 }
 
+/// Minimum, maximum, mean and standard deviation of the values of a scalar attribute. The values
+/// are accumulated one at a time, without being stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AttrStats {
+    count: u64,
+    min: f64,
+    max: f64,
+    mean: f64,
+    /// The sum of squared differences from the mean, as in Welford's algorithm.
+    m2: f64,
+}
+
+impl Default for AttrStats {
+    fn default() -> Self {
+        AttrStats {
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+}
+
+impl AttrStats {
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Adds all values of the data. Fails for vector attributes like color, which have no such
+    /// statistics.
+    pub fn add_data(&mut self, data: &AttributeData) -> std::result::Result<(), String> {
+        if data.dim() != 1 {
+            return Err(format!(
+                "Statistics are only available for scalar attributes, not for '{:?}'.",
+                data.data_type()
+            ));
+        }
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident, $self:ident) => {
+                $data.iter().for_each(|v| $self.add(*v as f64))
+            };
+        }
+        match_1d_attr_data!(data, rhs, self);
+        Ok(())
+    }
+
+    /// The number of values.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Infinity if there are no values.
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    /// Negative infinity if there are no values.
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// Zero if there are no values.
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    /// The population standard deviation, zero if there are no values.
+    pub fn std(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        (self.m2 / self.count as f64).sqrt()
+    }
+}
+
 macro_rules! try_from_impl {
     ($data:ident, $attribute_data_type:ident, $vec_data_type:ty) => {
         match $data {
@@ -269,4 +522,41 @@ try_from_attribute_data!(I64, i64);
 try_from_attribute_data!(F32, f32);
 try_from_attribute_data!(F64, f64);
 try_from_attribute_data!(U8Vec3, Vector3<u8>);
+try_from_attribute_data!(U16Vec3, Vector3<u16>);
 try_from_attribute_data!(F64Vec3, Vector3<f64>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streamed_stats_match_buffered() {
+        let values: Vec<f32> = (0..1000)
+            .map(|i| 1000.0 + (i as f32 * 0.37).sin() * 5.0)
+            .collect();
+        let mut stats = AttrStats::default();
+        for chunk in values.chunks(64) {
+            stats.add_data(&AttributeData::F32(chunk.to_vec())).unwrap();
+        }
+
+        let values: Vec<f64> = values.into_iter().map(f64::from).collect();
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        let variance =
+            values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / values.len() as f64;
+        assert_eq!(stats.count(), 1000);
+        assert_eq!(
+            stats.min(),
+            values.iter().cloned().fold(f64::INFINITY, f64::min)
+        );
+        assert_eq!(
+            stats.max(),
+            values.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+        );
+        assert!((stats.mean() - mean).abs() < 1e-9);
+        assert!((stats.std() - variance.sqrt()).abs() < 1e-9);
+
+        let color = AttributeData::U8Vec3(vec![Vector3::new(1, 2, 3)]);
+        assert!(stats.add_data(&color).is_err());
+        assert_eq!(stats.count(), 1000);
+    }
+}
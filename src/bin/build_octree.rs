@@ -13,17 +13,50 @@
 // limitations under the License.
 
 use clap::Clap;
-use point_viewer::octree::build_octree_from_file;
+use nalgebra::Point3;
+use point_viewer::geometry::Aabb;
+use point_viewer::octree::{
+    build_octree_from_file_with_options, build_octree_from_reader, estimate_octree,
+    estimate_octree_from_file, AxisConvention, BuildEstimate, BuildOptions,
+};
+use point_viewer::read_write::PlyIterator;
+use point_viewer::NUM_POINTS_PER_BATCH;
 use rayon::ThreadPoolBuilder;
 use std::path::PathBuf;
 
+/// The number of points a dry run looks at.
+const DRY_RUN_SAMPLES: usize = 1_000_000;
+
+fn aabb_from_str(s: &str) -> Result<Aabb, &'static str> {
+    let coords: Result<Vec<f64>, &'static str> = s
+        .split(|c| c == ' ' || c == ',' || c == ';')
+        .map(|s| {
+            s.parse::<f64>()
+                .map_err(|_| "Could not parse bounding box.")
+        })
+        .collect();
+    let coords = coords?;
+    if coords.len() != 6 {
+        return Err("Wrong number of coordinates.");
+    }
+    Ok(Aabb::new(
+        Point3::new(coords[0], coords[1], coords[2]),
+        Point3::new(coords[3], coords[4], coords[5]),
+    ))
+}
+
 #[derive(Clap, Debug)]
 #[clap(name = "build_octree")]
 struct CommandlineArguments {
-    /// PLY/PTS file to parse for the points.
+    /// PLY/PTS file to parse for the points, or '-' to read a binary PLY stream from stdin.
     #[clap(parse(from_os_str))]
     input: PathBuf,
 
+    /// The bounding box of the points as "min_x min_y min_z max_x max_y max_z". Only used, and
+    /// required, when reading from stdin, since the stream cannot be read twice to determine it.
+    #[clap(long, parse(try_from_str = aabb_from_str))]
+    bounding_box: Option<Aabb>,
+
     /// Output directory to write the octree into.
     #[clap(long, parse(from_os_str))]
     output_directory: PathBuf,
@@ -36,6 +69,36 @@ struct CommandlineArguments {
     /// The number of threads used to shard octree building. Set this as high as possible for SSDs.
     #[clap(long, default_value = "10")]
     num_threads: usize,
+
+    /// Only predict the depth, the number of nodes and the disk usage of the octree from a sample
+    /// of the points, without writing anything.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Nodes on this level are not split any further, even if they hold many points. This bounds
+    /// the number of files for dense point clouds.
+    #[clap(long)]
+    max_depth: Option<u8>,
+
+    /// The input is Y-up, and is converted into the Z-up convention of octrees. The bounding box
+    /// is Y-up as well.
+    #[clap(long)]
+    y_up: bool,
+
+    /// Bounds the number of points held in memory per thread, e.g. for inputs that are much
+    /// larger than the memory. Nodes are streamed from disk in batches of at most this size.
+    #[clap(long)]
+    max_points_in_memory: Option<usize>,
+}
+
+fn print_estimate(estimate: &BuildEstimate) {
+    println!("Points: {}", estimate.num_points);
+    println!("Nodes: {}", estimate.num_nodes);
+    println!("Depth: {}", estimate.depth);
+    println!(
+        "Disk usage: {:.1} MiB",
+        estimate.num_bytes as f64 / (1 << 20) as f64
+    );
 }
 
 fn main() {
@@ -44,10 +107,70 @@ fn main() {
         .num_threads(args.num_threads)
         .build_global()
         .expect("Could not create thread pool.");
-    build_octree_from_file(
+    let attributes = &["color", "intensity"];
+    let options = BuildOptions {
+        max_depth: args.max_depth,
+        input_axes: if args.y_up {
+            AxisConvention::YUp
+        } else {
+            AxisConvention::ZUp
+        },
+        max_points_in_memory: args.max_points_in_memory,
+        ..Default::default()
+    };
+    if args.dry_run {
+        let estimate = if args.input.as_os_str() != "-" {
+            estimate_octree_from_file(
+                args.resolution,
+                args.input,
+                attributes,
+                &options,
+                DRY_RUN_SAMPLES,
+            )
+        } else {
+            let bounding_box = args
+                .bounding_box
+                .expect("--bounding-box is required when reading from stdin.");
+            let resolution = args.resolution;
+            PlyIterator::from_reader(std::io::stdin(), NUM_POINTS_PER_BATCH).and_then(
+                |mut stream| {
+                    let estimate = estimate_octree(
+                        resolution,
+                        bounding_box,
+                        &mut stream,
+                        attributes,
+                        &options,
+                        DRY_RUN_SAMPLES,
+                    )?;
+                    stream.into_result()?;
+                    Ok(estimate)
+                },
+            )
+        };
+        print_estimate(&estimate.expect("Could not estimate octree."));
+        return;
+    }
+    if args.input.as_os_str() != "-" {
+        build_octree_from_file_with_options(
+            args.output_directory,
+            args.resolution,
+            args.input,
+            attributes,
+            &options,
+        )
+        .expect("Could not build octree.");
+        return;
+    }
+    let bounding_box = args
+        .bounding_box
+        .expect("--bounding-box is required when reading from stdin.");
+    build_octree_from_reader(
         args.output_directory,
         args.resolution,
-        args.input,
-        &["color", "intensity"],
-    );
+        bounding_box,
+        std::io::stdin(),
+        attributes,
+        &options,
+    )
+    .expect("Could not build octree.");
 }
@@ -0,0 +1,69 @@
+// Copyright 2016 Google Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use clap::Clap;
+use point_viewer::data_provider::OnDiskDataProvider;
+use point_viewer::errors::{Error, ErrorKind};
+use point_viewer::octree::{inspect_node, NodeId, Octree};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+fn node_id_from_str(s: &str) -> Result<NodeId, String> {
+    let is_valid = s.starts_with('r') && s[1..].chars().all(|c| ('0'..='7').contains(&c));
+    if !is_valid || s.len() > 43 {
+        return Err(format!(
+            "'{}' is not a node id, which are 'r' followed by octal digits.",
+            s
+        ));
+    }
+    NodeId::from_str(s).map_err(|err| err.to_string())
+}
+
+#[derive(Clap, Debug)]
+#[clap(name = "octree_inspect")]
+struct CommandlineArguments {
+    /// Directory of the octree.
+    #[clap(parse(from_os_str))]
+    directory: PathBuf,
+    /// The node to inspect, e.g. "r" for the root or "r05" for a node on level 2.
+    #[clap(parse(try_from_str = node_id_from_str))]
+    node_id: NodeId,
+    /// The number of points to print.
+    #[clap(long, default_value = "5")]
+    num_samples: usize,
+}
+
+fn main() {
+    let args = CommandlineArguments::parse();
+    let octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: args.directory.clone(),
+    }))
+    .expect("Could not open octree.");
+    let stdout = std::io::stdout();
+    match inspect_node(&octree, args.node_id, args.num_samples, &mut stdout.lock()) {
+        Ok(()) => (),
+        Err(Error(ErrorKind::NodeNotFound, _)) => {
+            eprintln!(
+                "The octree in {} has no node {}.",
+                args.directory.display(),
+                args.node_id
+            );
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Could not inspect node {}: {}", args.node_id, err);
+            std::process::exit(1);
+        }
+    }
+}
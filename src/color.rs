@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use nalgebra::RealField;
+use crate::math::ClosedInterval;
+use crate::{AttributeData, PointsBatch};
+use nalgebra::{RealField, Vector3};
 use std::iter::Sum;
-use std::ops::{Add, AddAssign, Div};
+use std::ops::{Add, AddAssign, Div, Mul};
 
 // Entries follow GL semantics: they are in [0.; 1.] with 1. being fully saturated.
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
@@ -109,6 +111,22 @@ where
     }
 }
 
+impl<T> Mul<T> for Color<T>
+where
+    T: RealField,
+{
+    type Output = Self;
+
+    fn mul(self, rhs: T) -> Self::Output {
+        Self {
+            red: self.red * rhs,
+            green: self.green * rhs,
+            blue: self.blue * rhs,
+            alpha: self.alpha * rhs,
+        }
+    }
+}
+
 pub const RED: Color<f32> = Color {
     red: 1.,
     green: 0.,
@@ -157,3 +175,100 @@ pub const TRANSPARENT: Color<f32> = Color {
     blue: 1.,
     alpha: 0.,
 };
+
+/// The U16Vec3 attribute of colors with 16 bits per channel, in the order red, green, blue. Octrees
+/// store it besides the 8 bit `color`, which they always need. Querying it from point clouds that
+/// only have 8 bit color fails, unless `PointQuery::upscale_color` is set.
+pub const COLOR16_ATTRIBUTE: &str = "color16";
+
+/// Scales 8 bit to 16 bit color, see `COLOR16_ATTRIBUTE`. 255 becomes 65535.
+pub fn upscale_color(color: &[Vector3<u8>]) -> Vec<Vector3<u16>> {
+    color
+        .iter()
+        .map(|c| c.map(|channel| u16::from(channel) * 257))
+        .collect()
+}
+
+/// The attribute produced by `pack_rgba`. Its `u32` values hold the red, green, blue and alpha
+/// bytes in this order in memory, i.e. they are little-endian, so they can be uploaded as RGBA
+/// textures or vertex data directly.
+pub const RGBA_ATTRIBUTE: &str = "rgba";
+
+/// Packs the `color` and `intensity` attributes of the batch into RGBA values, see
+/// `RGBA_ATTRIBUTE`. The intensities are mapped linearly from `intensity_range` to the alpha
+/// range and clamped. Without intensity, alpha is 255.
+pub fn pack_rgba(
+    batch: &PointsBatch,
+    intensity_range: &ClosedInterval<f64>,
+) -> std::result::Result<Vec<u32>, String> {
+    let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color")?;
+    let rgba = |c: &Vector3<u8>, alpha: u8| u32::from_le_bytes([c.x, c.y, c.z, alpha]);
+    let intensity = match batch.attributes.get("intensity") {
+        None => return Ok(color.iter().map(|c| rgba(c, 255)).collect()),
+        Some(intensity) => intensity,
+    };
+    let lower_bound = intensity_range.lower_bound();
+    let extent = intensity_range.upper_bound() - lower_bound;
+    let alpha = |intensity: f64| {
+        let normalized = if extent > 0.0 {
+            (intensity - lower_bound) / extent
+        } else if intensity < lower_bound {
+            0.0
+        } else {
+            1.0
+        };
+        (normalized.clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+    match intensity {
+        AttributeData::F32(intensity) => Ok(color
+            .iter()
+            .zip(intensity)
+            .map(|(c, i)| rgba(c, alpha(f64::from(*i))))
+            .collect()),
+        AttributeData::F64(intensity) => Ok(color
+            .iter()
+            .zip(intensity)
+            .map(|(c, i)| rgba(c, alpha(*i)))
+            .collect()),
+        other => Err(format!(
+            "Attribute 'intensity' has data type '{:?}', but a float is needed.",
+            other.data_type()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Point3;
+
+    fn batch_with_colors(intensity: Option<Vec<f32>>) -> PointsBatch {
+        let color = vec![Vector3::new(10, 20, 30), Vector3::new(255, 128, 0)];
+        let mut attributes: std::collections::BTreeMap<_, _> =
+            vec![("color".to_string(), AttributeData::U8Vec3(color))]
+                .into_iter()
+                .collect();
+        if let Some(intensity) = intensity {
+            attributes.insert("intensity".to_string(), AttributeData::F32(intensity));
+        }
+        PointsBatch {
+            position: vec![Point3::origin(); 2],
+            attributes,
+            bounding_box: None,
+        }
+    }
+
+    #[test]
+    fn test_pack_rgba() {
+        let batch = batch_with_colors(Some(vec![150.0, 500.0]));
+        let rgba = pack_rgba(&batch, &ClosedInterval::new(100.0, 300.0)).unwrap();
+        let bytes: Vec<[u8; 4]> = rgba.iter().map(|v| v.to_le_bytes()).collect();
+        // 150 is a quarter into the range, 500 is clamped to its end.
+        assert_eq!(bytes, vec![[10, 20, 30, 64], [255, 128, 0, 255]]);
+
+        let batch = batch_with_colors(None);
+        let rgba = pack_rgba(&batch, &ClosedInterval::new(0.0, 1.0)).unwrap();
+        let bytes: Vec<[u8; 4]> = rgba.iter().map(|v| v.to_le_bytes()).collect();
+        assert_eq!(bytes, vec![[10, 20, 30, 255], [255, 128, 0, 255]]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Conversion between the octree and the S2 cells representation of a point cloud, and rebuilding
+//! of octrees, without having to go back to the raw input data.
+
+use crate::errors::*;
+use crate::iterator::{PointCloud, PointLocation};
+use crate::octree::{build_octree_with_options, BuildOptions, Octree};
+use crate::read_write::{Encoding, NodeIterator, NodeWriter, OpenMode, RawNodeWriter, S2Splitter};
+use crate::s2_cells::S2Cells;
+use crate::{NumberOfPoints, PointsBatch, META_FILENAME, NUM_POINTS_PER_BATCH};
+use protobuf::Message;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Streams all points of one or several point clouds, node by node. The first node that can not be
+/// read ends the iteration, see `into_result`.
+pub struct PointCloudBatches<'a, C: PointCloud> {
+    attributes: &'a [&'a str],
+    nodes: std::vec::IntoIter<(&'a C, C::Id)>,
+    node_iterator: NodeIterator,
+    num_points: usize,
+    error: Option<Error>,
+}
+
+impl<'a, C: PointCloud> PointCloudBatches<'a, C> {
+    pub fn new(point_clouds: &'a [C], attributes: &'a [&'a str]) -> Self {
+        let nodes: Vec<_> = point_clouds
+            .iter()
+            .flat_map(|point_cloud| {
+                std::iter::repeat(point_cloud)
+                    .zip(point_cloud.nodes_in_location(&PointLocation::AllPoints))
+            })
+            .collect();
+        Self {
+            attributes,
+            nodes: nodes.into_iter(),
+            node_iterator: NodeIterator::default(),
+            num_points: point_clouds.iter().map(PointCloud::num_points).sum(),
+            error: None,
+        }
+    }
+
+    /// Fails with the error of the node that ended the iteration early, if any.
+    pub fn into_result(self) -> Result<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+}
+
+impl<'a, C: PointCloud> NumberOfPoints for PointCloudBatches<'a, C> {
+    fn num_points(&self) -> usize {
+        self.num_points
+    }
+}
+
+impl<'a, 'b, C: PointCloud> NumberOfPoints for &'b mut PointCloudBatches<'a, C> {
+    fn num_points(&self) -> usize {
+        self.num_points
+    }
+}
+
+impl<'a, C: PointCloud> Iterator for PointCloudBatches<'a, C> {
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        loop {
+            if let Some(batch) = self.node_iterator.next() {
+                return Some(batch);
+            }
+            if self.error.is_some() {
+                return None;
+            }
+            let (point_cloud, node_id) = self.nodes.next()?;
+            match point_cloud.points_in_node(self.attributes, node_id, NUM_POINTS_PER_BATCH, None) {
+                Ok(node_iterator) => self.node_iterator = node_iterator,
+                Err(e) => {
+                    let message = format!("Could not read node {}.", node_id.to_string());
+                    self.error = Some(Error::with_chain(e, message));
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Writes all points of the octree with the given attributes as S2 cells of `split_level` into
+/// `output_directory`. The attribute data types are taken over from the octree.
+pub fn octree_to_s2_cells(
+    octree: &Octree,
+    output_directory: impl AsRef<Path>,
+    split_level: u64,
+    attributes: &[&str],
+) -> Result<()> {
+    let output_directory = output_directory.as_ref();
+    fs::create_dir_all(output_directory)?;
+
+    let mut s2_writer: S2Splitter<RawNodeWriter> = S2Splitter::with_split_level(
+        split_level,
+        output_directory,
+        Encoding::Plain,
+        OpenMode::Truncate,
+    );
+    let mut batches = PointCloudBatches::new(std::slice::from_ref(octree), attributes);
+    for batch in &mut batches {
+        s2_writer.write(&batch)?;
+    }
+    batches.into_result()?;
+    // Consuming the splitter drops and thereby flushes all cell writers.
+    let meta = s2_writer
+        .get_meta()
+        .ok_or("The octree does not contain any points.")?
+        .to_proto();
+    let mut meta_writer = BufWriter::new(File::create(output_directory.join(META_FILENAME))?);
+    meta.write_to_writer(&mut meta_writer)
+        .chain_err(|| "Could not write meta proto.")?;
+    meta_writer
+        .flush()
+        .chain_err(|| "Could not write meta proto.")
+}
+
+/// Builds an octree with the given `resolution` from all points of the S2 cells with the given
+/// attributes into `output_directory`, which keep their data types.
+pub fn s2_cells_to_octree(
+    s2_cells: &S2Cells,
+    output_directory: impl AsRef<Path>,
+    resolution: f64,
+    attributes: &[&str],
+) -> Result<()> {
+    build_octree_from_point_clouds(
+        std::slice::from_ref(s2_cells),
+        output_directory,
+        resolution,
+        attributes,
+        &BuildOptions::default(),
+    )
+}
+
+/// Builds a new octree from all points of existing point clouds, e.g. to change the resolution or
+/// the node capacity of an octree without the raw input data. The bounding box of the new octree
+/// encloses all of the point clouds. The attributes keep the data types of the inputs, so custom
+/// attributes are rebuilt as well. Fails if an attribute is missing in an input, or if the inputs
+/// disagree on its data type.
+pub fn build_octree_from_point_clouds<C: PointCloud>(
+    point_clouds: &[C],
+    output_directory: impl AsRef<Path>,
+    resolution: f64,
+    attributes: &[&str],
+    options: &BuildOptions,
+) -> Result<()> {
+    let mut bounding_box = point_clouds
+        .first()
+        .ok_or("No point clouds to build an octree from.")?
+        .bounding_box()
+        .clone();
+    for point_cloud in point_clouds {
+        bounding_box.grow(*point_cloud.bounding_box().min());
+        bounding_box.grow(*point_cloud.bounding_box().max());
+    }
+
+    // The octree stores the attributes with the data types of the inputs, which must agree.
+    let mut attribute_data_types = HashMap::new();
+    for attribute in attributes {
+        let mut data_types = point_clouds
+            .iter()
+            .map(|point_cloud| point_cloud.attribute_data_types().get(*attribute).copied());
+        let data_type = data_types.next().flatten();
+        match data_type {
+            Some(data_type) if data_types.all(|other| other == Some(data_type)) => {
+                attribute_data_types.insert(attribute.to_string(), data_type);
+            }
+            _ => {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "Attribute '{}' is missing in or has different data types across the inputs.",
+                    attribute
+                ))
+                .into())
+            }
+        }
+    }
+    let options = BuildOptions {
+        attribute_data_types,
+        ..options.clone()
+    };
+
+    let mut batches = PointCloudBatches::new(point_clouds, attributes);
+    let result = build_octree_with_options(
+        output_directory,
+        resolution,
+        bounding_box,
+        &mut batches,
+        attributes,
+        &options,
+    );
+    // A node that could not be read ended the input early, which is the real cause of any error.
+    batches.into_result()?;
+    result
+}
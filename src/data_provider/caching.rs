@@ -0,0 +1,180 @@
+use crate::data_provider::DataProvider;
+use crate::errors::*;
+use crate::proto;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+
+/// The data of one attribute of one node, shared between the cache and its readers.
+#[derive(Clone)]
+struct SharedBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+type CacheKey = (String, String);
+
+struct NodeCache {
+    /// The budget is in bytes, not entries, so the cache itself is unbounded.
+    entries: LruCache<CacheKey, SharedBytes>,
+    num_bytes: usize,
+}
+
+impl NodeCache {
+    fn new() -> Self {
+        NodeCache {
+            entries: LruCache::unbounded(),
+            num_bytes: 0,
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<SharedBytes> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: CacheKey, bytes: SharedBytes, max_bytes: usize) {
+        if bytes.0.len() > max_bytes || self.entries.contains(&key) {
+            return;
+        }
+        while self.num_bytes + bytes.0.len() > max_bytes {
+            let (_, evicted) = self.entries.pop_lru().unwrap();
+            self.num_bytes -= evicted.0.len();
+        }
+        self.num_bytes += bytes.0.len();
+        self.entries.put(key, bytes);
+    }
+}
+
+/// Keeps the data of recently read nodes in memory, up to a budget, and reads everything else
+/// from the wrapped data provider.
+pub struct CachingDataProvider {
+    data_provider: Box<dyn DataProvider>,
+    max_bytes: usize,
+    cache: Mutex<NodeCache>,
+}
+
+impl CachingDataProvider {
+    /// Caches at most `max_bytes` of node data. The least recently used data is evicted first.
+    pub fn new(data_provider: Box<dyn DataProvider>, max_bytes: usize) -> Self {
+        CachingDataProvider {
+            data_provider,
+            max_bytes,
+            cache: Mutex::new(NodeCache::new()),
+        }
+    }
+
+    /// The number of bytes of node data currently in the cache.
+    pub fn cached_bytes(&self) -> usize {
+        self.cache.lock().unwrap().num_bytes
+    }
+}
+
+impl DataProvider for CachingDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        self.data_provider.meta_proto()
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        let mut data = HashMap::new();
+        let mut missing = Vec::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for attribute in node_attributes {
+                let key = (node_id.to_string(), attribute.to_string());
+                match cache.get(&key) {
+                    Some(bytes) => {
+                        data.insert(attribute.to_string(), bytes);
+                    }
+                    None => missing.push(*attribute),
+                }
+            }
+        }
+        if !missing.is_empty() {
+            // The cache is not locked while reading, so that other nodes can be served meanwhile.
+            let mut read = Vec::new();
+            for (attribute, mut reader) in self.data_provider.data(node_id, &missing)? {
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf)?;
+                read.push((attribute, SharedBytes(Arc::new(buf))));
+            }
+            let mut cache = self.cache.lock().unwrap();
+            for (attribute, bytes) in read {
+                let key = (node_id.to_string(), attribute.clone());
+                cache.insert(key, bytes.clone(), self.max_bytes);
+                data.insert(attribute, bytes);
+            }
+        }
+        Ok(data
+            .into_iter()
+            .map(|(attribute, bytes)| {
+                let reader: Box<dyn Read + Send> = Box::new(Cursor::new(bytes));
+                (attribute, reader)
+            })
+            .collect())
+    }
+
+    fn approx_memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.cached_bytes() + self.data_provider.approx_memory_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves 100 bytes per attribute.
+    struct ConstantDataProvider;
+
+    impl DataProvider for ConstantDataProvider {
+        fn meta_proto(&self) -> Result<proto::Meta> {
+            unimplemented!()
+        }
+
+        fn data(
+            &self,
+            _node_id: &str,
+            node_attributes: &[&str],
+        ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+            Ok(node_attributes
+                .iter()
+                .map(|attribute| {
+                    let reader: Box<dyn Read + Send> = Box::new(Cursor::new(vec![7u8; 100]));
+                    (attribute.to_string(), reader)
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_cache_stays_within_budget() {
+        let caching = CachingDataProvider::new(Box::new(ConstantDataProvider), 250);
+        for node_id in &["r0", "r1", "r0", "r2", "r0"] {
+            let mut readers = caching.data(node_id, &["position"]).unwrap();
+            let mut buf = Vec::new();
+            readers
+                .remove("position")
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            assert_eq!(buf, vec![7u8; 100]);
+            assert!(caching.cached_bytes() <= 250);
+        }
+        // Reading r2 evicted r1, the least recently used node, but r0 was always cached.
+        assert_eq!(caching.cached_bytes(), 200);
+        let cache = caching.cache.lock().unwrap();
+        assert!(cache
+            .entries
+            .contains(&("r0".to_string(), "position".to_string())));
+        assert!(!cache
+            .entries
+            .contains(&("r1".to_string(), "position".to_string())));
+    }
+}
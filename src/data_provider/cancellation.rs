@@ -0,0 +1,136 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag shared between a query and whoever may want to cancel it, e.g. a viewer whose camera
+/// moved on. Clones refer to the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Fails every read once the token is cancelled, cutting long reads short at the next call.
+pub struct CancellableRead<R> {
+    reader: R,
+    cancellation: CancellationToken,
+}
+
+impl<R> CancellableRead<R> {
+    pub fn new(reader: R, cancellation: CancellationToken) -> Self {
+        CancellableRead {
+            reader,
+            cancellation,
+        }
+    }
+}
+
+impl<R: Read> Read for CancellableRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancellation.is_cancelled() {
+            // Not `Interrupted`, which callers like `read_exact` retry.
+            return Err(io::Error::other("Read was cancelled."));
+        }
+        self.reader.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_provider::DataProvider;
+    use crate::errors::*;
+    use crate::proto;
+    use crate::read_write::{Encoding, NodeIterator};
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Delivers a single byte per read, slowly, like a congested network.
+    struct SlowRead;
+
+    impl Read for SlowRead {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(5));
+            match buf.first_mut() {
+                Some(byte) => {
+                    *byte = 0;
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    struct SlowDataProvider;
+
+    impl DataProvider for SlowDataProvider {
+        fn meta_proto(&self) -> Result<proto::Meta> {
+            unimplemented!()
+        }
+
+        fn data(
+            &self,
+            _node_id: &str,
+            node_attributes: &[&str],
+        ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+            Ok(node_attributes
+                .iter()
+                .map(|attribute| {
+                    let reader: Box<dyn Read + Send> = Box::new(SlowRead);
+                    (attribute.to_string(), reader)
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_cancellation_aborts_slow_read() {
+        let cancellation = CancellationToken::new();
+        // Reading a single batch would take minutes.
+        let mut node_iterator = NodeIterator::from_data_provider(
+            &SlowDataProvider,
+            &HashMap::new(),
+            Encoding::Plain,
+            &"r",
+            100_000,
+            1000,
+            Some(&cancellation),
+        )
+        .unwrap();
+        let canceller = {
+            let cancellation = cancellation.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                cancellation.cancel();
+            })
+        };
+        let start = Instant::now();
+        // The failed read is reported, so that the node is not taken for a shorter one.
+        match node_iterator.try_next() {
+            Err(Error(ErrorKind::Cancelled, _)) => (),
+            _ => panic!("Expected the read of a cancelled node to fail."),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(node_iterator.count(), 0);
+        canceller.join().unwrap();
+
+        match SlowDataProvider.data_cancellable("r", &["position"], &cancellation) {
+            Err(Error(ErrorKind::Cancelled, _)) => (),
+            _ => panic!("Expected the read of a cancelled query to fail."),
+        }
+    }
+}
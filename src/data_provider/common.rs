@@ -1,3 +1,4 @@
+use crate::data_provider::{CancellableRead, CancellationToken};
 use crate::errors::*;
 use crate::proto;
 use std::collections::HashMap;
@@ -5,9 +6,40 @@ use std::io::Read;
 
 pub trait DataProvider: Send + Sync {
     fn meta_proto(&self) -> Result<proto::Meta>;
+    /// Readers of the data of the node, by attribute. The data of every attribute of a node is
+    /// stored on its own, so that only the requested attributes are read and decoded, e.g. only
+    /// "position" for queries without attributes.
     fn data(
         &self,
         node_id: &str,
         node_attributes: &[&str],
     ) -> Result<HashMap<String, Box<dyn Read + Send>>>;
+
+    /// The approximate number of bytes the provider keeps in memory, e.g. for caches.
+    fn approx_memory_bytes(&self) -> usize {
+        0
+    }
+
+    /// Like `data`, but reading stops with an error once `cancellation` is cancelled. The
+    /// default implementation checks the token before every read of the returned readers.
+    /// Providers that read over the network should override it to also abort requests in flight.
+    fn data_cancellable(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+        cancellation: &CancellationToken,
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        if cancellation.is_cancelled() {
+            return Err(ErrorKind::Cancelled.into());
+        }
+        Ok(self
+            .data(node_id, node_attributes)?
+            .into_iter()
+            .map(|(attribute, reader)| {
+                let reader: Box<dyn Read + Send> =
+                    Box::new(CancellableRead::new(reader, cancellation.clone()));
+                (attribute, reader)
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,93 @@
+use crate::data_provider::{CancellationToken, DataProvider};
+use crate::errors::*;
+use crate::proto;
+use crate::read_write::{decode_attribute, AttributeCodec};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+
+/// Decompresses the node data of the attributes that were built with
+/// `BuildOptions::attribute_codecs`, so that readers get the plain values. `Octree` wraps its data
+/// provider in this if the meta data lists codecs.
+pub struct DecodingDataProvider {
+    data_provider: Box<dyn DataProvider>,
+    codecs: HashMap<String, Vec<AttributeCodec>>,
+    num_points: HashMap<String, usize>,
+}
+
+impl DecodingDataProvider {
+    /// Decodes the attributes with the codecs, by name. Other attributes are passed on. The
+    /// decoded data of a node must not hold more points than `num_points` lists for it, by node
+    /// id, which guards against corrupt data.
+    pub fn new(
+        data_provider: Box<dyn DataProvider>,
+        codecs: HashMap<String, Vec<AttributeCodec>>,
+        num_points: HashMap<String, usize>,
+    ) -> Self {
+        DecodingDataProvider {
+            data_provider,
+            codecs,
+            num_points,
+        }
+    }
+
+    fn decode(
+        &self,
+        node_id: &str,
+        data: HashMap<String, Box<dyn Read + Send>>,
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        let num_points = *self
+            .num_points
+            .get(node_id)
+            .ok_or(ErrorKind::NodeNotFound)?;
+        data.into_iter()
+            .map(
+                |(attribute, mut reader)| match self.codecs.get(&attribute) {
+                    Some(codecs) => {
+                        let mut encoded = Vec::new();
+                        reader.read_to_end(&mut encoded)?;
+                        let decoded = decode_attribute(encoded, codecs, num_points)?;
+                        let reader: Box<dyn Read + Send> = Box::new(Cursor::new(decoded));
+                        Ok((attribute, reader))
+                    }
+                    None => Ok((attribute, reader)),
+                },
+            )
+            .collect()
+    }
+}
+
+impl DataProvider for DecodingDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        self.data_provider.meta_proto()
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        self.decode(node_id, self.data_provider.data(node_id, node_attributes)?)
+    }
+
+    fn approx_memory_bytes(&self) -> usize {
+        self.data_provider.approx_memory_bytes()
+    }
+
+    fn data_cancellable(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+        cancellation: &CancellationToken,
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        let data = self
+            .data_provider
+            .data_cancellable(node_id, node_attributes, cancellation)?;
+        self.decode(node_id, data).map_err(|e| {
+            if cancellation.is_cancelled() {
+                ErrorKind::Cancelled.into()
+            } else {
+                e
+            }
+        })
+    }
+}
@@ -1,7 +1,13 @@
+mod caching;
+mod cancellation;
 mod common;
+mod decoding;
 mod factory;
 mod on_disk;
 
+pub use caching::CachingDataProvider;
+pub use cancellation::{CancellableRead, CancellationToken};
 pub use common::DataProvider;
+pub use decoding::DecodingDataProvider;
 pub use factory::{DataProviderFactory, DataProviderFactoryResult};
 pub use on_disk::OnDiskDataProvider;
@@ -5,8 +5,8 @@ use crate::proto;
 use crate::META_FILENAME;
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{Cursor, Read};
-use std::path::PathBuf;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
 
 pub struct OnDiskDataProvider {
     pub directory: PathBuf,
@@ -21,13 +21,13 @@ impl OnDiskDataProvider {
     // Get number of points from the file size of the color data.
     // Color data is required and always present.
     pub fn number_of_points(&self, node_id: &str) -> Result<i64> {
-        let stem = self.stem(node_id);
-        let file_meta_data_opt = fs::metadata(stem.with_extension(attribute_extension("color")));
-        if file_meta_data_opt.is_err() {
-            return Err(ErrorKind::NodeNotFound.into());
-        }
-
-        let file_size_bytes = file_meta_data_opt.unwrap().len();
+        let path = self
+            .stem(node_id)
+            .with_extension(attribute_extension("color"));
+        let file_size_bytes = match fs::metadata(&path) {
+            Ok(file_meta_data) => file_meta_data.len(),
+            Err(err) => return Err(node_file_error(err, &path)),
+        };
         // color has 3 bytes per point
         Ok((file_size_bytes / 3) as i64)
     }
@@ -40,11 +40,38 @@ impl DataProvider for OnDiskDataProvider {
             return Err(ErrorKind::InvalidVersion(3).into());
         }
 
+        let directory = self.directory.display().to_string();
+        let is_empty_dir = match fs::read_dir(&self.directory) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(ref err) if err.kind() == ::std::io::ErrorKind::NotFound => true,
+            Err(err) => {
+                return Err(Error::with_chain(
+                    err,
+                    ErrorKind::Provider(format!("Could not list the files in '{}'.", directory)),
+                ))
+            }
+        };
+        if is_empty_dir {
+            return Err(ErrorKind::PointCloudNotFound(directory).into());
+        }
+        // The meta data is written last, so without it the build did not finish.
         let mut data = Vec::new();
-        File::open(&self.directory.join(META_FILENAME))?.read_to_end(&mut data)?;
+        match File::open(&self.directory.join(META_FILENAME)) {
+            Err(ref err) if err.kind() == ::std::io::ErrorKind::NotFound => {
+                return Err(ErrorKind::IncompleteBuild(directory).into());
+            }
+            file => file
+                .and_then(|mut file| file.read_to_end(&mut data))
+                .chain_err(|| {
+                    ErrorKind::Provider(format!("Could not read the meta data in '{}'.", directory))
+                })?,
+        };
+        if data.is_empty() {
+            return Err(ErrorKind::IncompleteBuild(directory).into());
+        }
         Ok(
             protobuf::parse_from_reader::<proto::Meta>(&mut Cursor::new(data))
-                .chain_err(|| format!("Could not parse {}", META_FILENAME))?,
+                .chain_err(|| ErrorKind::IncompleteBuild(directory))?,
         )
     }
 
@@ -56,14 +83,21 @@ impl DataProvider for OnDiskDataProvider {
         let stem = self.stem(node_id);
         let mut readers = HashMap::<String, Box<dyn Read + Send>>::new();
         for node_attribute in node_attributes {
-            let file = match File::open(&stem.with_extension(attribute_extension(node_attribute))) {
-                Err(ref err) if err.kind() == ::std::io::ErrorKind::NotFound => {
-                    return Err(ErrorKind::NodeNotFound.into());
-                }
-                e => e,
-            }?;
+            let path = stem.with_extension(attribute_extension(node_attribute));
+            let file = File::open(&path).map_err(|err| node_file_error(err, &path))?;
             readers.insert((*node_attribute).to_string(), Box::new(file));
         }
         Ok(readers)
     }
 }
+
+/// A missing file means that the node does not exist, other errors that it can't be read. Both
+/// keep the IO error as their cause.
+fn node_file_error(err: io::Error, path: &Path) -> Error {
+    let kind = if err.kind() == io::ErrorKind::NotFound {
+        ErrorKind::NodeNotFound
+    } else {
+        ErrorKind::Provider(format!("Could not access '{}'.", path.display()))
+    };
+    Error::with_chain(err, kind)
+}
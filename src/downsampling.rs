@@ -0,0 +1,215 @@
+//! Downsampling of point batches to one point per voxel, e.g. to thin out overlapping scans.
+
+use crate::geometry::Aabb;
+use crate::{AttributeData, PointsBatch};
+use nalgebra::{Point3, Vector3};
+use num_traits::{NumCast, ToPrimitive};
+use std::collections::BTreeMap;
+
+/// How the attribute values of the points in a voxel are combined into one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blending {
+    /// The mean of all values.
+    Mean,
+    /// The median of the values, per channel for vector attributes like color. Resists outliers,
+    /// e.g. from sensors that disagree on the color of a surface.
+    Median,
+    /// The mean of the values left after discarding the given fraction of the lowest and of the
+    /// highest values, e.g. 0.25 for the interquartile mean. Per channel for vector attributes.
+    TrimmedMean(f64),
+}
+
+impl Blending {
+    /// Combines the values, which may be reordered in the process.
+    fn blend(self, values: &mut [f64]) -> f64 {
+        let sorted = |values: &mut [f64]| {
+            values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        };
+        let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+        match self {
+            Blending::Mean => mean(values),
+            Blending::Median => {
+                sorted(values);
+                // For an odd number of values, both indices are the same.
+                let len = values.len();
+                0.5 * (values[(len - 1) / 2] + values[len / 2])
+            }
+            Blending::TrimmedMean(fraction) => {
+                let num_trimmed = (values.len() as f64 * fraction).floor() as usize;
+                if 2 * num_trimmed >= values.len() {
+                    return Blending::Median.blend(values);
+                }
+                sorted(values);
+                mean(&values[num_trimmed..values.len() - num_trimmed])
+            }
+        }
+    }
+}
+
+/// Blends the values of every voxel, rounding for integer types.
+fn blend_voxels<T>(data: &[T], voxels: &[Vec<usize>], blending: Blending, round: bool) -> Vec<T>
+where
+    T: ToPrimitive + NumCast + Copy,
+{
+    let mut values = Vec::new();
+    voxels
+        .iter()
+        .map(|indices| {
+            values.clear();
+            values.extend(indices.iter().map(|i| data[*i].to_f64().unwrap()));
+            let value = blending.blend(&mut values);
+            T::from(if round { value.round() } else { value }).unwrap()
+        })
+        .collect()
+}
+
+fn blend_vec3_voxels<T>(
+    data: &[Vector3<T>],
+    voxels: &[Vec<usize>],
+    blending: Blending,
+    round: bool,
+) -> Vec<Vector3<T>>
+where
+    T: ToPrimitive + NumCast + Copy + PartialEq + std::fmt::Debug + 'static,
+{
+    let channels: Vec<Vec<T>> = (0..3)
+        .map(|c| {
+            let channel: Vec<T> = data.iter().map(|v| v[c]).collect();
+            blend_voxels(&channel, voxels, blending, round)
+        })
+        .collect();
+    (0..voxels.len())
+        .map(|i| Vector3::new(channels[0][i], channels[1][i], channels[2][i]))
+        .collect()
+}
+
+/// Returns one point per cube of edge length `voxel_size` that contains points of the batch. Its
+/// position is the mean of the positions of these points, its attributes are blended according
+/// to `blending`. The voxels are aligned with the origin.
+pub fn voxel_downsample(batch: &PointsBatch, voxel_size: f64, blending: Blending) -> PointsBatch {
+    assert!(voxel_size > 0.0, "The voxel size must be positive.");
+    // BTreeMap for deterministic output order.
+    let mut voxel_map: BTreeMap<(i64, i64, i64), Vec<usize>> = BTreeMap::new();
+    for (i, pos) in batch.position.iter().enumerate() {
+        let index = |coord: f64| (coord / voxel_size).floor() as i64;
+        voxel_map
+            .entry((index(pos.x), index(pos.y), index(pos.z)))
+            .or_default()
+            .push(i);
+    }
+    let voxels: Vec<Vec<usize>> = voxel_map.into_values().collect();
+
+    let position: Vec<Point3<f64>> = voxels
+        .iter()
+        .map(|indices| {
+            let sum = indices
+                .iter()
+                .fold(Vector3::zeros(), |sum, i| sum + batch.position[*i].coords);
+            Point3::from(sum / indices.len() as f64)
+        })
+        .collect();
+    let attributes = batch
+        .attributes
+        .iter()
+        .map(|(name, data)| {
+            let blended = match data {
+                AttributeData::U8(d) => AttributeData::U8(blend_voxels(d, &voxels, blending, true)),
+                AttributeData::U16(d) => {
+                    AttributeData::U16(blend_voxels(d, &voxels, blending, true))
+                }
+                AttributeData::U32(d) => {
+                    AttributeData::U32(blend_voxels(d, &voxels, blending, true))
+                }
+                AttributeData::U64(d) => {
+                    AttributeData::U64(blend_voxels(d, &voxels, blending, true))
+                }
+                AttributeData::I8(d) => AttributeData::I8(blend_voxels(d, &voxels, blending, true)),
+                AttributeData::I16(d) => {
+                    AttributeData::I16(blend_voxels(d, &voxels, blending, true))
+                }
+                AttributeData::I32(d) => {
+                    AttributeData::I32(blend_voxels(d, &voxels, blending, true))
+                }
+                AttributeData::I64(d) => {
+                    AttributeData::I64(blend_voxels(d, &voxels, blending, true))
+                }
+                AttributeData::F32(d) => {
+                    AttributeData::F32(blend_voxels(d, &voxels, blending, false))
+                }
+                AttributeData::F64(d) => {
+                    AttributeData::F64(blend_voxels(d, &voxels, blending, false))
+                }
+                AttributeData::U8Vec3(d) => {
+                    AttributeData::U8Vec3(blend_vec3_voxels(d, &voxels, blending, true))
+                }
+                AttributeData::U16Vec3(d) => {
+                    AttributeData::U16Vec3(blend_vec3_voxels(d, &voxels, blending, true))
+                }
+                AttributeData::F64Vec3(d) => {
+                    AttributeData::F64Vec3(blend_vec3_voxels(d, &voxels, blending, false))
+                }
+            };
+            (name.clone(), blended)
+        })
+        .collect();
+    let bounding_box = batch
+        .bounding_box
+        .as_ref()
+        .and_then(|_| Aabb::from_points(&position));
+    PointsBatch {
+        position,
+        attributes,
+        bounding_box,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Five points in the voxel at the origin, one of them with an outlier color and intensity,
+    /// and a single point in another voxel.
+    fn batch_with_outlier() -> PointsBatch {
+        let mut position = vec![Point3::new(0.5, 0.5, 0.5); 5];
+        position.push(Point3::new(1.5, 0.5, 0.5));
+        let mut color = vec![Vector3::new(100, 120, 140); 4];
+        color.push(Vector3::new(255, 0, 0));
+        color.push(Vector3::new(10, 20, 30));
+        let intensity = vec![1.0, 1.1, 0.9, 1.0, 50.0, 2.0];
+        PointsBatch {
+            position,
+            attributes: vec![
+                ("color".to_string(), AttributeData::U8Vec3(color)),
+                ("intensity".to_string(), AttributeData::F32(intensity)),
+            ]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        }
+    }
+
+    #[test]
+    fn test_robust_blending_ignores_outlier() {
+        let batch = batch_with_outlier();
+        for blending in &[Blending::Median, Blending::TrimmedMean(0.2)] {
+            let downsampled = voxel_downsample(&batch, 1.0, *blending);
+            assert_eq!(downsampled.position.len(), 2);
+            let color: &Vec<Vector3<u8>> = downsampled.get_attribute_vec("color").unwrap();
+            assert_eq!(
+                color,
+                &vec![Vector3::new(100, 120, 140), Vector3::new(10, 20, 30)]
+            );
+            let intensity: &Vec<f32> = downsampled.get_attribute_vec("intensity").unwrap();
+            assert!((intensity[0] - 1.0).abs() < 0.1, "{:?}", blending);
+            assert_eq!(intensity[1], 2.0);
+        }
+    }
+
+    #[test]
+    fn test_mean_blending() {
+        let downsampled = voxel_downsample(&batch_with_outlier(), 1.0, Blending::Mean);
+        let color: &Vec<Vector3<u8>> = downsampled.get_attribute_vec("color").unwrap();
+        assert_eq!(color[0], Vector3::new(131, 96, 112));
+        assert_eq!(downsampled.position[0], Point3::new(0.5, 0.5, 0.5));
+    }
+}
@@ -39,10 +39,40 @@ error_chain! {
             description("The node does not exist.")
         }
 
+        Decode(msg: String) {
+            description("The stored data is malformed.")
+            display("{}", msg)
+        }
+
+        InvalidQuery(msg: String) {
+            description("The query is invalid or not supported by the point cloud.")
+            display("{}", msg)
+        }
+
+        Provider(msg: String) {
+            description("The data provider could not access the stored data.")
+            display("{}", msg)
+        }
+
+        PointCloudNotFound(path: String) {
+            description("There is no point cloud at the location.")
+            display("There is no point cloud at '{}'.", path)
+        }
+
+        IncompleteBuild(path: String) {
+            description("The point cloud was not completely written.")
+            display("The point cloud at '{}' is incomplete, its meta data is missing or truncated. \
+            It may still be being built.", path)
+        }
+
         Grpc {
             description("Grpc request failed")
         }
 
+        Cancelled {
+            description("The operation was cancelled.")
+        }
+
         Channel(msg: String) {
             description("The current channel failed an operation")
             display("{}", msg)
@@ -50,3 +80,14 @@ error_chain! {
 
     }
 }
+
+impl Error {
+    /// Whether the requested node or point cloud does not exist, as opposed to existing but
+    /// failing to be read.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::NodeNotFound | ErrorKind::PointCloudNotFound(_)
+        )
+    }
+}
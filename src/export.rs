@@ -0,0 +1,167 @@
+//! Parallel export of query results into several standalone files.
+
+use crate::errors::*;
+use crate::iterator::{queue_jobs, steal_job, PointCloud, PointQuery};
+use crate::read_write::{Encoding, LasNodeWriter, NodeWriter, OpenMode, PlyNodeWriter};
+use crate::PointsBatch;
+use crossbeam::deque::Worker;
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+/// The name of the file listing the shards of an export.
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// One output file of a sharded export.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    /// The file name, relative to the output directory.
+    pub file_name: String,
+    pub num_points: usize,
+}
+
+/// Lists the shards of an export. Shards that did not receive any points are omitted.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardManifest {
+    pub shards: Vec<Shard>,
+}
+
+impl ShardManifest {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path).chain_err(|| "Could not open manifest.")?;
+        serde_json::from_reader(file).chain_err(|| "Could not parse manifest.")
+    }
+
+    pub fn num_points(&self) -> usize {
+        self.shards.iter().map(|shard| shard.num_points).sum()
+    }
+}
+
+/// The file format of the shards of an export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Ply,
+    Las,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Ply => "ply",
+            ExportFormat::Las => "las",
+        }
+    }
+}
+
+/// Writes one shard in the format of the export.
+enum ShardWriter {
+    Ply(PlyNodeWriter),
+    Las(LasNodeWriter),
+}
+
+impl ShardWriter {
+    fn new(path: &Path, format: ExportFormat, offset: Vector3<f64>) -> Result<Self> {
+        Ok(match format {
+            ExportFormat::Ply => ShardWriter::Ply(
+                PlyNodeWriter::new(path, Encoding::Plain, OpenMode::Truncate).with_offset(offset),
+            ),
+            ExportFormat::Las => ShardWriter::Las(LasNodeWriter::new(path)?.with_offset(offset)),
+        })
+    }
+
+    fn write(&mut self, batch: &PointsBatch) -> Result<()> {
+        match self {
+            ShardWriter::Ply(writer) => writer.write(batch)?,
+            ShardWriter::Las(writer) => writer.write(batch)?,
+        }
+        Ok(())
+    }
+}
+
+/// Writes the points matching the query into up to `num_shards` files of the `format` in
+/// `output_directory`, each one written by its own thread. Every shard is a valid file on its
+/// own, and the shards are listed in a `MANIFEST_FILENAME` file, which is also returned. Which
+/// point ends up in which shard is not deterministic. The positions are stored relative to the
+/// minimum of the bounding boxes of the point clouds, which is written to the header of every
+/// shard, see `PlyNodeWriter::with_offset` and `LasNodeWriter::with_offset`.
+pub fn export_sharded<C: PointCloud>(
+    point_clouds: &[C],
+    point_query: &PointQuery,
+    output_directory: impl AsRef<Path>,
+    format: ExportFormat,
+    num_shards: usize,
+    batch_size: usize,
+) -> Result<ShardManifest> {
+    assert!(num_shards > 0, "At least one shard is needed.");
+    let output_directory = output_directory.as_ref();
+    fs::create_dir_all(output_directory)?;
+    let offset = point_clouds
+        .iter()
+        .map(|point_cloud| point_cloud.bounding_box().min().coords)
+        .fold(None, |offset: Option<Vector3<f64>>, min| {
+            Some(offset.map_or(min, |offset| offset.inf(&min)))
+        })
+        .unwrap_or_else(Vector3::zeros);
+
+    let jobs = queue_jobs(point_clouds, &point_query.culling_location());
+
+    let shard_results = crossbeam::scope(|s| {
+        let handles: Vec<_> = (0..num_shards)
+            .map(|shard_index| {
+                let jobs = &jobs;
+                s.spawn(move |_| -> Result<Shard> {
+                    let file_name = format!("shard_{}.{}", shard_index, format.extension());
+                    let path = output_directory.join(&file_name);
+                    // The writer is only created on the first points, so that no empty, and hence
+                    // invalid, files are left behind.
+                    let mut writer: Option<ShardWriter> = None;
+                    let mut num_points = 0;
+                    let worker = Worker::new_fifo();
+                    while let Some((index, node_id)) = steal_job(jobs, &worker) {
+                        point_clouds[index].stream_points_for_query_in_node(
+                            point_query,
+                            node_id,
+                            batch_size,
+                            |batch| {
+                                if batch.position.is_empty() {
+                                    return Ok(());
+                                }
+                                if writer.is_none() {
+                                    writer = Some(ShardWriter::new(&path, format, offset)?);
+                                }
+                                writer.as_mut().unwrap().write(&batch)?;
+                                num_points += batch.position.len();
+                                Ok(())
+                            },
+                        )?;
+                    }
+                    // Dropping the writer finalizes the header.
+                    drop(writer);
+                    Ok(Shard {
+                        file_name,
+                        num_points,
+                    })
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Export thread panicked."))
+            .collect::<Vec<_>>()
+    })
+    .expect("Export thread panicked.");
+
+    let mut manifest = ShardManifest::default();
+    for shard in shard_results {
+        let shard = shard?;
+        if shard.num_points > 0 {
+            manifest.shards.push(shard);
+        }
+    }
+    let manifest_writer = BufWriter::new(File::create(output_directory.join(MANIFEST_FILENAME))?);
+    serde_json::to_writer_pretty(manifest_writer, &manifest)
+        .chain_err(|| "Could not write manifest.")?;
+    Ok(manifest)
+}
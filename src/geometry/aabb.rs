@@ -47,6 +47,18 @@ impl Aabb {
         nalgebra::partial_le(&self.mins, p) && nalgebra::partial_lt(p, &self.maxs)
     }
 
+    /// The box contained in both boxes, or `None` if they are disjoint. The intersection of boxes
+    /// that only touch is flat.
+    pub fn intersection(&self, other: &Aabb) -> Option<Aabb> {
+        let mins = self.mins.sup(&other.mins);
+        let maxs = self.maxs.inf(&other.maxs);
+        if nalgebra::partial_le(&mins, &maxs) {
+            Some(Aabb { mins, maxs })
+        } else {
+            None
+        }
+    }
+
     pub fn center(&self) -> Point3<f64> {
         nalgebra::center(&self.mins, &self.maxs)
     }
@@ -55,6 +67,22 @@ impl Aabb {
         self.maxs - self.mins
     }
 
+    /// The distance of the point to the closest face, relative to the largest such distance: 1
+    /// at the center, 0 on the boundary and outside.
+    pub fn normalized_depth(&self, p: &Point3<f64>) -> f64 {
+        box_normalized_depth(&(p - self.center()), &(self.diag() * 0.5))
+    }
+
+    /// The tight bounding box of the points, or `None` if there are none.
+    pub fn from_points<'a>(points: impl IntoIterator<Item = &'a Point3<f64>>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = *points.next()?;
+        Some(points.fold(Self::new(first, first), |mut aabb, p| {
+            aabb.grow(*p);
+            aabb
+        }))
+    }
+
     pub fn transform(&self, transform: &Isometry3<f64>) -> Aabb {
         let corners = self.compute_corners();
         let transformed_first = transform.transform_point(&corners[0]);
@@ -92,6 +120,16 @@ impl From<&Aabb> for proto::AxisAlignedCuboid {
     }
 }
 
+/// `normalized_depth` for a box centered at the origin, given the offset of the point from it.
+pub(super) fn box_normalized_depth(offset: &Vector3<f64>, half_extent: &Vector3<f64>) -> f64 {
+    let max_depth = half_extent.min();
+    if max_depth <= 0.0 {
+        return 0.0;
+    }
+    let depth = (half_extent - offset.abs()).min();
+    (depth / max_depth).max(0.0)
+}
+
 impl PointCulling for Aabb {
     fn contains(&self, p: &Point3<f64>) -> bool {
         self.contains(p)
@@ -160,6 +198,36 @@ impl Cube {
         Aabb::new(self.min(), self.max())
     }
 
+    /// The smallest cube that contains `aabb`, has an edge length of `cell_size` times a power
+    /// of two and is offset from `grid_origin` by a multiple of its edge length. Octrees built
+    /// with such cubes as roots have aligned node boundaries, and the root of the smaller one is
+    /// a node of the bigger one. All coordinates and the cell size must be finite.
+    pub fn aligned_to_grid(aabb: &Aabb, grid_origin: &Point3<f64>, cell_size: f64) -> Self {
+        assert!(
+            cell_size > 0.0 && cell_size.is_finite(),
+            "The cell size must be positive and finite."
+        );
+        let coords = aabb.min().iter().chain(aabb.max().iter());
+        assert!(
+            coords
+                .chain(grid_origin.iter())
+                .all(|coord| coord.is_finite()),
+            "The box and the grid origin must be finite."
+        );
+        let mut edge_length = cell_size;
+        loop {
+            let min = grid_origin
+                + ((aabb.min() - grid_origin) / edge_length).map(f64::floor) * edge_length;
+            if (aabb.max() - min)
+                .iter()
+                .all(|extent| *extent <= edge_length)
+            {
+                return Cube { min, edge_length };
+            }
+            edge_length *= 2.0;
+        }
+    }
+
     pub fn new(min: Point3<f64>, edge_length: f64) -> Self {
         Cube { min, edge_length }
     }
@@ -190,4 +258,49 @@ impl Cube {
             (min.z + max.z) / 2.,
         )
     }
+
+    /// The position of the point along the Morton (Z-order) curve through the cube, with 21 bits
+    /// per coordinate. The bits are interleaved as in `ChildIndex`, so the 8 children of the cube
+    /// follow each other on the curve. Points outside are clamped to the cube.
+    pub fn morton_code(&self, p: &Point3<f64>) -> u64 {
+        const NUM_BITS: u32 = 21;
+        let max_cell = (1u64 << NUM_BITS) - 1;
+        let cell = |coord: f64, min: f64| {
+            let scaled = ((coord - min) / self.edge_length * (1u64 << NUM_BITS) as f64).floor();
+            (scaled.max(0.0) as u64).min(max_cell)
+        };
+        // Spreads the bits of the value so that there are two zero bits between each of them.
+        let spread = |mut v: u64| {
+            v = (v | v << 32) & 0x001f_0000_0000_ffff;
+            v = (v | v << 16) & 0x001f_0000_ff00_00ff;
+            v = (v | v << 8) & 0x100f_00f0_0f00_f00f;
+            v = (v | v << 4) & 0x10c3_0c30_c30c_30c3;
+            (v | v << 2) & 0x1249_2492_4924_9249
+        };
+        spread(cell(p.x, self.min.x)) << 2
+            | spread(cell(p.y, self.min.y)) << 1
+            | spread(cell(p.z, self.min.z))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_morton_code() {
+        let cube = Cube::new(Point3::origin(), 2.0);
+        assert_eq!(cube.morton_code(&Point3::origin()), 0);
+        // The highest bit of every coordinate selects the child.
+        assert_eq!(cube.morton_code(&Point3::new(1.0, 0.0, 0.0)), 0b100 << 60);
+        assert_eq!(cube.morton_code(&Point3::new(0.0, 1.0, 1.0)), 0b011 << 60);
+        assert_eq!(
+            cube.morton_code(&Point3::new(2.0, 2.0, 2.0)),
+            (1u64 << 63) - 1
+        );
+        // One cell along x is bit 2, along z bit 0.
+        let cell = 2.0 / f64::from(1u32 << 21);
+        assert_eq!(cube.morton_code(&Point3::new(cell, 0.0, 0.0)), 0b100);
+        assert_eq!(cube.morton_code(&Point3::new(0.0, 0.0, cell)), 0b001);
+    }
 }
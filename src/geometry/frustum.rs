@@ -1,10 +1,13 @@
 //! An asymmetric frustum with an arbitrary 3D pose.
 
-use crate::math::base::{HasAabbIntersector, PointCulling};
+use super::aabb::Aabb;
+use super::sphere::Sphere;
+use crate::math::base::{HasAabbIntersector, IntersectAabb, PointCulling};
 use crate::math::sat::{CachedAxesIntersector, ConvexPolyhedron, Intersector};
 use arrayvec::ArrayVec;
-use nalgebra::{Isometry3, Matrix4, Perspective3, Point3, Unit, Vector3};
+use nalgebra::{Isometry3, Matrix4, Perspective3, Point2, Point3, Unit, Vector2, Vector3, Vector4};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 
 /// A perspective projection matrix analogous to cgmath::Perspective.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,6 +56,16 @@ impl Perspective {
         Self { matrix }
     }
 
+    /// Like `new`, but without a far plane, e.g. for rendering distant parts of a scene without
+    /// clipping them.
+    pub fn new_infinite(left: f64, right: f64, bottom: f64, top: f64, near: f64) -> Self {
+        let mut perspective = Self::new(left, right, bottom, top, near, 2.0 * near);
+        // The limits of the coefficients for `far` going to infinity.
+        perspective.matrix[(2, 2)] = -1.0;
+        perspective.matrix[(2, 3)] = -2.0 * near;
+        perspective
+    }
+
     pub fn as_matrix(&self) -> &Matrix4<f64> {
         &self.matrix
     }
@@ -91,7 +104,9 @@ impl From<Perspective3<f64>> for Perspective {
 /// defines a camera coordinate system. To get from OpenCV camera coordinates
 /// to eye coordinates, you need to rotate 180 deg around the x axis before
 /// creating the perspective projection, see also the frustum unit test below.
+/// It is serialized as its `clip_from_query` matrix.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "FrustumSpec", into = "FrustumSpec")]
 pub struct Frustum {
     query_from_clip: Matrix4<f64>,
     clip_from_query: Matrix4<f64>,
@@ -107,7 +122,9 @@ impl Frustum {
         }
     }
 
-    /// Fails if the matrix is not invertible.
+    /// Any invertible projection works, including off-axis and oblique ones or those with an
+    /// infinite far plane, and it may be combined with a non-rigid transform of the query
+    /// coordinates like a shear. Fails if the matrix is not invertible.
     pub fn from_matrix4(clip_from_query: Matrix4<f64>) -> Option<Self> {
         let query_from_clip = clip_from_query.try_inverse()?;
         Some(Self {
@@ -115,19 +132,132 @@ impl Frustum {
             clip_from_query,
         })
     }
+
+    /// The part of the view frustum of `clip_from_query` that projects into the rectangle between
+    /// two corners in pixels, e.g. for rubber-band selection. Pixels are counted from the top left
+    /// corner of a screen of `screen_size` pixels, with y pointing down. Fails if the rectangle is
+    /// empty or the matrix is not invertible.
+    pub fn from_screen_rect(
+        clip_from_query: &Matrix4<f64>,
+        screen_size: &Vector2<f64>,
+        corner_a: &Point2<f64>,
+        corner_b: &Point2<f64>,
+    ) -> Option<Self> {
+        let to_ndc = |p: &Point2<f64>| {
+            Point2::new(
+                2.0 * p.x / screen_size.x - 1.0,
+                1.0 - 2.0 * p.y / screen_size.y,
+            )
+        };
+        let (a, b) = (to_ndc(corner_a), to_ndc(corner_b));
+        let (min, max) = (a.inf(&b), a.sup(&b));
+        let extent = max - min;
+        if !(extent.x > 0.0 && extent.y > 0.0) {
+            return None;
+        }
+        // Maps the rectangle to the whole range of -1 to 1 of normalized device coordinates.
+        let center = nalgebra::center(&min, &max);
+        #[rustfmt::skip]
+        let ndc_from_rect = Matrix4::new(
+            2.0 / extent.x, 0.0,            0.0, -2.0 * center.x / extent.x,
+            0.0,            2.0 / extent.y, 0.0, -2.0 * center.y / extent.y,
+            0.0,            0.0,            1.0, 0.0,
+            0.0,            0.0,            0.0, 1.0,
+        );
+        Self::from_matrix4(ndc_from_rect * clip_from_query)
+    }
+
+    /// The planes of the six sides, extracted from the rows of `clip_from_query`. The far plane of
+    /// a projection with an infinite far plane degenerates and rejects nothing.
+    pub fn planes(&self) -> FrustumPlanes {
+        let m = &self.clip_from_query;
+        let mut planes = [(Vector3::zeros(), 0.0); 6];
+        for (i, plane) in planes.iter_mut().enumerate() {
+            // Inside, -w < x < w in clip coordinates, and the same for y and z.
+            let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            let coefficients = m.row(3) + sign * m.row(i / 2);
+            let normal = Vector3::new(coefficients[0], coefficients[1], coefficients[2]);
+            let norm = normal.norm();
+            // A degenerate plane rejects nothing.
+            if norm > 0.0 {
+                *plane = (normal / norm, coefficients[3] / norm);
+            }
+        }
+        FrustumPlanes { planes }
+    }
+}
+
+/// The planes bounding a frustum, for a test whether a sphere is outside that is much cheaper
+/// than intersecting a box with the frustum.
+#[derive(Debug, Clone)]
+pub struct FrustumPlanes {
+    /// The unit normals point inwards, and together with the offset they give the signed
+    /// distance of a point to the plane.
+    planes: [(Vector3<f64>, f64); 6],
+}
+
+impl FrustumPlanes {
+    /// False if the sphere is completely outside of one of the planes, and thereby of the
+    /// frustum. Spheres outside near edges or corners, but not outside any one plane, pass.
+    pub fn may_intersect_sphere(&self, sphere: &Sphere) -> bool {
+        self.planes.iter().all(|(normal, offset)| {
+            normal.dot(&sphere.center().coords) + offset >= -sphere.radius()
+        })
+    }
+}
+
+/// The serialized form of a `Frustum`, which leaves out the inverse matrix.
+#[derive(Serialize, Deserialize)]
+struct FrustumSpec {
+    clip_from_query: Matrix4<f64>,
+}
+
+impl TryFrom<FrustumSpec> for Frustum {
+    type Error = String;
+
+    fn try_from(spec: FrustumSpec) -> Result<Self, Self::Error> {
+        Frustum::from_matrix4(spec.clip_from_query).ok_or_else(|| {
+            "The clip_from_query matrix of a frustum must be invertible.".to_string()
+        })
+    }
+}
+
+impl From<Frustum> for FrustumSpec {
+    fn from(frustum: Frustum) -> Self {
+        FrustumSpec {
+            clip_from_query: frustum.clip_from_query,
+        }
+    }
 }
 
 impl PointCulling for Frustum {
     fn contains(&self, point: &Point3<f64>) -> bool {
-        let p_clip = self.clip_from_query.transform_point(point);
-        p_clip.coords.min() > -1.0 && p_clip.coords.max() < 1.0
+        // Compared before the division by w, which would let points behind the eye of some
+        // projections through.
+        let p_clip = self.clip_from_query * point.to_homogeneous();
+        let w = p_clip.w;
+        w > 0.0 && p_clip.xyz().iter().all(|c| -w < *c && *c < w)
     }
 }
 
+/// The z in normalized device coordinates of the far corners of a frustum with an infinite far
+/// plane, at a distance of 2e9 times the near distance.
+const INFINITE_FAR_Z: f64 = 1.0 - 1e-9;
+
 impl ConvexPolyhedron for Frustum {
     #[rustfmt::skip]
     fn compute_corners(&self) -> [Point3<f64>; 8] {
-        let corner_from = |x, y, z| self.query_from_clip.transform_point(&Point3::new(x, y, z));
+        let corner_from = |x, y, z| {
+            let corner = self.query_from_clip * Vector4::new(x, y, z, 1.0);
+            // The far corners of a projection with an infinite far plane are at infinity, those of
+            // a plane well beyond any point cloud are used instead.
+            let corner = if corner.w.abs() > 1e-12 * corner.xyz().norm() {
+                corner
+            } else {
+                self.query_from_clip * Vector4::new(x, y, INFINITE_FAR_Z, 1.0)
+            };
+            Point3::from_homogeneous(corner).unwrap()
+        };
         [
             corner_from(-1.0, -1.0, -1.0),
             corner_from(-1.0, -1.0,  1.0),
@@ -168,6 +298,67 @@ impl ConvexPolyhedron for Frustum {
 
 has_aabb_intersector_for_convex_polyhedron!(Frustum);
 
+/// The frustums of a sequence of views, e.g. the frames of a camera path. A point is inside if any
+/// of the frustums contains it, and which ones do is its `mask`. It is serialized as the list of
+/// frustums.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frustums(pub Vec<Frustum>);
+
+impl Frustums {
+    /// At most this many frustums fit into a mask.
+    pub const MAX_LEN: usize = 64;
+
+    /// The bits of the frustums that contain the point, bit i for frustum i.
+    pub fn mask(&self, p: &Point3<f64>) -> u64 {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, frustum)| frustum.contains(p))
+            .fold(0, |mask, (i, _)| mask | 1 << i)
+    }
+}
+
+impl PointCulling for Frustums {
+    fn contains(&self, p: &Point3<f64>) -> bool {
+        self.0.iter().any(|frustum| frustum.contains(p))
+    }
+}
+
+/// Tests boxes against the bounding box of all frustums before testing them against each
+/// frustum, which rejects most boxes far away from the views at once.
+pub struct FrustumsIntersector {
+    hull: Option<Aabb>,
+    intersectors: Vec<CachedAxesIntersector>,
+}
+
+impl IntersectAabb for FrustumsIntersector {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        match &self.hull {
+            Some(hull) if hull.intersection(aabb).is_some() => self
+                .intersectors
+                .iter()
+                .any(|intersector| intersector.intersect_aabb(aabb)),
+            _ => false,
+        }
+    }
+}
+
+impl<'a> HasAabbIntersector<'a> for Frustums {
+    type Intersector = FrustumsIntersector;
+
+    fn aabb_intersector(&'a self) -> Self::Intersector {
+        let corners: Vec<Point3<f64>> = self
+            .0
+            .iter()
+            .flat_map(|frustum| frustum.compute_corners().to_vec())
+            .collect();
+        FrustumsIntersector {
+            hull: Aabb::from_points(&corners),
+            intersectors: self.0.iter().map(Frustum::aabb_intersector).collect(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -203,4 +394,147 @@ mod tests {
             assert_eq!(el_a, el_b);
         }
     }
+
+    #[test]
+    fn test_planes_reject_spheres_outside() {
+        // Looks along the negative z axis.
+        let perspective = nalgebra::Perspective3::new(1.0, 1.0, 1.0, 10.0);
+        let planes = Frustum::new(Isometry3::identity(), perspective.into()).planes();
+        let sphere = |x, y, z, radius| Sphere::new(Point3::new(x, y, z), radius);
+        assert!(planes.may_intersect_sphere(&sphere(0.0, 0.0, -5.0, 0.1)));
+        // Behind the eye, beyond the far plane and to the side.
+        assert!(!planes.may_intersect_sphere(&sphere(0.0, 0.0, 1.0, 0.5)));
+        assert!(!planes.may_intersect_sphere(&sphere(0.0, 0.0, -12.0, 1.0)));
+        assert!(planes.may_intersect_sphere(&sphere(0.0, 0.0, -12.0, 3.0)));
+        assert!(!planes.may_intersect_sphere(&sphere(10.0, 0.0, -5.0, 1.0)));
+    }
+
+    #[test]
+    fn test_from_screen_rect() {
+        // Looks along the negative z axis, at 5 m distance the screen is 2 * tan(0.5) * 5 m wide.
+        let perspective = nalgebra::Perspective3::new(1.0, 1.0, 1.0, 10.0);
+        let frustum = Frustum::new(Isometry3::identity(), perspective.into());
+        let screen_size = Vector2::new(100.0, 100.0);
+        let from_rect = |a: (f64, f64), b: (f64, f64)| {
+            Frustum::from_screen_rect(
+                &frustum.clip_from_query,
+                &screen_size,
+                &Point2::new(a.0, a.1),
+                &Point2::new(b.0, b.1),
+            )
+            .unwrap()
+        };
+        // A point in the plane at 5 m distance, given in normalized device coordinates.
+        let half_width = 0.5f64.tan() * 5.0;
+        let point = |x: f64, y: f64| Point3::new(x * half_width, y * half_width, -5.0);
+
+        let full_screen = from_rect((100.0, 100.0), (0.0, 0.0));
+        let centered = from_rect((25.0, 25.0), (75.0, 75.0));
+        for p in &[point(0.0, 0.0), point(-0.4, 0.2), point(0.45, -0.45)] {
+            assert!(frustum.contains(p));
+            assert!(full_screen.contains(p));
+            assert!(centered.contains(p));
+        }
+        for p in &[point(0.9, 0.0), point(0.0, -0.6), point(-0.55, 0.55)] {
+            assert!(frustum.contains(p));
+            assert!(full_screen.contains(p));
+            assert!(!centered.contains(p));
+        }
+        // Pixels further down are lower in the scene.
+        let bottom_left = from_rect((0.0, 50.0), (50.0, 100.0));
+        assert!(bottom_left.contains(&point(-0.5, -0.5)));
+        assert!(!bottom_left.contains(&point(0.5, 0.5)));
+
+        let empty = Frustum::from_screen_rect(
+            &frustum.clip_from_query,
+            &screen_size,
+            &Point2::new(10.0, 10.0),
+            &Point2::new(10.0, 50.0),
+        );
+        assert!(empty.is_none());
+    }
+
+    #[test]
+    fn test_sheared_and_infinite_projections() {
+        use crate::geometry::Aabb;
+        use crate::math::base::IntersectAabb;
+
+        // An off-axis projection of a sheared space, with and without a far plane. With a near
+        // distance of 1, the bounds of the sides are the slopes of the sides.
+        #[rustfmt::skip]
+        let eye_from_query = Matrix4::new(
+            1.0, 0.0, 0.5, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let (left, right, bottom, top, near, far) = (-0.2, 0.6, -0.3, 0.1, 1.0, 20.0);
+        let reference = |p: &Point3<f64>, far: f64| {
+            let eye = eye_from_query.transform_point(p);
+            let distance = -eye.z;
+            near < distance
+                && distance < far
+                && left * distance < eye.x
+                && eye.x < right * distance
+                && bottom * distance < eye.y
+                && eye.y < top * distance
+        };
+        let finite = Frustum::from_matrix4(
+            Perspective::new(left, right, bottom, top, near, far).as_matrix() * eye_from_query,
+        )
+        .unwrap();
+        let infinite = Frustum::from_matrix4(
+            Perspective::new_infinite(left, right, bottom, top, near).as_matrix() * eye_from_query,
+        )
+        .unwrap();
+
+        for (frustum, far) in &[(&finite, far), (&infinite, std::f64::INFINITY)] {
+            let planes = frustum.planes();
+            let intersector = frustum.aabb_intersector();
+            let mut num_inside = 0;
+            for i in 0..32 {
+                for j in 0..32 {
+                    for k in 0..32 {
+                        let p = Point3::new(
+                            -16.13 + f64::from(i),
+                            -16.27 + f64::from(j),
+                            -27.41 + f64::from(k),
+                        );
+                        let inside = reference(&p, *far);
+                        num_inside += inside as usize;
+                        assert_eq!(frustum.contains(&p), inside, "{:?}", p);
+                        assert_eq!(
+                            planes.may_intersect_sphere(&Sphere::new(p, 0.0)),
+                            inside,
+                            "{:?}",
+                            p
+                        );
+                        if inside {
+                            assert!(intersector.intersect_aabb(&Aabb::new(p, p)));
+                        }
+                    }
+                }
+            }
+            assert!(num_inside > 100);
+        }
+
+        // Far away points are only inside without a far plane.
+        let distant = Point3::new(0.7e6, -0.1e6, -1e6);
+        assert!(reference(&distant, std::f64::INFINITY));
+        assert!(!finite.contains(&distant));
+        assert!(infinite.contains(&distant));
+        let distant_box = Aabb::new(
+            distant - Vector3::repeat(10.0),
+            distant + Vector3::repeat(10.0),
+        );
+        assert!(!finite.aabb_intersector().intersect_aabb(&distant_box));
+        assert!(infinite.aabb_intersector().intersect_aabb(&distant_box));
+        assert!(infinite
+            .planes()
+            .may_intersect_sphere(&Sphere::new(distant, 1.0)));
+        // Behind the eye.
+        let behind = Aabb::new(Point3::new(-1.0, -1.0, 5.0), Point3::new(1.0, 1.0, 6.0));
+        assert!(!infinite.aabb_intersector().intersect_aabb(&behind));
+        assert!(!infinite.contains(&Point3::new(0.25, -0.1, 1.0)));
+    }
 }
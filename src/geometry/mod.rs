@@ -3,10 +3,12 @@ mod aabb;
 mod frustum;
 mod obb;
 mod s2_cell_union;
+mod sphere;
 mod web_mercator_rect;
 
 pub use aabb::*;
 pub use frustum::*;
 pub use obb::*;
 pub use s2_cell_union::*;
+pub use sphere::*;
 pub use web_mercator_rect::*;
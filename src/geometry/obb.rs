@@ -1,21 +1,60 @@
 //! A bounding box with an arbitrary 3D pose.
 
-use super::aabb::Aabb;
+use super::aabb::{box_normalized_depth, Aabb};
 use crate::math::base::{HasAabbIntersector, PointCulling};
 use crate::math::sat::{CachedAxesIntersector, ConvexPolyhedron, Intersector};
 use arrayvec::ArrayVec;
 use nalgebra::{Isometry3, Point3, Unit, UnitQuaternion, Vector3};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::iter::FromIterator;
 
-/// An oriented bounding box.
+/// An oriented bounding box. It is serialized as its pose `query_from_obb` and its
+/// `half_extent`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "ObbSpec", into = "ObbSpec")]
 pub struct Obb {
     query_from_obb: Isometry3<f64>,
     obb_from_query: Isometry3<f64>,
     half_extent: Vector3<f64>,
 }
 
+/// The serialized form of an `Obb`, which leaves out the inverse pose.
+#[derive(Serialize, Deserialize)]
+struct ObbSpec {
+    query_from_obb: Isometry3<f64>,
+    half_extent: Vector3<f64>,
+}
+
+impl TryFrom<ObbSpec> for Obb {
+    type Error = String;
+
+    fn try_from(spec: ObbSpec) -> Result<Self, Self::Error> {
+        if (spec.query_from_obb.rotation.coords.norm() - 1.0).abs() > 1e-6 {
+            return Err(format!(
+                "The rotation of an OBB must be a unit quaternion, found {:?}.",
+                spec.query_from_obb.rotation.coords
+            ));
+        }
+        if !spec.half_extent.iter().all(|e| e.is_finite() && *e >= 0.0) {
+            return Err(format!(
+                "The half extent of an OBB must not be negative, found {:?}.",
+                spec.half_extent
+            ));
+        }
+        Ok(Obb::new(spec.query_from_obb, spec.half_extent))
+    }
+}
+
+impl From<Obb> for ObbSpec {
+    fn from(obb: Obb) -> Self {
+        ObbSpec {
+            query_from_obb: obb.query_from_obb,
+            half_extent: obb.half_extent,
+        }
+    }
+}
+
 impl From<&Aabb> for Obb {
     fn from(aabb: &Aabb) -> Self {
         Obb::new(
@@ -43,6 +82,17 @@ impl Obb {
     pub fn transformed(&self, global_from_query: &Isometry3<f64>) -> Self {
         Self::new(global_from_query * self.query_from_obb, self.half_extent)
     }
+
+    /// The box grown by the margin on every side, or shrunk by a negative one.
+    pub fn expanded(&self, margin: f64) -> Self {
+        Self::new(self.query_from_obb, self.half_extent.add_scalar(margin))
+    }
+
+    /// The distance of the point to the closest face, relative to the largest such distance: 1
+    /// at the center, 0 on the boundary and outside.
+    pub fn normalized_depth(&self, p: &Point3<f64>) -> f64 {
+        box_normalized_depth(&(self.obb_from_query * p).coords, &self.half_extent)
+    }
 }
 
 impl ConvexPolyhedron for Obb {
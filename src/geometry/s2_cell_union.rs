@@ -26,7 +26,10 @@ pub fn cells_intersecting_polyhedron(cells: &[Cell], polyhedron: &impl ConvexPol
 
 impl PointCulling for CellUnion {
     fn contains(&self, p: &Point3<f64>) -> bool {
-        self.contains_cellid(&CellID::from_point(p))
+        // Unlike `contains_cellid`, this does not assume a normalized union, i.e. sorted cells that
+        // do not overlap, so that unions of cells of mixed levels work in any order.
+        let point_cell = CellID::from_point(p);
+        self.0.iter().any(|cell| cell.contains(&point_cell))
     }
 }
 
@@ -0,0 +1,118 @@
+//! A ball around a center point.
+
+use super::aabb::{Aabb, Cube};
+use crate::math::base::{HasAabbIntersector, IntersectAabb, PointCulling};
+use crate::proto;
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+/// All points within `radius` of `center`, including the boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Sphere {
+    center: Point3<f64>,
+    radius: f64,
+}
+
+impl Sphere {
+    pub fn new(center: Point3<f64>, radius: f64) -> Self {
+        assert!(radius >= 0.0, "The radius must not be negative.");
+        Sphere { center, radius }
+    }
+
+    pub fn center(&self) -> &Point3<f64> {
+        &self.center
+    }
+
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    /// The smallest sphere containing the cube. The radius is rounded up, so that the corners are
+    /// inside despite rounding errors.
+    pub fn enclosing_cube(cube: &Cube) -> Self {
+        let radius = 0.5 * 3f64.sqrt() * cube.edge_length() * (1.0 + 4.0 * f64::EPSILON);
+        Sphere::new(Point3::from(cube.center()), radius)
+    }
+
+    /// The smallest sphere containing the box. Like for `enclosing_cube`, the radius is rounded
+    /// up.
+    pub fn enclosing_aabb(aabb: &Aabb) -> Self {
+        let radius = 0.5 * aabb.diag().norm() * (1.0 + 4.0 * f64::EPSILON);
+        Sphere::new(aabb.center(), radius)
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        let half_extent = Vector3::repeat(self.radius);
+        Aabb::new(self.center - half_extent, self.center + half_extent)
+    }
+
+    /// The distance of the point to the boundary, relative to the radius: 1 at the center, 0 on the
+    /// boundary and outside.
+    pub fn normalized_depth(&self, p: &Point3<f64>) -> f64 {
+        if self.radius == 0.0 {
+            return 0.0;
+        }
+        (1.0 - (p - self.center).norm() / self.radius).max(0.0)
+    }
+}
+
+impl From<&proto::Sphere> for Sphere {
+    fn from(sphere: &proto::Sphere) -> Self {
+        Sphere::new(Point3::from(sphere.get_center()), sphere.radius)
+    }
+}
+
+impl From<&Sphere> for proto::Sphere {
+    fn from(sphere: &Sphere) -> Self {
+        let mut proto = proto::Sphere::new();
+        proto.set_center(proto::Vector3d::from(&sphere.center));
+        proto.set_radius(sphere.radius);
+        proto
+    }
+}
+
+impl IntersectAabb for Sphere {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        // The point of the box closest to the center.
+        let closest = Point3::from(
+            self.center
+                .coords
+                .sup(&aabb.min().coords)
+                .inf(&aabb.max().coords),
+        );
+        (closest - self.center).norm_squared() <= self.radius * self.radius
+    }
+}
+
+impl<'a> HasAabbIntersector<'a> for Sphere {
+    type Intersector = Sphere;
+    fn aabb_intersector(&'a self) -> Self::Intersector {
+        *self
+    }
+}
+
+impl PointCulling for Sphere {
+    fn contains(&self, p: &Point3<f64>) -> bool {
+        (p - self.center).norm_squared() <= self.radius * self.radius
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sphere_intersects_aabb() {
+        let sphere = Sphere::new(Point3::origin(), 1.0);
+        let unit_box =
+            |x, y, z| Aabb::new(Point3::new(x, y, z), Point3::new(x + 1.0, y + 1.0, z + 1.0));
+        assert!(sphere.intersect_aabb(&unit_box(-0.5, -0.5, -0.5)));
+        assert!(sphere.intersect_aabb(&unit_box(0.9, -0.5, -0.5)));
+        // The corner of the box is closer than the radius only along the axes, not diagonally.
+        assert!(!sphere.intersect_aabb(&unit_box(0.8, 0.8, 0.8)));
+        assert!(sphere.intersect_aabb(&Aabb::new(
+            Point3::new(-10.0, -10.0, -10.0),
+            Point3::new(10.0, 10.0, 10.0)
+        )));
+    }
+}
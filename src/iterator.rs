@@ -1,22 +1,58 @@
+use crate::attributes::AttrStats;
+use crate::color::{pack_rgba, upscale_color, COLOR16_ATTRIBUTE, RGBA_ATTRIBUTE};
+use crate::data_provider::CancellationToken;
 use crate::errors::*;
-use crate::geometry::{Aabb, CellUnion, Frustum, Obb, WebMercatorRect};
-use crate::math::{AllPoints, ClosedInterval, PointCulling};
+use crate::geometry::{Aabb, CellUnion, Frustum, Frustums, Obb, Sphere, WebMercatorRect};
+use crate::math::{
+    AllPoints, ClosedInterval, FromPoint3, HasAabbIntersector, IntersectAabb, KdTree, PointCulling,
+};
+use crate::octree::{AxisConvention, CoordinateSystem, ORIGINAL_INDEX_ATTRIBUTE};
 use crate::read_write::{Encoding, NodeIterator};
-use crate::{match_1d_attr_data, AttributeData, PointsBatch};
+use crate::{match_1d_attr_data, AttributeData, AttributeDataType, PointsBatch};
 use crossbeam::deque::{Injector, Steal, Worker};
+use crossbeam::sync::WaitGroup;
+use nalgebra::{Matrix4, Point3, Vector3};
+use nav_types::{ECEF, WGS84};
 use num_traits::ToPrimitive;
+use s2::cellid::CellID;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PointLocation {
     AllPoints,
+    /// All points in nodes up to and including this depth, i.e. a coarse sampling of the whole
+    /// point cloud. Point clouds without a node hierarchy return all points.
+    AllPointsInDepthRange(u8),
     Aabb(Aabb),
     Frustum(Frustum),
+    /// The points visible in any of the frustums, e.g. of the frames of a camera path, in one
+    /// traversal. Which frustums contain a point is returned as the `FRUSTUM_MASK_ATTRIBUTE` on
+    /// request.
+    Frustums(Frustums),
     Obb(Obb),
     S2Cells(CellUnion),
+    Sphere(Sphere),
     WebMercatorRect(WebMercatorRect),
+    /// All points outside of the location, e.g. everything but a region that was already loaded.
+    /// Nodes can't be skipped for it, so this reads the whole point cloud.
+    Complement(Box<PointLocation>),
+    /// All points of the first location that are not in the second one, e.g. a box with a hole.
+    /// Only the nodes of the first location are read.
+    Difference(Box<PointLocation>, Box<PointLocation>),
+    /// The points with these `ORIGINAL_INDEX_ATTRIBUTE`s, e.g. to fetch a saved selection again
+    /// with the current attributes. The point cloud needs to store the attribute, see
+    /// `BuildOptions::original_index`, but it does not have to be requested. Nodes whose range
+    /// of indices contains none of them are skipped, see `NodeMeta::original_index_range`. The
+    /// positions alone don't tell whether a point is selected, so this can't be combined with
+    /// other locations.
+    ByOriginalIndex(HashSet<u64>),
 }
 
 impl Default for PointLocation {
@@ -28,16 +64,76 @@ impl Default for PointLocation {
 impl PointLocation {
     pub fn get_point_culling(&self) -> Box<dyn PointCulling> {
         match &self {
-            PointLocation::AllPoints => Box::new(AllPoints {}),
+            PointLocation::AllPoints
+            | PointLocation::AllPointsInDepthRange(_)
+            | PointLocation::ByOriginalIndex(_) => Box::new(AllPoints {}),
             PointLocation::Aabb(aabb) => Box::new(aabb.clone()),
             PointLocation::Frustum(frustum) => Box::new(frustum.clone()),
+            PointLocation::Frustums(frustums) => Box::new(frustums.clone()),
             PointLocation::Obb(obb) => Box::new(obb.clone()),
             PointLocation::S2Cells(cell_union) => Box::new(cell_union.clone()),
+            PointLocation::Sphere(sphere) => Box::new(*sphere),
             PointLocation::WebMercatorRect(wmr) => Box::new(wmr.clone()),
+            PointLocation::Complement(location) => Box::new(Complement(location.clone())),
+            PointLocation::Difference(minuend, subtrahend) => {
+                Box::new(Difference(minuend.clone(), subtrahend.clone()))
+            }
         }
     }
 }
 
+/// The points outside of the inner location, see `PointLocation::Complement`. It is either owned
+/// or borrowed.
+#[derive(Clone, Debug)]
+pub struct Complement<L>(pub L);
+
+impl<L: Deref<Target = PointLocation>> PointCulling for Complement<L> {
+    fn contains(&self, p: &Point3<f64>) -> bool {
+        !self.0.contains_point(p)
+    }
+}
+
+impl<L> IntersectAabb for Complement<L> {
+    fn intersect_aabb(&self, _aabb: &Aabb) -> bool {
+        // Finding out whether the box is completely inside of the inner location is not supported
+        // by all locations, so every box may contain points outside of it.
+        true
+    }
+}
+
+impl<'a, L> HasAabbIntersector<'a> for Complement<L> {
+    type Intersector = AllPoints;
+
+    fn aabb_intersector(&'a self) -> Self::Intersector {
+        AllPoints {}
+    }
+}
+
+/// The points of the first location that are not in the second one, see
+/// `PointLocation::Difference`. The locations are either owned or borrowed.
+#[derive(Clone, Debug)]
+pub struct Difference<L>(pub L, pub L);
+
+impl<L: Deref<Target = PointLocation>> PointCulling for Difference<L> {
+    fn contains(&self, p: &Point3<f64>) -> bool {
+        self.0.contains_point(p) && !self.1.contains_point(p)
+    }
+}
+
+impl<L: Deref<Target = PointLocation>> IntersectAabb for Difference<L> {
+    fn intersect_aabb(&self, aabb: &Aabb) -> bool {
+        self.0.intersects_aabb(aabb)
+    }
+}
+
+impl<'a, L: Deref<Target = PointLocation> + Clone + 'a> HasAabbIntersector<'a> for Difference<L> {
+    type Intersector = Self;
+
+    fn aabb_intersector(&'a self) -> Self::Intersector {
+        self.clone()
+    }
+}
+
 /// This macro is an alternative to `get_point_culling()`, to be used where
 /// performance is important (i.e. in an inner loop). This can make a difference
 /// of 5-10 % in queries measuered by the `point_cloud_test` crate.
@@ -53,22 +149,877 @@ macro_rules! dispatch_point_location {
     ($func:path, $location:expr $(,$arg:expr)*) => {
         match $location {
             PointLocation::AllPoints => $func($($arg,)* &AllPoints {}),
+            PointLocation::AllPointsInDepthRange(_) => $func($($arg,)* &AllPoints {}),
             PointLocation::Aabb(aabb) => $func($($arg,)* aabb),
             PointLocation::Frustum(f) => $func($($arg,)* f),
+            PointLocation::Frustums(f) => $func($($arg,)* f),
             PointLocation::Obb(obb) => $func($($arg,)* obb),
             PointLocation::S2Cells(cu) => $func($($arg,)* cu),
+            PointLocation::Sphere(sphere) => $func($($arg,)* sphere),
             PointLocation::WebMercatorRect(wmr) => $func($($arg,)* wmr),
+            PointLocation::Complement(location) => $func($($arg,)* &Complement(&**location)),
+            PointLocation::Difference(minuend, subtrahend) => {
+                $func($($arg,)* &Difference(&**minuend, &**subtrahend))
+            }
+            // The points are selected by their attribute, see `NodeRead::stream_points`.
+            PointLocation::ByOriginalIndex(_) => $func($($arg,)* &AllPoints {}),
+        }
+    }
+}
+
+fn intersects_aabb<'a, T: HasAabbIntersector<'a>>(aabb: &Aabb, location: &'a T) -> bool {
+    location.aabb_intersector().intersect_aabb(aabb)
+}
+
+fn contains_point<T: PointCulling>(point: &Point3<f64>, location: &T) -> bool {
+    location.contains(point)
+}
+
+impl PointLocation {
+    /// Whether the location may contain points inside the box. This is the same conservative test
+    /// that decides which octree nodes a query visits.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        dispatch_point_location!(intersects_aabb, self, aabb)
+    }
+
+    /// Whether the point is part of the location. This is the same test that filters the points
+    /// of a query.
+    pub fn contains_point(&self, point: &Point3<f64>) -> bool {
+        dispatch_point_location!(contains_point, self, point)
+    }
+
+    /// How deep inside the location the point is, from 1 at its center to 0 at its boundary, see
+    /// `QUERY_WEIGHT_ATTRIBUTE`. `None` for locations other than boxes and spheres.
+    pub fn query_weight(&self, point: &Point3<f64>) -> Option<f32> {
+        let depth = match self {
+            PointLocation::Aabb(aabb) => aabb.normalized_depth(point),
+            PointLocation::Obb(obb) => obb.normalized_depth(point),
+            PointLocation::Sphere(sphere) => sphere.normalized_depth(point),
+            _ => return None,
+        };
+        Some(depth as f32)
+    }
+
+    /// The location grown by the margin on every side, see `PointQuery::boundary_epsilon`. Boxes,
+    /// oriented boxes and spheres are grown, the complement of a location grows by shrinking it,
+    /// and a difference grows its first location and shrinks the second. The other locations stay
+    /// as they are.
+    pub fn expanded(&self, margin: f64) -> Cow<'_, PointLocation> {
+        if margin == 0.0 {
+            return Cow::Borrowed(self);
+        }
+        let expanded = match self {
+            PointLocation::Aabb(aabb) => PointLocation::Aabb(Aabb::new(
+                aabb.min() - Vector3::repeat(margin),
+                aabb.max() + Vector3::repeat(margin),
+            )),
+            PointLocation::Obb(obb) => PointLocation::Obb(obb.expanded(margin)),
+            PointLocation::Sphere(sphere) => PointLocation::Sphere(Sphere::new(
+                *sphere.center(),
+                (sphere.radius() + margin).max(0.0),
+            )),
+            PointLocation::Complement(location) => {
+                PointLocation::Complement(Box::new(location.expanded(-margin).into_owned()))
+            }
+            PointLocation::Difference(minuend, subtrahend) => PointLocation::Difference(
+                Box::new(minuend.expanded(margin).into_owned()),
+                Box::new(subtrahend.expanded(-margin).into_owned()),
+            ),
+            PointLocation::AllPoints
+            | PointLocation::AllPointsInDepthRange(_)
+            | PointLocation::Frustum(_)
+            | PointLocation::Frustums(_)
+            | PointLocation::S2Cells(_)
+            | PointLocation::WebMercatorRect(_)
+            | PointLocation::ByOriginalIndex(_) => return Cow::Borrowed(self),
+        };
+        Cow::Owned(expanded)
+    }
+
+    /// Checks the invariants that deserializing a location does not, e.g. that a box is not
+    /// inverted.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| Err(ErrorKind::InvalidQuery(msg).into());
+        let nested: Vec<&PointLocation> = match self {
+            PointLocation::Complement(location) => vec![location],
+            PointLocation::Difference(minuend, subtrahend) => vec![minuend, subtrahend],
+            _ => Vec::new(),
+        };
+        if nested
+            .iter()
+            .any(|location| matches!(location, PointLocation::ByOriginalIndex(_)))
+        {
+            return invalid(
+                "Selecting points by original index can't be combined with other locations."
+                    .to_string(),
+            );
+        }
+        match self {
+            PointLocation::Aabb(aabb) => {
+                let is_finite = aabb
+                    .min()
+                    .iter()
+                    .chain(aabb.max().iter())
+                    .all(|c| c.is_finite());
+                if !is_finite || !nalgebra::partial_le(aabb.min(), aabb.max()) {
+                    return invalid(format!(
+                        "The minimum {} of the box must not be greater than its maximum {}.",
+                        aabb.min(),
+                        aabb.max()
+                    ));
+                }
+            }
+            PointLocation::Sphere(sphere) => {
+                let is_finite = sphere.center().iter().all(|c| c.is_finite());
+                if !is_finite || !sphere.radius().is_finite() || sphere.radius() < 0.0 {
+                    return invalid(format!(
+                        "The sphere around {} must have a finite center and a radius that is not \
+                         negative, found {}.",
+                        sphere.center(),
+                        sphere.radius()
+                    ));
+                }
+            }
+            PointLocation::Frustums(frustums) => {
+                if frustums.0.is_empty() || frustums.0.len() > Frustums::MAX_LEN {
+                    return invalid(format!(
+                        "Between 1 and {} frustums are supported, found {}.",
+                        Frustums::MAX_LEN,
+                        frustums.0.len()
+                    ));
+                }
+            }
+            PointLocation::S2Cells(cell_union) => {
+                if let Some(cell_id) = cell_union.0.iter().find(|cell_id| !cell_id.is_valid()) {
+                    return invalid(format!("Invalid S2 cell id {}.", cell_id.0));
+                }
+            }
+            PointLocation::Complement(location) => {
+                if let PointLocation::AllPointsInDepthRange(_) = **location {
+                    return invalid(
+                        "The complement of a depth range is not supported.".to_string(),
+                    );
+                }
+                location.validate()?;
+            }
+            PointLocation::Difference(minuend, subtrahend) => {
+                if let PointLocation::AllPointsInDepthRange(_) = **subtrahend {
+                    return invalid("Subtracting a depth range is not supported.".to_string());
+                }
+                minuend.validate()?;
+                subtrahend.validate()?;
+            }
+            // The other locations are either valid by construction or checked when deserializing.
+            PointLocation::AllPoints
+            | PointLocation::AllPointsInDepthRange(_)
+            | PointLocation::Frustum(_)
+            | PointLocation::Obb(_)
+            | PointLocation::WebMercatorRect(_)
+            | PointLocation::ByOriginalIndex(_) => (),
         }
+        Ok(())
     }
 }
 
+/// A query can be read from JSON, e.g. when it is sent by a web client, with `from_json`:
+///
+/// ```json
+/// {
+///   "attributes": ["color", "intensity"],
+///   "location": {"Sphere": {"center": [1.0, 2.0, 3.0], "radius": 5.0}},
+///   "filter_intervals": {"intensity": {"lower_bound": 0.5, "upper_bound": 2.0}},
+///   "rgba_intensity_range": {"lower_bound": 0.0, "upper_bound": 255.0},
+///   "output_transforms": [{"SnapToGrid": {"cell": 0.01}}],
+///   "skip_failed_nodes": true,
+///   "upscale_color": true
+/// }
+/// ```
+///
+/// All fields are optional and default to a query for the positions of all points. Unknown fields
+/// are an error. The `location` is one of
+///
+/// - `"AllPoints"`
+/// - `{"AllPointsInDepthRange": 3}`
+/// - `{"Aabb": {"mins": [x, y, z], "maxs": [x, y, z]}}`
+/// - `{"Frustum": {"clip_from_query": [...]}}`, with the 16 entries of the matrix in column-major
+///   order
+/// - `{"Frustums": [{"clip_from_query": [...]}, ...]}`
+/// - `{"Obb": {"query_from_obb": {"rotation": [i, j, k, w], "translation": [x, y, z]},
+///   "half_extent": [x, y, z]}}`, where the rotation is a unit quaternion
+/// - `{"S2Cells": [cell_id, ...]}`, with the 64 bit ids of the cells
+/// - `{"Sphere": {"center": [x, y, z], "radius": r}}`
+/// - `{"WebMercatorRect": {"north_west": {"normalized": [x, y]}, "south_east": {"normalized":
+///   [x, y]}}}`, with the coordinates normalized to [0, 1) instead of being in zoom level 0
+/// - `{"Complement": location}` and `{"Difference": [location, location]}`, with the other
+///   locations
+/// - `{"ByOriginalIndex": [index, ...]}`
+///
+/// The `output_transforms` are `{"SnapToGrid": {"cell": c}}`, `{"S2CellIds": {"level": l}}`,
+/// `{"StatisticalOutlierRemoval": {"k": k, "std_mult": m}}`, `"SortByOriginalIndex"`,
+/// `{"SortBy": {"keys": [{"attribute": "classification", "ascending": true}, ...]}}`,
+/// `{"ColorizeByClass": {"palette": {"2": [r, g, b], ...}, "default_color": [r, g, b]}}`,
+/// `{"ConvertAxes": {"to": "YUp"}}` and `{"HeightAboveGround": {"grid": {"origin": [x, y],
+/// "cell_size": c, "num_columns": n, "heights": [h, ...]}, "min_height": a, "max_height": b}}`.
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct PointQuery<'a> {
-    #[serde(borrow)]
+    /// The attributes to return, which may use wildcards, see `resolve_attributes`. Positions
+    /// are always returned and are not listed here, so the default of no attributes returns
+    /// batches with positions only.
+    #[serde(borrow, default)]
     pub attributes: Vec<&'a str>,
+    #[serde(default)]
     pub location: PointLocation,
-    #[serde(borrow)]
+    #[serde(borrow, default)]
     pub filter_intervals: HashMap<&'a str, ClosedInterval<f64>>,
+    /// The intensities that are mapped to the alpha range if `RGBA_ATTRIBUTE` is requested and
+    /// the point clouds don't have such an attribute themselves. Defaults to 0 to 1.
+    #[serde(default)]
+    pub rgba_intensity_range: Option<ClosedInterval<f64>>,
+    /// Applied to the returned points in order, after they have been selected.
+    #[serde(default)]
+    pub output_transforms: Vec<OutputTransform>,
+    /// Instead of failing the query when a node can't be read, skips the node and reports its
+    /// id and point cloud in `QuerySummary::failures`, e.g. to serve a mosaic of several point clouds
+    /// even if one of them is corrupt. Points of a failed node may be returned partially.
+    #[serde(default)]
+    pub skip_failed_nodes: bool,
+    /// Returns the 8 bit color scaled to 16 bits if `COLOR16_ATTRIBUTE` is requested from point
+    /// clouds that don't have it. Otherwise, such queries fail.
+    #[serde(default)]
+    pub upscale_color: bool,
+    /// Adds the geographic coordinates of the points as the `LATITUDE_ATTRIBUTE`,
+    /// `LONGITUDE_ATTRIBUTE` and `ALTITUDE_ATTRIBUTE`, e.g. to plot them on a map. The positions
+    /// stay as they are. Fails for point clouds whose positions can't be converted to ECEF, see
+    /// `PointCloud::coordinate_system`.
+    #[serde(default)]
+    pub wgs84: bool,
+    /// Makes `ParallelIterator` and `PooledIterator` return the points in the same batches and
+    /// order regardless of the number of threads and their scheduling, e.g. for snapshot tests:
+    /// ordered by point cloud, then by node id, and within a node in the order they are stored
+    /// in. To this end, all points of the query are buffered in memory before the first batch is
+    /// returned.
+    #[serde(default)]
+    pub deterministic: bool,
+    /// Grows the `location` by this margin on every side, both for selecting the nodes and for
+    /// testing the points, see `PointLocation::expanded`. E.g. to include the points on the faces
+    /// of a box, which are partly outside of it due to its half-open test, or that rounding put
+    /// just outside. Must not be negative, and defaults to 0.
+    #[serde(default)]
+    pub boundary_epsilon: f64,
+    /// Returns only the first point of every node that matches the location and filters, e.g.
+    /// for an instant preview of the tree structure before streaming all points. Nodes are only
+    /// read up to this point.
+    #[serde(default)]
+    pub overview: bool,
+    /// Thins out the points with their distance from a center, e.g. full density around the
+    /// camera and sparser points farther away, in a single query. In JSON,
+    /// `{"center": [x, y, z], "full_radius": r, "falloff": f}`.
+    #[serde(default)]
+    pub radial_decimation: Option<RadialDecimation>,
+    /// Groups the returned points into square tiles of this size in the xy plane, e.g. to cache
+    /// the tiles independently: `ParallelIterator` and `PooledIterator` return one batch per tile
+    /// that holds all of its points, ordered by the tile coordinates, see `tile_coordinates`. The
+    /// batches have the `TILE_X_ATTRIBUTE` and `TILE_Y_ATTRIBUTE` of their tile and are not
+    /// coalesced. To this end, all points of the query are buffered in memory before the first
+    /// batch is returned. Must be positive.
+    #[serde(default)]
+    pub tile_output: Option<f64>,
+    /// Cancelling stops the query, including reads from the data provider in progress. The query
+    /// then fails with `ErrorKind::Cancelled`.
+    #[serde(skip)]
+    pub cancellation: Option<CancellationToken>,
+}
+
+/// A scalar attribute to sort by, see `OutputTransform::SortBy`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SortKey {
+    pub attribute: String,
+    pub ascending: bool,
+}
+
+/// Ground heights sampled on a regular grid in the xy plane, e.g. a coarse digital elevation
+/// model, see `OutputTransform::HeightAboveGround`. The heights are given row by row: the one at
+/// `row * num_columns + column` is the height at `origin + (column, row) * cell_size`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HeightGrid {
+    pub origin: [f64; 2],
+    pub cell_size: f64,
+    pub num_columns: usize,
+    pub heights: Vec<f64>,
+}
+
+impl HeightGrid {
+    /// The height at the point, interpolated bilinearly between the four surrounding samples.
+    /// `None` outside of the samples, and for grids without samples.
+    pub fn height_at(&self, x: f64, y: f64) -> Option<f64> {
+        if self.num_columns == 0 || self.heights.len() < self.num_columns {
+            return None;
+        }
+        let num_rows = self.heights.len() / self.num_columns;
+        // The index of the sample before the coordinate, the one after it and how far between
+        // them the coordinate is.
+        let locate = |coord: f64, origin: f64, num_samples: usize| {
+            let offset = (coord - origin) / self.cell_size;
+            if !(offset >= 0.0 && offset <= (num_samples - 1) as f64) {
+                return None;
+            }
+            let before = (offset.floor() as usize).min(num_samples.saturating_sub(2));
+            let after = (before + 1).min(num_samples - 1);
+            Some((before, after, offset - before as f64))
+        };
+        let (column, next_column, s) = locate(x, self.origin[0], self.num_columns)?;
+        let (row, next_row, t) = locate(y, self.origin[1], num_rows)?;
+        let height = |row: usize, column: usize| self.heights[row * self.num_columns + column];
+        let lower = (1.0 - s) * height(row, column) + s * height(row, next_column);
+        let upper = (1.0 - s) * height(next_row, column) + s * height(next_row, next_column);
+        Some((1.0 - t) * lower + t * upper)
+    }
+}
+
+/// Keeps all points within `full_radius` of the `center`, and a point at distance `d` beyond it
+/// with probability `(full_radius / d)^falloff`, see `PointQuery::radial_decimation`. E.g. a
+/// falloff of 2 halves the density at a distance of about 1.4 times the full radius. Whether a
+/// point is kept is decided by a hash of its position, so the same points are kept by every query,
+/// regardless of batches and threads.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RadialDecimation {
+    pub center: Point3<f64>,
+    pub full_radius: f64,
+    pub falloff: f64,
+}
+
+impl RadialDecimation {
+    /// The probability that a point at the position is kept.
+    pub fn keep_probability(&self, p: &Point3<f64>) -> f64 {
+        let distance = (p - self.center).norm();
+        if distance <= self.full_radius {
+            1.0
+        } else {
+            (self.full_radius / distance).powf(self.falloff)
+        }
+    }
+
+    pub fn keeps(&self, p: &Point3<f64>) -> bool {
+        // The mixed bits of the coordinates, taken as uniform in [0, 1).
+        let hash = p.iter().fold(0x9e37_79b9_7f4a_7c15_u64, |hash, c| {
+            mix_bits(hash ^ c.to_bits())
+        });
+        ((hash >> 11) as f64 / (1_u64 << 53) as f64) < self.keep_probability(p)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !self.center.iter().all(|c| c.is_finite())
+            || !self.full_radius.is_finite()
+            || self.full_radius <= 0.0
+            || !self.falloff.is_finite()
+            || self.falloff < 0.0
+        {
+            return Err(ErrorKind::InvalidQuery(format!(
+                "The radial decimation needs a finite center, a positive full radius and a falloff \
+                 that is not negative, found {:?}.",
+                self
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Changes the points returned by a query, see `PointQuery::output_transforms`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OutputTransform {
+    /// Rounds every coordinate of the positions to the nearest multiple of `cell`, e.g. to remove
+    /// jitter before comparing point clouds. Points that are closer to each other than the cell
+    /// size may end up at the same position.
+    SnapToGrid { cell: f64 },
+    /// Adds the `S2_CELL_ATTRIBUTE` with the id of the S2 cell of every point at `level`, e.g. to
+    /// group the points into tiles. The positions are taken as ECEF coordinates, as for S2 cells
+    /// point clouds.
+    S2CellIds { level: u8 },
+    /// Drops points whose mean distance to their `k` nearest neighbors exceeds the mean of these
+    /// distances over all points by more than `std_mult` standard deviations, e.g. to clean noisy
+    /// scans. Like all transforms, this runs on every batch read from a node separately, so the
+    /// neighbors are only searched among the points of the same batch, and larger batch sizes
+    /// give better statistics. Batches with no more than `k` points are kept as they are.
+    StatisticalOutlierRemoval { k: usize, std_mult: f64 },
+    /// Sorts the points of every batch read from a node by the `ORIGINAL_INDEX_ATTRIBUTE`, i.e.
+    /// back into the order of the input of the build. The attribute has to be requested; batches
+    /// without it, e.g. from point clouds built without it, are left as they are.
+    SortByOriginalIndex,
+    /// Sorts the points of every batch read from a node by the first key, points with equal
+    /// values by the second key and so on, e.g. to group the points by classification and order
+    /// each group by intensity. Points that are equal in all keys keep their order. The
+    /// attributes have to be scalar and requested; batches without one of them are left as they
+    /// are.
+    SortBy { keys: Vec<SortKey> },
+    /// Sets the `color` of every point to the palette color of its `CLASSIFICATION_ATTRIBUTE`,
+    /// e.g. to render the ground brown and vegetation green. Points of classes that are not in
+    /// the palette get the `default_color`. The classification has to be requested; batches
+    /// without it are left as they are.
+    ColorizeByClass {
+        palette: HashMap<u8, [u8; 3]>,
+        default_color: [u8; 3],
+    },
+    /// Converts the Z-up positions of the point cloud into the axis convention, e.g. to export
+    /// points for Y-up tools. Point clouds are Z-up even if they were built from Y-up input, see
+    /// `Octree::input_axes`, so this must not be applied to already converted points again.
+    ConvertAxes { to: AxisConvention },
+    /// Keeps the points whose z is between `min_height` and `max_height` above the ground height
+    /// of the grid at their x and y, both inclusive, e.g. to extract vegetation from 0.5 to 3 m
+    /// above the ground. Points outside of the grid are dropped.
+    HeightAboveGround {
+        grid: HeightGrid,
+        min_height: f64,
+        max_height: f64,
+    },
+}
+
+impl OutputTransform {
+    /// The attributes the transform needs, which the query has to request.
+    fn required_attributes(&self) -> Vec<&str> {
+        match self {
+            OutputTransform::SortByOriginalIndex => vec![ORIGINAL_INDEX_ATTRIBUTE],
+            OutputTransform::SortBy { keys } => {
+                keys.iter().map(|key| key.attribute.as_str()).collect()
+            }
+            OutputTransform::ColorizeByClass { .. } => vec![CLASSIFICATION_ATTRIBUTE],
+            _ => Vec::new(),
+        }
+    }
+
+    fn validate(&self, point_query: &PointQuery) -> Result<()> {
+        if let Some(attribute) = self
+            .required_attributes()
+            .into_iter()
+            .find(|attribute| !point_query.requests_attribute(attribute))
+        {
+            return Err(ErrorKind::InvalidQuery(format!(
+                "The output transform needs attribute '{}' to be requested.",
+                attribute
+            ))
+            .into());
+        }
+        match self {
+            OutputTransform::SnapToGrid { cell } => {
+                if !cell.is_finite() || *cell <= 0.0 {
+                    return Err(ErrorKind::InvalidQuery(format!(
+                        "The grid cell size must be positive, found {}.",
+                        cell
+                    ))
+                    .into());
+                }
+            }
+            OutputTransform::S2CellIds { level } => {
+                if u64::from(*level) > s2::cellid::MAX_LEVEL {
+                    return Err(ErrorKind::InvalidQuery(format!(
+                        "The S2 cell level must be at most {}, found {}.",
+                        s2::cellid::MAX_LEVEL,
+                        level
+                    ))
+                    .into());
+                }
+            }
+            OutputTransform::StatisticalOutlierRemoval { k, std_mult } => {
+                if *k == 0 || !std_mult.is_finite() {
+                    return Err(ErrorKind::InvalidQuery(format!(
+                        "Outlier removal needs at least one neighbor and a finite standard \
+                         deviation multiplier, found k = {} and std_mult = {}.",
+                        k, std_mult
+                    ))
+                    .into());
+                }
+            }
+            OutputTransform::SortByOriginalIndex
+            | OutputTransform::ColorizeByClass { .. }
+            | OutputTransform::ConvertAxes { .. } => (),
+            OutputTransform::SortBy { keys } => {
+                if keys.is_empty() {
+                    return Err(ErrorKind::InvalidQuery(
+                        "Sorting needs at least one key.".to_string(),
+                    )
+                    .into());
+                }
+            }
+            OutputTransform::HeightAboveGround {
+                grid,
+                min_height,
+                max_height,
+            } => {
+                if !grid.cell_size.is_finite()
+                    || grid.cell_size <= 0.0
+                    || grid.num_columns == 0
+                    || grid.heights.is_empty()
+                    || grid.heights.len() % grid.num_columns != 0
+                {
+                    return Err(ErrorKind::InvalidQuery(format!(
+                        "The height grid needs a positive cell size and whole rows of {} heights, \
+                         found a cell size of {} and {} heights.",
+                        grid.num_columns,
+                        grid.cell_size,
+                        grid.heights.len()
+                    ))
+                    .into());
+                }
+                if min_height.is_nan() || max_height.is_nan() || min_height > max_height {
+                    return Err(ErrorKind::InvalidQuery(format!(
+                        "The minimum height {} must not be greater than the maximum height {}.",
+                        min_height, max_height
+                    ))
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, batch: &mut PointsBatch) {
+        match self {
+            OutputTransform::SnapToGrid { cell } => {
+                for p in &mut batch.position {
+                    p.coords.apply(|c| (c / cell).round() * cell);
+                }
+                // Snapping moves points by up to half a cell, possibly out of the bounding box.
+                if batch.bounding_box.is_some() {
+                    batch.bounding_box = Aabb::from_points(&batch.position);
+                }
+            }
+            OutputTransform::S2CellIds { level } => {
+                let cell_ids = batch
+                    .position
+                    .iter()
+                    .map(|p| CellID::from_point(p).parent(u64::from(*level)).0)
+                    .collect();
+                batch
+                    .attributes
+                    .insert(S2_CELL_ATTRIBUTE.to_string(), AttributeData::U64(cell_ids));
+            }
+            OutputTransform::StatisticalOutlierRemoval { k, std_mult } => {
+                if batch.position.len() <= *k {
+                    return;
+                }
+                let kd_tree = KdTree::new(
+                    batch
+                        .position
+                        .iter()
+                        .enumerate()
+                        .map(|(index, p)| (*p, index as u64))
+                        .collect(),
+                );
+                // The closest point is the point itself.
+                let mean_distances: Vec<f64> = batch
+                    .position
+                    .iter()
+                    .map(|p| {
+                        let neighbors = kd_tree.k_nearest(p, k + 1);
+                        neighbors.iter().skip(1).map(|(_, d)| d).sum::<f64>() / *k as f64
+                    })
+                    .collect();
+                let mut stats = AttrStats::default();
+                mean_distances.iter().for_each(|d| stats.add(*d));
+                let max_distance = stats.mean() + std_mult * stats.std();
+                let keep: Vec<bool> = mean_distances.iter().map(|d| *d <= max_distance).collect();
+                batch.retain(&keep);
+            }
+            OutputTransform::SortByOriginalIndex => {
+                if let Ok(original_indices) =
+                    batch.get_attribute_vec::<u64>(ORIGINAL_INDEX_ATTRIBUTE)
+                {
+                    let mut order: Vec<usize> = (0..original_indices.len()).collect();
+                    order.sort_unstable_by_key(|i| original_indices[*i]);
+                    *batch = batch.select(&order);
+                }
+            }
+            OutputTransform::SortBy { keys } => {
+                let values: Result<Vec<Vec<f64>>> = keys
+                    .iter()
+                    .map(|key| scalar_values(batch, &key.attribute))
+                    .collect();
+                if let Ok(values) = values {
+                    let mut order: Vec<usize> = (0..batch.position.len()).collect();
+                    order.sort_by(|a, b| {
+                        keys.iter()
+                            .zip(&values)
+                            .map(|(key, values)| {
+                                let ordering = values[*a]
+                                    .partial_cmp(&values[*b])
+                                    .unwrap_or(std::cmp::Ordering::Equal);
+                                if key.ascending {
+                                    ordering
+                                } else {
+                                    ordering.reverse()
+                                }
+                            })
+                            .find(|ordering| *ordering != std::cmp::Ordering::Equal)
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                    });
+                    *batch = batch.select(&order);
+                }
+            }
+            OutputTransform::ColorizeByClass {
+                palette,
+                default_color,
+            } => {
+                if let Ok(classes) = scalar_values(batch, CLASSIFICATION_ATTRIBUTE) {
+                    let color = classes
+                        .iter()
+                        .map(|class| {
+                            // Classes that aren't a u8 can't be in the palette.
+                            let class = class.to_u8().filter(|c| f64::from(*c) == *class);
+                            let rgb = class
+                                .and_then(|class| palette.get(&class))
+                                .unwrap_or(default_color);
+                            Vector3::new(rgb[0], rgb[1], rgb[2])
+                        })
+                        .collect();
+                    batch
+                        .attributes
+                        .insert("color".to_string(), AttributeData::U8Vec3(color));
+                }
+            }
+            OutputTransform::ConvertAxes { to } => {
+                for p in &mut batch.position {
+                    *p = to.from_z_up(p);
+                }
+                if let Some(bounding_box) = &batch.bounding_box {
+                    batch.bounding_box = Some(to.aabb_from_z_up(bounding_box));
+                }
+            }
+            OutputTransform::HeightAboveGround {
+                grid,
+                min_height,
+                max_height,
+            } => {
+                let keep: Vec<bool> = batch
+                    .position
+                    .iter()
+                    .map(|p| {
+                        matches!(grid.height_at(p.x, p.y), Some(ground)
+                            if ground + min_height <= p.z && p.z <= ground + max_height)
+                    })
+                    .collect();
+                batch.retain(&keep);
+            }
+        }
+    }
+}
+
+/// The U64 attribute added by `OutputTransform::S2CellIds`.
+pub const S2_CELL_ATTRIBUTE: &str = "s2_cell";
+
+/// The scalar attribute that `OutputTransform::ColorizeByClass` maps to colors.
+pub const CLASSIFICATION_ATTRIBUTE: &str = "classification";
+
+/// The F64 attributes added by `PointQuery::wgs84`: the latitude and longitude in degrees and the
+/// altitude above the WGS84 ellipsoid in meters.
+pub const LATITUDE_ATTRIBUTE: &str = "latitude";
+pub const LONGITUDE_ATTRIBUTE: &str = "longitude";
+pub const ALTITUDE_ATTRIBUTE: &str = "altitude";
+
+/// The I64 attributes added by `PointQuery::tile_output`: the coordinates of the tile of the
+/// batch, which are the same for all of its points.
+pub const TILE_X_ATTRIBUTE: &str = "tile_x";
+pub const TILE_Y_ATTRIBUTE: &str = "tile_y";
+
+/// As the only entry of `PointQuery::attributes`, requests all attributes of the point cloud.
+pub const ALL_ATTRIBUTES: &str = "*";
+
+/// As an entry of `PointQuery::attributes`, requests an F32 attribute weighting every point by
+/// how deep inside the query location it is, for blending at the edges of the location. The
+/// weight falls linearly from 1 at the center to 0 at the boundary. Only supported for `Aabb`,
+/// `Obb` and `Sphere` locations, and only if the point clouds don't have such an attribute
+/// themselves.
+pub const QUERY_WEIGHT_ATTRIBUTE: &str = "query_weight";
+
+/// As an entry of `PointQuery::attributes`, requests a U64 attribute with bit i set for every
+/// point that frustum i of a `Frustums` location contains, e.g. to find out which frames of a
+/// camera path see the point. Only supported for `Frustums` locations, and only if the point
+/// clouds don't have such an attribute themselves.
+pub const FRUSTUM_MASK_ATTRIBUTE: &str = "frustum_mask";
+
+/// Prefix of entries of `PointQuery::attributes` that request all attributes of the point cloud
+/// except the named one, e.g. "-intensity".
+pub const EXCLUDED_ATTRIBUTE_PREFIX: char = '-';
+
+impl<'a> PointQuery<'a> {
+    /// Reads a query from JSON, see above for the format, and validates it. The attribute names
+    /// are borrowed from the JSON, so they must not contain escape sequences.
+    pub fn from_json(json: &'a str) -> Result<Self> {
+        let query: PointQuery = serde_json::from_str(json)
+            .map_err(|e| ErrorKind::InvalidQuery(format!("Malformed query: {}", e)))?;
+        query.validate()?;
+        Ok(query)
+    }
+
+    /// Checks the invariants that deserializing a query does not, e.g. that intervals are not
+    /// empty.
+    pub fn validate(&self) -> Result<()> {
+        let mut attributes = self.attributes.clone();
+        attributes.sort_unstable();
+        if let Some(duplicate) = attributes.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(ErrorKind::InvalidQuery(format!(
+                "Attribute '{}' is requested more than once.",
+                duplicate[0]
+            ))
+            .into());
+        }
+        let intervals = self
+            .filter_intervals
+            .iter()
+            .map(|(attribute, interval)| (format!("filter interval of '{}'", attribute), interval))
+            .chain(
+                self.rgba_intensity_range
+                    .iter()
+                    .map(|interval| ("RGBA intensity range".to_string(), interval)),
+            );
+        for transform in &self.output_transforms {
+            transform.validate(self)?;
+        }
+        for (name, interval) in intervals {
+            let ordering = interval.lower_bound().partial_cmp(&interval.upper_bound());
+            // Also rejects NaN.
+            if !matches!(
+                ordering,
+                Some(std::cmp::Ordering::Less) | Some(std::cmp::Ordering::Equal)
+            ) {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "The {} is empty, its lower bound {} is greater than its upper bound {}.",
+                    name,
+                    interval.lower_bound(),
+                    interval.upper_bound()
+                ))
+                .into());
+            }
+        }
+        if !self.boundary_epsilon.is_finite() || self.boundary_epsilon < 0.0 {
+            return Err(ErrorKind::InvalidQuery(format!(
+                "The boundary epsilon must be finite and not negative, found {}.",
+                self.boundary_epsilon
+            ))
+            .into());
+        }
+        if let Some(radial_decimation) = &self.radial_decimation {
+            radial_decimation.validate()?;
+        }
+        if let Some(tile_size) = self.tile_output {
+            if !tile_size.is_finite() || tile_size <= 0.0 {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "The tile size must be finite and positive, found {}.",
+                    tile_size
+                ))
+                .into());
+            }
+        }
+        self.location.validate()
+    }
+
+    /// The location that selects the nodes and points, i.e. grown by `boundary_epsilon`.
+    pub fn culling_location(&self) -> Cow<'_, PointLocation> {
+        self.location.expanded(self.boundary_epsilon)
+    }
+
+    /// The minimum batch size of `ParallelIterator::coalesce_batches`, which does not apply to the
+    /// tiles of `tile_output`.
+    fn min_batch_size(&self, min_batch_size: usize) -> usize {
+        if self.tile_output.is_some() {
+            0
+        } else {
+            min_batch_size
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        matches!(&self.cancellation, Some(cancellation) if cancellation.is_cancelled())
+    }
+
+    /// Whether the attributes are requested with `ALL_ATTRIBUTES` or by excluding some.
+    fn requests_all_attributes(&self) -> bool {
+        self.attributes.iter().any(|a| stands_for_all_attributes(a))
+    }
+
+    /// The attributes that are excluded from all attributes.
+    fn excluded_attributes(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.attributes
+            .iter()
+            .filter_map(|a| a.strip_prefix(EXCLUDED_ATTRIBUTE_PREFIX))
+    }
+
+    /// Resolves the requested attributes against the attributes available in a point cloud.
+    /// These are either the listed attributes, or if the list contains `ALL_ATTRIBUTES` or
+    /// excluded attributes, all available attributes except the excluded ones, in alphabetical
+    /// order. Listing attributes together with these is an error, as is excluding an attribute
+    /// that is not available, or filtering by an attribute that is not among the resolved ones,
+    /// since the filters need its values.
+    pub fn resolve_attributes<'b>(
+        &'b self,
+        available: &'b HashMap<String, AttributeDataType>,
+    ) -> Result<Vec<&'b str>> {
+        let attributes = self.expand_attributes(available)?;
+        let mut filter_attributes: Vec<&str> = self.filter_intervals.keys().copied().collect();
+        filter_attributes.sort_unstable();
+        if let Some(unrequested) = filter_attributes
+            .iter()
+            .find(|attribute| !attributes.contains(attribute))
+        {
+            return Err(ErrorKind::InvalidQuery(format!(
+                "The filter attribute '{}' needs to be requested as well.",
+                unrequested
+            ))
+            .into());
+        }
+        Ok(attributes)
+    }
+
+    /// Whether the attribute is listed, or included in all attributes without being excluded. It
+    /// may still not be available.
+    pub fn requests_attribute(&self, attribute: &str) -> bool {
+        self.attributes.contains(&attribute)
+            || self.requests_all_attributes() && !self.excluded_attributes().any(|a| a == attribute)
+    }
+
+    /// `resolve_attributes` without checking the filter attributes.
+    pub(crate) fn expand_attributes<'b>(
+        &'b self,
+        available: &'b HashMap<String, AttributeDataType>,
+    ) -> Result<Vec<&'b str>> {
+        let excluded: Vec<&str> = self.excluded_attributes().collect();
+        let listed: Vec<&str> = self
+            .attributes
+            .iter()
+            .copied()
+            .filter(|a| !stands_for_all_attributes(a))
+            .collect();
+        if listed.len() == self.attributes.len() {
+            return Ok(listed);
+        }
+        if !listed.is_empty() {
+            return Err(ErrorKind::InvalidQuery(format!(
+                "Attributes {:?} cannot be requested together with all attributes.",
+                listed
+            ))
+            .into());
+        }
+        if let Some(unknown) = excluded.iter().find(|a| !available.contains_key(**a)) {
+            return Err(ErrorKind::InvalidQuery(format!(
+                "Cannot exclude attribute '{}', which is not available.",
+                unknown
+            ))
+            .into());
+        }
+        let mut attributes: Vec<&str> = available
+            .keys()
+            .map(String::as_str)
+            .filter(|a| !excluded.contains(a))
+            .collect();
+        attributes.sort_unstable();
+        Ok(attributes)
+    }
+}
+
+/// Whether an entry of `PointQuery::attributes` stands for all attributes, i.e. is
+/// `ALL_ATTRIBUTES` or excludes an attribute from them.
+fn stands_for_all_attributes(attribute: &str) -> bool {
+    attribute == ALL_ATTRIBUTES || attribute.starts_with(EXCLUDED_ATTRIBUTE_PREFIX)
 }
 
 /// Iterator over the points of a point cloud node within the specified PointCulling
@@ -90,32 +1041,153 @@ where
     }
 }
 
+impl<'a, Culling: PointCulling> FilteredIterator<'a, Culling> {
+    fn filter_batch(&self, mut batch: PointsBatch) -> PointsBatch {
+        let mut keep: Vec<bool> = batch
+            .position
+            .iter()
+            .map(|pos| self.culling.contains(&pos))
+            .collect();
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident, $interval:expr) => {
+                update_keep(&mut keep, $data, $interval)
+            };
+        }
+        for (attrib, interval) in self.filter_intervals {
+            let attr_data = batch
+                .attributes
+                .get(*attrib)
+                .expect("Filter attribute needs to be specified as query attribute.");
+            match_1d_attr_data!(attr_data, rhs, interval)
+        }
+        batch.retain(&keep);
+        batch
+    }
+
+    /// Like `next`, but returns the error of a corrupt node instead of panicking, see
+    /// `NodeIterator::try_next`.
+    pub fn try_next(&mut self) -> Result<Option<PointsBatch>> {
+        Ok(self
+            .node_iterator
+            .try_next()?
+            .map(|batch| self.filter_batch(batch)))
+    }
+}
+
 impl<'a, Culling: PointCulling> Iterator for FilteredIterator<'a, Culling> {
     type Item = PointsBatch;
 
     fn next(&mut self) -> Option<PointsBatch> {
-        let culling = &self.culling;
-        self.node_iterator.next().map(|mut batch| {
-            let mut keep: Vec<bool> = batch
-                .position
-                .iter()
-                .map(|pos| culling.contains(&pos))
-                .collect();
-            macro_rules! rhs {
-                ($dtype:ident, $data:ident, $interval:expr) => {
-                    update_keep(&mut keep, $data, $interval)
-                };
-            }
-            for (attrib, interval) in self.filter_intervals {
-                let attr_data = batch
-                    .attributes
-                    .get(*attrib)
-                    .expect("Filter attribute needs to be specified as query attribute.");
-                match_1d_attr_data!(attr_data, rhs, interval)
+        let batch = self.node_iterator.next()?;
+        Some(self.filter_batch(batch))
+    }
+}
+
+/// A batch sent from the threads of a query to the receiving end. For `PointQuery::deterministic`
+/// queries, it holds all points of a node and is tagged with the index of the point cloud and the
+/// node id.
+type SentBatch = (Option<(usize, String)>, PointsBatch);
+
+/// Passes on the points of the batches of a `PointQuery::deterministic` query ordered by their
+/// nodes, in batches of `batch_size` points.
+fn deliver_in_node_order(
+    batches: impl Iterator<Item = SentBatch>,
+    batch_size: usize,
+    mut func: impl FnMut(PointsBatch) -> Result<()>,
+) -> Result<()> {
+    let mut batches: Vec<SentBatch> = batches.collect();
+    batches.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut buf = PointsBatch {
+        position: Vec::new(),
+        attributes: BTreeMap::new(),
+        bounding_box: None,
+    };
+    for (_, mut batch) in batches {
+        buf.append(&mut batch)?;
+        while buf.position.len() >= batch_size {
+            let rest = buf.split_off(batch_size);
+            func(std::mem::replace(&mut buf, rest))?;
+        }
+    }
+    if buf.position.is_empty() {
+        return Ok(());
+    }
+    func(buf)
+}
+
+/// The coordinates of the tile of `PointQuery::tile_output` that contains the position. The tile
+/// `(x, y)` covers `x * tile_size` up to, but excluding, `(x + 1) * tile_size` in x, and likewise
+/// in y.
+pub fn tile_coordinates(position: &Point3<f64>, tile_size: f64) -> (i64, i64) {
+    (
+        (position.x / tile_size).floor() as i64,
+        (position.y / tile_size).floor() as i64,
+    )
+}
+
+/// Passes on the points of the batches of a `PointQuery::tile_output` query as one batch per tile,
+/// ordered by the tile coordinates.
+fn deliver_in_tiles(
+    batches: impl Iterator<Item = PointsBatch>,
+    tile_size: f64,
+    mut func: impl FnMut(PointsBatch) -> Result<()>,
+) -> Result<()> {
+    let mut tiles: BTreeMap<(i64, i64), PointsBatch> = BTreeMap::new();
+    for batch in batches {
+        let mut indices_by_tile: BTreeMap<(i64, i64), Vec<usize>> = BTreeMap::new();
+        for (i, p) in batch.position.iter().enumerate() {
+            indices_by_tile
+                .entry(tile_coordinates(p, tile_size))
+                .or_default()
+                .push(i);
+        }
+        for (tile, indices) in indices_by_tile {
+            let mut tile_batch = batch.select(&indices);
+            match tiles.get_mut(&tile) {
+                Some(tile_points) => tile_points.append(&mut tile_batch)?,
+                None => {
+                    tiles.insert(tile, tile_batch);
+                }
             }
-            batch.retain(&keep);
-            batch
-        })
+        }
+    }
+    for ((x, y), mut batch) in tiles {
+        let num_points = batch.position.len();
+        batch.attributes.insert(
+            TILE_X_ATTRIBUTE.to_string(),
+            AttributeData::I64(vec![x; num_points]),
+        );
+        batch.attributes.insert(
+            TILE_Y_ATTRIBUTE.to_string(),
+            AttributeData::I64(vec![y; num_points]),
+        );
+        func(batch)?;
+    }
+    Ok(())
+}
+
+/// Passes on the batches received from the threads of a query, in node order for
+/// `PointQuery::deterministic` queries and per tile for `PointQuery::tile_output` queries.
+fn deliver_batches(
+    point_query: &PointQuery,
+    mut batches: impl Iterator<Item = SentBatch>,
+    batch_size: usize,
+    mut func: impl FnMut(PointsBatch) -> Result<()>,
+) -> Result<()> {
+    match (point_query.tile_output, point_query.deterministic) {
+        (Some(tile_size), true) => {
+            let mut sorted = Vec::new();
+            deliver_in_node_order(batches, batch_size, |batch| {
+                sorted.push(batch);
+                Ok(())
+            })?;
+            deliver_in_tiles(sorted.into_iter(), tile_size, func)
+        }
+        (Some(tile_size), false) => {
+            deliver_in_tiles(batches.map(|(_, batch)| batch), tile_size, func)
+        }
+        (None, true) => deliver_in_node_order(batches, batch_size, func),
+        (None, false) => batches.try_for_each(|(_, batch)| func(batch)),
     }
 }
 
@@ -138,6 +1210,7 @@ where
             buf: PointsBatch {
                 position: Vec::new(),
                 attributes: BTreeMap::new(),
+                bounding_box: None,
             },
             batch_size,
             func,
@@ -165,82 +1238,820 @@ where
     }
 }
 
-// TODO(nnmm): Move this somewhere else
-pub trait PointCloud: Sync {
-    type Id: ToString + Send + Copy;
-    fn nodes_in_location(&self, location: &PointLocation) -> Vec<Self::Id>;
-    fn encoding_for_node(&self, id: Self::Id) -> Encoding;
-    /// Return all points in the selected node.
-    fn points_in_node(
-        &self,
-        attributes: &[&str],
-        node_id: Self::Id,
-        batch_size: usize,
-    ) -> Result<NodeIterator>;
-    fn bounding_box(&self) -> &Aabb;
-
-    /// Return the points matching the query in the selected node.
-    /// Why only a single node? Because the nodes are distributed to several `PointStream` instances
-    /// working in parallel by the `ParallelIterator`.
-    fn stream_points_for_query_in_node<F>(
-        &self,
-        query: &PointQuery,
-        node_id: Self::Id,
-        batch_size: usize,
-        callback: F,
-    ) -> Result<()>
-    where
-        F: FnMut(PointsBatch) -> Result<()>,
-    {
-        let filter_intervals = &query.filter_intervals;
-        let node_iterator = self.points_in_node(&query.attributes, node_id, batch_size)?;
-
-        dispatch_point_location!(
-            stream,
-            &query.location,
-            filter_intervals,
-            node_iterator,
-            callback
-        )
+/// Returns the values of a scalar attribute of the batch as `f64`.
+pub fn scalar_values(batch: &PointsBatch, attribute: &str) -> Result<Vec<f64>> {
+    let data = batch
+        .attributes
+        .get(attribute)
+        .ok_or_else(|| format!("Attribute '{}' not found.", attribute))?;
+    macro_rules! rhs {
+        ($dtype:ident, $data:ident) => {
+            Ok($data
+                .iter()
+                .map(|v| v.to_f64().unwrap_or(f64::NAN))
+                .collect())
+        };
     }
-}
-
-// TODO(nnmm): Instead of having this helper function, make stream_points_for_query_in_node
-// accept a T: PointCulling, so we can dispatch to this function directly
-fn stream<'a, T: PointCulling + Clone, F: FnMut(PointsBatch) -> Result<()>>(
-    intv: &'a HashMap<&'a str, ClosedInterval<f64>>,
-    itr: NodeIterator,
-    callback: F,
-    culling: &T,
-) -> Result<()> {
-    let culling: T = culling.clone();
-    FilteredIterator {
-        culling,
-        filter_intervals: intv,
-        node_iterator: itr,
+    match data {
+        AttributeData::U8Vec3(_) | AttributeData::U16Vec3(_) | AttributeData::F64Vec3(_) => Err(
+            ErrorKind::InvalidInput(format!("Attribute '{}' is not a scalar.", attribute)).into(),
+        ),
+        _ => match_1d_attr_data!(data, rhs),
     }
-    .try_for_each(callback)
 }
 
-/// Iterator on point batches
-pub struct ParallelIterator<'a, C> {
-    point_clouds: &'a [C],
-    point_query: &'a PointQuery<'a>,
-    batch_size: usize,
-    num_threads: usize,
-    buffer_size: usize,
+/// Reorders a stream of batches by a scalar attribute, e.g. the capture time, and passes them on
+/// in windows of `window` length of that attribute. At most about `max_buffered_points` are held
+/// back, so the output is only fully sorted if no point arrives after more than half that many
+/// points with larger values. The points of one window may be passed on in several batches.
+pub struct WindowedSorter<F>
+where
+    F: FnMut(PointsBatch) -> Result<()>,
+{
+    attribute: String,
+    window: f64,
+    max_buffered_points: usize,
+    buf: PointsBatch,
+    func: F,
 }
 
-impl<'a, C> ParallelIterator<'a, C>
+impl<F> WindowedSorter<F>
 where
-    C: PointCloud,
+    F: FnMut(PointsBatch) -> Result<()>,
 {
     pub fn new(
-        point_clouds: &'a [C],
-        point_query: &'a PointQuery<'a>,
-        batch_size: usize,
-        num_threads: usize,
-        buffer_size: usize,
+        attribute: impl Into<String>,
+        window: f64,
+        max_buffered_points: usize,
+        func: F,
+    ) -> Self {
+        assert!(window > 0.0, "The window length must be positive.");
+        WindowedSorter {
+            attribute: attribute.into(),
+            window,
+            max_buffered_points,
+            buf: PointsBatch {
+                position: Vec::new(),
+                attributes: BTreeMap::new(),
+                bounding_box: None,
+            },
+            func,
+        }
+    }
+
+    pub fn push(&mut self, mut batch: PointsBatch) -> Result<()> {
+        self.buf.append(&mut batch)?;
+        if self.buf.position.len() > self.max_buffered_points {
+            // Keep the later half buffered to absorb points that arrive late.
+            let num_points = self.buf.position.len() - self.max_buffered_points / 2;
+            self.emit(num_points)?;
+        }
+        Ok(())
+    }
+
+    /// Passes on all points that are still buffered.
+    pub fn finish(mut self) -> Result<()> {
+        let num_points = self.buf.position.len();
+        self.emit(num_points)
+    }
+
+    /// Sorts the buffer and passes on its first `num_points` points.
+    fn emit(&mut self, num_points: usize) -> Result<()> {
+        if num_points == 0 {
+            return Ok(());
+        }
+        let values = scalar_values(&self.buf, &self.attribute)?;
+        let mut order: Vec<usize> = (0..values.len()).collect();
+        order.sort_by(|a, b| {
+            values[*a]
+                .partial_cmp(&values[*b])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let mut sorted = self.buf.select(&order);
+        self.buf = sorted.split_off(num_points);
+
+        let window = self.window;
+        let window_index = |i: usize| (values[order[i]] / window).floor();
+        let mut windows = Vec::new();
+        for i in (1..num_points).rev() {
+            if window_index(i) != window_index(i - 1) {
+                windows.push(sorted.split_off(i));
+            }
+        }
+        windows.push(sorted);
+        windows.into_iter().rev().try_for_each(&mut self.func)
+    }
+}
+
+/// Merges a stream of batches into batches of at least `min_points` points, e.g. to reduce the
+/// overhead of a callback for the many small batches of the nodes of a fine octree. Only the last
+/// batch passed on by `finish` may be smaller.
+pub struct BatchCoalescer<F>
+where
+    F: FnMut(PointsBatch) -> Result<()>,
+{
+    min_points: usize,
+    buf: PointsBatch,
+    func: F,
+}
+
+impl<F> BatchCoalescer<F>
+where
+    F: FnMut(PointsBatch) -> Result<()>,
+{
+    pub fn new(min_points: usize, func: F) -> Self {
+        BatchCoalescer {
+            min_points,
+            buf: PointsBatch {
+                position: Vec::new(),
+                attributes: BTreeMap::new(),
+                bounding_box: None,
+            },
+            func,
+        }
+    }
+
+    pub fn push(&mut self, mut batch: PointsBatch) -> Result<()> {
+        self.buf.append(&mut batch)?;
+        if self.buf.position.len() >= self.min_points {
+            let batch = self.buf.split_off(0);
+            (self.func)(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Passes on the points that are still buffered, if any.
+    pub fn finish(mut self) -> Result<()> {
+        if self.buf.position.is_empty() {
+            return Ok(());
+        }
+        (self.func)(self.buf.split_off(0))
+    }
+}
+
+/// Mixes the bits of `x`, see SplitMix64.
+fn mix_bits(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Draws a uniformly random sample of exactly `num_samples` points from a stream of batches, or
+/// all points if there are fewer. Every point gets a pseudo-random key derived from the seed and
+/// its position, and the points with the smallest keys are kept. So the sample only depends on
+/// the seed and the set of points, not on the order in which they arrive, which is what makes
+/// it reproducible for parallel queries. Points at the same position have the same key and are
+/// usually sampled together. At most about twice `num_samples` points are held in memory.
+pub struct ReservoirSampler {
+    num_samples: usize,
+    seed: u64,
+    buf: PointsBatch,
+    keys: Vec<u64>,
+    /// Points with larger keys can not be in the sample anymore.
+    max_key: u64,
+}
+
+impl ReservoirSampler {
+    pub fn new(num_samples: usize, seed: u64) -> Self {
+        ReservoirSampler {
+            num_samples,
+            seed,
+            buf: PointsBatch {
+                position: Vec::new(),
+                attributes: BTreeMap::new(),
+                bounding_box: None,
+            },
+            keys: Vec::new(),
+            max_key: u64::MAX,
+        }
+    }
+
+    pub fn push(&mut self, mut batch: PointsBatch) -> Result<()> {
+        if self.num_samples == 0 {
+            return Ok(());
+        }
+        let seed = mix_bits(self.seed);
+        let keys: Vec<u64> = batch
+            .position
+            .iter()
+            .map(|p| {
+                p.coords
+                    .iter()
+                    .fold(seed, |key, coord| mix_bits(key ^ coord.to_bits()))
+            })
+            .collect();
+        let max_key = self.max_key;
+        let keep: Vec<bool> = keys.iter().map(|key| *key <= max_key).collect();
+        batch.retain(&keep);
+        self.buf.append(&mut batch)?;
+        self.keys
+            .extend(keys.into_iter().filter(|key| *key <= max_key));
+        if self.keys.len() >= 2 * self.num_samples {
+            self.shrink();
+        }
+        Ok(())
+    }
+
+    /// Returns the sampled points, ordered by their keys.
+    pub fn finish(mut self) -> PointsBatch {
+        self.shrink();
+        self.buf
+    }
+
+    /// Keeps only the points with the `num_samples` smallest keys, ordered by key.
+    fn shrink(&mut self) {
+        let mut order: Vec<usize> = (0..self.keys.len()).collect();
+        if order.len() > self.num_samples {
+            order.select_nth_unstable_by_key(self.num_samples, |i| self.keys[*i]);
+            order.truncate(self.num_samples);
+        }
+        order.sort_unstable_by_key(|i| self.keys[*i]);
+        if let Some(last) = order.last() {
+            if order.len() == self.num_samples {
+                self.max_key = self.keys[*last];
+            }
+        }
+        self.buf = self.buf.select(&order);
+        self.keys = order.iter().map(|i| self.keys[*i]).collect();
+    }
+}
+
+// TODO(nnmm): Move this somewhere else
+pub trait PointCloud: Sync {
+    type Id: ToString + Send + Copy;
+    fn nodes_in_location(&self, location: &PointLocation) -> Vec<Self::Id>;
+    fn encoding_for_node(&self, id: Self::Id) -> Encoding;
+    /// The number of points in the node according to the meta data, i.e. without reading the
+    /// node data. `None` if the point cloud does not contain the node.
+    fn node_point_count(&self, id: Self::Id) -> Option<u64>;
+    /// Return all points in the selected node. Once `cancellation` is cancelled, reading stops
+    /// and the iterator ends early.
+    fn points_in_node(
+        &self,
+        attributes: &[&str],
+        node_id: Self::Id,
+        batch_size: usize,
+        cancellation: Option<&CancellationToken>,
+    ) -> Result<NodeIterator>;
+    fn bounding_box(&self) -> &Aabb;
+    /// The total number of points in all nodes.
+    fn num_points(&self) -> usize;
+    /// The data types of the attributes that can be queried.
+    fn attribute_data_types(&self) -> &HashMap<String, AttributeDataType>;
+    /// How the positions relate to the earth, if known.
+    fn coordinate_system(&self) -> Option<&CoordinateSystem> {
+        None
+    }
+    /// The attributes that only the leaf nodes store, see `BuildOptions::leaf_only_attributes`.
+    fn leaf_only_attributes(&self) -> &[String] {
+        &[]
+    }
+    /// Whether the node has no children. Point clouds without a node hierarchy only have leaves.
+    fn is_leaf(&self, _node_id: Self::Id) -> bool {
+        true
+    }
+
+    /// What running the query would cost, from the meta data and the nodes the location selects,
+    /// i.e. without reading any node data. E.g. to ask before starting a query that takes long.
+    fn estimate_query(&self, query: &PointQuery) -> Result<QueryEstimate> {
+        let attribute_data_types = self.attribute_data_types();
+        // Attributes that are computed while querying, like "rgba", are not stored.
+        let attribute_bytes: usize = query
+            .resolve_attributes(attribute_data_types)?
+            .iter()
+            .filter_map(|attribute| attribute_data_types.get(*attribute))
+            .map(|data_type| data_type.size_of())
+            .sum();
+        let mut estimate = QueryEstimate::default();
+        for node_id in self.nodes_in_location(&query.culling_location()) {
+            let num_points = self.node_point_count(node_id).unwrap_or(0);
+            let bytes_per_coordinate = match self.encoding_for_node(node_id) {
+                Encoding::Plain => std::mem::size_of::<f64>(),
+                Encoding::ScaledToCube(_, _, position_encoding) => {
+                    position_encoding.bytes_per_coordinate()
+                }
+            };
+            estimate.candidate_nodes += 1;
+            estimate.approx_points += num_points;
+            estimate.approx_bytes +=
+                num_points * (3 * bytes_per_coordinate + attribute_bytes) as u64;
+        }
+        Ok(estimate)
+    }
+
+    /// Return the points matching the query in the selected node.
+    /// Why only a single node? Because the nodes are distributed to several `PointStream` instances
+    /// working in parallel by the `ParallelIterator`.
+    fn stream_points_for_query_in_node<F>(
+        &self,
+        query: &PointQuery,
+        node_id: Self::Id,
+        batch_size: usize,
+        callback: F,
+    ) -> Result<()>
+    where
+        F: FnMut(PointsBatch) -> Result<()>,
+    {
+        self.read_node_for_query(query, node_id, batch_size, false)?
+            .stream_points(query, callback)
+    }
+
+    /// Opens the selected node for `stream_points_for_query_in_node`. With `prefetch`, all of its
+    /// data is read into memory right away, so that streaming its points does no more IO.
+    fn read_node_for_query(
+        &self,
+        query: &PointQuery,
+        node_id: Self::Id,
+        batch_size: usize,
+        prefetch: bool,
+    ) -> Result<NodeRead> {
+        let mut attributes = query.resolve_attributes(self.attribute_data_types())?;
+        let leaf_only = self.leaf_only_attributes();
+        let is_leaf_only = |attribute: &str| leaf_only.iter().any(|l| l == attribute);
+        if query.requests_all_attributes() {
+            // All batches of a query have the same attributes, which the points of inner nodes
+            // don't have.
+            attributes.retain(|attribute| !is_leaf_only(attribute));
+        } else if let Some(attribute) = attributes.iter().find(|a| is_leaf_only(a)) {
+            if let PointLocation::AllPointsInDepthRange(_) = query.location {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "The attribute '{}' is only available at full resolution, not for the coarse \
+                     levels.",
+                    attribute
+                ))
+                .into());
+            }
+            // Only the points of the leaves are returned.
+            if !self.is_leaf(node_id) {
+                return Ok(NodeRead::empty());
+            }
+        }
+        // If points are selected by original index, whether the index was not requested itself.
+        let mut original_index_selection = None;
+        if let PointLocation::ByOriginalIndex(_) = query.location {
+            if !self
+                .attribute_data_types()
+                .contains_key(ORIGINAL_INDEX_ATTRIBUTE)
+            {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "Selecting points by original index needs the '{}' attribute, which is not \
+                     stored.",
+                    ORIGINAL_INDEX_ATTRIBUTE
+                ))
+                .into());
+            }
+            // The points that are moved into inner nodes lose a leaf only index.
+            if is_leaf_only(ORIGINAL_INDEX_ATTRIBUTE) && !self.is_leaf(node_id) {
+                return Ok(NodeRead::empty());
+            }
+            let is_unrequested = !attributes.contains(&ORIGINAL_INDEX_ATTRIBUTE);
+            if is_unrequested {
+                attributes.push(ORIGINAL_INDEX_ATTRIBUTE);
+            }
+            original_index_selection = Some(is_unrequested);
+        }
+        // Whether 8 bit color is read to be upscaled, and if so, whether it was not requested
+        // itself.
+        let mut upscaled_color = None;
+        if attributes.contains(&COLOR16_ATTRIBUTE)
+            && !self.attribute_data_types().contains_key(COLOR16_ATTRIBUTE)
+            && self.attribute_data_types().contains_key("color")
+        {
+            if !query.upscale_color {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "'{}' is not stored, only 8 bit 'color', which is only upscaled on request.",
+                    COLOR16_ATTRIBUTE
+                ))
+                .into());
+            }
+            attributes.retain(|attribute| *attribute != COLOR16_ATTRIBUTE);
+            let is_unrequested = !attributes.contains(&"color");
+            if is_unrequested {
+                attributes.push("color");
+            }
+            upscaled_color = Some(is_unrequested);
+        }
+        // The sources of packed RGBA values that were not requested themselves.
+        let mut rgba_sources = None;
+        if attributes.contains(&RGBA_ATTRIBUTE)
+            && !self.attribute_data_types().contains_key(RGBA_ATTRIBUTE)
+        {
+            if !self.attribute_data_types().contains_key("color") {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "'{}' is not stored and needs a 'color' attribute to be packed from.",
+                    RGBA_ATTRIBUTE
+                ))
+                .into());
+            }
+            attributes.retain(|attribute| *attribute != RGBA_ATTRIBUTE);
+            let mut unrequested = Vec::new();
+            for source in &["color", "intensity"] {
+                let is_available = self.attribute_data_types().contains_key(*source);
+                if is_available && !attributes.contains(source) {
+                    attributes.push(source);
+                    unrequested.push(*source);
+                }
+            }
+            rgba_sources = Some(unrequested);
+        }
+        let emit_query_weight = attributes.contains(&QUERY_WEIGHT_ATTRIBUTE)
+            && !self
+                .attribute_data_types()
+                .contains_key(QUERY_WEIGHT_ATTRIBUTE);
+        if emit_query_weight {
+            // Any point tells whether the location supports weights at all.
+            if query.location.query_weight(&Point3::origin()).is_none() {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "'{}' is not supported for this location.",
+                    QUERY_WEIGHT_ATTRIBUTE
+                ))
+                .into());
+            }
+            attributes.retain(|attribute| *attribute != QUERY_WEIGHT_ATTRIBUTE);
+        }
+        let emit_frustum_mask = attributes.contains(&FRUSTUM_MASK_ATTRIBUTE)
+            && !self
+                .attribute_data_types()
+                .contains_key(FRUSTUM_MASK_ATTRIBUTE);
+        if emit_frustum_mask {
+            if !matches!(query.location, PointLocation::Frustums(_)) {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "'{}' is only supported for a Frustums location.",
+                    FRUSTUM_MASK_ATTRIBUTE
+                ))
+                .into());
+            }
+            attributes.retain(|attribute| *attribute != FRUSTUM_MASK_ATTRIBUTE);
+        }
+        let ecef_from_positions = if query.wgs84 {
+            let coordinate_system = self.coordinate_system().ok_or_else(|| {
+                ErrorKind::InvalidQuery(
+                    "The point cloud is not georeferenced, so its positions can't be converted \
+                     to WGS84."
+                        .to_string(),
+                )
+            })?;
+            let ecef_from_positions = coordinate_system.ecef_from_positions().ok_or_else(|| {
+                ErrorKind::InvalidQuery(format!(
+                    "Positions in {:?} can't be converted to WGS84.",
+                    coordinate_system
+                ))
+            })?;
+            Some(ecef_from_positions)
+        } else {
+            None
+        };
+        let mut node_iterator = self.points_in_node(
+            &attributes,
+            node_id,
+            batch_size,
+            query.cancellation.as_ref(),
+        )?;
+        if prefetch {
+            node_iterator.prefetch()?;
+        }
+        Ok(NodeRead {
+            node_iterator,
+            original_index_selection,
+            upscaled_color,
+            rgba_sources,
+            emit_query_weight,
+            emit_frustum_mask,
+            ecef_from_positions,
+        })
+    }
+}
+
+/// A node opened for a query, see `PointCloud::read_node_for_query`.
+pub struct NodeRead {
+    node_iterator: NodeIterator,
+    /// If points are selected by original index, whether the index was not requested itself.
+    original_index_selection: Option<bool>,
+    /// If 8 bit color is upscaled, whether it was not requested itself.
+    upscaled_color: Option<bool>,
+    /// The sources of packed RGBA values that were not requested themselves.
+    rgba_sources: Option<Vec<&'static str>>,
+    emit_query_weight: bool,
+    emit_frustum_mask: bool,
+    /// Set if WGS84 coordinates are added.
+    ecef_from_positions: Option<Matrix4<f64>>,
+}
+
+impl NodeRead {
+    /// A node without points for the query.
+    fn empty() -> Self {
+        NodeRead {
+            node_iterator: NodeIterator::default(),
+            original_index_selection: None,
+            upscaled_color: None,
+            rgba_sources: None,
+            emit_query_weight: false,
+            emit_frustum_mask: false,
+            ecef_from_positions: None,
+        }
+    }
+
+    /// Streams the points of the node that match the query.
+    pub fn stream_points<F>(self, query: &PointQuery, callback: F) -> Result<()>
+    where
+        F: FnMut(PointsBatch) -> Result<()>,
+    {
+        let NodeRead {
+            node_iterator,
+            original_index_selection,
+            upscaled_color,
+            rgba_sources,
+            emit_query_weight,
+            emit_frustum_mask,
+            ecef_from_positions,
+        } = self;
+        let filter_intervals = &query.filter_intervals;
+        let intensity_range = query
+            .rgba_intensity_range
+            .unwrap_or_else(|| ClosedInterval::new(0.0, 1.0));
+        let mut callback = callback;
+        // Set once the point of an overview is found, which ends reading the node.
+        let overview_done = Cell::new(false);
+        let callback = |mut batch: PointsBatch| {
+            if let (Some(is_unrequested), PointLocation::ByOriginalIndex(indices)) =
+                (original_index_selection, &query.location)
+            {
+                let keep: Vec<bool> = batch
+                    .get_attribute_vec::<u64>(ORIGINAL_INDEX_ATTRIBUTE)?
+                    .iter()
+                    .map(|index| indices.contains(index))
+                    .collect();
+                batch.retain(&keep);
+                if is_unrequested {
+                    batch.attributes.remove(ORIGINAL_INDEX_ATTRIBUTE);
+                }
+            }
+            if let Some(radial_decimation) = &query.radial_decimation {
+                let keep: Vec<bool> = batch
+                    .position
+                    .iter()
+                    .map(|p| radial_decimation.keeps(p))
+                    .collect();
+                batch.retain(&keep);
+            }
+            if query.overview {
+                if overview_done.get() || batch.position.is_empty() {
+                    return Ok(());
+                }
+                batch.split_off(1);
+                overview_done.set(true);
+            }
+            if upscaled_color.is_some() {
+                let color16 = upscale_color(batch.get_attribute_vec("color")?);
+                batch.attributes.insert(
+                    COLOR16_ATTRIBUTE.to_string(),
+                    AttributeData::U16Vec3(color16),
+                );
+            }
+            if let Some(unrequested) = &rgba_sources {
+                let rgba = pack_rgba(&batch, &intensity_range)?;
+                for source in unrequested {
+                    batch.attributes.remove(*source);
+                }
+                batch
+                    .attributes
+                    .insert(RGBA_ATTRIBUTE.to_string(), AttributeData::U32(rgba));
+            }
+            // Packing RGBA needs the color as well, so it is removed only now.
+            if upscaled_color == Some(true) {
+                batch.attributes.remove("color");
+            }
+            if emit_query_weight {
+                let weights = batch
+                    .position
+                    .iter()
+                    .map(|p| query.location.query_weight(p).unwrap())
+                    .collect();
+                batch.attributes.insert(
+                    QUERY_WEIGHT_ATTRIBUTE.to_string(),
+                    AttributeData::F32(weights),
+                );
+            }
+            if let (true, PointLocation::Frustums(frustums)) = (emit_frustum_mask, &query.location)
+            {
+                let masks = batch.position.iter().map(|p| frustums.mask(p)).collect();
+                batch.attributes.insert(
+                    FRUSTUM_MASK_ATTRIBUTE.to_string(),
+                    AttributeData::U64(masks),
+                );
+            }
+            if let Some(ecef_from_positions) = &ecef_from_positions {
+                let (mut latitudes, mut longitudes, mut altitudes) =
+                    (Vec::new(), Vec::new(), Vec::new());
+                for p in &batch.position {
+                    let ecef = ecef_from_positions.transform_point(p);
+                    let lat_lng_alt = WGS84::from(ECEF::new(ecef.x, ecef.y, ecef.z));
+                    latitudes.push(lat_lng_alt.latitude_degrees());
+                    longitudes.push(lat_lng_alt.longitude_degrees());
+                    altitudes.push(lat_lng_alt.altitude());
+                }
+                for (name, values) in [
+                    (LATITUDE_ATTRIBUTE, latitudes),
+                    (LONGITUDE_ATTRIBUTE, longitudes),
+                    (ALTITUDE_ATTRIBUTE, altitudes),
+                ] {
+                    batch
+                        .attributes
+                        .insert(name.to_string(), AttributeData::F64(values));
+                }
+            }
+            for transform in &query.output_transforms {
+                transform.apply(&mut batch);
+            }
+            callback(batch)
+        };
+        dispatch_point_location!(
+            stream,
+            &*query.culling_location(),
+            filter_intervals,
+            node_iterator,
+            &overview_done,
+            callback
+        )?;
+        // A cancelled node iterator ends early, which must not look like a complete result.
+        if query.is_cancelled() {
+            return Err(ErrorKind::Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+// TODO(nnmm): Instead of having this helper function, make stream_points_for_query_in_node
+// accept a T: PointCulling, so we can dispatch to this function directly
+fn stream<'a, T: PointCulling + Clone, F: FnMut(PointsBatch) -> Result<()>>(
+    intv: &'a HashMap<&'a str, ClosedInterval<f64>>,
+    itr: NodeIterator,
+    done: &Cell<bool>,
+    callback: F,
+    culling: &T,
+) -> Result<()> {
+    let culling: T = culling.clone();
+    let mut callback = callback;
+    let mut filtered = FilteredIterator {
+        culling,
+        filter_intervals: intv,
+        node_iterator: itr,
+    };
+    while !done.get() {
+        match filtered.try_next()? {
+            Some(batch) => callback(batch)?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// The cost of a query before running it, see `PointCloud::estimate_query`. The points and bytes
+/// are those of the selected nodes, i.e. an upper bound, since usually not all of their points
+/// match the location and filters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryEstimate {
+    /// The number of nodes the query reads.
+    pub candidate_nodes: usize,
+    pub approx_points: u64,
+    /// The size of the encoded positions and requested attributes of the points.
+    pub approx_bytes: u64,
+}
+
+impl std::ops::AddAssign for QueryEstimate {
+    fn add_assign(&mut self, other: Self) {
+        self.candidate_nodes += other.candidate_nodes;
+        self.approx_points += other.approx_points;
+        self.approx_bytes += other.approx_bytes;
+    }
+}
+
+/// What a successfully completed query returned, to distinguish a query without matches from one
+/// that never ran.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuerySummary {
+    /// The number of points passed to the callback.
+    pub points: usize,
+    /// The number of nodes whose points were streamed.
+    pub nodes_visited: usize,
+    /// How the work was distributed, one entry per thread or task of the query. Their points and
+    /// nodes add up to the totals.
+    pub threads: Vec<ThreadSummary>,
+    /// The point clouds with nodes that could not be read, ordered by index. Always empty unless
+    /// the query skips failed nodes.
+    pub failures: Vec<PointCloudFailure>,
+}
+
+/// A point cloud that nodes were skipped of, see `PointQuery::skip_failed_nodes`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PointCloudFailure {
+    /// The index of the point cloud among those queried.
+    pub point_cloud: usize,
+    pub nodes_failed: usize,
+    /// The ids of the failed nodes, sorted, e.g. to rebuild or inspect them.
+    pub node_ids: Vec<String>,
+    /// The error of one of the failed nodes.
+    pub error: String,
+}
+
+/// What one thread or task of a query did, e.g. to spot load imbalance through a node that
+/// dominates the query.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadSummary {
+    /// The number of points the thread sent to the callback.
+    pub points: usize,
+    pub nodes_visited: usize,
+    /// The time spent reading and filtering points.
+    pub busy: Duration,
+    /// The time spent waiting: for the thread pool to start the task, for the callback to keep up
+    /// with the points, for nodes to be read by IO threads, see `ParallelIterator::io_threads`,
+    /// and for the other threads to finish after running out of nodes.
+    pub idle: Duration,
+}
+
+/// What `stream_jobs` records to compute a `ThreadSummary` once the query is done.
+struct ThreadRecord {
+    points: usize,
+    nodes_visited: usize,
+    started: Instant,
+    finished: Instant,
+    blocked: Duration,
+    /// The point cloud index, node id and error of every node that failed and was skipped.
+    failed_nodes: Vec<(usize, String, String)>,
+}
+
+impl ThreadRecord {
+    fn summary(&self, query_started: Instant, query_finished: Instant) -> ThreadSummary {
+        let active = self.finished.saturating_duration_since(self.started);
+        let busy = active.checked_sub(self.blocked).unwrap_or_default();
+        let total = query_finished.saturating_duration_since(query_started);
+        ThreadSummary {
+            points: self.points,
+            nodes_visited: self.nodes_visited,
+            busy,
+            idle: total.checked_sub(busy).unwrap_or_default(),
+        }
+    }
+}
+
+impl QuerySummary {
+    fn from_threads(
+        records: &[ThreadRecord],
+        query_started: Instant,
+        query_finished: Instant,
+    ) -> Self {
+        let threads: Vec<ThreadSummary> = records
+            .iter()
+            .map(|record| record.summary(query_started, query_finished))
+            .collect();
+        let mut failures: BTreeMap<usize, PointCloudFailure> = BTreeMap::new();
+        for (point_cloud, node_id, error) in records.iter().flat_map(|record| &record.failed_nodes)
+        {
+            let failure = failures
+                .entry(*point_cloud)
+                .or_insert_with(|| PointCloudFailure {
+                    point_cloud: *point_cloud,
+                    nodes_failed: 0,
+                    node_ids: Vec::new(),
+                    error: error.clone(),
+                });
+            failure.nodes_failed += 1;
+            failure.node_ids.push(node_id.clone());
+        }
+        for failure in failures.values_mut() {
+            failure.node_ids.sort_unstable();
+        }
+        QuerySummary {
+            points: threads.iter().map(|thread| thread.points).sum(),
+            nodes_visited: threads.iter().map(|thread| thread.nodes_visited).sum(),
+            threads,
+            failures: failures.into_values().collect(),
+        }
+    }
+}
+
+/// Iterator on point batches
+pub struct ParallelIterator<'a, C> {
+    point_clouds: &'a [C],
+    point_query: &'a PointQuery<'a>,
+    batch_size: usize,
+    num_threads: usize,
+    buffer_size: usize,
+    num_io_threads: usize,
+    min_batch_size: usize,
+}
+
+impl<'a, C> ParallelIterator<'a, C>
+where
+    C: PointCloud,
+{
+    pub fn new(
+        point_clouds: &'a [C],
+        point_query: &'a PointQuery<'a>,
+        batch_size: usize,
+        num_threads: usize,
+        buffer_size: usize,
     ) -> Self {
         ParallelIterator {
             point_clouds,
@@ -248,87 +2059,1193 @@ where
             batch_size,
             num_threads,
             buffer_size,
+            num_io_threads: 0,
+            min_batch_size: 0,
         }
     }
 
+    /// Reads the nodes into memory on `num_io_threads` threads of their own, which pass them on
+    /// to the `num_threads` threads that decode and filter the points through a queue of at most
+    /// `num_io_threads` nodes. This keeps the decoding threads busy while many reads are in
+    /// flight, e.g. for data providers with a high latency. By default, or with 0, every thread
+    /// reads the nodes that it decodes itself.
+    pub fn io_threads(mut self, num_io_threads: usize) -> Self {
+        self.num_io_threads = num_io_threads;
+        self
+    }
+
+    /// Merges the batches of the threads into batches of at least `min_batch_size` points before
+    /// passing them to the function, see `BatchCoalescer`. Every thread sends batches of
+    /// `batch_size` points, but those that it sends last and those of small queries are smaller.
+    /// By default, or with 0, they are passed on as they come.
+    pub fn coalesce_batches(mut self, min_batch_size: usize) -> Self {
+        self.min_batch_size = min_batch_size;
+        self
+    }
+
     /// compute a function while iterating on a batch of points
-    pub fn try_for_each_batch<F>(&mut self, func: F) -> Result<()>
+    pub fn try_for_each_batch<F>(&mut self, mut func: F) -> Result<QuerySummary>
     where
         F: FnMut(PointsBatch) -> Result<()>,
     {
+        let query_started = Instant::now();
         // get thread safe fifo
-        let jobs = Injector::<(&C, C::Id)>::new();
-        let mut number_of_jobs = 0;
-        self.point_clouds
-            .iter()
-            .flat_map(|point_cloud| {
-                std::iter::repeat(point_cloud)
-                    .zip(point_cloud.nodes_in_location(&self.point_query.location))
-            })
-            .for_each(|(node_id, point_cloud)| {
-                jobs.push((node_id, point_cloud));
-                number_of_jobs += 1;
-            });
+        let jobs = queue_jobs(self.point_clouds, &self.point_query.culling_location());
 
         // operate on nodes with limited number of threads
         crossbeam::scope(|s| {
-            let (tx, rx) = crossbeam::channel::bounded::<PointsBatch>(self.buffer_size);
+            let (tx, rx) = crossbeam::channel::bounded::<SentBatch>(self.buffer_size);
+            let (node_tx, node_rx) =
+                crossbeam::channel::bounded::<PrefetchedNode<C::Id>>(self.num_io_threads);
+            for _ in 0..self.num_io_threads {
+                let node_tx = node_tx.clone();
+                let point_clouds = self.point_clouds;
+                let point_query = &self.point_query;
+                let batch_size = self.batch_size;
+                let jobs = &jobs;
+                s.spawn(move |_| {
+                    prefetch_jobs(point_clouds, jobs, point_query, batch_size, &node_tx)
+                });
+            }
+            // The decoding threads run out of nodes once the IO threads are done.
+            drop(node_tx);
+            let mut threads = Vec::with_capacity(self.num_threads);
             for curr_thread in 0..self.num_threads {
                 let tx = tx.clone();
+                // Once the decoding threads exit, the IO threads stop as well.
+                let node_rx = node_rx.clone();
+                let point_clouds = self.point_clouds;
                 let point_query = &self.point_query;
                 let batch_size = self.batch_size;
-                let worker = Worker::new_fifo();
                 let jobs = &jobs;
+                let num_io_threads = self.num_io_threads;
 
-                s.spawn(move |_| {
-                    let send_func = |batch: PointsBatch| match tx.send(batch) {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(ErrorKind::Channel(format!(
-                            "Thread {}: sending operation failed, nothing more to do {:?}",
-                            curr_thread, e,
-                        ))
-                        .into()),
+                threads.push(s.spawn(move |_| {
+                    let nodes = if num_io_threads > 0 {
+                        NodeSource::Prefetched(&node_rx)
+                    } else {
+                        NodeSource::Jobs(jobs)
                     };
-
-                    // One `PointStream` per thread vs one per node allows to send more full point batches
-                    let mut point_stream = PointStream::new(batch_size, &send_func);
-
-                    while let Some((point_cloud, node_id)) = worker.pop().or_else(|| {
-                        std::iter::repeat_with(|| jobs.steal_batch_and_pop(&worker))
-                            .find(|task| !task.is_retry())
-                            .and_then(Steal::success)
-                    }) {
-                        // executing on the available next task if the function still requires it
-                        match point_cloud.stream_points_for_query_in_node(
-                            &point_query,
-                            node_id,
-                            batch_size,
-                            |batch| point_stream.push_points_and_callback(batch),
-                        ) {
-                            Ok(_) => continue,
-                            Err(ref e) => {
-                                match e.kind() {
-                                    ErrorKind::Channel(ref _s) => break, // done with the function computation
-                                    _ => panic!("ParallelIterator: Thread error {}", e), //some other error
-                                }
-                            }
-                        }
-                    }
-                    // last batch of points: calling callback
-                    if let Err(ref e) = point_stream.callback() {
-                        match e.kind() {
-                            ErrorKind::Channel(ref _s) => (), // done with the function computation
-                            _ => panic!("ParallelIterator: Thread error {}", e), //some other error
-                        }
-                    }
-                });
+                    stream_jobs(
+                        point_clouds,
+                        nodes,
+                        point_query,
+                        batch_size,
+                        &tx,
+                        curr_thread,
+                    )
+                }));
             }
-            // ensure to close the channel after the threads exit
+            // ensure to close the channels after the threads exit
             drop(tx);
+            drop(node_rx);
 
             // receiver collects all the messages
-            rx.iter().try_for_each(func)
+            let mut coalescer = BatchCoalescer::new(
+                self.point_query.min_batch_size(self.min_batch_size),
+                &mut func,
+            );
+            deliver_batches(&self.point_query, rx.iter(), self.batch_size, |batch| {
+                coalescer.push(batch)
+            })?;
+            coalescer.finish()?;
+            let query_finished = Instant::now();
+            // The first error of a thread fails the query, like in `PooledIterator`.
+            let records: Vec<ThreadRecord> = threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Result<_>>()?;
+            Ok(QuerySummary::from_threads(
+                &records,
+                query_started,
+                query_finished,
+            ))
         })
         .expect("ParallelIterator: Panic in try_for_each_batch child thread")
+        .and_then(|summary| {
+            if self.point_query.is_cancelled() {
+                return Err(ErrorKind::Cancelled.into());
+            }
+            Ok(summary)
+        })
+    }
+}
+
+/// Queues the nodes of all point clouds in the location, as pairs of point cloud index and node.
+pub(crate) fn queue_jobs<C: PointCloud>(
+    point_clouds: &[C],
+    location: &PointLocation,
+) -> Injector<(usize, C::Id)> {
+    let jobs = Injector::new();
+    for (index, point_cloud) in point_clouds.iter().enumerate() {
+        for node_id in point_cloud.nodes_in_location(location) {
+            jobs.push((index, node_id));
+        }
+    }
+    jobs
+}
+
+/// Takes the next job, preferring those already taken from `jobs` by the worker.
+pub(crate) fn steal_job<Id>(
+    jobs: &Injector<(usize, Id)>,
+    worker: &Worker<(usize, Id)>,
+) -> Option<(usize, Id)> {
+    worker.pop().or_else(|| {
+        std::iter::repeat_with(|| jobs.steal_batch_and_pop(worker))
+            .find(|task| !task.is_retry())
+            .and_then(Steal::success)
+    })
+}
+
+/// A node read into memory by an IO thread, with the index of its point cloud.
+type PrefetchedNode<Id> = (usize, Id, Result<NodeRead>);
+
+/// Where `stream_jobs` takes its nodes from.
+enum NodeSource<'a, Id> {
+    /// The nodes still to be read.
+    Jobs(&'a Injector<(usize, Id)>),
+    /// The nodes read by `prefetch_jobs`.
+    Prefetched(&'a crossbeam::channel::Receiver<PrefetchedNode<Id>>),
+}
+
+/// Reads the nodes taken from `jobs` into memory and passes them on to `tx`, until there are no
+/// more nodes or the receiving end hung up. Errors are passed on with the node.
+fn prefetch_jobs<C: PointCloud>(
+    point_clouds: &[C],
+    jobs: &Injector<(usize, C::Id)>,
+    point_query: &PointQuery,
+    batch_size: usize,
+    tx: &crossbeam::channel::Sender<PrefetchedNode<C::Id>>,
+) {
+    let worker = Worker::new_fifo();
+    while let Some((index, node_id)) = steal_job(jobs, &worker) {
+        if point_query.is_cancelled() {
+            break;
+        }
+        let node_read =
+            point_clouds[index].read_node_for_query(point_query, node_id, batch_size, true);
+        if tx.send((index, node_id, node_read)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Streams the points of nodes taken from `nodes` into `tx` until there are no more nodes or the
+/// receiving end hung up. Returns errors other than the latter.
+fn stream_jobs<C: PointCloud>(
+    point_clouds: &[C],
+    nodes: NodeSource<C::Id>,
+    point_query: &PointQuery,
+    batch_size: usize,
+    tx: &crossbeam::channel::Sender<SentBatch>,
+    curr_thread: usize,
+) -> Result<ThreadRecord> {
+    let started = Instant::now();
+    let points = Cell::new(0);
+    let blocked = Cell::new(Duration::default());
+    let mut nodes_visited = 0;
+    let mut failed_nodes = Vec::new();
+    let send = |node: Option<(usize, String)>, batch: PointsBatch| {
+        let num_points = batch.position.len();
+        let send_started = Instant::now();
+        // Blocks while the channel is full.
+        let result = tx.send((node, batch));
+        blocked.set(blocked.get() + send_started.elapsed());
+        match result {
+            Ok(_) => {
+                points.set(points.get() + num_points);
+                Ok(())
+            }
+            Err(e) => Err(ErrorKind::Channel(format!(
+                "Thread {}: sending operation failed, nothing more to do {:?}",
+                curr_thread, e,
+            ))
+            .into()),
+        }
+    };
+
+    let send_func = |batch| send(None, batch);
+    // One `PointStream` per thread vs one per node allows to send more full point batches
+    let mut point_stream = PointStream::new(batch_size, &send_func);
+
+    let worker = Worker::new_fifo();
+    let result = loop {
+        let next_node = match &nodes {
+            NodeSource::Jobs(jobs) => {
+                steal_job(jobs, &worker).map(|(index, node_id)| (index, node_id, None))
+            }
+            NodeSource::Prefetched(rx) => {
+                let receive_started = Instant::now();
+                // Blocks until an IO thread read a node.
+                let next_node = rx.recv().ok();
+                blocked.set(blocked.get() + receive_started.elapsed());
+                next_node.map(|(index, node_id, node_read)| (index, node_id, Some(node_read)))
+            }
+        };
+        let (index, node_id, node_read) = match next_node {
+            Some(node) => node,
+            // last batch of points: calling callback
+            None => break point_stream.callback(),
+        };
+        if point_query.is_cancelled() {
+            break Ok(());
+        }
+        // Deterministic queries send all points of a node at once, tagged with the node.
+        let mut node_points = PointsBatch {
+            position: Vec::new(),
+            attributes: BTreeMap::new(),
+            bounding_box: None,
+        };
+        let mut push = |mut batch: PointsBatch| {
+            if point_query.deterministic {
+                node_points.append(&mut batch).map_err(Error::from)
+            } else {
+                point_stream.push_points_and_callback(batch)
+            }
+        };
+        // executing on the available next task if the function still requires it
+        let streamed = match node_read {
+            None => point_clouds[index].stream_points_for_query_in_node(
+                point_query,
+                node_id,
+                batch_size,
+                &mut push,
+            ),
+            Some(node_read) => {
+                node_read.and_then(|node_read| node_read.stream_points(point_query, &mut push))
+            }
+        }
+        .and_then(|_| {
+            if node_points.position.is_empty() {
+                return Ok(());
+            }
+            send(Some((index, node_id.to_string())), node_points)
+        });
+        match streamed {
+            Ok(_) => nodes_visited += 1,
+            Err(e)
+                if point_query.skip_failed_nodes
+                    && !matches!(e.kind(), ErrorKind::Channel(_) | ErrorKind::Cancelled) =>
+            {
+                let node_id = node_id.to_string();
+                let error = format!("Node {}: {}", node_id, e);
+                failed_nodes.push((index, node_id, error));
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    match result {
+        Ok(_) => (),
+        Err(e) => match e.kind() {
+            ErrorKind::Channel(ref _s) => (), // done with the function computation
+            ErrorKind::Cancelled => (),       // reported once all threads are done
+            _ => return Err(e),               //some other error
+        },
+    }
+    Ok(ThreadRecord {
+        points: points.get(),
+        nodes_visited,
+        started,
+        finished: Instant::now(),
+        blocked: blocked.get(),
+        failed_nodes,
+    })
+}
+
+/// A copy of a `PointQuery` that owns its strings, so that it can be moved into thread pool tasks.
+/// Both conversions name every field, so that a new option of `PointQuery` must be added here.
+pub struct OwnedPointQuery {
+    attributes: Vec<String>,
+    location: PointLocation,
+    filter_intervals: HashMap<String, ClosedInterval<f64>>,
+    rgba_intensity_range: Option<ClosedInterval<f64>>,
+    output_transforms: Vec<OutputTransform>,
+    skip_failed_nodes: bool,
+    upscale_color: bool,
+    wgs84: bool,
+    deterministic: bool,
+    boundary_epsilon: f64,
+    overview: bool,
+    radial_decimation: Option<RadialDecimation>,
+    tile_output: Option<f64>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl OwnedPointQuery {
+    pub fn new(point_query: &PointQuery) -> Self {
+        let PointQuery {
+            attributes,
+            location,
+            filter_intervals,
+            rgba_intensity_range,
+            output_transforms,
+            skip_failed_nodes,
+            upscale_color,
+            wgs84,
+            deterministic,
+            boundary_epsilon,
+            overview,
+            radial_decimation,
+            tile_output,
+            cancellation,
+        } = point_query;
+        OwnedPointQuery {
+            attributes: attributes.iter().map(|a| a.to_string()).collect(),
+            location: location.clone(),
+            filter_intervals: filter_intervals
+                .iter()
+                .map(|(attribute, interval)| (attribute.to_string(), *interval))
+                .collect(),
+            rgba_intensity_range: *rgba_intensity_range,
+            output_transforms: output_transforms.clone(),
+            skip_failed_nodes: *skip_failed_nodes,
+            upscale_color: *upscale_color,
+            wgs84: *wgs84,
+            deterministic: *deterministic,
+            boundary_epsilon: *boundary_epsilon,
+            overview: *overview,
+            radial_decimation: *radial_decimation,
+            tile_output: *tile_output,
+            cancellation: cancellation.clone(),
+        }
+    }
+
+    pub fn as_point_query(&self) -> PointQuery<'_> {
+        PointQuery {
+            attributes: self.attributes.iter().map(String::as_str).collect(),
+            location: self.location.clone(),
+            filter_intervals: self
+                .filter_intervals
+                .iter()
+                .map(|(attribute, interval)| (attribute.as_str(), *interval))
+                .collect(),
+            rgba_intensity_range: self.rgba_intensity_range,
+            output_transforms: self.output_transforms.clone(),
+            skip_failed_nodes: self.skip_failed_nodes,
+            upscale_color: self.upscale_color,
+            wgs84: self.wgs84,
+            deterministic: self.deterministic,
+            boundary_epsilon: self.boundary_epsilon,
+            overview: self.overview,
+            radial_decimation: self.radial_decimation,
+            tile_output: self.tile_output,
+            cancellation: self.cancellation.clone(),
+        }
+    }
+}
+
+/// What the tasks of one `PooledIterator` query share.
+struct PooledQuery<C: PointCloud> {
+    point_clouds: Arc<[C]>,
+    point_query: OwnedPointQuery,
+    jobs: Injector<(usize, C::Id)>,
+    records: Mutex<Vec<ThreadRecord>>,
+    error: Mutex<Option<Error>>,
+}
+
+/// Like `ParallelIterator`, but runs the work on a thread pool that is shared across queries,
+/// instead of spawning threads for every query. This pays off for many small queries.
+pub struct PooledIterator<'a, C> {
+    thread_pool: &'a rayon::ThreadPool,
+    point_clouds: Arc<[C]>,
+    point_query: &'a PointQuery<'a>,
+    batch_size: usize,
+    num_tasks: usize,
+    buffer_size: usize,
+    min_batch_size: usize,
+}
+
+impl<'a, C> PooledIterator<'a, C>
+where
+    C: PointCloud + Send + 'static,
+    C::Id: 'static,
+{
+    /// At most `num_tasks` tasks of the query run on the pool at the same time.
+    pub fn new(
+        thread_pool: &'a rayon::ThreadPool,
+        point_clouds: Arc<[C]>,
+        point_query: &'a PointQuery<'a>,
+        batch_size: usize,
+        num_tasks: usize,
+        buffer_size: usize,
+    ) -> Self {
+        PooledIterator {
+            thread_pool,
+            point_clouds,
+            point_query,
+            batch_size,
+            num_tasks,
+            buffer_size,
+            min_batch_size: 0,
+        }
+    }
+
+    /// Merges the batches of the tasks before passing them to the function of
+    /// `try_for_each_batch`, see `ParallelIterator::coalesce_batches`.
+    pub fn coalesce_batches(mut self, min_batch_size: usize) -> Self {
+        self.min_batch_size = min_batch_size;
+        self
+    }
+
+    /// Spawns the tasks of the query, which stream the points into the returned channel. The
+    /// channel is closed once all tasks are done, and so is the wait group.
+    fn spawn_tasks(
+        &self,
+        point_query: OwnedPointQuery,
+    ) -> (
+        Arc<PooledQuery<C>>,
+        crossbeam::channel::Receiver<SentBatch>,
+        WaitGroup,
+    ) {
+        let query = Arc::new(PooledQuery {
+            jobs: queue_jobs(
+                &self.point_clouds,
+                &point_query.location.expanded(point_query.boundary_epsilon),
+            ),
+            point_clouds: Arc::clone(&self.point_clouds),
+            point_query,
+            records: Mutex::new(Vec::with_capacity(self.num_tasks)),
+            error: Mutex::new(None),
+        });
+
+        let (tx, rx) = crossbeam::channel::bounded::<SentBatch>(self.buffer_size);
+        let tasks = WaitGroup::new();
+        for curr_task in 0..self.num_tasks {
+            let tx = tx.clone();
+            let query = Arc::clone(&query);
+            let batch_size = self.batch_size;
+            let task = tasks.clone();
+            self.thread_pool.spawn(move || {
+                let result = stream_jobs(
+                    &query.point_clouds,
+                    NodeSource::Jobs(&query.jobs),
+                    &query.point_query.as_point_query(),
+                    batch_size,
+                    &tx,
+                    curr_task,
+                );
+                match result {
+                    Ok(record) => query.records.lock().unwrap().push(record),
+                    Err(e) => {
+                        query.error.lock().unwrap().get_or_insert(e);
+                    }
+                }
+                drop(task);
+            });
+        }
+        (query, rx, tasks)
+    }
+
+    /// compute a function while iterating on a batch of points. The function runs on the calling
+    /// thread, which must not be a thread of the pool.
+    pub fn try_for_each_batch<F>(&mut self, mut func: F) -> Result<QuerySummary>
+    where
+        F: FnMut(PointsBatch) -> Result<()>,
+    {
+        let query_started = Instant::now();
+        let (query, rx, _) = self.spawn_tasks(OwnedPointQuery::new(self.point_query));
+        let mut coalescer = BatchCoalescer::new(
+            self.point_query.min_batch_size(self.min_batch_size),
+            &mut func,
+        );
+        deliver_batches(self.point_query, rx.iter(), self.batch_size, |batch| {
+            coalescer.push(batch)
+        })?;
+        coalescer.finish()?;
+        let query_finished = Instant::now();
+        if let Some(e) = query.error.lock().unwrap().take() {
+            return Err(e);
+        }
+        if self.point_query.is_cancelled() {
+            return Err(ErrorKind::Cancelled.into());
+        }
+        let records = query.records.lock().unwrap();
+        Ok(QuerySummary::from_threads(
+            &records,
+            query_started,
+            query_finished,
+        ))
+    }
+
+    /// Runs the query and returns its points as an iterator, e.g. to stop at the first points of
+    /// interest with `break`. Errors of the query end the iteration. Dropping the iterator before
+    /// the end cancels the query, including the cancellation token of the query if it has one,
+    /// and waits for the tasks to stop, which happens after at most a node each. Thus, it must
+    /// not be consumed or dropped on a thread of the pool.
+    pub fn batches(&self) -> QueryBatches<C> {
+        let mut point_query = OwnedPointQuery::new(self.point_query);
+        point_query
+            .cancellation
+            .get_or_insert_with(CancellationToken::new);
+        let (query, rx, tasks) = self.spawn_tasks(point_query);
+        QueryBatches {
+            query,
+            rx,
+            batch_size: self.batch_size,
+            sorted: None,
+            tasks: Some(tasks),
+            finished: false,
+        }
+    }
+}
+
+/// The points of a query, see `PooledIterator::batches`.
+pub struct QueryBatches<C: PointCloud> {
+    query: Arc<PooledQuery<C>>,
+    rx: crossbeam::channel::Receiver<SentBatch>,
+    batch_size: usize,
+    /// All batches of a `PointQuery::deterministic` or `PointQuery::tile_output` query, once they
+    /// are received.
+    sorted: Option<std::vec::IntoIter<PointsBatch>>,
+    tasks: Option<WaitGroup>,
+    finished: bool,
+}
+
+impl<C: PointCloud> Iterator for QueryBatches<C> {
+    type Item = Result<PointsBatch>;
+
+    fn next(&mut self) -> Option<Result<PointsBatch>> {
+        if self.finished {
+            return None;
+        }
+        let point_query = &self.query.point_query;
+        let buffered = point_query.deterministic || point_query.tile_output.is_some();
+        if buffered && self.sorted.is_none() {
+            let mut batches = Vec::new();
+            let point_query = point_query.as_point_query();
+            let result = deliver_batches(&point_query, self.rx.iter(), self.batch_size, |batch| {
+                batches.push(batch);
+                Ok(())
+            });
+            self.sorted = Some(batches.into_iter());
+            if let Err(e) = result {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+        match &mut self.sorted {
+            Some(sorted) => {
+                if let Some(batch) = sorted.next() {
+                    return Some(Ok(batch));
+                }
+            }
+            None => {
+                if let Ok((_, batch)) = self.rx.recv() {
+                    return Some(Ok(batch));
+                }
+            }
+        }
+        // The channel is closed once all tasks are done.
+        self.finished = true;
+        if let Some(e) = self.query.error.lock().unwrap().take() {
+            return Some(Err(e));
+        }
+        if matches!(&self.query.point_query.cancellation, Some(c) if c.is_cancelled()) {
+            return Some(Err(ErrorKind::Cancelled.into()));
+        }
+        None
+    }
+}
+
+impl<C: PointCloud> Drop for QueryBatches<C> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Some(cancellation) = &self.query.point_query.cancellation {
+                cancellation.cancel();
+            }
+        }
+        // Tasks blocked on sending give up once the receiver is gone.
+        self.rx = crossbeam::channel::never();
+        if let Some(tasks) = self.tasks.take() {
+            tasks.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::{local_frame_from_lat_lng, WebMercatorCoord};
+    use nalgebra::{Isometry3, Perspective3, Vector2, Vector3};
+    use nav_types::{ECEF, WGS84};
+    use s2::latlng::LatLng;
+    use s2::s1::Deg;
+
+    fn cube(center: Point3<f64>, half_edge: f64) -> Aabb {
+        let half_diag = Vector3::repeat(half_edge);
+        Aabb::new(center - half_diag, center + half_diag)
+    }
+
+    /// Checks a location that lies around `center` and is smaller than 10 m against boxes that are
+    /// contained in it, intersect it and are disjoint from it.
+    fn check_location(location: PointLocation, center: Point3<f64>, away: Vector3<f64>) {
+        let contained = cube(center, 0.1);
+        let intersecting = cube(center, 1000.0);
+        let disjoint = cube(center + away, 1.0);
+        assert!(location.intersects_aabb(&contained));
+        assert!(location.intersects_aabb(&intersecting));
+        assert!(!location.intersects_aabb(&disjoint));
+        assert!(location.contains_point(&center));
+        assert!(!location.contains_point(&(center + away)));
+    }
+
+    /// A point on the surface of the earth, and a vector pointing 10 km away along the surface.
+    fn ecef_center_and_away() -> (Point3<f64>, Vector3<f64>) {
+        let ecef_from_local = local_frame_from_lat_lng(37.7, -122.4).inverse();
+        (
+            ecef_from_local * Point3::origin(),
+            ecef_from_local * Vector3::new(10_000.0, 0.0, 0.0),
+        )
+    }
+
+    fn batch_with_times(times: Vec<f64>) -> PointsBatch {
+        PointsBatch {
+            position: times.iter().map(|t| Point3::new(*t, 0.0, 0.0)).collect(),
+            attributes: vec![("gps_time".to_string(), AttributeData::F64(times))]
+                .into_iter()
+                .collect(),
+            bounding_box: None,
+        }
+    }
+
+    #[test]
+    fn test_resolve_attributes() {
+        let available: HashMap<String, AttributeDataType> = vec![
+            ("color".to_string(), AttributeDataType::U8Vec3),
+            ("intensity".to_string(), AttributeDataType::F32),
+            ("time".to_string(), AttributeDataType::F64),
+        ]
+        .into_iter()
+        .collect();
+        let resolve = |attributes: Vec<&str>| {
+            PointQuery {
+                attributes,
+                ..Default::default()
+            }
+            .resolve_attributes(&available)
+            .map(|resolved| resolved.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            resolve(vec!["time", "color"]).unwrap(),
+            vec!["time", "color"]
+        );
+        assert_eq!(
+            resolve(vec![ALL_ATTRIBUTES]).unwrap(),
+            vec!["color", "intensity", "time"]
+        );
+        assert_eq!(resolve(vec!["-intensity"]).unwrap(), vec!["color", "time"]);
+        assert_eq!(
+            resolve(vec![ALL_ATTRIBUTES, "-intensity", "-time"]).unwrap(),
+            vec!["color"]
+        );
+        assert!(resolve(vec![ALL_ATTRIBUTES, "color"]).is_err());
+        assert!(resolve(vec!["-intensity", "color"]).is_err());
+        assert!(resolve(vec!["-normal"]).is_err());
+
+        let query = PointQuery {
+            attributes: vec!["-intensity"],
+            filter_intervals: vec![("intensity", ClosedInterval::new(0.0, 1.0))]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert!(query.resolve_attributes(&available).is_err());
+        let query = PointQuery {
+            attributes: vec![ALL_ATTRIBUTES],
+            ..query
+        };
+        assert!(query.resolve_attributes(&available).is_ok());
+    }
+
+    #[test]
+    fn test_malformed_json_queries() {
+        let error = |json| match PointQuery::from_json(json).unwrap_err().kind() {
+            ErrorKind::InvalidQuery(msg) => msg.clone(),
+            other => panic!("Unexpected error {:?}", other),
+        };
+        assert!(
+            error(r#"{"attributes": ["color"], "locaton": "AllPoints"}"#)
+                .contains("unknown field `locaton`")
+        );
+        assert!(error(r#"{"location": {"Cube": {}}}"#).contains("unknown variant `Cube`"));
+        assert!(error(r#"{"attributes": ["color", "color"]}"#).contains("more than once"));
+        assert!(error(
+            r#"{"filter_intervals": {"intensity": {"lower_bound": 2.0, "upper_bound": 1.0}}}"#
+        )
+        .contains("filter interval of 'intensity' is empty"));
+        assert!(
+            error(r#"{"location": {"Aabb": {"mins": [0, 0, 1], "maxs": [1, 1, 0]}}}"#)
+                .contains("must not be greater than its maximum")
+        );
+        assert!(
+            error(r#"{"location": {"Sphere": {"center": [0, 0, 0], "radius": -1}}}"#)
+                .contains("radius")
+        );
+        assert!(error(
+            r#"{"location": {"Obb": {"query_from_obb": {"rotation": [0, 0, 0, 2],
+                "translation": [0, 0, 0]}, "half_extent": [1, 1, 1]}}}"#
+        )
+        .contains("unit quaternion"));
+        assert!(error(
+            r#"{"location": {"Frustum": {"clip_from_query": [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]}}}"#
+        )
+        .contains("invertible"));
+        assert!(error(r#"{"location": {"S2Cells": [0]}}"#).contains("Invalid S2 cell id"));
+
+        let query = PointQuery::from_json("{}").unwrap();
+        assert!(query.attributes.is_empty());
+        assert!(matches!(query.location, PointLocation::AllPoints));
+    }
+
+    #[test]
+    fn test_windowed_sorter() {
+        // Blocks of 100 points in reverse time order, i.e. points arrive up to 100 points late.
+        let batches = (0..50).map(|block| {
+            let times = (0..100).rev().map(|i| (block * 100 + i) as f64 * 0.01);
+            batch_with_times(times.collect())
+        });
+        let mut emitted = Vec::new();
+        let mut sorter = WindowedSorter::new("gps_time", 1.0, 300, |batch| {
+            emitted.push(batch);
+            Ok(())
+        });
+        batches
+            .into_iter()
+            .try_for_each(|b| sorter.push(b))
+            .unwrap();
+        sorter.finish().unwrap();
+
+        let mut last_time = f64::NEG_INFINITY;
+        let mut num_points = 0;
+        for batch in &emitted {
+            let times: &Vec<f64> = batch.get_attribute_vec("gps_time").unwrap();
+            let window = times[0].floor();
+            for (t, p) in times.iter().zip(&batch.position) {
+                assert!(*t >= last_time);
+                assert_eq!(t.floor(), window);
+                assert_eq!(*t, p.x);
+                last_time = *t;
+            }
+            num_points += times.len();
+        }
+        assert_eq!(num_points, 5000);
+    }
+
+    #[test]
+    fn test_s2_cell_ids_transform() {
+        let lat_lngs = [
+            (0.0, 0.0),
+            (0.0, 90.0),
+            (0.0, -123.4),
+            (90.0, 0.0),
+            (-37.8, 144.9),
+        ];
+        let position = lat_lngs
+            .iter()
+            .map(|(lat, lng)| {
+                let ecef = ECEF::from(WGS84::from_degrees_and_meters(*lat, *lng, 0.0));
+                Point3::new(ecef.x(), ecef.y(), ecef.z())
+            })
+            .collect();
+        let mut batch = PointsBatch {
+            position,
+            attributes: BTreeMap::new(),
+            bounding_box: None,
+        };
+        let query =
+            PointQuery::from_json(r#"{"output_transforms": [{"S2CellIds": {"level": 12}}]}"#)
+                .unwrap();
+        query.output_transforms[0].apply(&mut batch);
+        let cell_ids: &Vec<u64> = batch.get_attribute_vec(S2_CELL_ATTRIBUTE).unwrap();
+        for ((lat, lng), (cell_id, p)) in lat_lngs.iter().zip(cell_ids.iter().zip(&batch.position))
+        {
+            // S2 uses geocentric latitudes, which only equal the geodetic ones at the equator and
+            // the poles.
+            let lat = if *lat == 0.0 || lat.abs() == 90.0 {
+                *lat
+            } else {
+                p.z.atan2(p.x.hypot(p.y)).to_degrees()
+            };
+            let lat_lng = LatLng::new(Deg(lat).into(), Deg(*lng).into());
+            assert_eq!(*cell_id, CellID::from(lat_lng).parent(12).0);
+        }
+        assert!(
+            PointQuery::from_json(r#"{"output_transforms": [{"S2CellIds": {"level": 31}}]}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_statistical_outlier_removal() {
+        // A grid of 10 x 10 x 10 inliers, and outliers far away from it and from each other.
+        let mut position: Vec<Point3<f64>> = (0..1000)
+            .map(|i| Point3::new((i % 10) as f64, (i / 10 % 10) as f64, (i / 100) as f64))
+            .collect();
+        let outliers = [
+            Point3::new(50.0, 0.0, 0.0),
+            Point3::new(-40.0, 30.0, 5.0),
+            Point3::new(4.0, 4.0, 60.0),
+        ];
+        position.insert(500, outliers[0]);
+        position.insert(0, outliers[1]);
+        position.push(outliers[2]);
+        let mut batch = PointsBatch {
+            attributes: vec![(
+                "intensity".to_string(),
+                AttributeData::F32(position.iter().map(|p| p.x as f32).collect()),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+            bounding_box: None,
+        };
+        let query = PointQuery::from_json(
+            r#"{"output_transforms": [{"StatisticalOutlierRemoval": {"k": 8, "std_mult": 1.0}}]}"#,
+        )
+        .unwrap();
+        query.output_transforms[0].apply(&mut batch);
+        assert_eq!(batch.position.len(), 1000);
+        assert!(batch.position.iter().all(|p| !outliers.contains(p)));
+        let intensity: &Vec<f32> = batch.get_attribute_vec("intensity").unwrap();
+        assert!(intensity
+            .iter()
+            .zip(&batch.position)
+            .all(|(i, p)| *i == p.x as f32));
+        assert!(PointQuery::from_json(
+            r#"{"output_transforms": [{"StatisticalOutlierRemoval": {"k": 0, "std_mult": 1.0}}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sort_by_composite_key() {
+        let mut batch = PointsBatch {
+            position: (0..6)
+                .map(|i| Point3::new(f64::from(i), 0.0, 0.0))
+                .collect(),
+            attributes: vec![
+                (
+                    "classification".to_string(),
+                    AttributeData::U8(vec![2, 1, 2, 1, 2, 2]),
+                ),
+                (
+                    "intensity".to_string(),
+                    AttributeData::F32(vec![0.5, 0.1, 0.9, 0.7, 0.5, 0.2]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        };
+        let query = PointQuery::from_json(
+            r#"{"attributes": ["classification", "intensity"],
+            "output_transforms": [{"SortBy": {"keys": [
+                {"attribute": "classification", "ascending": true},
+                {"attribute": "intensity", "ascending": false}
+            ]}}]}"#,
+        )
+        .unwrap();
+        query.output_transforms[0].apply(&mut batch);
+        // Ties in both keys keep their order.
+        let x: Vec<f64> = batch.position.iter().map(|p| p.x).collect();
+        assert_eq!(x, vec![3.0, 1.0, 2.0, 0.0, 4.0, 5.0]);
+        let classification: &Vec<u8> = batch.get_attribute_vec("classification").unwrap();
+        assert_eq!(classification, &vec![1, 1, 2, 2, 2, 2]);
+
+        // Batches without one of the attributes are left as they are.
+        let transform = OutputTransform::SortBy {
+            keys: vec![SortKey {
+                attribute: "gps_time".to_string(),
+                ascending: true,
+            }],
+        };
+        let before = batch.position.clone();
+        transform.apply(&mut batch);
+        assert_eq!(batch.position, before);
+        assert!(
+            PointQuery::from_json(r#"{"output_transforms": [{"SortBy": {"keys": []}}]}"#).is_err()
+        );
+        // The keys have to be requested.
+        let query = r#"{"attributes": ATTRIBUTES, "output_transforms": [{"SortBy": {"keys": [
+            {"attribute": "intensity", "ascending": true}
+        ]}}]}"#;
+        for (attributes, is_valid) in &[
+            (r#"["intensity"]"#, true),
+            (r#"["*"]"#, true),
+            (r#"["-color"]"#, true),
+            (r#"["color"]"#, false),
+            (r#"["-intensity"]"#, false),
+        ] {
+            let json = query.replace("ATTRIBUTES", attributes);
+            assert_eq!(PointQuery::from_json(&json).is_ok(), *is_valid, "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_colorize_by_class() {
+        let mut batch = PointsBatch {
+            position: vec![Point3::origin(); 4],
+            attributes: vec![(
+                CLASSIFICATION_ATTRIBUTE.to_string(),
+                AttributeData::U8(vec![2, 5, 3, 2]),
+            )]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        };
+        let query = PointQuery::from_json(
+            r#"{"attributes": ["classification"], "output_transforms": [{"ColorizeByClass": {
+                "palette": {"2": [139, 69, 19], "5": [0, 128, 0]},
+                "default_color": [128, 128, 128]
+            }}]}"#,
+        )
+        .unwrap();
+        assert!(PointQuery {
+            attributes: vec!["color"],
+            ..query.clone()
+        }
+        .validate()
+        .is_err());
+        query.output_transforms[0].apply(&mut batch);
+        let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+        assert_eq!(
+            color,
+            &vec![
+                Vector3::new(139, 69, 19),
+                Vector3::new(0, 128, 0),
+                Vector3::new(128, 128, 128),
+                Vector3::new(139, 69, 19),
+            ]
+        );
+
+        // Other scalar types work as well, values that aren't classes get the default color.
+        batch.attributes.insert(
+            CLASSIFICATION_ATTRIBUTE.to_string(),
+            AttributeData::F32(vec![5.0, 2.5, -2.0, 300.0]),
+        );
+        query.output_transforms[0].apply(&mut batch);
+        let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+        assert_eq!(color[0], Vector3::new(0, 128, 0));
+        assert!(color[1..].iter().all(|c| *c == Vector3::new(128, 128, 128)));
+    }
+
+    #[test]
+    fn test_height_above_ground() {
+        // A flat ground at 10 m, from 0 to 20 m in x and 0 to 10 m in y.
+        let query = PointQuery::from_json(
+            r#"{"output_transforms": [{"HeightAboveGround": {
+                "grid": {"origin": [0.0, 0.0], "cell_size": 10.0, "num_columns": 3,
+                         "heights": [10.0, 10.0, 10.0, 10.0, 10.0, 10.0]},
+                "min_height": 0.5,
+                "max_height": 3.0
+            }}]}"#,
+        )
+        .unwrap();
+        let position = vec![
+            Point3::new(5.0, 5.0, 10.2),
+            Point3::new(5.0, 5.0, 10.5),
+            Point3::new(12.0, 3.0, 12.0),
+            Point3::new(20.0, 10.0, 13.0),
+            Point3::new(15.0, 8.0, 13.5),
+            Point3::new(25.0, 5.0, 12.0),
+            Point3::new(5.0, -1.0, 12.0),
+        ];
+        let mut batch = PointsBatch {
+            bounding_box: Aabb::from_points(&position),
+            attributes: vec![(
+                "intensity".to_string(),
+                AttributeData::F32((0..position.len()).map(|i| i as f32).collect()),
+            )]
+            .into_iter()
+            .collect(),
+            position,
+        };
+        query.output_transforms[0].apply(&mut batch);
+        let intensity: &Vec<f32> = batch.get_attribute_vec("intensity").unwrap();
+        assert_eq!(intensity, &vec![1.0, 2.0, 3.0]);
+        assert_eq!(
+            batch.bounding_box,
+            Some(Aabb::new(
+                Point3::new(5.0, 3.0, 10.5),
+                Point3::new(20.0, 10.0, 13.0)
+            ))
+        );
+
+        // The heights are interpolated bilinearly.
+        let grid = HeightGrid {
+            origin: [100.0, 200.0],
+            cell_size: 2.0,
+            num_columns: 2,
+            heights: vec![0.0, 4.0, 8.0, 12.0],
+        };
+        assert_eq!(grid.height_at(100.0, 200.0), Some(0.0));
+        assert_eq!(grid.height_at(101.0, 201.0), Some(6.0));
+        assert_eq!(grid.height_at(102.0, 202.0), Some(12.0));
+        assert_eq!(grid.height_at(102.1, 201.0), None);
+        let empty_grid = |num_columns| HeightGrid {
+            num_columns,
+            heights: Vec::new(),
+            ..grid.clone()
+        };
+        assert_eq!(empty_grid(0).height_at(100.0, 200.0), None);
+        assert_eq!(empty_grid(2).height_at(100.0, 200.0), None);
+
+        assert!(PointQuery::from_json(
+            r#"{"output_transforms": [{"HeightAboveGround": {
+                "grid": {"origin": [0.0, 0.0], "cell_size": 1.0, "num_columns": 2,
+                         "heights": [1.0, 2.0, 3.0]},
+                "min_height": 0.0,
+                "max_height": 1.0
+            }}]}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_reservoir_sampler() {
+        // A line of points at x = 0 to 9999, in batches of 100.
+        let batches: Vec<PointsBatch> = (0..100)
+            .map(|block| batch_with_times((0..100).map(|i| (block * 100 + i) as f64).collect()))
+            .collect();
+        let sample = |batches: &mut dyn Iterator<Item = &PointsBatch>, seed| {
+            let mut sampler = ReservoirSampler::new(1000, seed);
+            for batch in batches {
+                sampler.push(batch.clone()).unwrap();
+            }
+            let mut xs: Vec<f64> = sampler.finish().position.iter().map(|p| p.x).collect();
+            xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            xs
+        };
+        let xs = sample(&mut batches.iter(), 42);
+        assert_eq!(xs.len(), 1000);
+        assert!(xs.windows(2).all(|w| w[0] < w[1]));
+        // About 100 points from each tenth of the line.
+        for tenth in 0..10 {
+            let lower = tenth as f64 * 1000.0;
+            let count = xs
+                .iter()
+                .filter(|x| **x >= lower && **x < lower + 1000.0)
+                .count();
+            assert!(
+                count > 60 && count < 140,
+                "{} points in tenth {}",
+                count,
+                tenth
+            );
+        }
+        assert_eq!(xs, sample(&mut batches.iter().rev(), 42));
+        assert_ne!(xs, sample(&mut batches.iter(), 43));
+        assert_eq!(sample(&mut batches.iter().take(3), 42).len(), 300);
+    }
+
+    #[test]
+    fn test_all_points_location() {
+        let location = PointLocation::AllPoints;
+        assert!(location.intersects_aabb(&cube(Point3::new(1e9, 0.0, 0.0), 1.0)));
+        assert!(location.contains_point(&Point3::new(-1e9, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_complement_location() {
+        let aabb = cube(Point3::origin(), 5.0);
+        let location = PointLocation::Complement(Box::new(PointLocation::Aabb(aabb.clone())));
+        assert!(!location.contains_point(&Point3::origin()));
+        assert!(location.contains_point(&Point3::new(100.0, 0.0, 0.0)));
+        assert!(!location.get_point_culling().contains(&Point3::origin()));
+        // Nodes inside of the box are not pruned.
+        assert!(location.intersects_aabb(&cube(Point3::origin(), 1.0)));
+        assert!(location.validate().is_ok());
+
+        // The inner location is validated.
+        let cells = CellUnion(vec![CellID(0)]);
+        assert!(
+            PointLocation::Complement(Box::new(PointLocation::S2Cells(cells)))
+                .validate()
+                .is_err()
+        );
+        assert!(
+            PointLocation::Complement(Box::new(PointLocation::AllPointsInDepthRange(2)))
+                .validate()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_difference_location() {
+        let location = PointLocation::Difference(
+            Box::new(PointLocation::Aabb(cube(Point3::origin(), 5.0))),
+            Box::new(PointLocation::Sphere(Sphere::new(Point3::origin(), 2.0))),
+        );
+        assert!(location.contains_point(&Point3::new(4.0, 0.0, 0.0)));
+        assert!(!location.contains_point(&Point3::new(1.0, 0.0, 0.0)));
+        assert!(!location.contains_point(&Point3::new(6.0, 0.0, 0.0)));
+        assert!(!location.get_point_culling().contains(&Point3::origin()));
+        // Nodes are selected by the first location.
+        assert!(location.intersects_aabb(&cube(Point3::origin(), 1.0)));
+        assert!(!location.intersects_aabb(&cube(Point3::new(100.0, 0.0, 0.0), 1.0)));
+        assert!(location.validate().is_ok());
+    }
+
+    #[test]
+    fn test_aabb_location() {
+        let location = PointLocation::Aabb(cube(Point3::origin(), 5.0));
+        check_location(location, Point3::origin(), Vector3::new(100.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_obb_location() {
+        let rotation = Isometry3::rotation(Vector3::new(0.3, 0.5, 0.7));
+        let location = PointLocation::Obb(Obb::new(rotation, Vector3::new(5.0, 2.0, 1.0)));
+        check_location(location, Point3::origin(), Vector3::new(0.0, 100.0, 0.0));
+    }
+
+    #[test]
+    fn test_sphere_location() {
+        let location = PointLocation::Sphere(Sphere::new(Point3::new(1.0, 2.0, 3.0), 5.0));
+        check_location(
+            location,
+            Point3::new(1.0, 2.0, 3.0),
+            Vector3::new(0.0, 0.0, 100.0),
+        );
+    }
+
+    #[test]
+    fn test_frustum_location() {
+        let perspective = Perspective3::new(1.0, 1.2, 0.1, 10.0);
+        let location =
+            PointLocation::Frustum(Frustum::new(Isometry3::identity(), perspective.into()));
+        // The camera looks along the negative z axis.
+        check_location(
+            location,
+            Point3::new(0.0, 0.0, -5.0),
+            Vector3::new(0.0, 0.0, 100.0),
+        );
+    }
+
+    #[test]
+    fn test_s2_cells_location() {
+        let (center, away) = ecef_center_and_away();
+        let cell_id = CellID::from_point(&center).parent(20);
+        let location = PointLocation::S2Cells(CellUnion(vec![cell_id]));
+        check_location(location, center, away);
+    }
+
+    #[test]
+    fn test_web_mercator_rect_location() {
+        let (center, away) = ecef_center_and_away();
+        let lat_lng: WGS84<f64> = ECEF::new(center.x, center.y, center.z).into();
+        let zoomed = WebMercatorCoord::from_lat_lng(&lat_lng)
+            .to_zoomed_coordinate(21)
+            .unwrap();
+        let location = PointLocation::WebMercatorRect(
+            WebMercatorRect::from_zoomed_coordinates(
+                zoomed - Vector2::new(8.0, 8.0),
+                zoomed + Vector2::new(8.0, 8.0),
+                21,
+            )
+            .unwrap(),
+        );
+        check_location(location, center, away);
     }
 }
@@ -17,22 +17,30 @@
 #[macro_use]
 pub mod math;
 
+#[cfg(feature = "arrow")]
+pub mod arrow_export;
 #[macro_use]
 pub mod attributes;
 pub mod color;
+pub mod conversion;
 pub mod data_provider;
+pub mod downsampling;
 // Workaround for https://github.com/rust-lang-nursery/error-chain/issues/254
 #[allow(deprecated)]
 pub mod errors;
+pub mod export;
 pub mod geometry;
 #[macro_use]
 pub mod iterator;
 pub mod octree;
+#[macro_use]
+pub mod point_layout;
 pub mod read_write;
 pub mod s2_cells;
 pub mod utils;
 
 use errors::Result;
+use geometry::Aabb;
 use nalgebra::Point3;
 use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
@@ -56,7 +64,7 @@ pub trait NumberOfPoints {
     fn num_points(&self) -> usize;
 }
 
-use attributes::{AttributeData, AttributeDataType};
+use attributes::{AttributeColumn, AttributeData, AttributeDataType};
 
 // TODO(nnmm): Remove
 #[derive(Debug, Clone)]
@@ -103,6 +111,9 @@ pub struct PointsBatch {
     pub position: Vec<Point3<f64>>,
     // BTreeMap for deterministic iteration order.
     pub attributes: BTreeMap<String, AttributeData>,
+    /// The tight bounds of `position`, if known. Batches read from a point cloud always carry
+    /// them, and they are kept up to date by the methods below.
+    pub bounding_box: Option<Aabb>,
 }
 
 impl PointsBatch {
@@ -111,6 +122,16 @@ impl PointsBatch {
             *self = other.split_off(0);
         } else {
             assert_eq!(self.attributes.len(), other.attributes.len());
+            self.bounding_box = match (&self.bounding_box, &other.bounding_box) {
+                (Some(a), Some(b)) => {
+                    let mut bounding_box = a.clone();
+                    bounding_box.grow(*b.min());
+                    bounding_box.grow(*b.max());
+                    Some(bounding_box)
+                }
+                _ => None,
+            };
+            other.bounding_box = None;
             self.position.append(&mut other.position);
             for (s, o) in self
                 .attributes
@@ -130,9 +151,16 @@ impl PointsBatch {
             .iter_mut()
             .map(|(n, a)| (n.clone(), a.split_off(at)))
             .collect();
+        let bounding_box = if self.bounding_box.is_some() {
+            self.bounding_box = Aabb::from_points(&self.position);
+            Aabb::from_points(&position)
+        } else {
+            None
+        };
         Self {
             position,
             attributes,
+            bounding_box,
         }
     }
 
@@ -148,6 +176,74 @@ impl PointsBatch {
             }
             match_attr_data!(a, rhs, keep)
         }
+        if self.bounding_box.is_some() {
+            self.bounding_box = Aabb::from_points(&self.position);
+        }
+    }
+
+    /// Returns the points at the given indices, in that order.
+    pub fn select(&self, indices: &[usize]) -> Self {
+        let position: Vec<_> = indices.iter().map(|i| self.position[*i]).collect();
+        let bounding_box = self
+            .bounding_box
+            .as_ref()
+            .and_then(|_| Aabb::from_points(&position));
+        Self {
+            position,
+            attributes: self
+                .attributes
+                .iter()
+                .map(|(n, a)| (n.clone(), a.select(indices)))
+                .collect(),
+            bounding_box,
+        }
+    }
+
+    /// Converts the batch into its proto, e.g. to embed points in messages of a custom transport.
+    pub fn to_proto(&self) -> proto::PointsBatch {
+        let mut batch_proto = proto::PointsBatch::new();
+        batch_proto.set_positions(self.position.iter().map(proto::Vector3d::from).collect());
+        for (name, data) in &self.attributes {
+            let mut values = proto::AttributeValues::new();
+            values.set_name(name.clone());
+            values.set_data_type(data.data_type().to_proto());
+            values.set_data(data.to_le_bytes());
+            batch_proto.mut_attributes().push(values);
+        }
+        if let Some(bounding_box) = &self.bounding_box {
+            batch_proto.set_bounding_box(bounding_box.into());
+        }
+        batch_proto
+    }
+
+    /// Reverses `to_proto`. Fails if an attribute does not have a value for every point.
+    pub fn from_proto(batch_proto: &proto::PointsBatch) -> Result<Self> {
+        let position: Vec<Point3<f64>> = batch_proto.positions.iter().map(Point3::from).collect();
+        let mut attributes = BTreeMap::new();
+        for values in batch_proto.attributes.iter() {
+            let data_type = AttributeDataType::from_proto(values.data_type)?;
+            let data = AttributeData::from_le_bytes(data_type, &values.data)?;
+            if data.len() != position.len() {
+                return Err(errors::ErrorKind::Decode(format!(
+                    "Attribute '{}' has {} values for {} points.",
+                    values.name,
+                    data.len(),
+                    position.len()
+                ))
+                .into());
+            }
+            attributes.insert(values.name.clone(), data);
+        }
+        Ok(PointsBatch {
+            position,
+            attributes,
+            bounding_box: batch_proto.bounding_box.as_ref().map(Aabb::from),
+        })
+    }
+
+    /// The values of the attribute, typed by its data type. `None` if the attribute is missing.
+    pub fn column(&self, key: impl AsRef<str>) -> Option<AttributeColumn<'_>> {
+        self.attributes.get(key.as_ref()).map(AttributeData::column)
     }
 
     pub fn get_attribute_vec<'a, T>(
@@ -188,6 +284,167 @@ impl PointsBatch {
             .ok_or_else(|| format!("Attribute '{}' not found.", key.as_ref()))
             .and_then(|val| val.try_into())
     }
+
+    /// Iterates over the positions together with the values of a scalar attribute, e.g.
+    /// intensity, both converted to `f32`. Fails if the attribute is missing or not a scalar.
+    pub fn iter_position_scalar<'a>(
+        &'a self,
+        key: impl AsRef<str>,
+    ) -> std::result::Result<impl Iterator<Item = (Point3<f32>, f32)> + 'a, String> {
+        let data = self
+            .attributes
+            .get(key.as_ref())
+            .ok_or_else(|| format!("Attribute '{}' not found.", key.as_ref()))?;
+        macro_rules! rhs {
+            ($dtype:ident, $data:ident) => {
+                Box::new($data.iter().map(|value| *value as f32))
+                    as Box<dyn Iterator<Item = f32> + 'a>
+            };
+        }
+        let values = match data {
+            AttributeData::U8Vec3(_) | AttributeData::U16Vec3(_) | AttributeData::F64Vec3(_) => {
+                return Err(format!(
+                    "Attribute '{}' has data type '{:?}', which is not a scalar.",
+                    key.as_ref(),
+                    data.data_type()
+                ))
+            }
+            _ => match_1d_attr_data!(data, rhs),
+        };
+        Ok(self
+            .position
+            .iter()
+            .map(|p| Point3::new(p.x as f32, p.y as f32, p.z as f32))
+            .zip(values))
+    }
 }
 
 pub use point_viewer_proto_rust::proto;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    #[test]
+    fn test_iter_position_scalar() {
+        let batch = PointsBatch {
+            position: vec![Point3::new(1.0, 2.0, 3.0), Point3::new(-0.5, 0.25, 8.0)],
+            attributes: vec![
+                ("intensity".to_string(), AttributeData::F32(vec![0.5, 7.0])),
+                ("label".to_string(), AttributeData::U16(vec![3, 65_000])),
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(1, 2, 3); 2]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        };
+        let intensity: &Vec<f32> = batch.get_attribute_vec("intensity").unwrap();
+        let tuples: Vec<_> = batch.iter_position_scalar("intensity").unwrap().collect();
+        assert_eq!(tuples.len(), batch.position.len());
+        for ((position, value), (expected_position, expected_value)) in
+            tuples.iter().zip(batch.position.iter().zip(intensity))
+        {
+            assert_eq!(position.coords, expected_position.coords.map(|c| c as f32));
+            assert_eq!(value, expected_value);
+        }
+        let labels: Vec<f32> = batch
+            .iter_position_scalar("label")
+            .unwrap()
+            .map(|(_, label)| label)
+            .collect();
+        assert_eq!(labels, vec![3.0, 65_000.0]);
+        assert!(batch.iter_position_scalar("color").is_err());
+        assert!(batch.iter_position_scalar("missing").is_err());
+    }
+
+    #[test]
+    fn test_column() {
+        let mut batch = PointsBatch {
+            position: vec![Point3::origin(); 2],
+            attributes: BTreeMap::new(),
+            bounding_box: None,
+        };
+        macro_rules! check_column {
+            ($dtype:ident, $values:expr) => {
+                let values = $values;
+                let name = stringify!($dtype);
+                batch
+                    .attributes
+                    .insert(name.to_string(), AttributeData::$dtype(values.clone()));
+                assert_eq!(
+                    batch.column(name),
+                    Some(AttributeColumn::$dtype(&values[..]))
+                );
+            };
+        }
+        check_column!(U8, vec![1u8, 255]);
+        check_column!(U16, vec![2u16, 65_000]);
+        check_column!(U32, vec![3u32, 4_000_000_000]);
+        check_column!(U64, vec![4u64, u64::MAX]);
+        check_column!(I8, vec![-5i8, 127]);
+        check_column!(I16, vec![-6i16, 7]);
+        check_column!(I32, vec![-7i32, 8]);
+        check_column!(I64, vec![-8i64, i64::MIN]);
+        check_column!(F32, vec![0.5f32, -9.25]);
+        check_column!(F64, vec![0.125f64, 1e300]);
+        check_column!(U8Vec3, vec![Vector3::new(1u8, 2, 3); 2]);
+        check_column!(U16Vec3, vec![Vector3::new(1u16, 2, 65_000); 2]);
+        check_column!(F64Vec3, vec![Vector3::new(0.5, -1.0, 2.0); 2]);
+        assert_eq!(batch.column("missing"), None);
+    }
+
+    #[test]
+    fn test_proto_round_trip() {
+        let position = vec![Point3::new(1.0, 2.0, 3.0), Point3::new(-0.5, 0.25, 1e9)];
+        let batch = PointsBatch {
+            bounding_box: Aabb::from_points(&position),
+            position,
+            attributes: vec![
+                ("intensity".to_string(), AttributeData::F32(vec![0.5, -7.0])),
+                ("label".to_string(), AttributeData::I8(vec![-3, 100])),
+                ("time".to_string(), AttributeData::F64(vec![1e-9, 2.5])),
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(1, 2, 3), Vector3::new(4, 5, 255)]),
+                ),
+                (
+                    "normal".to_string(),
+                    AttributeData::F64Vec3(vec![Vector3::new(0.0, -1.0, 0.5); 2]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let batch_proto = batch.to_proto();
+        let bytes = protobuf::Message::write_to_bytes(&batch_proto).unwrap();
+        let parsed: proto::PointsBatch = protobuf::parse_from_bytes(&bytes).unwrap();
+        let round_tripped = PointsBatch::from_proto(&parsed).unwrap();
+        assert_eq!(round_tripped.position, batch.position);
+        assert_eq!(round_tripped.bounding_box, batch.bounding_box);
+        assert_eq!(round_tripped.attributes.len(), batch.attributes.len());
+        for name in batch.attributes.keys() {
+            assert_eq!(round_tripped.column(name), batch.column(name));
+        }
+
+        let unbounded = PointsBatch {
+            bounding_box: None,
+            ..batch
+        };
+        assert_eq!(
+            PointsBatch::from_proto(&unbounded.to_proto())
+                .unwrap()
+                .bounding_box,
+            None
+        );
+        // Every point needs a value of every attribute.
+        let mut truncated = batch_proto;
+        truncated.mut_attributes()[0].mut_data().truncate(3);
+        assert!(PointsBatch::from_proto(&truncated).is_err());
+        truncated.mut_attributes()[0].mut_data().clear();
+        assert!(PointsBatch::from_proto(&truncated).is_err());
+    }
+}
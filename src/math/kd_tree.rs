@@ -0,0 +1,224 @@
+//! A static k-d tree over labeled 3D points, e.g. reference features to match a point cloud
+//! against.
+
+use nalgebra::Point3;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// A balanced k-d tree. Every point carries an id, which is returned by the lookups.
+#[derive(Debug, Clone)]
+pub struct KdTree {
+    /// The tree is stored implicitly: The median element of a slice is the node splitting it,
+    /// the elements before and after it form its two subtrees. The splitting axis cycles through
+    /// x, y and z with increasing depth.
+    points: Vec<(Point3<f64>, u64)>,
+}
+
+impl KdTree {
+    pub fn new(mut points: Vec<(Point3<f64>, u64)>) -> Self {
+        build(&mut points, 0);
+        KdTree { points }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Returns the id of the point closest to `query` together with its distance, if there is one
+    /// within `radius`.
+    pub fn nearest_within(&self, query: &Point3<f64>, radius: f64) -> Option<(u64, f64)> {
+        let mut nearest = None;
+        let mut nearest_distance_sq = radius * radius;
+        search(
+            &self.points,
+            0,
+            query,
+            &mut nearest,
+            &mut nearest_distance_sq,
+        );
+        nearest.map(|id| (id, nearest_distance_sq.sqrt()))
+    }
+
+    /// Returns the ids of the `k` points closest to `query` together with their distances, closest
+    /// first. Fewer are returned if the tree has fewer points.
+    pub fn k_nearest(&self, query: &Point3<f64>, k: usize) -> Vec<(u64, f64)> {
+        if k == 0 {
+            return Vec::new();
+        }
+        let mut nearest = BinaryHeap::with_capacity(k + 1);
+        search_k(&self.points, 0, query, k, &mut nearest);
+        nearest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|neighbor| (neighbor.id, neighbor.distance_sq.sqrt()))
+            .collect()
+    }
+}
+
+/// An entry of the heap of `k_nearest`, ordered by distance, so that the farthest is on top.
+struct Neighbor {
+    distance_sq: f64,
+    id: u64,
+}
+
+impl PartialEq for Neighbor {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Neighbor {}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance_sq
+            .partial_cmp(&other.distance_sq)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+fn build(points: &mut [(Point3<f64>, u64)], axis: usize) {
+    if points.len() <= 1 {
+        return;
+    }
+    points.sort_unstable_by(|a, b| {
+        a.0[axis]
+            .partial_cmp(&b.0[axis])
+            .expect("KdTree points must not be NaN.")
+    });
+    let (left, right) = points.split_at_mut(points.len() / 2);
+    build(left, (axis + 1) % 3);
+    build(&mut right[1..], (axis + 1) % 3);
+}
+
+fn search(
+    points: &[(Point3<f64>, u64)],
+    axis: usize,
+    query: &Point3<f64>,
+    nearest: &mut Option<u64>,
+    nearest_distance_sq: &mut f64,
+) {
+    if points.is_empty() {
+        return;
+    }
+    let mid = points.len() / 2;
+    let (pos, id) = &points[mid];
+    let distance_sq = (pos - query).norm_squared();
+    if distance_sq <= *nearest_distance_sq {
+        *nearest_distance_sq = distance_sq;
+        *nearest = Some(*id);
+    }
+    let offset = query[axis] - pos[axis];
+    let (near, far) = if offset < 0.0 {
+        (&points[..mid], &points[mid + 1..])
+    } else {
+        (&points[mid + 1..], &points[..mid])
+    };
+    let next_axis = (axis + 1) % 3;
+    search(near, next_axis, query, nearest, nearest_distance_sq);
+    // The other side can only contain a closer point if the splitting plane is close enough.
+    if offset * offset <= *nearest_distance_sq {
+        search(far, next_axis, query, nearest, nearest_distance_sq);
+    }
+}
+
+fn search_k(
+    points: &[(Point3<f64>, u64)],
+    axis: usize,
+    query: &Point3<f64>,
+    k: usize,
+    nearest: &mut BinaryHeap<Neighbor>,
+) {
+    if points.is_empty() {
+        return;
+    }
+    let mid = points.len() / 2;
+    let (pos, id) = &points[mid];
+    let distance_sq = (pos - query).norm_squared();
+    if nearest.len() < k || distance_sq < nearest.peek().unwrap().distance_sq {
+        nearest.push(Neighbor {
+            distance_sq,
+            id: *id,
+        });
+        if nearest.len() > k {
+            nearest.pop();
+        }
+    }
+    let offset = query[axis] - pos[axis];
+    let (near, far) = if offset < 0.0 {
+        (&points[..mid], &points[mid + 1..])
+    } else {
+        (&points[mid + 1..], &points[..mid])
+    };
+    let next_axis = (axis + 1) % 3;
+    search_k(near, next_axis, query, k, nearest);
+    if nearest.len() < k || offset * offset < nearest.peek().unwrap().distance_sq {
+        search_k(far, next_axis, query, k, nearest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_nearest_within_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut random_point =
+            || Point3::new(rng.gen_range(-10.0, 10.0), rng.gen_range(-10.0, 10.0), 0.0);
+        let points: Vec<_> = (0..200).map(|id| (random_point(), id)).collect();
+        let kd_tree = KdTree::new(points.clone());
+        for _ in 0..100 {
+            let query = random_point();
+            let expected = points
+                .iter()
+                .map(|(pos, id)| (*id, (pos - query).norm()))
+                .filter(|(_, distance)| *distance <= 1.0)
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            assert_eq!(kd_tree.nearest_within(&query, 1.0), expected);
+        }
+    }
+
+    #[test]
+    fn test_k_nearest_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut random_point = || {
+            Point3::new(
+                rng.gen_range(-10.0, 10.0),
+                rng.gen_range(-10.0, 10.0),
+                rng.gen_range(-10.0, 10.0),
+            )
+        };
+        let points: Vec<_> = (0..300).map(|id| (random_point(), id)).collect();
+        let kd_tree = KdTree::new(points.clone());
+        for _ in 0..50 {
+            let query = random_point();
+            let mut expected: Vec<_> = points
+                .iter()
+                .map(|(pos, id)| (*id, (pos - query).norm()))
+                .collect();
+            expected.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            expected.truncate(5);
+            assert_eq!(kd_tree.k_nearest(&query, 5), expected);
+        }
+        assert_eq!(kd_tree.k_nearest(&Point3::origin(), 1000).len(), 300);
+    }
+
+    #[test]
+    fn test_nearest_within_empty() {
+        let kd_tree = KdTree::new(Vec::new());
+        assert_eq!(kd_tree.nearest_within(&Point3::origin(), 100.0), None);
+    }
+}
@@ -0,0 +1,137 @@
+//! Projections of lat/lng coordinates onto a 2D map, for generating map tiles.
+
+use nalgebra::Vector2;
+use nav_types::WGS84;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+const TWO_PI: f64 = 2.0 * PI;
+
+/// A projection of the earth onto a map. The map coordinates are normalized to `[0, 1)` in both
+/// dimensions, with x pointing right and y pointing down, like in an image. Zooming in is then
+/// only a matter of scaling the normalized coordinates.
+pub trait MapProjection {
+    /// Projects the lat/lng coordinate onto the map. The altitude is ignored.
+    fn project(&self, lat_lng: &WGS84<f64>) -> Vector2<f64>;
+
+    /// The inverse of `project`. The altitude returned is always 0.
+    fn unproject(&self, normalized: &Vector2<f64>) -> WGS84<f64>;
+}
+
+/// The equirectangular projection, also known as plate carrée: Longitude and latitude are linear
+/// in x and y. The whole earth is covered, with the poles on the top and bottom edges.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Equirectangular;
+
+impl MapProjection for Equirectangular {
+    fn project(&self, lat_lng: &WGS84<f64>) -> Vector2<f64> {
+        Vector2::new(
+            0.5 + lat_lng.longitude_radians() / TWO_PI,
+            0.5 - lat_lng.latitude_radians() / PI,
+        )
+    }
+
+    fn unproject(&self, normalized: &Vector2<f64>) -> WGS84<f64> {
+        let latitude = nalgebra::clamp((0.5 - normalized.y) * PI, -FRAC_PI_2, FRAC_PI_2);
+        let longitude = nalgebra::clamp((normalized.x - 0.5) * TWO_PI, -PI, PI);
+        WGS84::from_radians_and_meters(latitude, longitude, 0.0)
+    }
+}
+
+/// The pole a polar stereographic map is centered on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pole {
+    North,
+    South,
+}
+
+/// The polar stereographic projection of a sphere, as used for maps of the Arctic and Antarctic.
+/// The pole is in the center of the map, and the hemisphere around it is mapped to the disk
+/// inscribed in the map, with the equator on its boundary. Points of the other hemisphere are
+/// projected outside of `[0, 1)`. The prime meridian points down for the north pole and up for
+/// the south pole, as seen from above the respective pole.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PolarStereographic {
+    pub pole: Pole,
+}
+
+impl PolarStereographic {
+    /// The sign that turns latitudes towards the pole positive, and the direction of the prime
+    /// meridian in y.
+    fn sign(&self) -> f64 {
+        match self.pole {
+            Pole::North => 1.0,
+            Pole::South => -1.0,
+        }
+    }
+}
+
+impl MapProjection for PolarStereographic {
+    fn project(&self, lat_lng: &WGS84<f64>) -> Vector2<f64> {
+        let sign = self.sign();
+        // The distance from the pole, with the equator at 1.
+        let radius = (FRAC_PI_4 - 0.5 * sign * lat_lng.latitude_radians()).tan();
+        let (sin_lng, cos_lng) = lat_lng.longitude_radians().sin_cos();
+        Vector2::new(
+            0.5 + 0.5 * radius * sin_lng,
+            0.5 + 0.5 * sign * radius * cos_lng,
+        )
+    }
+
+    fn unproject(&self, normalized: &Vector2<f64>) -> WGS84<f64> {
+        let sign = self.sign();
+        let offset = 2.0 * (normalized - Vector2::new(0.5, 0.5));
+        let radius = offset.norm();
+        let latitude = sign * (FRAC_PI_2 - 2.0 * radius.atan());
+        let longitude = offset.x.atan2(sign * offset.y);
+        WGS84::from_radians_and_meters(latitude, longitude, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    fn assert_round_trip(projection: &impl MapProjection, lat_lng: &WGS84<f64>) {
+        let normalized = projection.project(lat_lng);
+        let unprojected = projection.unproject(&normalized);
+        assert_relative_eq!(
+            lat_lng.latitude_radians(),
+            unprojected.latitude_radians(),
+            epsilon = 1e-12
+        );
+        assert_relative_eq!(
+            lat_lng.longitude_radians(),
+            unprojected.longitude_radians(),
+            epsilon = 1e-12
+        );
+    }
+
+    #[test]
+    fn equirectangular_round_trip() {
+        for (lat, lng) in &[(37.407204, -122.147604), (-89.0, 179.0), (0.0, 0.0)] {
+            let lat_lng = WGS84::from_degrees_and_meters(*lat, *lng, 0.0);
+            assert_round_trip(&Equirectangular, &lat_lng);
+        }
+        let corner = Equirectangular.project(&WGS84::from_degrees_and_meters(90.0, -180.0, 0.0));
+        assert_relative_eq!(corner, Vector2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn polar_stereographic_round_trip() {
+        let north = PolarStereographic { pole: Pole::North };
+        let south = PolarStereographic { pole: Pole::South };
+        for (lat, lng) in &[(78.22, 15.65), (60.0, -150.0), (-10.0, 90.0)] {
+            let lat_lng = WGS84::from_degrees_and_meters(*lat, *lng, 0.0);
+            assert_round_trip(&north, &lat_lng);
+            let mirrored = WGS84::from_degrees_and_meters(-lat, *lng, 0.0);
+            assert_round_trip(&south, &mirrored);
+        }
+        // The pole is in the center, the equator on the boundary of the inscribed disk.
+        let pole = north.project(&WGS84::from_degrees_and_meters(90.0, 0.0, 0.0));
+        assert_relative_eq!(pole, Vector2::new(0.5, 0.5));
+        let equator = south.project(&WGS84::from_degrees_and_meters(0.0, 0.0, 0.0));
+        assert_relative_eq!(equator, Vector2::new(0.5, 0.0), epsilon = 1e-12);
+    }
+}
@@ -21,9 +21,13 @@ use std::str::FromStr;
 
 #[macro_use]
 pub mod base;
+pub mod kd_tree;
+pub mod map_projection;
 pub mod sat;
 pub mod web_mercator;
 pub use base::*;
+pub use kd_tree::*;
+pub use map_projection::*;
 pub use sat::*;
 pub use web_mercator::*;
 
@@ -89,6 +93,16 @@ where
     }
 }
 
+impl<T: Copy> ClosedInterval<T> {
+    pub fn lower_bound(&self) -> T {
+        self.lower_bound
+    }
+
+    pub fn upper_bound(&self) -> T {
+        self.upper_bound
+    }
+}
+
 impl<T> FromStr for ClosedInterval<T>
 where
     T: std::str::FromStr,
@@ -1,5 +1,6 @@
 //! Calculations with Web Mercator coordinates.
 
+use crate::math::map_projection::MapProjection;
 use nalgebra::Vector2;
 use nav_types::WGS84;
 use serde::{Deserialize, Serialize};
@@ -77,6 +78,15 @@ impl WebMercatorCoord {
         }
     }
 
+    /// Like [`to_zoomed_coordinate`](#method.to_zoomed_coordinate), but represents the
+    /// coordinate at [`MAX_ZOOM`](index.html#constant.MAX_ZOOM) when `z` is greater, e.g. for
+    /// viewers that allow zooming in further than there is data for. Returns the coordinate and
+    /// the zoom level it is in.
+    pub fn to_zoomed_coordinate_clamped(&self, z: u8) -> (Vector2<f64>, u8) {
+        let z = z.min(MAX_ZOOM);
+        (self.to_zoomed_coordinate(z).unwrap(), z)
+    }
+
     /// The inverse of [`to_zoomed_coordinate`](#method.to_zoomed_coordinate).
     ///
     /// Returns `None` when `z` is greater than [`MAX_ZOOM`](index.html#constant.max_zoom)
@@ -97,6 +107,24 @@ impl WebMercatorCoord {
     }
 }
 
+/// The Web Mercator projection, whose map coordinates are those of
+/// [`WebMercatorCoord`](struct.WebMercatorCoord.html).
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebMercator;
+
+impl MapProjection for WebMercator {
+    fn project(&self, lat_lng: &WGS84<f64>) -> Vector2<f64> {
+        WebMercatorCoord::from_lat_lng(lat_lng).normalized
+    }
+
+    fn unproject(&self, normalized: &Vector2<f64>) -> WGS84<f64> {
+        WebMercatorCoord {
+            normalized: *normalized,
+        }
+        .to_lat_lng()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -127,6 +155,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn zoomed_coordinate_clamped() {
+        let coord = WebMercatorCoord::from_lat_lng(&WGS84::from_degrees_and_meters(
+            37.407204,
+            -122.147604,
+            0.0,
+        ));
+        assert_eq!(coord.to_zoomed_coordinate(MAX_ZOOM + 1), None);
+        let at_max_zoom = (coord.to_zoomed_coordinate(MAX_ZOOM).unwrap(), MAX_ZOOM);
+        assert_eq!(
+            coord.to_zoomed_coordinate_clamped(MAX_ZOOM + 1),
+            at_max_zoom
+        );
+        assert_eq!(coord.to_zoomed_coordinate_clamped(u8::MAX), at_max_zoom);
+        assert_eq!(
+            coord.to_zoomed_coordinate_clamped(19),
+            (coord.to_zoomed_coordinate(19).unwrap(), 19)
+        );
+    }
+
     #[test]
     fn projection_roundtrip() {
         // Checks that unprojection of a projection returns the original coordinate,
@@ -145,6 +193,27 @@ mod tests {
         assert_eq!(unprojected.altitude(), 0.0);
     }
 
+    #[test]
+    fn map_projection_roundtrip() {
+        let test_coordinate = WGS84::from_degrees_and_meters(-33.856784, 151.215297, 0.0);
+        let normalized = WebMercator.project(&test_coordinate);
+        assert_eq!(
+            normalized * 256.0,
+            WebMercatorCoord::from_lat_lng(&test_coordinate)
+                .to_zoomed_coordinate(0)
+                .unwrap()
+        );
+        let unprojected = WebMercator.unproject(&normalized);
+        assert_relative_eq!(
+            test_coordinate.longitude_radians(),
+            unprojected.longitude_radians()
+        );
+        assert_relative_eq!(
+            test_coordinate.latitude_radians(),
+            unprojected.latitude_radians()
+        );
+    }
+
     #[test]
     fn projection_ground_truth() {
         let test_coordinate = WGS84::from_degrees_and_meters(37.407204, -122.147604, 0.0);
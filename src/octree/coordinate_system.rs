@@ -0,0 +1,110 @@
+use crate::errors::*;
+use crate::geometry::Aabb;
+use crate::proto;
+use nalgebra::{Matrix4, Point3};
+use serde::{Deserialize, Serialize};
+
+/// The EPSG code of ECEF coordinates.
+pub const ECEF_EPSG_CODE: u32 = 4978;
+
+/// Describes how the positions of an octree relate to the earth, so that clients can reproject
+/// them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CoordinateSystem {
+    /// The positions are in the coordinate reference system with this EPSG code, e.g. 4978 for
+    /// ECEF.
+    Epsg(u32),
+    /// The positions are in a local frame, and this matrix transforms ECEF coordinates into it.
+    LocalFromEcef(Matrix4<f64>),
+}
+
+impl CoordinateSystem {
+    /// Transforms the positions into ECEF coordinates, e.g. to convert them to WGS84. `None` if
+    /// they are in another coordinate reference system than ECEF, or the matrix can't be inverted.
+    pub fn ecef_from_positions(&self) -> Option<Matrix4<f64>> {
+        match self {
+            CoordinateSystem::Epsg(ECEF_EPSG_CODE) => Some(Matrix4::identity()),
+            CoordinateSystem::Epsg(_) => None,
+            CoordinateSystem::LocalFromEcef(local_from_ecef) => local_from_ecef.try_inverse(),
+        }
+    }
+
+    /// Returns `None` if the proto does not describe a coordinate system.
+    pub fn from_proto(proto: &proto::CoordinateSystem) -> Result<Option<Self>> {
+        match &proto.kind {
+            None => Ok(None),
+            Some(proto::CoordinateSystem_oneof_kind::epsg_code(code)) => {
+                Ok(Some(CoordinateSystem::Epsg(*code)))
+            }
+            Some(proto::CoordinateSystem_oneof_kind::local_from_ecef(matrix)) => {
+                if matrix.entries.len() != 16 {
+                    return Err(ErrorKind::InvalidInput(format!(
+                        "Proto: Matrix4d has {} entries instead of 16",
+                        matrix.entries.len()
+                    ))
+                    .into());
+                }
+                Ok(Some(CoordinateSystem::LocalFromEcef(
+                    Matrix4::from_column_slice(&matrix.entries),
+                )))
+            }
+        }
+    }
+
+    pub fn to_proto(&self) -> proto::CoordinateSystem {
+        let mut proto = proto::CoordinateSystem::new();
+        match self {
+            CoordinateSystem::Epsg(code) => proto.set_epsg_code(*code),
+            CoordinateSystem::LocalFromEcef(matrix) => {
+                let mut matrix_proto = proto::Matrix4d::new();
+                matrix_proto.set_entries(matrix.as_slice().to_vec());
+                proto.set_local_from_ecef(matrix_proto);
+            }
+        }
+        proto
+    }
+}
+
+/// Which axis points up. Octrees are Z-up, so Y-up input, e.g. from mesh tools, is converted on
+/// import with `BuildOptions::input_axes`, and can be converted back on export with
+/// `OutputTransform::ConvertAxes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AxisConvention {
+    ZUp,
+    YUp,
+}
+
+impl Default for AxisConvention {
+    fn default() -> Self {
+        AxisConvention::ZUp
+    }
+}
+
+impl AxisConvention {
+    /// Converts a position in this convention into Z-up. Both frames are right-handed, so a Y-up
+    /// (x, y, z) becomes (x, -z, y).
+    pub fn to_z_up(self, p: &Point3<f64>) -> Point3<f64> {
+        match self {
+            AxisConvention::ZUp => *p,
+            AxisConvention::YUp => Point3::new(p.x, -p.z, p.y),
+        }
+    }
+
+    /// The inverse of `to_z_up`.
+    pub fn from_z_up(self, p: &Point3<f64>) -> Point3<f64> {
+        match self {
+            AxisConvention::ZUp => *p,
+            AxisConvention::YUp => Point3::new(p.x, p.z, -p.y),
+        }
+    }
+
+    /// The box around the converted box, which is the same size, since the axes are only swapped.
+    pub fn aabb_to_z_up(self, aabb: &Aabb) -> Aabb {
+        Aabb::new(self.to_z_up(aabb.min()), self.to_z_up(aabb.max()))
+    }
+
+    /// The inverse of `aabb_to_z_up`.
+    pub fn aabb_from_z_up(self, aabb: &Aabb) -> Aabb {
+        Aabb::new(self.from_z_up(aabb.min()), self.from_z_up(aabb.max()))
+    }
+}
@@ -0,0 +1,47 @@
+use crate::errors::*;
+use crate::geometry::Cube;
+use crate::iterator::PointCloud;
+use crate::octree::{NodeId, Octree};
+use rayon::prelude::*;
+
+/// Returns the nodes whose points differ between the two octrees, e.g. to find what changed
+/// between two scans of the same area. Nodes of the same id only cover the same space if the
+/// octrees have the same root cube, and their data is only comparable at the same resolution, so
+/// both are required. A node differs if its number of points or the hash of its data differs,
+/// where a node missing from one octree counts as empty. Since the points in inner nodes are
+/// samples of those in their children, a change usually shows up in the ancestors of a node as
+/// well. The nodes are sorted by level, then index.
+pub fn diff_octrees(a: &Octree, b: &Octree) -> Result<Vec<NodeId>> {
+    let root_cube_a = Cube::bounding(&a.meta.bounding_box);
+    let root_cube_b = Cube::bounding(&b.meta.bounding_box);
+    if root_cube_a.min() != root_cube_b.min()
+        || root_cube_a.edge_length() != root_cube_b.edge_length()
+        || a.meta.resolution != b.meta.resolution
+    {
+        return Err(ErrorKind::InvalidInput(
+            "Only octrees with the same root cube and resolution can be compared.".to_string(),
+        )
+        .into());
+    }
+
+    let mut node_ids: Vec<NodeId> = a.nodes.keys().chain(b.nodes.keys()).copied().collect();
+    node_ids.sort_unstable_by_key(|id| (id.level(), id.index()));
+    node_ids.dedup();
+    let changed = node_ids
+        .into_par_iter()
+        .map(|id| {
+            let num_points = a.node_point_count(id).unwrap_or(0);
+            if num_points != b.node_point_count(id).unwrap_or(0) {
+                return Ok(Some(id));
+            }
+            // Empty nodes have no data.
+            if num_points == 0 {
+                return Ok(None);
+            }
+            let id_str = id.to_string();
+            let is_changed = a.node_content_hash(&id_str)? != b.node_content_hash(&id_str)?;
+            Ok(if is_changed { Some(id) } else { None })
+        })
+        .collect::<Result<Vec<Option<NodeId>>>>()?;
+    Ok(changed.into_iter().flatten().collect())
+}
@@ -0,0 +1,209 @@
+use crate::errors::*;
+use crate::geometry::{Aabb, Cube};
+use crate::octree::generation::SplitDecision;
+use crate::octree::{BuildOptions, ChildIndex, NodeId, OctreeMeta};
+use crate::read_write::{PlyIterator, PositionEncoding};
+use crate::{AttributeDataType, NumberOfPoints, PointCloudMeta, PointsBatch, NUM_POINTS_PER_BATCH};
+use nalgebra::Point3;
+use std::cmp;
+use std::path::Path;
+
+/// The predicted structure of an octree, see `estimate_octree`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildEstimate {
+    /// The input points inside the bounding box, extrapolated from the samples.
+    pub num_points: usize,
+    pub num_nodes: usize,
+    /// The level of the deepest nodes.
+    pub depth: u8,
+    /// The size of the node data on disk, without the meta data.
+    pub num_bytes: u64,
+}
+
+struct Estimator<'a> {
+    octree_meta: &'a OctreeMeta,
+    options: &'a BuildOptions,
+    root_cube: Cube,
+    /// The number of input points every sampled point stands for.
+    weight: f64,
+    attribute_bytes_per_point: usize,
+    num_nodes: usize,
+    depth: u8,
+    num_bytes: f64,
+}
+
+impl<'a> Estimator<'a> {
+    fn bytes_per_point(&self, bounding_cube: &Cube) -> f64 {
+        let position_encoding = PositionEncoding::new(bounding_cube, self.octree_meta.resolution);
+        (3 * position_encoding.bytes_per_coordinate() + self.attribute_bytes_per_point) as f64
+    }
+
+    /// Splits the node like the build does, and returns the number of points it holds before its
+    /// parent is subsampled from it.
+    fn estimate_node(&mut self, id: NodeId, positions: Vec<Point3<f64>>) -> f64 {
+        self.num_nodes += 1;
+        self.depth = cmp::max(self.depth, id.level());
+        let num_points = positions.len() as f64 * self.weight;
+        let max_points = self.options.max_points_per_node as f64;
+        let bounding_cube = id.find_bounding_cube(&self.root_cube);
+        // The root is always split.
+        let is_leaf = id.level() > 0
+            && self.options.split_decision(
+                id,
+                &bounding_cube,
+                self.octree_meta.resolution,
+                num_points,
+            ) != SplitDecision::Split;
+        if is_leaf {
+            return if self.options.decimate_leaves {
+                num_points.min(max_points)
+            } else {
+                num_points
+            };
+        }
+
+        let mut children = vec![Vec::new(); 8];
+        for p in positions {
+            children[ChildIndex::from_bounding_cube(&bounding_cube, &p).as_u8() as usize].push(p);
+        }
+        let mut num_subsampled = 0.0;
+        for (child_index, child_positions) in children.into_iter().enumerate() {
+            if child_positions.is_empty() {
+                continue;
+            }
+            let child_id = id.get_child_id(ChildIndex::from_u8(child_index as u8));
+            let child_points = self.estimate_node(child_id, child_positions);
+            // Every 8th point of the child moves into this node.
+            let moved = (child_points / 8.0).ceil();
+            let child_cube = child_id.find_bounding_cube(&self.root_cube);
+            self.num_bytes += (child_points - moved) * self.bytes_per_point(&child_cube);
+            num_subsampled += moved;
+        }
+        if id.level() == 0 {
+            self.num_bytes += num_subsampled * self.bytes_per_point(&bounding_cube);
+        }
+        num_subsampled
+    }
+}
+
+/// Predicts the structure of the octree that `build_octree_with_options` would build from the
+/// input, without writing anything, e.g. to choose the node capacity before a long build. Only the
+/// first `max_samples` points of the input are read and held in memory, and each stands in for the
+/// same share of the `num_points` of the input. They should thus be spread over all of it, like
+/// those of `PlyIterator::with_stride`. Small clusters can be missed this way, so the estimate is
+/// only exact if the whole input is read. Points outside of the bounding box are ignored.
+pub fn estimate_octree(
+    resolution: f64,
+    bounding_box: Aabb,
+    input: impl Iterator<Item = PointsBatch> + NumberOfPoints,
+    attributes: &[&str],
+    options: &BuildOptions,
+    max_samples: usize,
+) -> Result<BuildEstimate> {
+    let num_input_points = input.num_points();
+    let positions = input
+        .flat_map(|batch| batch.position)
+        .take(cmp::max(max_samples, 1));
+    estimate_from_positions(
+        resolution,
+        bounding_box,
+        positions,
+        num_input_points,
+        attributes,
+        options,
+    )
+}
+
+/// Like `estimate_octree`, for the points of a PLY file. At most `max_samples` points spread evenly
+/// over the file are read, and their bounding box stands in for the one that
+/// `build_octree_from_file` determines from all points.
+pub fn estimate_octree_from_file(
+    resolution: f64,
+    filename: impl AsRef<Path>,
+    attributes: &[&str],
+    options: &BuildOptions,
+    max_samples: usize,
+) -> Result<BuildEstimate> {
+    let stream = PlyIterator::from_file(filename, NUM_POINTS_PER_BATCH)?;
+    let num_input_points = stream.num_points();
+    let stride = num_input_points.saturating_sub(1) / cmp::max(max_samples, 1) + 1;
+    let mut stream = stream.with_stride(stride);
+    let positions: Vec<Point3<f64>> = (&mut stream).flat_map(|batch| batch.position).collect();
+    stream.into_result()?;
+    let bounding_box = Aabb::from_points(&positions).unwrap_or_else(Aabb::zero);
+    estimate_from_positions(
+        resolution,
+        bounding_box,
+        positions.into_iter(),
+        num_input_points,
+        attributes,
+        options,
+    )
+}
+
+/// Estimates the octree from the sampled positions, each of which stands for the same number of
+/// the input points.
+fn estimate_from_positions(
+    resolution: f64,
+    bounding_box: Aabb,
+    positions: impl Iterator<Item = Point3<f64>>,
+    num_input_points: usize,
+    attributes: &[&str],
+    options: &BuildOptions,
+) -> Result<BuildEstimate> {
+    let bounding_box = options.octree_bounding_box(&bounding_box);
+    let mut octree_meta = OctreeMeta::new_with_standard_attributes(resolution, bounding_box);
+    octree_meta.add_optional_attributes(attributes);
+    octree_meta
+        .attribute_data_types
+        .extend(options.attribute_data_types.clone());
+    let mut attribute_bytes_per_point = octree_meta
+        .attribute_data_types_for(attributes)?
+        .values()
+        .map(|data_type| data_type.size_of())
+        .sum();
+    if options.original_index {
+        attribute_bytes_per_point += AttributeDataType::U64.size_of();
+    }
+
+    let mut num_read_points = 0;
+    let mut samples = Vec::new();
+    for p in positions {
+        num_read_points += 1;
+        let p = options.octree_position(&p);
+        // Like the build, this includes the max, which tight bounding boxes touch.
+        let bounding_box = &octree_meta.bounding_box;
+        if nalgebra::partial_le(bounding_box.min(), &p)
+            && nalgebra::partial_le(&p, bounding_box.max())
+        {
+            samples.push(p);
+        }
+    }
+    let mut estimate = BuildEstimate {
+        num_points: 0,
+        num_nodes: 0,
+        depth: 0,
+        num_bytes: 0,
+    };
+    if samples.is_empty() {
+        return Ok(estimate);
+    }
+    let weight = cmp::max(num_input_points, num_read_points) as f64 / num_read_points as f64;
+    estimate.num_points = (samples.len() as f64 * weight).round() as usize;
+
+    let mut estimator = Estimator {
+        octree_meta: &octree_meta,
+        options,
+        root_cube: Cube::bounding(&octree_meta.bounding_box),
+        weight,
+        attribute_bytes_per_point,
+        num_nodes: 0,
+        depth: 0,
+        num_bytes: 0.0,
+    };
+    estimator.estimate_node(NodeId::root(), samples);
+    estimate.num_nodes = estimator.num_nodes;
+    estimate.depth = estimator.depth;
+    estimate.num_bytes = estimator.num_bytes.round() as u64;
+    Ok(estimate)
+}
@@ -12,30 +12,455 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::attributes::AttributeAnnotation;
 use crate::data_provider::OnDiskDataProvider;
 use crate::errors::*;
-use crate::geometry::{Aabb, Cube};
-use crate::octree::{self, to_meta_proto, to_node_proto, ChildIndex, NodeId, OctreeMeta};
+use crate::geometry::{Aabb, Cube, Sphere};
+use crate::math::ConvexPolyhedron;
+use crate::octree::{
+    self, to_meta_proto, to_node_proto, AxisConvention, ChildIndex, CoordinateSystem, NodeId,
+    OctreeMeta, OctreeSummary, SUMMARY_FILENAME,
+};
 use crate::proto;
 use crate::read_write::{
-    attempt_increasing_rlimit_to_max, Encoding, NodeIterator, NodeWriter, OpenMode, PlyIterator,
-    PositionEncoding, RawNodeWriter,
+    attempt_increasing_rlimit_to_max, encode_attribute, AttributeCodec, Encoding, NodeIterator,
+    NodeWriter, OpenMode, PlyIterator, PositionEncoding, RawNodeWriter,
+};
+use crate::utils::{create_progress_bar, remove_file_if_exists};
+use crate::{attribute_extension, META_FILENAME};
+use crate::{
+    AttributeData, AttributeDataType, NumberOfPoints, PointCloudMeta, PointsBatch,
+    NUM_POINTS_PER_BATCH,
 };
-use crate::utils::create_progress_bar;
-use crate::META_FILENAME;
-use crate::{AttributeDataType, NumberOfPoints, PointCloudMeta, PointsBatch, NUM_POINTS_PER_BATCH};
 use fnv::{FnvHashMap, FnvHashSet};
+use nalgebra::{Point3, Similarity3};
+use pbr::ProgressBar;
 use protobuf::Message;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use rayon::Scope;
 use std::cmp;
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::BufWriter;
-use std::path::Path;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 const MAX_POINTS_PER_NODE: i64 = 100_000;
 
+/// The directory in the output directory that nodes are moved into while they are rewritten, so
+/// that their points can be streamed from the moved files instead of being read into memory.
+const SPILL_DIRECTORY: &str = "spill";
+
+/// The U64 attribute stored by `BuildOptions::original_index`.
+pub const ORIGINAL_INDEX_ATTRIBUTE: &str = "original_index";
+
+/// What to do with input points outside of the bounding box of the octree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutOfBounds {
+    /// Stop reading the input and fail the build.
+    Error,
+    /// Leave the points out of the octree.
+    Drop,
+}
+
+/// Tuning parameters for building an octree.
+#[derive(Clone, Debug)]
+pub struct BuildOptions {
+    /// Nodes with more points are split further, unless they are already at the resolution.
+    pub max_points_per_node: i64,
+    pub out_of_bounds: OutOfBounds,
+    /// Stored in the meta data, for clients to reproject the points.
+    pub coordinate_system: Option<CoordinateSystem>,
+    /// Sorts the points of every node by their `Cube::morton_code` in the node's bounding cube, so
+    /// that points close to each other are mostly stored close to each other. This is recorded in
+    /// the meta data; queries return the same points either way.
+    pub morton_order: bool,
+    /// Nodes are only split down to the resolution, so leaves can hold more than
+    /// `max_points_per_node` points. By default they keep all of them, and the octree contains
+    /// every input point. Decimating the leaves keeps evenly spaced points up to the capacity
+    /// instead, which bounds the size of nodes for very dense data. This is recorded in the meta
+    /// data.
+    pub decimate_leaves: bool,
+    /// Stores the `ORIGINAL_INDEX_ATTRIBUTE` with the position of every point in the input,
+    /// counting from 0, so that the input order can be recovered after a query, see
+    /// `OutputTransform::SortByOriginalIndex`. Points outside of the bounding box count as well.
+    pub original_index: bool,
+    /// Also writes the meta data as JSON into `SUMMARY_FILENAME`, see `OctreeSummary`.
+    pub json_summary: bool,
+    /// Attributes that are only stored in the leaf nodes, to save space for large attributes
+    /// like normals that are not needed for the coarse levels. The points that are moved into
+    /// inner nodes lose them. Queries for all attributes leave them out, and queries that
+    /// request them explicitly only return the points of the leaves, or fail for the coarse
+    /// levels of `PointLocation::AllPointsInDepthRange`. This is recorded in the meta data. The
+    /// color can not be leaf only.
+    pub leaf_only_attributes: Vec<String>,
+    /// Stored in the meta data, by attribute name. Only attributes that are built can be
+    /// annotated.
+    pub attribute_annotations: HashMap<String, AttributeAnnotation>,
+    /// Nodes on this level are not split any further, like those at the resolution, which bounds
+    /// the number of nodes and files for dense data. Their points are kept, or decimated with
+    /// `decimate_leaves`. The root is always split, so this must be at least 1. This is recorded
+    /// in the meta data.
+    pub max_depth: Option<u8>,
+    /// The axis convention of the input positions and of the bounding box, which are converted
+    /// into the Z-up convention of octrees, e.g. for Y-up PLY files. Other attributes, like
+    /// normals, are stored as they are. This is recorded in the meta data.
+    pub input_axes: AxisConvention,
+    /// Compresses the node data of attributes with a chain of codecs, by attribute name, e.g.
+    /// `[Delta, Deflate]` for "position" with `morton_order`, or `[RunLength]` for the
+    /// classification. This is recorded in the meta data, so that reading decodes the data, which
+    /// is then read into memory per node instead of streamed. Only "position" and attributes
+    /// that are built can be compressed.
+    pub attribute_codecs: HashMap<String, Vec<AttributeCodec>>,
+    /// Applied to the input positions and the bounding box, e.g. the pose of the sensor in the
+    /// world for points in the sensor frame, before the conversion of `input_axes`. The bounding
+    /// box becomes the one enclosing the transformed box. An F64Vec3 "normal" attribute is
+    /// rotated along, other attributes are stored as they are.
+    pub input_transform: Option<Similarity3<f64>>,
+    /// Bounds the number of points that building holds in memory per thread, besides the batches
+    /// of the input. Nodes are written to files on disk and streamed back in batches of at most
+    /// this many points, so this does not limit the size of the input or of the nodes. Only
+    /// `morton_order` and `attribute_codecs` need all points of a node in memory, and building
+    /// fails instead of exceeding this for nodes that are too large, e.g. leaves that are not
+    /// decimated.
+    pub max_points_in_memory: Option<usize>,
+    /// The data types of attributes by name, which take precedence over the standard ones, e.g.
+    /// to keep the custom attributes of existing point clouds. Only attributes that are built can
+    /// have data types.
+    pub attribute_data_types: HashMap<String, AttributeDataType>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            max_points_per_node: MAX_POINTS_PER_NODE,
+            out_of_bounds: OutOfBounds::Error,
+            coordinate_system: None,
+            morton_order: false,
+            decimate_leaves: false,
+            original_index: false,
+            json_summary: false,
+            leaf_only_attributes: Vec::new(),
+            attribute_annotations: HashMap::new(),
+            max_depth: None,
+            input_axes: AxisConvention::ZUp,
+            attribute_codecs: HashMap::new(),
+            input_transform: None,
+            max_points_in_memory: None,
+            attribute_data_types: HashMap::new(),
+        }
+    }
+}
+
+/// Whether the build splits a node, see `BuildOptions::split_decision`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum SplitDecision {
+    Split,
+    /// The node has at most `max_points_per_node` points.
+    Fits,
+    /// The node has too many points, but is already at the resolution.
+    TooSmall,
+    /// The node has too many points, but is already at the `max_depth`.
+    TooDeep,
+}
+
+impl BuildOptions {
+    /// Whether nodes on the level must not be split, see `max_depth`.
+    fn is_at_max_depth(&self, level: u8) -> bool {
+        matches!(self.max_depth, Some(max_depth) if level >= max_depth)
+    }
+
+    /// Whether the build splits the node with the number of points, which is fractional for
+    /// estimates. This holds for all nodes but the root, which is always split.
+    pub(super) fn split_decision(
+        &self,
+        id: NodeId,
+        bounding_cube: &Cube,
+        resolution: f64,
+        num_points: f64,
+    ) -> SplitDecision {
+        if num_points <= self.max_points_per_node as f64 {
+            SplitDecision::Fits
+        } else if bounding_cube.edge_length() <= resolution {
+            SplitDecision::TooSmall
+        } else if self.is_at_max_depth(id.level()) {
+            SplitDecision::TooDeep
+        } else {
+            SplitDecision::Split
+        }
+    }
+
+    /// The bounding box of the octree for the bounding box of the input, see `input_transform`
+    /// and `input_axes`.
+    pub(super) fn octree_bounding_box(&self, bounding_box: &Aabb) -> Aabb {
+        let bounding_box = match &self.input_transform {
+            Some(transform) => transform_aabb(transform, bounding_box),
+            None => bounding_box.clone(),
+        };
+        self.input_axes.aabb_to_z_up(&bounding_box)
+    }
+
+    /// The position in the octree of an input position, see `input_transform` and `input_axes`.
+    pub(super) fn octree_position(&self, p: &Point3<f64>) -> Point3<f64> {
+        let p = match &self.input_transform {
+            Some(transform) => transform * p,
+            None => *p,
+        };
+        self.input_axes.to_z_up(&p)
+    }
+
+    /// The number of points that are read at a time from the nodes on disk.
+    fn batch_size(&self) -> usize {
+        self.max_points_in_memory
+            .map_or(NUM_POINTS_PER_BATCH, |max_points| {
+                cmp::min(max_points, NUM_POINTS_PER_BATCH)
+            })
+    }
+
+    /// Fails if holding the points in memory for `what` exceeds `max_points_in_memory`.
+    fn check_points_in_memory(
+        &self,
+        num_points: usize,
+        what: impl FnOnce() -> String,
+    ) -> Result<()> {
+        match self.max_points_in_memory {
+            Some(max_points) if num_points > max_points => Err(ErrorKind::InvalidInput(format!(
+                "{} needs {} points in memory, but at most {} are allowed.",
+                what(),
+                num_points,
+                max_points
+            ))
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The most points of the nodes on disk that were held in memory at once by a thread of a build,
+/// by output directory, see `track_points_in_memory`.
+#[cfg(test)]
+pub(super) static MAX_POINTS_IN_MEMORY: std::sync::Mutex<Vec<(PathBuf, usize)>> =
+    std::sync::Mutex::new(Vec::new());
+
+/// Records that a thread of the build holds the points of nodes on disk in memory, to test
+/// `BuildOptions::max_points_in_memory`.
+#[cfg(test)]
+fn track_points_in_memory(octree_data_provider: &OnDiskDataProvider, num_points: usize) {
+    let mut max_points_in_memory = MAX_POINTS_IN_MEMORY.lock().unwrap();
+    let directory = &octree_data_provider.directory;
+    match max_points_in_memory
+        .iter_mut()
+        .find(|(other_directory, _)| other_directory == directory)
+    {
+        Some((_, max_points)) => *max_points = cmp::max(*max_points, num_points),
+        None => max_points_in_memory.push((directory.clone(), num_points)),
+    }
+}
+
+#[cfg(not(test))]
+fn track_points_in_memory(_: &OnDiskDataProvider, _: usize) {}
+
+/// Passes on the points inside the bounding box, and handles the others according to the policy.
+struct BoundsChecked<'a, P> {
+    input: P,
+    bounding_box: &'a Aabb,
+    policy: OutOfBounds,
+    num_outside: &'a AtomicUsize,
+}
+
+impl<'a, P> Iterator for BoundsChecked<'a, P>
+where
+    P: Iterator<Item = PointsBatch>,
+{
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = self.input.next()?;
+        // Unlike Aabb::contains(), this includes the max, which tight bounding boxes touch.
+        let keep: Vec<bool> = batch
+            .position
+            .iter()
+            .map(|p| {
+                nalgebra::partial_le(self.bounding_box.min(), p)
+                    && nalgebra::partial_le(p, self.bounding_box.max())
+            })
+            .collect();
+        let num_outside = keep.iter().filter(|inside| !**inside).count();
+        if num_outside > 0 {
+            self.num_outside.fetch_add(num_outside, Ordering::Relaxed);
+            match self.policy {
+                OutOfBounds::Error => return None,
+                OutOfBounds::Drop => batch.retain(&keep),
+            }
+        }
+        Some(batch)
+    }
+}
+
+impl<'a, P> NumberOfPoints for BoundsChecked<'a, P>
+where
+    P: NumberOfPoints,
+{
+    fn num_points(&self) -> usize {
+        self.input.num_points()
+    }
+}
+
+/// The box enclosing the transformed box.
+fn transform_aabb(transform: &Similarity3<f64>, aabb: &Aabb) -> Aabb {
+    let corners: Vec<Point3<f64>> = aabb
+        .compute_corners()
+        .iter()
+        .map(|corner| transform * corner)
+        .collect();
+    Aabb::from_points(&corners).unwrap()
+}
+
+/// Transforms the positions and normals, see `BuildOptions::input_transform`.
+struct Transformed<P> {
+    input: P,
+    transform: Option<Similarity3<f64>>,
+}
+
+impl<P> Iterator for Transformed<P>
+where
+    P: Iterator<Item = PointsBatch>,
+{
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = self.input.next()?;
+        if let Some(transform) = &self.transform {
+            for p in &mut batch.position {
+                *p = transform * *p;
+            }
+            if let Some(AttributeData::F64Vec3(normals)) = batch.attributes.get_mut("normal") {
+                for n in normals {
+                    *n = transform.isometry.rotation * *n;
+                }
+            }
+            if let Some(bounding_box) = &batch.bounding_box {
+                batch.bounding_box = Some(transform_aabb(transform, bounding_box));
+            }
+        }
+        Some(batch)
+    }
+}
+
+impl<P> NumberOfPoints for Transformed<P>
+where
+    P: NumberOfPoints,
+{
+    fn num_points(&self) -> usize {
+        self.input.num_points()
+    }
+}
+
+/// Converts the positions into Z-up, see `BuildOptions::input_axes`.
+struct AxesConverted<P> {
+    input: P,
+    axes: AxisConvention,
+}
+
+impl<P> Iterator for AxesConverted<P>
+where
+    P: Iterator<Item = PointsBatch>,
+{
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = self.input.next()?;
+        if self.axes != AxisConvention::ZUp {
+            for p in &mut batch.position {
+                *p = self.axes.to_z_up(p);
+            }
+            if let Some(bounding_box) = &batch.bounding_box {
+                batch.bounding_box = Some(self.axes.aabb_to_z_up(bounding_box));
+            }
+        }
+        Some(batch)
+    }
+}
+
+impl<P> NumberOfPoints for AxesConverted<P>
+where
+    P: NumberOfPoints,
+{
+    fn num_points(&self) -> usize {
+        self.input.num_points()
+    }
+}
+
+/// Adds the `ORIGINAL_INDEX_ATTRIBUTE` to the points if `next_index` is set, and passes them on
+/// unchanged otherwise.
+struct OriginalIndexed<P> {
+    input: P,
+    next_index: Option<u64>,
+}
+
+impl<P> Iterator for OriginalIndexed<P>
+where
+    P: Iterator<Item = PointsBatch>,
+{
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let mut batch = self.input.next()?;
+        if let Some(next_index) = &mut self.next_index {
+            let start = *next_index;
+            *next_index += batch.position.len() as u64;
+            batch.attributes.insert(
+                ORIGINAL_INDEX_ATTRIBUTE.to_string(),
+                AttributeData::U64((start..*next_index).collect()),
+            );
+        }
+        Some(batch)
+    }
+}
+
+impl<P> NumberOfPoints for OriginalIndexed<P>
+where
+    P: NumberOfPoints,
+{
+    fn num_points(&self) -> usize {
+        self.input.num_points()
+    }
+}
+
+/// Reports the progress of reading the input, which e.g. for a stream is not known in advance.
+struct ProgressReported<P> {
+    input: P,
+    progress_bar: ProgressBar<io::Stderr>,
+}
+
+impl<P> Iterator for ProgressReported<P>
+where
+    P: Iterator<Item = PointsBatch>,
+{
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        let batch = self.input.next();
+        match &batch {
+            Some(batch) => {
+                self.progress_bar.add(batch.position.len() as u64);
+            }
+            None => self.progress_bar.finish(),
+        }
+        batch
+    }
+}
+
+impl<P> NumberOfPoints for ProgressReported<P>
+where
+    P: NumberOfPoints,
+{
+    fn num_points(&self) -> usize {
+        self.input.num_points()
+    }
+}
+
 impl RawNodeWriter {
     fn from_data_provider(
         octree_data_provider: &OnDiskDataProvider,
@@ -58,6 +483,7 @@ impl RawNodeWriter {
 fn split<P>(
     octree_data_provider: &OnDiskDataProvider,
     octree_meta: &octree::OctreeMeta,
+    options: &BuildOptions,
     node_id: &octree::NodeId,
     stream: P,
 ) -> (Vec<octree::NodeId>, Vec<octree::NodeId>)
@@ -68,14 +494,18 @@ where
         vec![None, None, None, None, None, None, None, None];
     let size = stream.num_points();
     eprintln!(
-        "Splitting {} which has {} points ({:.2}x MAX_POINTS_PER_NODE).",
+        "Splitting {} which has {} points ({:.2}x max_points_per_node).",
         node_id,
         size,
-        size as f64 / MAX_POINTS_PER_NODE as f64
+        size as f64 / options.max_points_per_node as f64
     );
 
     let bounding_cube = node_id.find_bounding_cube(&Cube::bounding(&octree_meta.bounding_box));
     stream.for_each(|batch| {
+        // Only the root is split from the batches of the input.
+        if node_id.level() > 0 {
+            track_points_in_memory(octree_data_provider, batch.position.len());
+        }
         let child_indices: Vec<_> = batch
             .position
             .iter()
@@ -106,6 +536,14 @@ where
     // rewritten by subsampling the children in the second step anyways. We also ignore file
     // removing error. For example, we never write out the root, so it cannot be removed.
     RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, node_id);
+    // Subsampling will not rewrite the attributes that are only stored in the leaves.
+    let stem = octree_data_provider.stem(&node_id.to_string());
+    for attribute in &octree_meta.leaf_only_attributes {
+        let path = stem.with_extension(attribute_extension(attribute));
+        if let Err(err) = remove_file_if_exists(&path) {
+            eprintln!("Could not remove {}: {}", path.display(), err);
+        }
+    }
 
     let mut leaf_nodes = Vec::new();
     let mut split_nodes = Vec::new();
@@ -116,7 +554,7 @@ where
         let c = c.unwrap();
         let child_id = node_id.get_child_id(octree::ChildIndex::from_u8(child_index as u8));
 
-        if should_split_node(&child_id, c.num_written(), octree_meta) {
+        if should_split_node(child_id, c.num_written(), octree_meta, options) {
             split_nodes.push(child_id);
         } else {
             leaf_nodes.push(child_id);
@@ -126,41 +564,56 @@ where
 }
 
 fn should_split_node(
-    id: &octree::NodeId,
+    id: NodeId,
     num_points: i64,
     octree_meta: &octree::OctreeMeta,
+    options: &BuildOptions,
 ) -> bool {
-    if num_points <= MAX_POINTS_PER_NODE {
-        return false;
-    }
     let bounding_cube = id.find_bounding_cube(&Cube::bounding(&octree_meta.bounding_box));
-    if bounding_cube.edge_length() <= octree_meta.resolution {
-        // TODO(hrapp): If the data has billion of points in this small spot, performance will
-        // greatly suffer if we display it. Drop points?
-        eprintln!(
-            "Node {} which has {} points ({:.2}x MAX_POINTS_PER_NODE) \
-             is too small to be split, keeping all points.",
-            id,
-            num_points,
-            num_points as f64 / MAX_POINTS_PER_NODE as f64
-        );
-        return false;
-    }
-    true
+    let too = match options.split_decision(
+        id,
+        &bounding_cube,
+        octree_meta.resolution,
+        num_points as f64,
+    ) {
+        SplitDecision::Split => return true,
+        SplitDecision::Fits => return false,
+        SplitDecision::TooSmall => "small",
+        SplitDecision::TooDeep => "deep",
+    };
+    // If the data has billion of points in this small spot, performance will greatly suffer
+    // if we display it, unless the leaves are decimated.
+    eprintln!(
+        "Node {} which has {} points ({:.2}x max_points_per_node) \
+         is too {} to be split, {}.",
+        id,
+        num_points,
+        num_points as f64 / options.max_points_per_node as f64,
+        too,
+        if options.decimate_leaves {
+            "decimating it"
+        } else {
+            "keeping all points"
+        }
+    );
+    false
 }
 
+#[allow(clippy::too_many_arguments)]
 fn split_node<'a, P>(
     scope: &Scope<'a>,
     octree_data_provider: &'a OnDiskDataProvider,
     octree_meta: &'a octree::OctreeMeta,
+    options: &'a BuildOptions,
     attribute_data_types: &'a HashMap<String, AttributeDataType>,
     node_id: &octree::NodeId,
     stream: P,
-    leaf_nodes_sender: &crossbeam::channel::Sender<octree::NodeId>,
+    leaf_nodes_sender: &crossbeam::channel::Sender<Result<octree::NodeId>>,
 ) where
     P: Iterator<Item = PointsBatch> + NumberOfPoints,
 {
-    let (leaf_nodes, split_nodes) = split(octree_data_provider, octree_meta, node_id, stream);
+    let (leaf_nodes, split_nodes) =
+        split(octree_data_provider, octree_meta, options, node_id, stream);
     for child_id in split_nodes {
         let leaf_nodes_sender_clone = leaf_nodes_sender.clone();
         scope.spawn(move |scope| {
@@ -172,13 +625,15 @@ fn split_node<'a, P>(
                 octree_data_provider
                     .number_of_points(&child_id.to_string())
                     .unwrap() as usize,
-                NUM_POINTS_PER_BATCH,
+                options.batch_size(),
+                None,
             )
             .unwrap();
             split_node(
                 scope,
                 octree_data_provider,
                 octree_meta,
+                options,
                 attribute_data_types,
                 &child_id,
                 stream,
@@ -188,54 +643,395 @@ fn split_node<'a, P>(
     }
 
     for id in leaf_nodes {
-        leaf_nodes_sender.send(id).unwrap();
+        // Failures are sent along with the leaves, and end the build once the splitting is done.
+        let result = if options.decimate_leaves {
+            decimate_leaf(
+                octree_data_provider,
+                octree_meta,
+                attribute_data_types,
+                &id,
+                options.max_points_per_node as usize,
+                options.batch_size(),
+            )
+            .map(|()| id)
+        } else {
+            Ok(id)
+        };
+        leaf_nodes_sender.send(result).unwrap();
     }
 }
 
-fn subsample_children_into(
+fn spill_provider(octree_data_provider: &OnDiskDataProvider) -> OnDiskDataProvider {
+    OnDiskDataProvider {
+        directory: octree_data_provider.directory.join(SPILL_DIRECTORY),
+    }
+}
+
+/// The files of a node with the attributes, including the position.
+fn node_files<'a>(
+    data_provider: &'a OnDiskDataProvider,
+    attribute_data_types: &'a HashMap<String, AttributeDataType>,
+    id: &octree::NodeId,
+) -> impl Iterator<Item = PathBuf> + 'a {
+    let stem = data_provider.stem(&id.to_string());
+    iter::once("position")
+        .chain(attribute_data_types.keys().map(String::as_str))
+        .map(move |attribute| stem.with_extension(attribute_extension(attribute)))
+}
+
+/// Moves the files of the node into the `SPILL_DIRECTORY` and returns an iterator over their
+/// points, so that the node can be rewritten while its points are streamed. The moved files are
+/// removed with `remove_spilled_node` once the node is rewritten.
+fn spill_node(
     octree_data_provider: &OnDiskDataProvider,
     octree_meta: &octree::OctreeMeta,
     attribute_data_types: &HashMap<String, AttributeDataType>,
+    id: &octree::NodeId,
+    num_points: usize,
+    batch_size: usize,
+) -> Result<NodeIterator> {
+    let spill_provider = spill_provider(octree_data_provider);
+    for (from, to) in node_files(octree_data_provider, attribute_data_types, id).zip(node_files(
+        &spill_provider,
+        attribute_data_types,
+        id,
+    )) {
+        fs::rename(from, to)?;
+    }
+    NodeIterator::from_data_provider(
+        &spill_provider,
+        attribute_data_types,
+        octree_meta.encoding_for_node(*id),
+        id,
+        num_points,
+        batch_size,
+        None,
+    )
+}
+
+fn remove_spilled_node(
+    octree_data_provider: &OnDiskDataProvider,
+    attribute_data_types: &HashMap<String, AttributeDataType>,
+    id: &octree::NodeId,
+) -> Result<()> {
+    let spill_provider = spill_provider(octree_data_provider);
+    for path in node_files(&spill_provider, attribute_data_types, id) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Appends the batch, e.g. to sort the points of a node once they are all known.
+fn append_batch(batch: &mut Option<PointsBatch>, mut other: PointsBatch) -> Result<()> {
+    match batch {
+        Some(batch) => batch.append(&mut other)?,
+        None => *batch = Some(other),
+    }
+    Ok(())
+}
+
+/// Rewrites the leaf with evenly spaced points of it, if it has more than `max_points`.
+fn decimate_leaf(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &octree::OctreeMeta,
+    attribute_data_types: &HashMap<String, AttributeDataType>,
+    id: &octree::NodeId,
+    max_points: usize,
+    batch_size: usize,
+) -> Result<()> {
+    let num_points = octree_data_provider.number_of_points(&id.to_string())? as usize;
+    if num_points <= max_points {
+        return Ok(());
+    }
+    let node_iterator = spill_node(
+        octree_data_provider,
+        octree_meta,
+        attribute_data_types,
+        id,
+        num_points,
+        batch_size,
+    )?;
+    let mut writer = RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, id);
+    // The points at `i * num_points / max_points` are kept.
+    let mut next_kept = 0;
+    let mut offset = 0;
+    for batch in node_iterator {
+        track_points_in_memory(octree_data_provider, batch.position.len());
+        let end = offset + batch.position.len();
+        let mut indices = Vec::new();
+        while next_kept < max_points && next_kept * num_points / max_points < end {
+            indices.push(next_kept * num_points / max_points - offset);
+            next_kept += 1;
+        }
+        writer.write(&batch.select(&indices))?;
+        offset = end;
+    }
+    remove_spilled_node(octree_data_provider, attribute_data_types, id)
+}
+
+/// Rewrites the node data of the attributes with codecs encoded, see
+/// `BuildOptions::attribute_codecs`.
+fn compress_nodes(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &octree::OctreeMeta,
+    options: &BuildOptions,
+    attribute_data_types: &HashMap<String, AttributeDataType>,
+    nodes: &FnvHashMap<NodeId, i64>,
+) -> Result<()> {
+    if let Some((id, num_points)) = nodes.iter().max_by_key(|(_, num_points)| **num_points) {
+        options
+            .check_points_in_memory(*num_points as usize, || format!("Compressing node {}", id))?;
+    }
+    let root_cube = Cube::bounding(&octree_meta.bounding_box);
+    let nodes: Vec<NodeId> = nodes
+        .iter()
+        .filter(|(_, num_points)| **num_points > 0)
+        .map(|(id, _)| *id)
+        .collect();
+    nodes.par_iter().try_for_each(|id| -> Result<()> {
+        let stem = octree_data_provider.stem(&id.to_string());
+        for (attribute, codecs) in &octree_meta.attribute_codecs {
+            let (value_size, components) = if attribute == "position" {
+                let position_encoding = PositionEncoding::new(
+                    &id.find_bounding_cube(&root_cube),
+                    octree_meta.resolution,
+                );
+                (position_encoding.bytes_per_coordinate(), 3)
+            } else {
+                let data_type = attribute_data_types[attribute];
+                let components = match data_type {
+                    AttributeDataType::U8Vec3
+                    | AttributeDataType::U16Vec3
+                    | AttributeDataType::F64Vec3 => 3,
+                    _ => 1,
+                };
+                (data_type.size_of() / components, components)
+            };
+            let path = stem.with_extension(attribute_extension(attribute));
+            // Inner nodes don't store the leaf only attributes.
+            if !path.exists() {
+                continue;
+            }
+            let encoded = encode_attribute(fs::read(&path)?, codecs, value_size, components)?;
+            fs::write(&path, encoded)?;
+        }
+        Ok(())
+    })
+}
+
+/// The smallest and largest `ORIGINAL_INDEX_ATTRIBUTE` of the points of every node that stores
+/// it, see `NodeMeta::original_index_range`. This reads the written, not yet compressed, data.
+fn original_index_ranges(
+    octree_data_provider: &OnDiskDataProvider,
+    nodes: &FnvHashMap<NodeId, i64>,
+) -> Result<FnvHashMap<NodeId, (u64, u64)>> {
+    let nodes: Vec<NodeId> = nodes
+        .iter()
+        .filter(|(_, num_points)| **num_points > 0)
+        .map(|(id, _)| *id)
+        .collect();
+    let ranges = nodes
+        .par_iter()
+        .map(|id| -> Result<Option<(NodeId, (u64, u64))>> {
+            let path = octree_data_provider
+                .stem(&id.to_string())
+                .with_extension(attribute_extension(ORIGINAL_INDEX_ATTRIBUTE));
+            // Inner nodes don't store the attribute if it is leaf only.
+            if !path.exists() {
+                return Ok(None);
+            }
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut bytes = [0; 8];
+            let mut range = None;
+            loop {
+                match reader.read_exact(&mut bytes) {
+                    Ok(()) => (),
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err.into()),
+                }
+                let index = u64::from_le_bytes(bytes);
+                range = match range {
+                    Some((min, max)) => Some((cmp::min(min, index), cmp::max(max, index))),
+                    None => Some((index, index)),
+                };
+            }
+            Ok(range.map(|range| (*id, range)))
+        })
+        .collect::<Vec<_>>();
+    let mut result = FnvHashMap::default();
+    for range in ranges {
+        result.extend(range?);
+    }
+    Ok(result)
+}
+
+/// The sphere around the points of every node and of all its descendants that has any, see
+/// `NodeMeta::bounding_sphere`. This reads the written, not yet compressed, data.
+fn bounding_spheres(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &octree::OctreeMeta,
+    nodes: &FnvHashMap<NodeId, i64>,
+) -> Result<FnvHashMap<NodeId, Sphere>> {
+    let non_empty_nodes: Vec<(NodeId, i64)> = nodes
+        .iter()
+        .filter(|(_, num_points)| **num_points > 0)
+        .map(|(id, num_points)| (*id, *num_points))
+        .collect();
+    let extents = non_empty_nodes
+        .par_iter()
+        .map(|(id, num_points)| -> Result<(NodeId, Aabb)> {
+            let node_iterator = NodeIterator::from_data_provider(
+                octree_data_provider,
+                &HashMap::new(),
+                octree_meta.encoding_for_node(*id),
+                id,
+                *num_points as usize,
+                NUM_POINTS_PER_BATCH,
+                None,
+            )?;
+            Ok((*id, compute_extent(node_iterator)))
+        })
+        .collect::<Vec<_>>();
+    let mut subtree_extents = FnvHashMap::default();
+    for extent in extents {
+        let (id, extent) = extent?;
+        subtree_extents.insert(id, extent);
+    }
+    // Children come before their parents, so that their extents are complete when they are
+    // added to the parent's.
+    let mut ids: Vec<NodeId> = nodes.keys().copied().collect();
+    ids.sort_unstable_by_key(|id| cmp::Reverse(id.level()));
+    for id in ids {
+        if let (Some(extent), Some(parent_id)) = (subtree_extents.get(&id).cloned(), id.parent()) {
+            subtree_extents
+                .entry(parent_id)
+                .and_modify(|parent_extent: &mut Aabb| {
+                    parent_extent.grow(*extent.min());
+                    parent_extent.grow(*extent.max());
+                })
+                .or_insert(extent);
+        }
+    }
+    Ok(subtree_extents
+        .iter()
+        .map(|(id, extent)| (*id, Sphere::enclosing_aabb(extent)))
+        .collect())
+}
+
+fn sort_in_morton_order(batch: &mut PointsBatch, bounding_cube: &Cube) {
+    let codes: Vec<u64> = batch
+        .position
+        .iter()
+        .map(|p| bounding_cube.morton_code(p))
+        .collect();
+    let mut indices: Vec<usize> = (0..codes.len()).collect();
+    indices.sort_by_key(|i| codes[*i]);
+    *batch = batch.select(&indices);
+}
+
+/// The data types of the attributes of the leaf nodes and of the inner nodes, which don't store
+/// the leaf only attributes.
+struct NodeAttributes<'a> {
+    leaf_nodes: FnvHashSet<octree::NodeId>,
+    leaf: &'a HashMap<String, AttributeDataType>,
+    inner: HashMap<String, AttributeDataType>,
+}
+
+impl<'a> NodeAttributes<'a> {
+    fn for_node(&self, id: &octree::NodeId) -> &HashMap<String, AttributeDataType> {
+        if self.leaf_nodes.contains(id) {
+            self.leaf
+        } else {
+            &self.inner
+        }
+    }
+}
+
+fn subsample_children_into(
+    octree_data_provider: &OnDiskDataProvider,
+    octree_meta: &octree::OctreeMeta,
+    options: &BuildOptions,
+    node_attributes: &NodeAttributes,
     node_id: &octree::NodeId,
     nodes_sender: &crossbeam::channel::Sender<(octree::NodeId, i64)>,
 ) -> Result<()> {
+    let root_cube = Cube::bounding(&octree_meta.bounding_box);
     let mut parent_writer =
         RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, node_id);
+    // In Morton order, the parent's points are only written once they are all known.
+    let mut sorted_parent_batch: Option<PointsBatch> = None;
     for i in 0..8 {
         let child_id = node_id.get_child_id(octree::ChildIndex::from_u8(i));
         let num_points = match octree_data_provider.number_of_points(&child_id.to_string()) {
-            Ok(num_points) => num_points,
+            Ok(num_points) => num_points as usize,
             Err(Error(ErrorKind::NodeNotFound, _)) => continue,
             Err(err) => return Err(err),
         };
-        let mut node_iterator = NodeIterator::from_data_provider(
+        if octree_meta.points_in_morton_order {
+            let num_parent_points = sorted_parent_batch
+                .as_ref()
+                .map_or(0, |batch| batch.position.len());
+            options.check_points_in_memory(num_parent_points + num_points, || {
+                format!("Sorting node {} in Morton order", child_id)
+            })?;
+        }
+        let attribute_data_types = node_attributes.for_node(&child_id);
+        let node_iterator = spill_node(
             octree_data_provider,
+            octree_meta,
             attribute_data_types,
-            octree_meta.encoding_for_node(child_id),
             &child_id,
-            num_points as usize,
-            NUM_POINTS_PER_BATCH,
+            num_points,
+            options.batch_size(),
         )?;
 
-        // We read all points into memory, because the new node writer will rewrite this child's
-        // file(s).
-        let mut batch = node_iterator.next().unwrap();
-        node_iterator.for_each(|mut b| batch.append(&mut b).unwrap());
-        let (keep_parent, keep_child): (Vec<bool>, Vec<bool>) = (0..batch.position.len())
-            .map(|i| {
-                let in_parent = i % 8 == 0;
-                (in_parent, !in_parent)
-            })
-            .unzip();
-        let mut parent_batch = batch.clone();
-        parent_batch.retain(&keep_parent);
-        let mut child_batch = batch;
-        child_batch.retain(&keep_child);
-
         let mut child_writer =
             RawNodeWriter::from_data_provider(octree_data_provider, octree_meta, &child_id);
-        parent_writer.write(&parent_batch)?;
-        child_writer.write(&child_batch)?;
+        // In Morton order, the child's points are only written once they are all known as well.
+        let mut sorted_child_batch: Option<PointsBatch> = None;
+        let mut offset = 0;
+        for batch in node_iterator {
+            let num_sorted = |batch: &Option<PointsBatch>| {
+                batch.as_ref().map_or(0, |batch| batch.position.len())
+            };
+            track_points_in_memory(
+                octree_data_provider,
+                batch.position.len()
+                    + num_sorted(&sorted_parent_batch)
+                    + num_sorted(&sorted_child_batch),
+            );
+            // Every 8th point of the child moves into the parent.
+            let (keep_parent, keep_child): (Vec<bool>, Vec<bool>) = (offset
+                ..offset + batch.position.len())
+                .map(|i| {
+                    let in_parent = i % 8 == 0;
+                    (in_parent, !in_parent)
+                })
+                .unzip();
+            offset += batch.position.len();
+            let mut parent_batch = batch.clone();
+            parent_batch.retain(&keep_parent);
+            for attribute in &octree_meta.leaf_only_attributes {
+                parent_batch.attributes.remove(attribute);
+            }
+            let mut child_batch = batch;
+            child_batch.retain(&keep_child);
+            if octree_meta.points_in_morton_order {
+                append_batch(&mut sorted_parent_batch, parent_batch)?;
+                append_batch(&mut sorted_child_batch, child_batch)?;
+            } else {
+                parent_writer.write(&parent_batch)?;
+                child_writer.write(&child_batch)?;
+            }
+        }
+        if let Some(mut sorted_child_batch) = sorted_child_batch {
+            sort_in_morton_order(
+                &mut sorted_child_batch,
+                &child_id.find_bounding_cube(&root_cube),
+            );
+            child_writer.write(&sorted_child_batch)?;
+        }
+        remove_spilled_node(octree_data_provider, attribute_data_types, &child_id)?;
 
         // Update child.
         nodes_sender
@@ -243,6 +1039,14 @@ fn subsample_children_into(
             .unwrap();
     }
 
+    if let Some(mut sorted_parent_batch) = sorted_parent_batch {
+        sort_in_morton_order(
+            &mut sorted_parent_batch,
+            &node_id.find_bounding_cube(&root_cube),
+        );
+        parent_writer.write(&sorted_parent_batch)?;
+    }
+
     // Make sure the root node is also tracked as an existing node.
     if node_id.level() == 0 {
         nodes_sender
@@ -253,7 +1057,7 @@ fn subsample_children_into(
 }
 
 /// Returns the bounding box containing all points
-fn find_bounding_box(filename: impl AsRef<Path>) -> Aabb {
+pub(super) fn find_bounding_box(filename: impl AsRef<Path>) -> Aabb {
     let mut bounding_box = None;
     let stream = PlyIterator::from_file(filename, NUM_POINTS_PER_BATCH).unwrap();
     let mut progress_bar = create_progress_bar(stream.num_points(), "Determining bounding box");
@@ -269,21 +1073,123 @@ fn find_bounding_box(filename: impl AsRef<Path>) -> Aabb {
     bounding_box.unwrap_or_else(Aabb::zero)
 }
 
+/// Returns the tight bounding box of the points of a stream, e.g. to find the extents of several
+/// tiles before deciding on a common root with `recommend_root`. The batches are consumed one at a
+/// time. An empty stream has a zero bounding box, like `find_bounding_box`.
+pub fn compute_extent(points: impl IntoIterator<Item = PointsBatch>) -> Aabb {
+    points
+        .into_iter()
+        .filter_map(|batch| Aabb::from_points(&batch.position))
+        .fold(None, |extent: Option<Aabb>, batch_extent| match extent {
+            Some(mut extent) => {
+                extent.grow(*batch_extent.min());
+                extent.grow(*batch_extent.max());
+                Some(extent)
+            }
+            None => Some(batch_extent),
+        })
+        .unwrap_or_else(Aabb::zero)
+}
+
+/// The bounding box to build the points of `extent` with, so that octrees of different extents
+/// built with the same grid have aligned nodes, see `Cube::aligned_to_grid`. The root is a cube of
+/// `cell_size` times a power of two, offset from `grid_origin` by a multiple of its edge length.
+/// No cell of the grid reaches across its origin, so this fails for an extent that does, and the
+/// origin is best chosen below all extents. This also fails for coordinates that are not finite
+/// and for a cell size that is not positive and finite.
+pub fn recommend_root(extent: &Aabb, grid_origin: &Point3<f64>, cell_size: f64) -> Result<Aabb> {
+    let coords = extent.min().iter().chain(extent.max().iter());
+    if !coords
+        .chain(grid_origin.iter())
+        .all(|coord| coord.is_finite())
+    {
+        return Err(ErrorKind::InvalidInput(format!(
+            "The extent {:?} and the grid origin {:?} must be finite.",
+            extent, grid_origin
+        ))
+        .into());
+    }
+    if !(cell_size > 0.0 && cell_size.is_finite()) {
+        return Err(ErrorKind::InvalidInput(format!(
+            "The cell size must be positive and finite, found {}.",
+            cell_size
+        ))
+        .into());
+    }
+    if (0..3).any(|i| extent.min()[i] < grid_origin[i] && grid_origin[i] < extent.max()[i]) {
+        return Err(ErrorKind::InvalidInput(format!(
+            "The extent {:?} reaches across the grid origin {:?}.",
+            extent, grid_origin
+        ))
+        .into());
+    }
+    Ok(Cube::aligned_to_grid(extent, grid_origin, cell_size).to_aabb())
+}
+
 pub fn build_octree_from_file(
     output_directory: impl AsRef<Path>,
     resolution: f64,
     filename: impl AsRef<Path>,
     attributes: &[&str],
 ) {
+    build_octree_from_file_with_options(
+        output_directory,
+        resolution,
+        filename,
+        attributes,
+        &BuildOptions::default(),
+    )
+    .expect("Could not build octree.")
+}
+
+/// Like `build_octree_from_file`, with the bounding box of the points in the file.
+pub fn build_octree_from_file_with_options(
+    output_directory: impl AsRef<Path>,
+    resolution: f64,
+    filename: impl AsRef<Path>,
+    attributes: &[&str],
+    options: &BuildOptions,
+) -> Result<()> {
     let bounding_box = find_bounding_box(filename.as_ref());
-    let stream = PlyIterator::from_file(filename, NUM_POINTS_PER_BATCH).unwrap();
-    build_octree(
+    let mut stream = PlyIterator::from_file(filename, NUM_POINTS_PER_BATCH)?;
+    build_octree_with_options(
         output_directory,
         resolution,
         bounding_box,
-        stream,
+        &mut stream,
         attributes,
-    )
+        options,
+    )?;
+    stream.into_result()
+}
+
+/// Builds an octree from a binary little-endian PLY stream, e.g. stdin, without intermediate
+/// files. Since the stream can only be read once, the bounding box has to be given; points outside
+/// of it are handled according to `options.out_of_bounds`. The stream is read as fast as the
+/// points can be sorted into the octree.
+pub fn build_octree_from_reader(
+    output_directory: impl AsRef<Path>,
+    resolution: f64,
+    bounding_box: Aabb,
+    input: impl Read + Send,
+    attributes: &[&str],
+    options: &BuildOptions,
+) -> Result<()> {
+    let mut stream = PlyIterator::from_reader(input, NUM_POINTS_PER_BATCH)?;
+    let progress_bar = create_progress_bar(stream.num_points(), "Reading input");
+    let input = ProgressReported {
+        input: &mut stream,
+        progress_bar,
+    };
+    build_octree_with_options(
+        output_directory,
+        resolution,
+        bounding_box,
+        input,
+        attributes,
+        options,
+    )?;
+    stream.into_result()
 }
 
 pub fn build_octree(
@@ -293,21 +1199,151 @@ pub fn build_octree(
     input: impl Iterator<Item = PointsBatch> + NumberOfPoints + Send,
     attributes: &[&str],
 ) {
+    build_octree_with_options(
+        output_directory,
+        resolution,
+        bounding_box,
+        input,
+        attributes,
+        &BuildOptions::default(),
+    )
+    .expect("Could not build octree.")
+}
+
+pub fn build_octree_with_options(
+    output_directory: impl AsRef<Path>,
+    resolution: f64,
+    bounding_box: Aabb,
+    input: impl Iterator<Item = PointsBatch> + NumberOfPoints + Send,
+    attributes: &[&str],
+    options: &BuildOptions,
+) -> Result<()> {
     attempt_increasing_rlimit_to_max();
 
-    let octree_meta =
-        &octree::OctreeMeta::new_with_standard_attributes(resolution, bounding_box.clone());
-    let attribute_data_types = &octree_meta.attribute_data_types_for(attributes).unwrap();
+    let bounding_box = options.octree_bounding_box(&bounding_box);
+    let mut octree_meta =
+        octree::OctreeMeta::new_with_standard_attributes(resolution, bounding_box.clone());
+    octree_meta.coordinate_system = options.coordinate_system.clone();
+    octree_meta.points_in_morton_order = options.morton_order;
+    octree_meta.leaves_decimated = options.decimate_leaves;
+    if options.max_depth == Some(0) {
+        return Err(ErrorKind::InvalidInput(
+            "The maximum depth must be at least 1, since the root is always split.".to_string(),
+        )
+        .into());
+    }
+    octree_meta.max_depth = options.max_depth;
+    octree_meta.input_axes = options.input_axes;
+    if let Some(name) = options
+        .attribute_annotations
+        .keys()
+        .find(|name| !attributes.contains(&name.as_str()))
+    {
+        return Err(ErrorKind::InvalidInput(format!(
+            "Attribute '{}' is annotated, but not built.",
+            name
+        ))
+        .into());
+    }
+    octree_meta.attribute_annotations = options.attribute_annotations.clone();
+    if let Some(name) = options
+        .leaf_only_attributes
+        .iter()
+        .find(|name| *name == "color" || !attributes.contains(&name.as_str()))
+    {
+        return Err(ErrorKind::InvalidInput(format!(
+            "Attribute '{}' can not be stored only in the leaves.",
+            name
+        ))
+        .into());
+    }
+    octree_meta.leaf_only_attributes = options.leaf_only_attributes.clone();
+    octree_meta.leaf_only_attributes.sort_unstable();
+    octree_meta.leaf_only_attributes.dedup();
+    octree_meta.add_optional_attributes(attributes);
+    if let Some(name) = options
+        .attribute_data_types
+        .keys()
+        .find(|name| !attributes.contains(&name.as_str()))
+    {
+        return Err(ErrorKind::InvalidInput(format!(
+            "Attribute '{}' has a data type, but is not built.",
+            name
+        ))
+        .into());
+    }
+    octree_meta
+        .attribute_data_types
+        .extend(options.attribute_data_types.clone());
+    if let Some(name) = options.attribute_codecs.keys().find(|name| {
+        *name != "position"
+            && !attributes.contains(&name.as_str())
+            && !(options.original_index && *name == ORIGINAL_INDEX_ATTRIBUTE)
+    }) {
+        return Err(ErrorKind::InvalidInput(format!(
+            "Attribute '{}' has codecs, but is not built.",
+            name
+        ))
+        .into());
+    }
+    octree_meta.attribute_codecs = options.attribute_codecs.clone();
+    let mut attributes = attributes.to_vec();
+    if options.original_index {
+        if attributes.contains(&ORIGINAL_INDEX_ATTRIBUTE) {
+            return Err(ErrorKind::InvalidInput(format!(
+                "Attribute '{}' is reserved for the original index.",
+                ORIGINAL_INDEX_ATTRIBUTE
+            ))
+            .into());
+        }
+        octree_meta
+            .attribute_data_types
+            .insert(ORIGINAL_INDEX_ATTRIBUTE.to_string(), AttributeDataType::U64);
+        attributes.push(ORIGINAL_INDEX_ATTRIBUTE);
+    }
+    let octree_meta = &octree_meta;
+    let attribute_data_types = &octree_meta.attribute_data_types_for(&attributes)?;
     let octree_data_provider = OnDiskDataProvider {
         directory: output_directory.as_ref().to_path_buf(),
     };
     let octree_data_provider = &octree_data_provider;
 
+    if options.max_points_in_memory == Some(0) {
+        return Err(ErrorKind::InvalidInput(
+            "At least one point must be allowed in memory.".to_string(),
+        )
+        .into());
+    }
+
     // Ignore errors, maybe directory is already there.
     let _ = fs::create_dir(output_directory.as_ref());
+    let _ = fs::create_dir(spill_provider(octree_data_provider).directory);
 
     eprintln!("Creating octree structure.");
 
+    let num_outside = AtomicUsize::new(0);
+    let input = Transformed {
+        input,
+        transform: options.input_transform,
+    };
+    let input = AxesConverted {
+        input,
+        axes: options.input_axes,
+    };
+    let input = OriginalIndexed {
+        input,
+        next_index: if options.original_index {
+            Some(0)
+        } else {
+            None
+        },
+    };
+    let input = BoundsChecked {
+        input,
+        bounding_box: &octree_meta.bounding_box,
+        policy: options.out_of_bounds,
+        num_outside: &num_outside,
+    };
     let (leaf_nodes_sender, leaf_nodes_receiver) = crossbeam::channel::unbounded();
     rayon::scope(move |scope| {
         let root_node = octree::Node::root_with_bounding_cube(Cube::bounding(&bounding_box));
@@ -315,20 +1351,47 @@ pub fn build_octree(
             scope,
             octree_data_provider,
             octree_meta,
+            options,
             attribute_data_types,
             &root_node.id,
             input,
             &leaf_nodes_sender,
         );
     });
+    let num_outside = num_outside.load(Ordering::Relaxed);
+    if num_outside > 0 {
+        match options.out_of_bounds {
+            OutOfBounds::Error => {
+                return Err(ErrorKind::InvalidInput(format!(
+                    "The input contains points outside of the bounding box {:?}.",
+                    octree_meta.bounding_box
+                ))
+                .into())
+            }
+            OutOfBounds::Drop => eprintln!(
+                "Dropped {} points outside of the bounding box.",
+                num_outside
+            ),
+        }
+    }
 
     let mut nodes_to_subsample = Vec::new();
     let mut deepest_level = 0u8;
     for id in leaf_nodes_receiver {
+        let id = id?;
         deepest_level = cmp::max(deepest_level, id.level());
         nodes_to_subsample.push(id);
     }
     let mut finished_nodes = FnvHashMap::default();
+    let mut inner_attribute_data_types = attribute_data_types.clone();
+    for attribute in &octree_meta.leaf_only_attributes {
+        inner_attribute_data_types.remove(attribute);
+    }
+    let node_attributes = NodeAttributes {
+        leaf_nodes: nodes_to_subsample.iter().copied().collect(),
+        leaf: attribute_data_types,
+        inner: inner_attribute_data_types,
+    };
 
     // sub sampling returns the list of finished nodes including all meta data
     // We start on the deepest level and work our way up the tree.
@@ -352,7 +1415,7 @@ pub fn build_octree(
 
         let (finished_nodes_sender, finished_nodes_receiver) = crossbeam::channel::unbounded();
         let (progress_tx, progress_rx) = crossbeam::channel::unbounded();
-        rayon::scope(|scope| {
+        let result = rayon::scope(|scope| {
             scope.spawn(|_| {
                 for (id, num_points) in finished_nodes_receiver {
                     finished_nodes.insert(id, num_points);
@@ -365,39 +1428,81 @@ pub fn build_octree(
                 }
             });
 
-            parent_ids.par_iter().for_each(|id| {
+            let result = parent_ids.par_iter().try_for_each(|id| -> Result<()> {
                 subsample_children_into(
                     octree_data_provider,
                     octree_meta,
-                    attribute_data_types,
+                    options,
+                    &node_attributes,
                     id,
                     &finished_nodes_sender,
-                )
-                .unwrap();
+                )?;
                 progress_tx.send(()).unwrap();
+                Ok(())
             });
             drop(finished_nodes_sender);
             drop(progress_tx);
+            result
         });
         progress_bar.finish();
+        result?;
 
         // The nodes that were just now created through sub-sampling will be required to create
         // their parents.
         nodes_to_subsample.extend(parent_ids.into_iter());
     }
 
+    let original_index_ranges = if options.original_index {
+        original_index_ranges(octree_data_provider, &finished_nodes)?
+    } else {
+        FnvHashMap::default()
+    };
+    let bounding_spheres = bounding_spheres(octree_data_provider, octree_meta, &finished_nodes)?;
+    if !octree_meta.attribute_codecs.is_empty() {
+        compress_nodes(
+            octree_data_provider,
+            octree_meta,
+            options,
+            attribute_data_types,
+            &finished_nodes,
+        )?;
+    }
+    fs::remove_dir_all(spill_provider(octree_data_provider).directory)?;
+
     // Add all non-zero node meta data to meta file
     let nodes: Vec<proto::OctreeNode> = finished_nodes
         .iter()
         .map(|(id, num_points)| {
             let bounding_cube = id.find_bounding_cube(&Cube::bounding(&octree_meta.bounding_box));
             let position_encoding = PositionEncoding::new(&bounding_cube, octree_meta.resolution);
-            to_node_proto(&id, *num_points, &position_encoding)
+            let bounding_sphere = bounding_spheres
+                .get(id)
+                .copied()
+                .unwrap_or_else(|| Sphere::enclosing_cube(&bounding_cube));
+            to_node_proto(
+                &id,
+                *num_points,
+                &position_encoding,
+                original_index_ranges.get(id).copied(),
+                &bounding_sphere,
+            )
         })
         .collect();
     let meta = to_meta_proto(&octree_meta, nodes);
 
-    let mut buf_writer =
-        BufWriter::new(File::create(&output_directory.as_ref().join(META_FILENAME)).unwrap());
-    meta.write_to_writer(&mut buf_writer).unwrap();
+    let meta_path = output_directory.as_ref().join(META_FILENAME);
+    let mut buf_writer = BufWriter::new(
+        File::create(&meta_path)
+            .chain_err(|| format!("Could not create {}.", meta_path.display()))?,
+    );
+    meta.write_to_writer(&mut buf_writer)
+        .chain_err(|| "Could not write meta data.")?;
+    buf_writer
+        .flush()
+        .chain_err(|| "Could not write meta data.")?;
+    if options.json_summary {
+        OctreeSummary::new(octree_meta, finished_nodes.into_iter())
+            .write_to_file(output_directory.as_ref().join(SUMMARY_FILENAME))?;
+    }
+    Ok(())
 }
@@ -0,0 +1,87 @@
+use crate::errors::*;
+use crate::geometry::Cube;
+use crate::iterator::PointCloud;
+use crate::octree::{NodeId, Octree};
+use crate::AttributeData;
+use std::io::Write;
+
+fn format_value(data: &AttributeData, idx: usize) -> String {
+    macro_rules! rhs {
+        ($dtype:ident, $data:ident, $idx:expr) => {
+            $data[$idx].to_string()
+        };
+    }
+    match data {
+        AttributeData::U8Vec3(d) => format!("({}, {}, {})", d[idx].x, d[idx].y, d[idx].z),
+        AttributeData::U16Vec3(d) => format!("({}, {}, {})", d[idx].x, d[idx].y, d[idx].z),
+        AttributeData::F64Vec3(d) => format!("({}, {}, {})", d[idx].x, d[idx].y, d[idx].z),
+        _ => match_1d_attr_data!(data, rhs, idx),
+    }
+}
+
+/// Writes a human readable description of the node to `out`: its bounding cube, its number of
+/// points, the attributes and up to `num_samples` of its points, e.g. to debug a single node
+/// without writing a query. Fails with `ErrorKind::NodeNotFound` if the octree has no such node.
+pub fn inspect_node(
+    octree: &Octree,
+    node_id: NodeId,
+    num_samples: usize,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let num_points = octree
+        .node_point_count(node_id)
+        .ok_or(ErrorKind::NodeNotFound)?;
+    let bounding_cube = node_id.find_bounding_cube(&Cube::bounding(&octree.meta.bounding_box));
+    let min = bounding_cube.min();
+    writeln!(out, "Node: {}", node_id)?;
+    writeln!(
+        out,
+        "Bounding cube: min ({}, {}, {}), edge length {}",
+        min.x,
+        min.y,
+        min.z,
+        bounding_cube.edge_length()
+    )?;
+    writeln!(out, "Number of points: {}", num_points)?;
+
+    // Octrees don't need to store all attributes, e.g. intensity, and empty nodes have no data.
+    let id_str = node_id.to_string();
+    let mut stored = Vec::new();
+    writeln!(out, "Attributes:")?;
+    for attribute in &octree.attributes() {
+        write!(out, "  {}: {:?}", attribute.name, attribute.data_type)?;
+        if num_points > 0 {
+            match octree.data_provider.data(&id_str, &[&attribute.name]) {
+                Ok(_) => stored.push(attribute.name.clone()),
+                Err(Error(ErrorKind::NodeNotFound, _)) => write!(out, ", not stored")?,
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(unit) = &attribute.annotation.unit {
+            write!(out, ", unit: {}", unit)?;
+        }
+        if let Some(semantic) = &attribute.annotation.semantic {
+            write!(out, ", semantic: {}", semantic)?;
+        }
+        writeln!(out)?;
+    }
+
+    let num_samples = num_samples.min(num_points as usize);
+    if num_samples == 0 {
+        return Ok(());
+    }
+    let attribute_names: Vec<&str> = stored.iter().map(String::as_str).collect();
+    let batch = octree
+        .points_in_node(&attribute_names, node_id, num_samples, None)?
+        .next()
+        .ok_or_else(|| ErrorKind::Decode(format!("Node {} has no point data.", node_id)))?;
+    writeln!(out, "Sample points:")?;
+    for (i, p) in batch.position.iter().enumerate() {
+        write!(out, "  ({}, {}, {})", p.x, p.y, p.z)?;
+        for (name, data) in &batch.attributes {
+            write!(out, " {}: {}", name, format_value(data, i))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
@@ -11,25 +11,53 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use crate::data_provider::DataProvider;
+use crate::attributes::{AttributeAnnotation, AttributeDescription};
+use crate::color::COLOR16_ATTRIBUTE;
+use crate::data_provider::{CancellationToken, DataProvider, DecodingDataProvider};
 use crate::errors::*;
-use crate::geometry::{Aabb, Cube, Frustum};
-use crate::iterator::{PointCloud, PointLocation};
+use crate::geometry::{Aabb, Cube, Frustum, Sphere};
+use crate::iterator::{Complement, Difference, PointCloud, PointLocation};
 use crate::math::base::{HasAabbIntersector, IntersectAabb};
 use crate::math::sat::{ConvexPolyhedron, Relation};
 use crate::math::AllPoints;
 use crate::proto;
-use crate::read_write::{Encoding, NodeIterator, PositionEncoding};
+use crate::read_write::{AttributeCodec, Encoding, NodeIterator, PositionEncoding};
 use crate::{AttributeDataType, PointCloudMeta, CURRENT_VERSION};
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHasher};
 use nalgebra::{Matrix4, Point3};
 use num::clamp;
+use protobuf::Message;
+use rayon::prelude::*;
 use std::cmp::Ordering;
 use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hasher;
 use std::io::{BufReader, Read};
+use std::sync::Arc;
+
+mod coordinate_system;
+pub use self::coordinate_system::{AxisConvention, CoordinateSystem, ECEF_EPSG_CODE};
+
+mod diff;
+pub use self::diff::diff_octrees;
+
+mod estimate;
+pub use self::estimate::{estimate_octree, estimate_octree_from_file, BuildEstimate};
 
 mod generation;
-pub use self::generation::{build_octree, build_octree_from_file};
+pub use self::generation::{
+    build_octree, build_octree_from_file, build_octree_from_file_with_options,
+    build_octree_from_reader, build_octree_with_options, compute_extent, recommend_root,
+    BuildOptions, OutOfBounds, ORIGINAL_INDEX_ATTRIBUTE,
+};
+
+mod partition;
+pub use self::partition::OctreePartitioner;
+
+mod reencode;
+pub use self::reencode::reencode_attribute;
+
+mod inspect;
+pub use self::inspect::inspect_node;
 
 mod node;
 pub use self::node::{to_node_proto, ChildIndex, Node, NodeId, NodeMeta};
@@ -37,6 +65,15 @@ pub use self::node::{to_node_proto, ChildIndex, Node, NodeId, NodeMeta};
 mod octree_iterator;
 pub use self::octree_iterator::NodeIdsIterator;
 
+mod pick;
+pub use self::pick::Pick;
+
+mod summary;
+pub use self::summary::{LevelSummary, OctreeSummary, SUMMARY_FILENAME};
+
+mod validation;
+pub use self::validation::QueryValidationError;
+
 #[cfg(test)]
 mod tests;
 
@@ -44,6 +81,22 @@ mod tests;
 pub struct OctreeMeta {
     pub resolution: f64,
     pub bounding_box: Aabb,
+    pub coordinate_system: Option<CoordinateSystem>,
+    /// Whether the points of every node are sorted by `Cube::morton_code` of its bounding cube.
+    pub points_in_morton_order: bool,
+    /// Whether leaves were thinned out to the node capacity, see `BuildOptions::decimate_leaves`.
+    pub leaves_decimated: bool,
+    /// By attribute name. Attributes without annotations are left out.
+    pub attribute_annotations: HashMap<String, AttributeAnnotation>,
+    /// Sorted by name, see `BuildOptions::leaf_only_attributes`.
+    pub leaf_only_attributes: Vec<String>,
+    /// The level below which nodes were not split, see `BuildOptions::max_depth`.
+    pub max_depth: Option<u8>,
+    /// The convention of the input the positions were converted from, see
+    /// `BuildOptions::input_axes`. The positions themselves are always Z-up.
+    pub input_axes: AxisConvention,
+    /// By attribute name, see `BuildOptions::attribute_codecs`.
+    pub attribute_codecs: HashMap<String, Vec<AttributeCodec>>,
     attribute_data_types: HashMap<String, AttributeDataType>,
 }
 
@@ -69,10 +122,56 @@ impl OctreeMeta {
         Self {
             resolution,
             bounding_box,
+            coordinate_system: None,
+            points_in_morton_order: false,
+            leaves_decimated: false,
+            attribute_annotations: HashMap::new(),
+            leaf_only_attributes: Vec::new(),
+            max_depth: None,
+            input_axes: AxisConvention::ZUp,
+            attribute_codecs: HashMap::new(),
             attribute_data_types,
         }
     }
 
+    /// Adds the data types of the attributes that are not implied, but can be built.
+    pub(super) fn add_optional_attributes(&mut self, attributes: &[&str]) {
+        if attributes.contains(&COLOR16_ATTRIBUTE) {
+            self.attribute_data_types
+                .insert(COLOR16_ATTRIBUTE.to_string(), AttributeDataType::U16Vec3);
+        }
+    }
+
+    fn attribute_descriptions(&self) -> Vec<AttributeDescription> {
+        let mut attributes: Vec<AttributeDescription> = self
+            .attribute_data_types
+            .iter()
+            .map(|(name, data_type)| AttributeDescription {
+                name: name.clone(),
+                data_type: *data_type,
+                annotation: self
+                    .attribute_annotations
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_default(),
+            })
+            .collect();
+        attributes.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        attributes
+    }
+
+    /// The data type the attribute is stored with unless it was re-encoded, see
+    /// `reencode_attribute`.
+    fn implied_data_type(attribute: &str) -> Option<AttributeDataType> {
+        match attribute {
+            "color" => Some(AttributeDataType::U8Vec3),
+            "intensity" => Some(AttributeDataType::F32),
+            ORIGINAL_INDEX_ATTRIBUTE => Some(AttributeDataType::U64),
+            COLOR16_ATTRIBUTE => Some(AttributeDataType::U16Vec3),
+            _ => None,
+        }
+    }
+
     pub fn encoding_for_node(&self, id: NodeId) -> Encoding {
         let bounding_cube = id.find_bounding_cube(&Cube::bounding(&self.bounding_box));
         let position_encoding = PositionEncoding::new(&bounding_cube, self.resolution);
@@ -87,6 +186,51 @@ impl OctreeMeta {
 pub fn to_meta_proto(octree_meta: &OctreeMeta, nodes: Vec<proto::OctreeNode>) -> proto::Meta {
     let mut octree_proto = proto::OctreeMeta::new();
     octree_proto.set_resolution(octree_meta.resolution);
+    if let Some(coordinate_system) = &octree_meta.coordinate_system {
+        octree_proto.set_coordinate_system(coordinate_system.to_proto());
+    }
+    octree_proto.set_points_in_morton_order(octree_meta.points_in_morton_order);
+    octree_proto.set_leaves_decimated(octree_meta.leaves_decimated);
+    octree_proto.set_max_depth(octree_meta.max_depth.map_or(0, u32::from));
+    octree_proto.set_converted_from_y_up(octree_meta.input_axes == AxisConvention::YUp);
+    octree_proto.set_has_original_index(
+        octree_meta
+            .attribute_data_types
+            .contains_key(ORIGINAL_INDEX_ATTRIBUTE),
+    );
+    octree_proto.set_has_color16(
+        octree_meta
+            .attribute_data_types
+            .contains_key(COLOR16_ATTRIBUTE),
+    );
+    octree_proto.set_leaf_only_attributes(octree_meta.leaf_only_attributes.clone().into());
+    let mut reencoded_attributes: Vec<_> = octree_meta
+        .attribute_data_types
+        .iter()
+        .filter(|(name, data_type)| OctreeMeta::implied_data_type(name) != Some(**data_type))
+        .collect();
+    reencoded_attributes.sort_unstable_by_key(|(name, _)| *name);
+    for (name, data_type) in reencoded_attributes {
+        let mut attribute = proto::Attribute::new();
+        attribute.set_name(name.clone());
+        attribute.set_data_type(data_type.to_proto());
+        octree_proto.mut_attribute_data_types().push(attribute);
+    }
+    let mut compressed_attributes: Vec<_> = octree_meta.attribute_codecs.iter().collect();
+    compressed_attributes.sort_unstable_by_key(|(name, _)| *name);
+    for (name, codecs) in compressed_attributes {
+        let mut attribute_codecs = proto::AttributeCodecs::new();
+        attribute_codecs.set_name(name.clone());
+        attribute_codecs.set_codecs(codecs.iter().map(|codec| codec.to_proto()).collect());
+        octree_proto.mut_attribute_codecs().push(attribute_codecs);
+    }
+    let mut annotated_attributes: Vec<_> = octree_meta.attribute_annotations.iter().collect();
+    annotated_attributes.sort_unstable_by_key(|(name, _)| *name);
+    for (name, annotation) in annotated_attributes {
+        octree_proto
+            .mut_attribute_annotations()
+            .push(annotation.to_proto(name));
+    }
 
     let octree_nodes = ::protobuf::RepeatedField::<proto::OctreeNode>::from_vec(nodes);
     octree_proto.set_nodes(octree_nodes);
@@ -138,10 +282,25 @@ fn relative_size_on_screen(bounding_cube: &Cube, matrix: &Matrix4<f64>) -> f64 {
     diag.x * diag.y
 }
 
+/// An opened octree. Clones are cheap, since they share the meta data and the data provider, so
+/// that an octree that was opened once can be handed to many threads, e.g. the request handlers
+/// of a server. Queries only read from it, so any number of them can run concurrently.
+#[derive(Clone)]
 pub struct Octree {
-    data_provider: Box<dyn DataProvider>,
-    meta: OctreeMeta,
-    nodes: FnvHashMap<NodeId, NodeMeta>,
+    data_provider: Arc<dyn DataProvider>,
+    meta: Arc<OctreeMeta>,
+    nodes: Arc<FnvHashMap<NodeId, NodeMeta>>,
+}
+
+/// What the meta data tells about a node, without reading its points, see `Octree::iter_nodes`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub level: u8,
+    pub bounding_box: Aabb,
+    pub num_points: u64,
+    /// Bit i is set if the child with `ChildIndex` i exists.
+    pub child_mask: u8,
 }
 
 #[derive(Debug)]
@@ -184,11 +343,48 @@ impl Octree {
                 } else {
                     meta_proto.get_bounding_box()
                 });
-                (
-                    bounding_box.clone(),
-                    OctreeMeta::new_with_standard_attributes(octree_meta.resolution, bounding_box),
-                    octree_meta.get_nodes(),
-                )
+                let mut meta =
+                    OctreeMeta::new_with_standard_attributes(octree_meta.resolution, bounding_box);
+                meta.coordinate_system =
+                    CoordinateSystem::from_proto(octree_meta.get_coordinate_system())?;
+                meta.points_in_morton_order = octree_meta.points_in_morton_order;
+                meta.leaves_decimated = octree_meta.leaves_decimated;
+                if octree_meta.max_depth > 0 {
+                    meta.max_depth = Some(octree_meta.max_depth as u8);
+                }
+                if octree_meta.converted_from_y_up {
+                    meta.input_axes = AxisConvention::YUp;
+                }
+                if octree_meta.has_original_index {
+                    meta.attribute_data_types
+                        .insert(ORIGINAL_INDEX_ATTRIBUTE.to_string(), AttributeDataType::U64);
+                }
+                if octree_meta.has_color16 {
+                    meta.attribute_data_types
+                        .insert(COLOR16_ATTRIBUTE.to_string(), AttributeDataType::U16Vec3);
+                }
+                meta.leaf_only_attributes = octree_meta.get_leaf_only_attributes().to_vec();
+                for attribute in octree_meta.get_attribute_data_types() {
+                    meta.attribute_data_types.insert(
+                        attribute.name.clone(),
+                        AttributeDataType::from_proto(attribute.data_type)?,
+                    );
+                }
+                for attribute_codecs in octree_meta.get_attribute_codecs() {
+                    let codecs = attribute_codecs
+                        .get_codecs()
+                        .iter()
+                        .map(|codec| AttributeCodec::from_proto(*codec))
+                        .collect::<Result<_>>()?;
+                    meta.attribute_codecs
+                        .insert(attribute_codecs.name.clone(), codecs);
+                }
+                meta.attribute_annotations = octree_meta
+                    .get_attribute_annotations()
+                    .iter()
+                    .map(AttributeAnnotation::from_proto)
+                    .collect();
+                (meta.bounding_box.clone(), meta, octree_meta.get_nodes())
             }
             _ => return Err(ErrorKind::InvalidVersion(meta_proto.version).into()),
         };
@@ -197,20 +393,43 @@ impl Octree {
 
         for node_proto in nodes_proto.iter() {
             let node_id = NodeId::from_proto(node_proto.id.as_ref().unwrap());
+            let bounding_cube = node_id.find_bounding_cube(&Cube::bounding(&bounding_box));
+            let bounding_sphere = match node_proto.bounding_sphere.as_ref() {
+                Some(bounding_sphere) => Sphere::from(bounding_sphere),
+                None => Sphere::enclosing_cube(&bounding_cube),
+            };
             nodes.insert(
                 node_id,
                 NodeMeta {
                     num_points: node_proto.num_points,
                     position_encoding: PositionEncoding::from_proto(node_proto.position_encoding)?,
-                    bounding_cube: node_id.find_bounding_cube(&Cube::bounding(&bounding_box)),
+                    bounding_cube,
+                    bounding_sphere,
+                    original_index_range: node_proto
+                        .original_index_range
+                        .as_ref()
+                        .map(|range| (range.min, range.max)),
                 },
             );
         }
 
+        let data_provider: Box<dyn DataProvider> = if meta.attribute_codecs.is_empty() {
+            data_provider
+        } else {
+            let num_points = nodes
+                .iter()
+                .map(|(id, node)| (id.to_string(), node.num_points as usize))
+                .collect();
+            Box::new(DecodingDataProvider::new(
+                data_provider,
+                meta.attribute_codecs.clone(),
+                num_points,
+            ))
+        };
         Ok(Octree {
-            meta,
-            nodes,
-            data_provider,
+            meta: Arc::new(meta),
+            nodes: Arc::new(nodes),
+            data_provider: Arc::from(data_provider),
         })
     }
 
@@ -219,12 +438,94 @@ impl Octree {
             .nodes
             .iter()
             .map(|(id, node_meta)| {
-                to_node_proto(&id, node_meta.num_points, &node_meta.position_encoding)
+                to_node_proto(
+                    &id,
+                    node_meta.num_points,
+                    &node_meta.position_encoding,
+                    node_meta.original_index_range,
+                    &node_meta.bounding_sphere,
+                )
             })
             .collect();
         to_meta_proto(&self.meta, nodes)
     }
 
+    /// The approximate number of bytes the octree keeps in memory, i.e. its meta data and the
+    /// caches of its data provider, but not the node data that is read on demand.
+    pub fn approx_memory_bytes(&self) -> usize {
+        // The hash map stores a control byte per bucket.
+        let nodes_bytes = self.nodes.capacity()
+            * (std::mem::size_of::<NodeId>() + std::mem::size_of::<NodeMeta>() + 1);
+        let attributes_bytes: usize = self
+            .meta
+            .attribute_data_types()
+            .keys()
+            .map(|name| name.capacity() + std::mem::size_of::<(String, AttributeDataType)>())
+            .sum();
+        std::mem::size_of::<Self>()
+            + nodes_bytes
+            + attributes_bytes
+            + self.data_provider.approx_memory_bytes()
+    }
+
+    /// How the positions are georeferenced, if that was set when building the octree.
+    pub fn coordinate_system(&self) -> Option<&CoordinateSystem> {
+        self.meta.coordinate_system.as_ref()
+    }
+
+    /// The attributes of the points besides the position, sorted by name.
+    pub fn attributes(&self) -> Vec<AttributeDescription> {
+        self.meta.attribute_descriptions()
+    }
+
+    /// The meta data in the form that `BuildOptions::json_summary` writes.
+    pub fn summary(&self) -> OctreeSummary {
+        OctreeSummary::new(
+            &self.meta,
+            self.nodes
+                .iter()
+                .map(|(id, node_meta)| (*id, node_meta.num_points)),
+        )
+    }
+
+    /// Whether the points of every node are stored in Morton order, see
+    /// `BuildOptions::morton_order`.
+    pub fn points_in_morton_order(&self) -> bool {
+        self.meta.points_in_morton_order
+    }
+
+    /// Whether some input points may be missing from the octree, see
+    /// `BuildOptions::decimate_leaves`. Otherwise, every input point is stored in exactly one node.
+    pub fn leaves_decimated(&self) -> bool {
+        self.meta.leaves_decimated
+    }
+
+    /// The level below which nodes were not split, if the build capped it, see
+    /// `BuildOptions::max_depth`.
+    pub fn max_depth(&self) -> Option<u8> {
+        self.meta.max_depth
+    }
+
+    /// The axis convention the input was converted from, see `BuildOptions::input_axes`. The
+    /// positions are Z-up either way, so this tells whether converting them to Y-up restores the
+    /// input.
+    pub fn input_axes(&self) -> AxisConvention {
+        self.meta.input_axes
+    }
+
+    /// How the node data of the attributes is compressed, by attribute name, see
+    /// `BuildOptions::attribute_codecs`. Reading the octree decodes it.
+    pub fn attribute_codecs(&self) -> &HashMap<String, Vec<AttributeCodec>> {
+        &self.meta.attribute_codecs
+    }
+
+    /// Whether the points carry the `ORIGINAL_INDEX_ATTRIBUTE`, see `BuildOptions::original_index`.
+    pub fn has_original_index(&self) -> bool {
+        self.meta
+            .attribute_data_types
+            .contains_key(ORIGINAL_INDEX_ATTRIBUTE)
+    }
+
     pub fn get_visible_nodes(&self, projection_matrix: &Matrix4<f64>) -> Vec<NodeId> {
         let frustum =
             Frustum::from_matrix4(*projection_matrix).expect("Invalid projection matrix.");
@@ -282,6 +583,50 @@ impl Octree {
         visible
     }
 
+    /// The nodes whose bounding cubes contain the position, from the root down to the leaf that
+    /// a point at the position would be stored in, e.g. to stream the points around the camera
+    /// from coarse to fine. Empty if the position is outside of the bounding box.
+    pub fn node_path_to(&self, position: &Point3<f64>) -> Vec<NodeId> {
+        let mut path = Vec::new();
+        // Like the build, this includes the max, which tight bounding boxes touch.
+        let bounding_box = &self.meta.bounding_box;
+        if !(nalgebra::partial_le(bounding_box.min(), position)
+            && nalgebra::partial_le(position, bounding_box.max()))
+        {
+            return path;
+        }
+        let mut id = NodeId::root();
+        while let Some(node_meta) = self.nodes.get(&id) {
+            path.push(id);
+            id = id.get_child_id(ChildIndex::from_bounding_cube(
+                &node_meta.bounding_cube,
+                position,
+            ));
+        }
+        path
+    }
+
+    /// All nodes from the meta data, breadth first from the root, e.g. to draw the octree as
+    /// boxes. This reads no points.
+    pub fn iter_nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        NodeIdsIterator::new(self, |_, _| true).map(move |id| {
+            let node_meta = &self.nodes[&id];
+            let child_mask = (0..8)
+                .filter(|child_index| {
+                    self.nodes
+                        .contains_key(&id.get_child_id(ChildIndex::from_u8(*child_index)))
+                })
+                .fold(0, |mask, child_index| mask | 1 << child_index);
+            NodeInfo {
+                id,
+                level: id.level(),
+                bounding_box: node_meta.bounding_cube.to_aabb(),
+                num_points: node_meta.num_points as u64,
+                child_mask,
+            }
+        })
+    }
+
     pub fn get_node_data(&self, node_id: &NodeId) -> Result<NodeData> {
         // TODO(hrapp): If we'd randomize the points while writing, we could just read the
         // first N points instead of reading everything and skipping over a few.
@@ -302,13 +647,106 @@ impl Octree {
         let position = get_data("position", "Could not read position")?;
         let color = get_data("color", "Could not read color")?;
 
+        let meta = self.nodes[node_id].clone();
+        let num_points = meta.num_points as usize;
+        let expected_lengths = [
+            (
+                "position",
+                position.len(),
+                3 * meta.position_encoding.bytes_per_coordinate(),
+            ),
+            ("color", color.len(), AttributeDataType::U8Vec3.size_of()),
+        ];
+        for (attribute, len, bytes_per_point) in &expected_lengths {
+            if *len != num_points * bytes_per_point {
+                return Err(ErrorKind::Decode(format!(
+                    "Node {} has {} bytes of {} data, but {} points need {}.",
+                    node_id,
+                    len,
+                    attribute,
+                    num_points,
+                    num_points * bytes_per_point
+                ))
+                .into());
+            }
+        }
         Ok(NodeData {
             position,
             color,
-            meta: self.nodes[node_id].clone(),
+            meta,
         })
     }
 
+    /// A hash of the meta data and the data of all nodes, e.g. to tell whether cached query
+    /// results are still valid. The meta data is hashed as it is stored, without the nodes, so
+    /// that e.g. the coordinate system and the attributes count as well. It does not depend on
+    /// the platform or the run, but reading all node data is as expensive as querying all points.
+    pub fn content_hash(&self) -> Result<u64> {
+        let mut node_ids: Vec<(String, NodeId)> =
+            self.nodes.keys().map(|id| (id.to_string(), *id)).collect();
+        node_ids.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let node_hashes = node_ids
+            .par_iter()
+            .map(|(id_str, id)| {
+                // Empty nodes have no data.
+                if self.nodes[id].num_points == 0 {
+                    Ok(0)
+                } else {
+                    self.node_content_hash(id_str)
+                }
+            })
+            .collect::<Result<Vec<u64>>>()?;
+
+        let mut hasher = FnvHasher::default();
+        // All repeated fields of the meta data are sorted by name.
+        let meta_bytes = to_meta_proto(&self.meta, Vec::new())
+            .write_to_bytes()
+            .chain_err(|| "Could not serialize meta data.")?;
+        hasher.write(&meta_bytes);
+        for ((id_str, id), node_hash) in node_ids.iter().zip(node_hashes) {
+            let node_meta = &self.nodes[id];
+            write_str(&mut hasher, id_str);
+            hasher.write(&node_meta.num_points.to_le_bytes());
+            hasher
+                .write(&(node_meta.position_encoding.bytes_per_coordinate() as u64).to_le_bytes());
+            hasher.write(&node_hash.to_le_bytes());
+        }
+        Ok(hasher.finish())
+    }
+
+    /// The hash of the data of all attributes of the node, including the position.
+    fn node_content_hash(&self, node_id: &str) -> Result<u64> {
+        let mut attributes: Vec<&str> = self
+            .meta
+            .attribute_data_types()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        attributes.sort_unstable();
+        let mut hasher = FnvHasher::default();
+        let mut buf = Vec::new();
+        for attribute in std::iter::once("position").chain(attributes) {
+            write_str(&mut hasher, attribute);
+            // Octrees don't need to store all attributes, e.g. intensity.
+            let mut reader = match self.data_provider.data(node_id, &[attribute]) {
+                Ok(mut readers) => readers
+                    .remove(attribute)
+                    .ok_or_else(|| format!("No data for attribute '{}'.", attribute))?,
+                Err(Error(ErrorKind::NodeNotFound, _)) if attribute != "position" => {
+                    hasher.write_u8(0);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            hasher.write_u8(1);
+            buf.clear();
+            reader.read_to_end(&mut buf)?;
+            hasher.write(&(buf.len() as u64).to_le_bytes());
+            hasher.write(&buf);
+        }
+        Ok(hasher.finish())
+    }
+
     fn nodes_in_location_impl<'a, T: HasAabbIntersector<'a>>(
         &self,
         location: &'a T,
@@ -326,23 +764,94 @@ impl Octree {
     }
 }
 
+impl Octree {
+    /// Like `nodes_in_location_impl`, but nodes whose bounding sphere is outside of the frustum
+    /// are rejected before the more expensive exact test. Since the spheres only enclose the
+    /// points, this can leave out nodes whose bounding cube intersects the frustum, but none with
+    /// points in it.
+    fn nodes_in_frustum(&self, frustum: &Frustum) -> Vec<NodeId> {
+        let planes = frustum.planes();
+        let isec = frustum.aabb_intersector();
+        NodeIdsIterator::new(&self, |node_id, octree| {
+            let node_meta = &octree.nodes[&node_id];
+            planes.may_intersect_sphere(&node_meta.bounding_sphere)
+                && isec.intersect_aabb(&node_meta.bounding_cube.to_aabb())
+        })
+        .collect()
+    }
+}
+
 impl PointCloud for Octree {
     type Id = NodeId;
 
     fn nodes_in_location(&self, location: &PointLocation) -> Vec<Self::Id> {
-        dispatch_point_location!(Octree::nodes_in_location_impl, location, &self)
+        // All points are inside of the bounding box, so queries outside of it can stop right away.
+        if !location.intersects_aabb(&self.meta.bounding_box) {
+            return Vec::new();
+        }
+        let mut node_ids = match location {
+            PointLocation::AllPointsInDepthRange(max_depth) => {
+                NodeIdsIterator::new(&self, |node_id, _| node_id.level() <= *max_depth).collect()
+            }
+            // Only the part of the box inside of the bounding box needs to be traversed. The root
+            // cube usually extends beyond the bounding box, so this skips nodes without matches.
+            PointLocation::Aabb(aabb) => match aabb.intersection(&self.meta.bounding_box) {
+                Some(clamped) => self.nodes_in_location_impl(&clamped),
+                None => Vec::new(),
+            },
+            PointLocation::Frustum(frustum) => self.nodes_in_frustum(frustum),
+            PointLocation::Difference(minuend, _) => return self.nodes_in_location(minuend),
+            PointLocation::ByOriginalIndex(indices) => {
+                let mut indices: Vec<u64> = indices.iter().copied().collect();
+                indices.sort_unstable();
+                NodeIdsIterator::new(&self, |_, _| true)
+                    .filter(|node_id| match self.nodes[node_id].original_index_range {
+                        // The first selected index that is not too small must not be too large.
+                        Some((min, max)) => {
+                            let first = indices.partition_point(|index| *index < min);
+                            matches!(indices.get(first), Some(index) if *index <= max)
+                        }
+                        None => true,
+                    })
+                    .collect()
+            }
+            _ => dispatch_point_location!(Octree::nodes_in_location_impl, location, &self),
+        };
+        // Empty nodes still need to be traversed for their children, but have nothing to read.
+        node_ids.retain(|node_id| self.node_point_count(*node_id) != Some(0));
+        node_ids
     }
 
     fn encoding_for_node(&self, id: Self::Id) -> Encoding {
         self.meta.encoding_for_node(id)
     }
 
+    fn node_point_count(&self, id: Self::Id) -> Option<u64> {
+        self.nodes
+            .get(&id)
+            .map(|node_meta| node_meta.num_points as u64)
+    }
+
     fn points_in_node(
         &self,
         attributes: &[&str],
         node_id: Self::Id,
         batch_size: usize,
+        cancellation: Option<&CancellationToken>,
     ) -> Result<NodeIterator> {
+        if let Some(attribute) = attributes
+            .iter()
+            .find(|a| self.meta.leaf_only_attributes.iter().any(|l| l == *a))
+        {
+            if !self.is_leaf(node_id) {
+                return Err(ErrorKind::InvalidQuery(format!(
+                    "The attribute '{}' is only stored at full resolution, in the leaf nodes, \
+                     but node {} is an inner node.",
+                    attribute, node_id
+                ))
+                .into());
+            }
+        }
         let node_iterator = NodeIterator::from_data_provider(
             &*self.data_provider,
             &self.meta.attribute_data_types_for(&attributes)?,
@@ -350,6 +859,7 @@ impl PointCloud for Octree {
             &node_id,
             self.nodes[&node_id].num_points as usize,
             batch_size,
+            cancellation,
         )?;
         Ok(node_iterator)
     }
@@ -358,6 +868,36 @@ impl PointCloud for Octree {
     fn bounding_box(&self) -> &Aabb {
         &self.meta.bounding_box
     }
+
+    fn num_points(&self) -> usize {
+        self.nodes.values().map(|n| n.num_points as usize).sum()
+    }
+
+    fn attribute_data_types(&self) -> &HashMap<String, AttributeDataType> {
+        self.meta.attribute_data_types()
+    }
+
+    fn coordinate_system(&self) -> Option<&CoordinateSystem> {
+        self.meta.coordinate_system.as_ref()
+    }
+
+    fn leaf_only_attributes(&self) -> &[String] {
+        &self.meta.leaf_only_attributes
+    }
+
+    fn is_leaf(&self, node_id: Self::Id) -> bool {
+        (0..8).all(|child_index| {
+            !self
+                .nodes
+                .contains_key(&node_id.get_child_id(ChildIndex::from_u8(child_index)))
+        })
+    }
+}
+
+/// Hashes the length along with the string, so that consecutive strings can't be confused.
+fn write_str(hasher: &mut FnvHasher, s: &str) {
+    hasher.write(&(s.len() as u64).to_le_bytes());
+    hasher.write(s.as_bytes());
 }
 
 struct OpenNode {
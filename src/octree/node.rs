@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::geometry::Cube;
+use crate::geometry::{Cube, Sphere};
 use crate::proto;
 use crate::read_write::PositionEncoding;
 use nalgebra::Point3;
@@ -111,10 +111,18 @@ impl NodeId {
     }
 
     /// Returns the root node of the octree.
-    fn root() -> Self {
+    pub fn root() -> Self {
         NodeId(0)
     }
 
+    /// Returns the id of the child in the given octant, with the bits of `octant` selecting the
+    /// upper half in x, y and z, from most to least significant, see `ChildIndex`. In the name of
+    /// the node, and hence of its files, this appends the octant as a digit. Panics if `octant` is
+    /// not below 8.
+    pub fn child(&self, octant: u8) -> Self {
+        self.get_child_id(ChildIndex::from_u8(octant))
+    }
+
     /// Returns the NodeId for the corresponding 'child_index'.
     #[inline]
     pub fn get_child_id(&self, child_index: ChildIndex) -> Self {
@@ -132,6 +140,11 @@ impl NodeId {
         Some(ChildIndex(self.index() as u8 & 7))
     }
 
+    /// Same as `parent_id`.
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent_id()
+    }
+
     /// Returns the parents id or None if this is the root.
     pub fn parent_id(&self) -> Option<NodeId> {
         if self.level() == 0 {
@@ -249,6 +262,13 @@ pub struct NodeMeta {
     pub num_points: i64,
     pub position_encoding: PositionEncoding,
     pub bounding_cube: Cube,
+    /// Encloses the points of the node and of all its descendants, for cheaper culling. Octrees
+    /// that did not store it use the sphere around the bounding cube.
+    pub bounding_sphere: Sphere,
+    /// The smallest and largest `ORIGINAL_INDEX_ATTRIBUTE` of the points in the node, to skip it
+    /// when selecting points by their index. Not known for nodes without the attribute and for
+    /// octrees built before it was recorded.
+    pub original_index_range: Option<(u64, u64)>,
 }
 
 impl NodeMeta {
@@ -261,11 +281,19 @@ pub fn to_node_proto(
     node_id: &NodeId,
     num_points: i64,
     position_encoding: &PositionEncoding,
+    original_index_range: Option<(u64, u64)>,
+    bounding_sphere: &Sphere,
 ) -> proto::OctreeNode {
     let mut proto = proto::OctreeNode::new();
     *proto.mut_id() = node_id.to_proto();
     proto.set_num_points(num_points);
     proto.set_position_encoding(position_encoding.to_proto());
+    proto.set_bounding_sphere(proto::Sphere::from(bounding_sphere));
+    if let Some((min, max)) = original_index_range {
+        let range = proto.mut_original_index_range();
+        range.set_min(min);
+        range.set_max(max);
+    }
     proto
 }
 
@@ -282,6 +310,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_child_parent_round_trip() {
+        let root = NodeId::root();
+        assert_eq!(root.to_string(), "r");
+        assert_eq!(root.level(), 0);
+        assert_eq!(root.parent(), None);
+        let mut node = root;
+        for (level, octant) in [3u8, 0, 7, 5, 1].iter().enumerate() {
+            let child = node.child(*octant);
+            assert_eq!(child.level(), level as u8 + 1);
+            assert_eq!(child.parent(), Some(node));
+            assert_eq!(child.to_string(), format!("{}{}", node, octant));
+            node = child;
+        }
+        assert_eq!(node, NodeId::from_str("r30751").unwrap());
+    }
+
     #[test]
     fn test_child_index() {
         assert_eq!(
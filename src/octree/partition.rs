@@ -0,0 +1,95 @@
+use crate::errors::*;
+use crate::geometry::{Aabb, Cube};
+use crate::octree::generation::SplitDecision;
+use crate::octree::{BuildOptions, ChildIndex, NodeId};
+use nalgebra::Point3;
+
+/// Sorts points into the leaf nodes that `build_octree_with_options` would sort them into, without
+/// writing anything, e.g. to shard points for distributed processing with a custom storage. The
+/// build later moves every 8th point of a node into its parent to subsample the coarse levels, so
+/// a point of the built octree is in its leaf or in one of the ancestors of the leaf. Decimated
+/// leaves are not taken into account.
+#[derive(Clone, Debug)]
+pub struct OctreePartitioner {
+    bounding_box: Aabb,
+    root_cube: Cube,
+    resolution: f64,
+    options: BuildOptions,
+}
+
+impl OctreePartitioner {
+    /// Partitions like the build of an octree with the bounding box and resolution, which splits
+    /// nodes according to the `max_points_per_node` and `max_depth` of the options. Like for the
+    /// build, the bounding box and the positions are those of the input, before the
+    /// `input_transform` and `input_axes` of the options.
+    pub fn new(bounding_box: Aabb, resolution: f64, options: &BuildOptions) -> Self {
+        let bounding_box = options.octree_bounding_box(&bounding_box);
+        OctreePartitioner {
+            root_cube: Cube::bounding(&bounding_box),
+            bounding_box,
+            resolution,
+            options: options.clone(),
+        }
+    }
+
+    /// The bounding cube of the node, like that of the node in the built octree, i.e. after the
+    /// `input_transform` and `input_axes`.
+    pub fn bounding_cube(&self, id: NodeId) -> Cube {
+        id.find_bounding_cube(&self.root_cube)
+    }
+
+    /// The leaf node of every point, in order. Whether a node is split depends on all points in
+    /// it, so all points have to be partitioned at once. Fails if a point is outside of the
+    /// bounding box.
+    pub fn partition(&self, positions: &[Point3<f64>]) -> Result<Vec<NodeId>> {
+        let positions: Vec<Point3<f64>> = positions
+            .iter()
+            .map(|p| self.options.octree_position(p))
+            .collect();
+        // Like the build, this includes the max, which tight bounding boxes touch.
+        if let Some(p) = positions.iter().find(|p| {
+            !(nalgebra::partial_le(self.bounding_box.min(), *p)
+                && nalgebra::partial_le(*p, self.bounding_box.max()))
+        }) {
+            return Err(ErrorKind::InvalidInput(format!(
+                "The point {:?} is outside of the bounding box {:?}.",
+                p, self.bounding_box
+            ))
+            .into());
+        }
+        let mut leaves = vec![NodeId::root(); positions.len()];
+        // The root is always split.
+        let mut to_split = vec![(NodeId::root(), (0..positions.len()).collect::<Vec<usize>>())];
+        while let Some((id, indices)) = to_split.pop() {
+            let bounding_cube = self.bounding_cube(id);
+            let mut children = vec![Vec::new(); 8];
+            for i in indices {
+                let child_index = ChildIndex::from_bounding_cube(&bounding_cube, &positions[i]);
+                children[child_index.as_u8() as usize].push(i);
+            }
+            for (child_index, child_indices) in children.into_iter().enumerate() {
+                if child_indices.is_empty() {
+                    continue;
+                }
+                let child_id = id.get_child_id(ChildIndex::from_u8(child_index as u8));
+                if self.should_split(child_id, child_indices.len()) {
+                    to_split.push((child_id, child_indices));
+                } else {
+                    for i in child_indices {
+                        leaves[i] = child_id;
+                    }
+                }
+            }
+        }
+        Ok(leaves)
+    }
+
+    fn should_split(&self, id: NodeId, num_points: usize) -> bool {
+        self.options.split_decision(
+            id,
+            &self.bounding_cube(id),
+            self.resolution,
+            num_points as f64,
+        ) == SplitDecision::Split
+    }
+}
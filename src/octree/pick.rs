@@ -0,0 +1,114 @@
+use crate::errors::*;
+use crate::geometry::Aabb;
+use crate::iterator::PointCloud;
+use crate::octree::{ChildIndex, NodeId, Octree};
+use crate::NUM_POINTS_PER_BATCH;
+use nalgebra::Point3;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::Instant;
+
+/// The result of `Octree::pick_nearest`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pick {
+    /// The closest point that was found and its distance, if any is within the maximum distance.
+    pub nearest: Option<(Point3<f64>, f64)>,
+    /// Set if the deadline passed before all nodes that may hold a closer point were read, so
+    /// that a closer point may exist.
+    pub approximate: bool,
+}
+
+/// A node to read, ordered by the distance of its bounding cube, so that the closest is on top.
+struct Candidate {
+    distance: f64,
+    id: NodeId,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance
+            .partial_cmp(&self.distance)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+fn distance_to_aabb(p: &Point3<f64>, aabb: &Aabb) -> f64 {
+    let closest = p.coords.sup(&aabb.min().coords).inf(&aabb.max().coords);
+    (p.coords - closest).norm()
+}
+
+impl Octree {
+    /// The point closest to the target, at most `max_distance` away, e.g. to pick the point under
+    /// the cursor. Nodes are read closest first, so good candidates are found early. Once the
+    /// deadline passes, the closest point so far is returned as `Pick::approximate`, e.g. to keep
+    /// picking interactive under load. The deadline is only checked once a candidate is found, so
+    /// that an expired deadline still returns a point if there is one.
+    pub fn pick_nearest(
+        &self,
+        target: &Point3<f64>,
+        max_distance: f64,
+        deadline: Option<Instant>,
+    ) -> Result<Pick> {
+        let mut nearest: Option<(Point3<f64>, f64)> = None;
+        let mut candidates = BinaryHeap::new();
+        if let Some(root) = self.nodes.get(&NodeId::root()) {
+            candidates.push(Candidate {
+                distance: distance_to_aabb(target, &root.bounding_cube.to_aabb()),
+                id: NodeId::root(),
+            });
+        }
+        while let Some(Candidate { distance, id }) = candidates.pop() {
+            let bound = nearest.map_or(max_distance, |(_, nearest_distance)| nearest_distance);
+            if distance > bound {
+                break;
+            }
+            if nearest.is_some() && matches!(deadline, Some(deadline) if Instant::now() >= deadline)
+            {
+                return Ok(Pick {
+                    nearest,
+                    approximate: true,
+                });
+            }
+            if self.nodes[&id].num_points > 0 {
+                for batch in self.points_in_node(&[], id, NUM_POINTS_PER_BATCH, None)? {
+                    for p in batch.position {
+                        let distance = (p - target).norm();
+                        let bound =
+                            nearest.map_or(max_distance, |(_, nearest_distance)| nearest_distance);
+                        if distance <= bound {
+                            nearest = Some((p, distance));
+                        }
+                    }
+                }
+            }
+            for child_index in 0..8 {
+                let child_id = id.get_child_id(ChildIndex::from_u8(child_index));
+                if let Some(child) = self.nodes.get(&child_id) {
+                    candidates.push(Candidate {
+                        distance: distance_to_aabb(target, &child.bounding_cube.to_aabb()),
+                        id: child_id,
+                    });
+                }
+            }
+        }
+        Ok(Pick {
+            nearest,
+            approximate: false,
+        })
+    }
+}
@@ -0,0 +1,199 @@
+use crate::data_provider::OnDiskDataProvider;
+use crate::errors::*;
+use crate::iterator::PointCloud;
+use crate::octree::{Octree, SUMMARY_FILENAME};
+use crate::read_write::{DataWriter, OpenMode, WriteLE};
+use crate::utils::remove_file_if_exists;
+use crate::{
+    attribute_extension, AttributeData, AttributeDataType, PointCloudMeta, META_FILENAME,
+    NUM_POINTS_PER_BATCH,
+};
+use num_traits::{NumCast, ToPrimitive};
+use protobuf::Message;
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub(super) fn is_scalar(data_type: AttributeDataType) -> bool {
+    !matches!(
+        data_type,
+        AttributeDataType::U8Vec3 | AttributeDataType::U16Vec3 | AttributeDataType::F64Vec3
+    )
+}
+
+/// Converts the values of a scalar attribute into the data type, rounding them for integer types.
+/// `None` if a value does not fit into it.
+fn convert(data: &AttributeData, data_type: AttributeDataType) -> Option<AttributeData> {
+    macro_rules! to_f64 {
+        ($dtype:ident, $data:ident) => {
+            $data
+                .iter()
+                .map(|v| v.to_f64())
+                .collect::<Option<Vec<f64>>>()
+        };
+    }
+    let values = match_1d_attr_data!(data, to_f64)?;
+    macro_rules! cast {
+        ($dtype:ident, $round:expr) => {
+            values
+                .iter()
+                .map(|v| NumCast::from(if $round { v.round() } else { *v }))
+                .collect::<Option<Vec<_>>>()
+                .map(AttributeData::$dtype)
+        };
+    }
+    match data_type {
+        AttributeDataType::U8 => cast!(U8, true),
+        AttributeDataType::U16 => cast!(U16, true),
+        AttributeDataType::U32 => cast!(U32, true),
+        AttributeDataType::U64 => cast!(U64, true),
+        AttributeDataType::I8 => cast!(I8, true),
+        AttributeDataType::I16 => cast!(I16, true),
+        AttributeDataType::I32 => cast!(I32, true),
+        AttributeDataType::I64 => cast!(I64, true),
+        AttributeDataType::F32 => cast!(F32, false),
+        AttributeDataType::F64 => cast!(F64, false),
+        AttributeDataType::U8Vec3 | AttributeDataType::U16Vec3 | AttributeDataType::F64Vec3 => None,
+    }
+}
+
+/// Writes the converted attribute of every node next to the current one, and returns the paths of
+/// both.
+fn write_reencoded_nodes(
+    octree: &Octree,
+    data_provider: &OnDiskDataProvider,
+    attribute: &str,
+    data_type: AttributeDataType,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let extension = attribute_extension(attribute);
+    let is_leaf_only = octree.leaf_only_attributes().iter().any(|a| a == attribute);
+    octree
+        .nodes
+        .par_iter()
+        .filter(|(id, node_meta)| {
+            node_meta.num_points > 0 && (!is_leaf_only || octree.is_leaf(**id))
+        })
+        .map(|(id, _)| {
+            let path = data_provider
+                .stem(&id.to_string())
+                .with_extension(extension);
+            let reencoded_path = path.with_extension(format!("{}.reencoded", extension));
+            let mut writer = DataWriter::new(&reencoded_path, OpenMode::Truncate)?;
+            for batch in octree.points_in_node(&[attribute], *id, NUM_POINTS_PER_BATCH, None)? {
+                let converted =
+                    convert(&batch.attributes[attribute], data_type).ok_or_else(|| {
+                        ErrorKind::InvalidInput(format!(
+                            "A value of attribute '{}' in node {} does not fit into {:?}.",
+                            attribute, id, data_type
+                        ))
+                    })?;
+                converted.write_le(&mut writer)?;
+            }
+            // Dropping the writer would ignore errors of the last write.
+            writer.flush()?;
+            Ok((reencoded_path, path))
+        })
+        .collect::<Vec<Result<_>>>()
+        .into_iter()
+        .collect()
+}
+
+/// Stores the attribute of the octree in `directory` with another data type, e.g. intensities as
+/// U16 instead of F32 to save space. Only the data of this attribute is rewritten: The nodes, the
+/// points in them and their order stay the same, so this is much faster than rebuilding the
+/// octree. Values are rounded when converting to integers. Only scalar attributes other than
+/// "color" can be re-encoded, and nothing is changed if a value does not fit into the new type.
+/// The attribute is no longer compressed afterwards, see `BuildOptions::attribute_codecs`.
+pub fn reencode_attribute(
+    directory: impl AsRef<Path>,
+    attribute: &str,
+    data_type: AttributeDataType,
+) -> Result<()> {
+    let directory = directory.as_ref();
+    let data_provider = OnDiskDataProvider {
+        directory: directory.to_path_buf(),
+    };
+    let mut octree = Octree::from_data_provider(Box::new(OnDiskDataProvider {
+        directory: directory.to_path_buf(),
+    }))?;
+    let current_data_type = *octree
+        .meta
+        .attribute_data_types_for(&[attribute])?
+        .values()
+        .next()
+        .unwrap();
+    if attribute == "color" || !is_scalar(current_data_type) || !is_scalar(data_type) {
+        return Err(ErrorKind::InvalidInput(format!(
+            "Only scalar attributes other than 'color' can be re-encoded, but '{}' has type {:?} \
+             and {:?} was requested.",
+            attribute, current_data_type, data_type
+        ))
+        .into());
+    }
+    if current_data_type == data_type {
+        return Ok(());
+    }
+
+    let paths = match write_reencoded_nodes(&octree, &data_provider, attribute, data_type) {
+        Ok(paths) => paths,
+        Err(err) => {
+            let extension = attribute_extension(attribute);
+            for id in octree.nodes.keys() {
+                let path = data_provider
+                    .stem(&id.to_string())
+                    .with_extension(format!("{}.reencoded", extension));
+                // The error that stopped the re-encoding is the one to return.
+                if let Err(err) = remove_file_if_exists(&path) {
+                    eprintln!("Could not remove {}: {}", path.display(), err);
+                }
+            }
+            return Err(err);
+        }
+    };
+    for (reencoded_path, path) in paths {
+        fs::rename(reencoded_path, path)?;
+    }
+    let meta = Arc::make_mut(&mut octree.meta);
+    meta.attribute_data_types
+        .insert(attribute.to_string(), data_type);
+    // The new data is written without the codecs.
+    meta.attribute_codecs.remove(attribute);
+    write_and_rename(&directory.join(META_FILENAME), |writer| {
+        octree
+            .to_meta_proto()
+            .write_to_writer(writer)
+            .chain_err(|| "Could not write meta data.")
+    })?;
+    let summary_path = directory.join(SUMMARY_FILENAME);
+    if summary_path.exists() {
+        write_and_rename(&summary_path, |writer| {
+            serde_json::to_writer_pretty(writer, &octree.summary())
+                .chain_err(|| "Could not write summary.")
+        })?;
+    }
+    Ok(())
+}
+
+/// Writes the file next to `path` and only replaces `path` with it once it is completely written,
+/// so that a failure leaves the old file intact.
+fn write_and_rename(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let result = write(&mut writer).and_then(|()| Ok(writer.flush()?));
+    drop(writer);
+    if let Err(err) = result {
+        if let Err(remove_err) = remove_file_if_exists(&tmp_path) {
+            eprintln!("Could not remove {}: {}", tmp_path.display(), remove_err);
+        }
+        return Err(err);
+    }
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
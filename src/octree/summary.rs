@@ -0,0 +1,83 @@
+use crate::attributes::AttributeDescription;
+use crate::errors::*;
+use crate::geometry::Aabb;
+use crate::octree::{AxisConvention, CoordinateSystem, NodeId, OctreeMeta};
+use crate::CURRENT_VERSION;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// The file that `BuildOptions::json_summary` writes next to the meta data. Octrees of version
+/// 3 had their meta data in "meta.json", so that name is taken.
+pub const SUMMARY_FILENAME: &str = "meta_summary.json";
+
+/// The nodes of one level of an octree.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelSummary {
+    pub level: u8,
+    pub num_nodes: usize,
+    pub num_points: i64,
+}
+
+/// A human-readable description of the meta data of an octree, e.g. to inspect or diff builds.
+/// It is never read when opening an octree, the binary meta data is the only one that counts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OctreeSummary {
+    pub version: i32,
+    pub resolution: f64,
+    pub bounding_box: Aabb,
+    pub coordinate_system: Option<CoordinateSystem>,
+    pub points_in_morton_order: bool,
+    pub leaves_decimated: bool,
+    pub max_depth: Option<u8>,
+    #[serde(default)]
+    pub input_axes: AxisConvention,
+    /// Sorted by name.
+    pub attributes: Vec<AttributeDescription>,
+    pub num_points: i64,
+    /// From the root down to the deepest level.
+    pub levels: Vec<LevelSummary>,
+}
+
+impl OctreeSummary {
+    /// Summarizes the nodes, given as ids and numbers of points.
+    pub(super) fn new(meta: &OctreeMeta, nodes: impl Iterator<Item = (NodeId, i64)>) -> Self {
+        let mut levels = BTreeMap::new();
+        for (id, num_points) in nodes {
+            let level = levels.entry(id.level()).or_insert(LevelSummary {
+                level: id.level(),
+                num_nodes: 0,
+                num_points: 0,
+            });
+            level.num_nodes += 1;
+            level.num_points += num_points;
+        }
+        let levels: Vec<LevelSummary> = levels.into_values().collect();
+        OctreeSummary {
+            version: CURRENT_VERSION,
+            resolution: meta.resolution,
+            bounding_box: meta.bounding_box.clone(),
+            coordinate_system: meta.coordinate_system.clone(),
+            points_in_morton_order: meta.points_in_morton_order,
+            leaves_decimated: meta.leaves_decimated,
+            max_depth: meta.max_depth,
+            input_axes: meta.input_axes,
+            attributes: meta.attribute_descriptions(),
+            num_points: levels.iter().map(|level| level.num_points).sum(),
+            levels,
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(path).chain_err(|| "Could not open summary.")?;
+        serde_json::from_reader(file).chain_err(|| "Could not parse summary.")
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self).chain_err(|| "Could not write summary.")?;
+        writer.flush().chain_err(|| "Could not write summary.")
+    }
+}
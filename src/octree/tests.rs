@@ -1,10 +1,42 @@
-use crate::data_provider::OnDiskDataProvider;
-use crate::errors::Result;
-use crate::geometry::Aabb;
-use crate::iterator::{ParallelIterator, PointQuery};
-use crate::octree::{build_octree, Octree};
-use crate::{AttributeData, NumberOfPoints, PointsBatch};
-use nalgebra::{Point3, Vector3};
+use crate::attributes::{AttributeAnnotation, AttributeDescription};
+use crate::color::{COLOR16_ATTRIBUTE, RGBA_ATTRIBUTE};
+use crate::conversion::{build_octree_from_point_clouds, octree_to_s2_cells};
+use crate::data_provider::{CachingDataProvider, DataProvider, OnDiskDataProvider};
+use crate::errors::{ErrorKind, Result};
+use crate::geometry::{Aabb, Cube, Frustum, Frustums, Sphere};
+use crate::iterator::{tile_coordinates, PointCloud};
+use crate::iterator::{
+    OutputTransform, ParallelIterator, PointLocation, PointQuery, PooledIterator, RadialDecimation,
+    ALL_ATTRIBUTES, ALTITUDE_ATTRIBUTE, FRUSTUM_MASK_ATTRIBUTE, LATITUDE_ATTRIBUTE,
+    LONGITUDE_ATTRIBUTE, QUERY_WEIGHT_ATTRIBUTE, TILE_X_ATTRIBUTE, TILE_Y_ATTRIBUTE,
+};
+use crate::math::{ClosedInterval, PointCulling};
+use crate::octree::{
+    build_octree, build_octree_from_reader, build_octree_with_options, compute_extent,
+    diff_octrees, estimate_octree, estimate_octree_from_file, inspect_node, recommend_root,
+    reencode_attribute, AxisConvention, BuildOptions, ChildIndex, CoordinateSystem, NodeId,
+    NodeInfo, Octree, OctreePartitioner, OctreeSummary, OutOfBounds, QueryValidationError,
+    ORIGINAL_INDEX_ATTRIBUTE, SUMMARY_FILENAME,
+};
+use crate::proto;
+use crate::read_write::{
+    AttributeCodec, Encoding, NodeWriter, OpenMode, PlyIterator, PlyNodeWriter, RawNodeWriter,
+};
+use crate::s2_cells::S2Cells;
+use crate::{
+    attribute_extension, AttributeData, AttributeDataType, NumberOfPoints, PointsBatch,
+    META_FILENAME,
+};
+use nalgebra::{
+    Isometry3, Perspective3, Point3, Similarity3, Translation3, UnitQuaternion, Vector3,
+};
+use nav_types::{ECEF, WGS84};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tempdir::TempDir;
 
 const NUM_POINTS: usize = 100_001;
@@ -16,6 +48,11 @@ impl NumberOfPoints for std::vec::IntoIter<PointsBatch> {
 }
 
 fn build_test_octree() -> Octree {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    build_test_octree_in(&tmp_dir.into_path())
+}
+
+fn build_test_octree_in(directory: &Path) -> Octree {
     let mut batch = PointsBatch {
         position: vec![Point3::new(0.0, 0.0, 0.0); NUM_POINTS],
         attributes: vec![(
@@ -24,23 +61,37 @@ fn build_test_octree() -> Octree {
         )]
         .into_iter()
         .collect(),
+        bounding_box: None,
     };
 
     batch.position[NUM_POINTS - 1] = Point3::new(-200., -40., 30.);
 
     let bounding_box = Aabb::new(batch.position[0], batch.position[NUM_POINTS - 1]);
 
-    let tmp_dir = TempDir::new("octree").unwrap();
-
     build_octree(
-        &tmp_dir,
+        directory,
         1.0,
         bounding_box,
         vec![batch].into_iter(),
         &["color"],
     );
+    open_test_octree(directory)
+}
+
+fn blue_batch(position: Vec<Point3<f64>>) -> PointsBatch {
+    let color = vec![Vector3::new(0, 0, 255); position.len()];
+    PointsBatch {
+        position,
+        attributes: vec![("color".to_string(), AttributeData::U8Vec3(color))]
+            .into_iter()
+            .collect(),
+        bounding_box: None,
+    }
+}
+
+fn open_test_octree(directory: &Path) -> Octree {
     Octree::from_data_provider(Box::new(OnDiskDataProvider {
-        directory: tmp_dir.into_path(),
+        directory: directory.to_owned(),
     }))
     .unwrap()
 }
@@ -129,8 +180,3443 @@ fn test_batch_iterator_more_points() {
     let octree_slice: &[Octree] = std::slice::from_ref(&octree);
     let mut parallel_iterator = ParallelIterator::new(octree_slice, &location, batch_size, 2, 2);
 
-    parallel_iterator
+    let summary = parallel_iterator
         .try_for_each_batch(|points_batch| c.consume(points_batch))
         .expect("Iterator errored even though callback should not have errored.");
     assert_eq!(c.num_received_points, NUM_POINTS);
+    assert_eq!(summary.points, NUM_POINTS);
+    // Empty nodes are skipped.
+    let num_nonempty_nodes = octree
+        .to_meta_proto()
+        .get_octree()
+        .get_nodes()
+        .iter()
+        .filter(|node| node.num_points > 0)
+        .count();
+    assert_eq!(summary.nodes_visited, num_nonempty_nodes);
+}
+
+#[test]
+fn test_thread_summaries_add_up() {
+    let octree = build_test_octree();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let mut num_received_points = 0;
+    let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 5000, 3, 2)
+        .try_for_each_batch(|batch| {
+            num_received_points += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(summary.threads.len(), 3);
+    let thread_points: usize = summary.threads.iter().map(|thread| thread.points).sum();
+    assert_eq!(thread_points, num_received_points);
+    assert_eq!(thread_points, NUM_POINTS);
+    let thread_nodes: usize = summary
+        .threads
+        .iter()
+        .map(|thread| thread.nodes_visited)
+        .sum();
+    assert_eq!(thread_nodes, summary.nodes_visited);
+
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let octrees: Arc<[Octree]> = Arc::from(vec![octree]);
+    let summary = PooledIterator::new(&thread_pool, octrees, &query, 5000, 4, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .unwrap();
+    assert_eq!(summary.threads.len(), 4);
+    let thread_points: usize = summary.threads.iter().map(|thread| thread.points).sum();
+    assert_eq!(thread_points, NUM_POINTS);
+}
+
+#[test]
+fn test_coalesce_batches() {
+    let octree = build_test_octree();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let check_sizes = |sizes: &[usize]| {
+        assert!(sizes.len() > 1);
+        assert!(sizes[..sizes.len() - 1].iter().all(|size| *size >= 30_000));
+        assert_eq!(sizes.iter().sum::<usize>(), NUM_POINTS);
+    };
+    let mut sizes = Vec::new();
+    ParallelIterator::new(std::slice::from_ref(&octree), &query, 1000, 3, 2)
+        .coalesce_batches(30_000)
+        .try_for_each_batch(|batch| {
+            sizes.push(batch.position.len());
+            assert_eq!(batch.attributes["color"].len(), batch.position.len());
+            Ok(())
+        })
+        .unwrap();
+    check_sizes(&sizes);
+
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let octrees: Arc<[Octree]> = Arc::from(vec![octree]);
+    let mut sizes = Vec::new();
+    PooledIterator::new(&thread_pool, octrees, &query, 1000, 4, 2)
+        .coalesce_batches(30_000)
+        .try_for_each_batch(|batch| {
+            sizes.push(batch.position.len());
+            Ok(())
+        })
+        .unwrap();
+    check_sizes(&sizes);
+}
+
+#[test]
+fn test_deterministic_query() {
+    let dir = TempDir::new("octree").unwrap();
+    let options = BuildOptions {
+        max_points_per_node: 50,
+        ..Default::default()
+    };
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(2000.0, 1.0, 1.0)),
+        vec![blue_batch(
+            (0..2000)
+                .map(|i| Point3::new(f64::from(i), 0.5, 0.5))
+                .collect(),
+        )]
+        .into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let query = PointQuery {
+        attributes: vec!["color"],
+        deterministic: true,
+        ..Default::default()
+    };
+    let query_xs = |num_threads| {
+        let mut batches = Vec::new();
+        ParallelIterator::new(std::slice::from_ref(&octree), &query, 64, num_threads, 2)
+            .try_for_each_batch(|batch| {
+                batches.push(batch.position.iter().map(|p| p.x).collect::<Vec<_>>());
+                Ok(())
+            })
+            .unwrap();
+        batches
+    };
+    let single_threaded = query_xs(1);
+    assert_eq!(single_threaded.iter().map(Vec::len).sum::<usize>(), 2000);
+    assert!(single_threaded[..single_threaded.len() - 1]
+        .iter()
+        .all(|xs| xs.len() == 64));
+    assert_eq!(query_xs(4), single_threaded);
+
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(3)
+        .build()
+        .unwrap();
+    let octrees: Arc<[Octree]> = Arc::from(vec![octree.clone()]);
+    let pooled: Vec<Vec<f64>> = PooledIterator::new(&thread_pool, octrees, &query, 64, 3, 2)
+        .batches()
+        .map(|batch| batch.unwrap().position.iter().map(|p| p.x).collect())
+        .collect();
+    assert_eq!(pooled, single_threaded);
+}
+
+#[test]
+fn test_concurrent_queries_on_shared_octree() {
+    fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+    assert_shareable::<Octree>();
+
+    let dir = TempDir::new("octree").unwrap();
+    let (octree, positions) = build_grid_octree(dir.path(), [20, 20, 20], 0.5, 200);
+    let octree = Arc::new(octree);
+    let aabb = |i: usize| {
+        // Away from the grid of the points, which are stored with the resolution.
+        let min = f64::from(i as u32) * 0.25 + 0.1;
+        Aabb::new(
+            Point3::new(min, 0.0, min),
+            Point3::new(min + 4.0, 10.0, 10.0),
+        )
+    };
+
+    let handles: Vec<_> = (0..16)
+        .map(|i| {
+            let shared = Arc::clone(&octree);
+            // Half of the threads query a clone, the others the shared octree itself.
+            let cloned = if i % 2 == 0 {
+                Some(Octree::clone(&octree))
+            } else {
+                None
+            };
+            std::thread::spawn(move || query_box(cloned.as_ref().unwrap_or(&shared), aabb(i)))
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        let (num_points, sum) = handle.join().unwrap();
+        let expected: Vec<&Point3<f64>> =
+            positions.iter().filter(|p| aabb(i).contains(p)).collect();
+        assert!(!expected.is_empty());
+        assert_eq!(num_points, expected.len());
+        let expected_sum: f64 = expected.iter().map(|p| p.x + p.y + p.z).sum();
+        assert!((sum - expected_sum).abs() < 0.003 * num_points as f64);
+    }
+}
+
+/// The number of points in the box and the sum of their coordinates.
+fn query_box(octree: &Octree, aabb: Aabb) -> (usize, f64) {
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: PointLocation::Aabb(aabb),
+        ..Default::default()
+    };
+    let mut sum = 0.0;
+    let summary = ParallelIterator::new(std::slice::from_ref(octree), &query, 500, 2, 2)
+        .try_for_each_batch(|batch| {
+            sum += batch.position.iter().map(|p| p.x + p.y + p.z).sum::<f64>();
+            Ok(())
+        })
+        .unwrap();
+    (summary.points, sum)
+}
+
+#[test]
+fn test_node_point_count() {
+    let octree = build_test_octree();
+    let all_node_ids: Vec<NodeId> = octree
+        .to_meta_proto()
+        .get_octree()
+        .get_nodes()
+        .iter()
+        .map(|node| NodeId::from_proto(node.get_id()))
+        .collect();
+    let queried_node_ids = octree.nodes_in_location(&PointLocation::AllPoints);
+    for node_id in all_node_ids {
+        let num_points = octree.node_point_count(node_id).unwrap();
+        if num_points == 0 {
+            assert!(!queried_node_ids.contains(&node_id));
+            continue;
+        }
+        let num_decoded: usize = octree
+            .points_in_node(&["color"], node_id, 10_000, None)
+            .unwrap()
+            .map(|batch| batch.position.len())
+            .sum();
+        assert_eq!(num_decoded as u64, num_points, "{}", node_id);
+    }
+    assert_eq!(
+        octree.node_point_count(NodeId::from_level_index(20, 0)),
+        None
+    );
+}
+
+#[test]
+fn test_node_path_to() {
+    let dir = TempDir::new("octree").unwrap();
+    let options = BuildOptions {
+        max_points_per_node: 50,
+        ..Default::default()
+    };
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(1000.0, 1.0, 1.0)),
+        vec![blue_batch(
+            (0..1000)
+                .map(|i| Point3::new(f64::from(i), 0.5, 0.5))
+                .collect(),
+        )]
+        .into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let position = Point3::new(123.4, 0.5, 0.5);
+    let path = octree.node_path_to(&position);
+    assert!(path.len() > 2);
+    assert_eq!(path[0], NodeId::root());
+    let root_cube = Cube::bounding(octree.bounding_box());
+    for (level, id) in path.iter().enumerate() {
+        assert_eq!(usize::from(id.level()), level);
+        assert!(id
+            .find_bounding_cube(&root_cube)
+            .to_aabb()
+            .contains(&position));
+    }
+    // Every node is the child of the previous one, so their boxes are nested.
+    for window in path.windows(2) {
+        assert_eq!(window[1].parent(), Some(window[0]));
+    }
+    assert!(octree.is_leaf(*path.last().unwrap()));
+
+    assert!(octree.node_path_to(&Point3::new(-1.0, 0.5, 0.5)).is_empty());
+}
+
+#[test]
+fn test_pooled_iterator_reuses_pool() {
+    let octrees: Arc<[Octree]> = Arc::from(vec![build_test_octree()]);
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    for _ in 0..3 {
+        let mut c = Consumer::new(NUM_POINTS + 1);
+        let mut pooled_iterator =
+            PooledIterator::new(&thread_pool, Arc::clone(&octrees), &query, 7000, 4, 2);
+        let summary = pooled_iterator
+            .try_for_each_batch(|points_batch| c.consume(points_batch))
+            .unwrap();
+        assert_eq!(c.num_received_points, NUM_POINTS);
+        assert_eq!(summary.points, NUM_POINTS);
+    }
+
+    // An erroring callback ends the query, and the pool stays usable.
+    let mut c = Consumer::new(10_000);
+    PooledIterator::new(&thread_pool, Arc::clone(&octrees), &query, 5000, 4, 2)
+        .try_for_each_batch(|points_batch| c.consume(points_batch))
+        .expect_err("Iterator did not error even though callback errored.");
+    let summary = PooledIterator::new(&thread_pool, octrees, &query, 7000, 4, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .unwrap();
+    assert_eq!(summary.points, NUM_POINTS);
+}
+
+#[test]
+fn test_batch_bounding_boxes() {
+    let octree = build_test_octree();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let octree_slice: &[Octree] = std::slice::from_ref(&octree);
+    let mut parallel_iterator = ParallelIterator::new(octree_slice, &query, 7000, 2, 2);
+    parallel_iterator
+        .try_for_each_batch(|points_batch| {
+            let bounding_box = points_batch.bounding_box.unwrap();
+            for i in 0..3 {
+                let coords = points_batch.position.iter().map(|p| p[i]);
+                let min = coords.clone().fold(std::f64::INFINITY, f64::min);
+                let max = coords.fold(std::f64::NEG_INFINITY, f64::max);
+                assert_eq!(bounding_box.min()[i], min);
+                assert_eq!(bounding_box.max()[i], max);
+            }
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_coordinate_system_round_trip() {
+    let dir = TempDir::new("octree").unwrap();
+    let local_from_ecef =
+        crate::math::local_frame_from_lat_lng(48.137_154, 11.576_124).to_homogeneous();
+    let options = BuildOptions {
+        coordinate_system: Some(CoordinateSystem::LocalFromEcef(local_from_ecef)),
+        ..Default::default()
+    };
+    let points = vec![Point3::new(-1.0, 2.0, 3.0), Point3::new(0.5, -0.25, 1.0)];
+    build_octree_with_options(
+        dir.path(),
+        0.01,
+        Aabb::from_points(&points).unwrap(),
+        vec![blue_batch(points)].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    // Exact comparison, the matrix must not lose precision.
+    assert_eq!(
+        octree.coordinate_system(),
+        Some(&CoordinateSystem::LocalFromEcef(local_from_ecef))
+    );
+    assert_eq!(build_test_octree().coordinate_system(), None);
+}
+
+#[test]
+fn test_y_up_import() {
+    let dir = TempDir::new("octree").unwrap();
+    let options = BuildOptions {
+        input_axes: AxisConvention::YUp,
+        json_summary: true,
+        ..Default::default()
+    };
+    // The point is 2 up and 3 towards the viewer in Y-up, i.e. 3 to the back in Z-up.
+    let y_up = Point3::new(1.0, 2.0, 3.0);
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0)),
+        vec![blue_batch(vec![y_up])].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    assert_eq!(octree.input_axes(), AxisConvention::YUp);
+    assert_eq!(
+        OctreeSummary::from_file(dir.path().join(SUMMARY_FILENAME))
+            .unwrap()
+            .input_axes,
+        AxisConvention::YUp
+    );
+    assert_eq!(build_test_octree().input_axes(), AxisConvention::ZUp);
+    assert_eq!(
+        octree.bounding_box(),
+        &Aabb::new(Point3::new(0.0, -4.0, 0.0), Point3::new(4.0, 0.0, 4.0))
+    );
+
+    let query_positions = |output_transforms: Vec<OutputTransform>| {
+        let query = PointQuery {
+            output_transforms,
+            ..Default::default()
+        };
+        let mut positions = Vec::new();
+        ParallelIterator::new(std::slice::from_ref(&octree), &query, 10, 1, 1)
+            .try_for_each_batch(|batch| {
+                positions.extend(batch.position);
+                Ok(())
+            })
+            .unwrap();
+        positions
+    };
+    let z_up = query_positions(Vec::new());
+    assert_eq!(z_up.len(), 1);
+    assert!((z_up[0] - Point3::new(1.0, -3.0, 2.0)).norm() < 0.001);
+    // Exporting to Y-up restores the input.
+    let exported = query_positions(vec![OutputTransform::ConvertAxes {
+        to: AxisConvention::YUp,
+    }]);
+    assert!((exported[0] - y_up).norm() < 0.001);
+}
+
+#[test]
+fn test_input_transform() {
+    let dir = TempDir::new("octree").unwrap();
+    // A quarter turn around z, scaled by 2 and moved away from the origin.
+    let world_from_sensor = Similarity3::new(
+        Vector3::new(10.0, 20.0, 30.0),
+        Vector3::z() * std::f64::consts::FRAC_PI_2,
+        2.0,
+    );
+    let options = BuildOptions {
+        original_index: true,
+        max_points_per_node: 10,
+        input_transform: Some(world_from_sensor),
+        ..Default::default()
+    };
+    let sensor_positions: Vec<Point3<f64>> = (0..100)
+        .map(|i| Point3::new(f64::from(i % 5), f64::from(i / 5 % 5), f64::from(i / 25)) * 0.5)
+        .collect();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(2.0, 2.0, 2.0)),
+        vec![blue_batch(sensor_positions.clone())].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    // The box enclosing the transformed bounding box.
+    let expected_bounding_box =
+        Aabb::new(Point3::new(6.0, 20.0, 30.0), Point3::new(10.0, 24.0, 34.0));
+    assert!((octree.bounding_box().min() - expected_bounding_box.min()).norm() < 1e-9);
+    assert!((octree.bounding_box().max() - expected_bounding_box.max()).norm() < 1e-9);
+
+    let query = PointQuery {
+        attributes: vec![ORIGINAL_INDEX_ATTRIBUTE],
+        ..Default::default()
+    };
+    let mut num_points = 0;
+    ParallelIterator::new(std::slice::from_ref(&octree), &query, 10, 1, 1)
+        .try_for_each_batch(|batch| {
+            let indices = batch.get_attribute_vec::<u64>(ORIGINAL_INDEX_ATTRIBUTE)?;
+            for (p, index) in batch.position.iter().zip(indices) {
+                let expected = world_from_sensor * sensor_positions[*index as usize];
+                assert!((p - expected).norm() < 0.01, "{} {}", p, expected);
+                num_points += 1;
+            }
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_points, 100);
+}
+
+#[test]
+fn test_wgs84_query_round_trips() {
+    let dir = TempDir::new("octree").unwrap();
+    let local_from_ecef =
+        crate::math::local_frame_from_lat_lng(48.137_154, 11.576_124).to_homogeneous();
+    let options = BuildOptions {
+        coordinate_system: Some(CoordinateSystem::LocalFromEcef(local_from_ecef)),
+        ..Default::default()
+    };
+    let points: Vec<Point3<f64>> = (0..100)
+        .map(|i| Point3::new(i as f64, -0.5 * i as f64, 0.1 * (i % 7) as f64))
+        .collect();
+    build_octree_with_options(
+        dir.path(),
+        0.01,
+        Aabb::from_points(&points).unwrap(),
+        vec![blue_batch(points)].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let query = PointQuery {
+        wgs84: true,
+        ..Default::default()
+    };
+    let mut num_points = 0;
+    ParallelIterator::new(std::slice::from_ref(&octree), &query, 30, 1, 1)
+        .try_for_each_batch(|batch| {
+            let latitudes: &Vec<f64> = batch.get_attribute_vec(LATITUDE_ATTRIBUTE)?;
+            let longitudes: &Vec<f64> = batch.get_attribute_vec(LONGITUDE_ATTRIBUTE)?;
+            let altitudes: &Vec<f64> = batch.get_attribute_vec(ALTITUDE_ATTRIBUTE)?;
+            for (i, p) in batch.position.iter().enumerate() {
+                // The points are within 100 m of the origin of the local frame.
+                assert!((latitudes[i] - 48.137_154).abs() < 0.01);
+                assert!((longitudes[i] - 11.576_124).abs() < 0.01);
+                let ecef = ECEF::from(WGS84::from_degrees_and_meters(
+                    latitudes[i],
+                    longitudes[i],
+                    altitudes[i],
+                ));
+                let local =
+                    local_from_ecef.transform_point(&Point3::new(ecef.x(), ecef.y(), ecef.z()));
+                assert!((local - p).norm() < 1e-6);
+            }
+            num_points += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_points, 100);
+
+    // Without georeferencing, the positions can't be converted.
+    assert!(build_test_octree()
+        .read_node_for_query(&query, NodeId::root(), 30, false)
+        .is_err());
+}
+
+#[test]
+fn test_grid_aligned_roots() {
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        ..Default::default()
+    };
+    let build = |directory: &Path, points: &[Point3<f64>], root: &Cube, options: &BuildOptions| {
+        build_octree_with_options(
+            directory,
+            0.001,
+            root.to_aabb(),
+            vec![blue_batch(points.to_vec())].into_iter(),
+            &["color"],
+            options,
+        )
+    };
+    // Two neighboring datasets, built separately.
+    let points_a: Vec<_> = (0..1000)
+        .map(|i| Point3::new(f64::from(i) * 0.003, 1.0 + f64::from(i % 10) * 0.5, 2.0))
+        .collect();
+    let points_b: Vec<_> = points_a.iter().map(|p| p + Vector3::x() * 4.0).collect();
+    let root_for = |points: &[Point3<f64>]| {
+        let bounding_box = Aabb::from_points(points).unwrap();
+        Cube::aligned_to_grid(&bounding_box, &Point3::origin(), 8.0)
+    };
+    let (root_a, root_b) = (root_for(&points_a), root_for(&points_b));
+    assert_eq!(root_a.min(), root_b.min());
+    assert_eq!(root_a.edge_length(), root_b.edge_length());
+
+    let (dir_a, dir_b) = (
+        TempDir::new("octree").unwrap(),
+        TempDir::new("octree").unwrap(),
+    );
+    build(dir_a.path(), &points_a, &root_a, &options).unwrap();
+    build(dir_b.path(), &points_b, &root_b, &options).unwrap();
+    let (octree_a, octree_b) = (
+        open_test_octree(dir_a.path()),
+        open_test_octree(dir_b.path()),
+    );
+    let mut num_shared_nodes = 0;
+    for (id, node_meta) in octree_a.nodes.iter() {
+        // Every node is a cell of the global grid at its level.
+        let cube = &node_meta.bounding_cube;
+        for coord in cube.min().iter() {
+            assert_eq!((coord / cube.edge_length()).fract(), 0.0, "{}", id);
+        }
+        if let Some(other_meta) = octree_b.nodes.get(id) {
+            num_shared_nodes += 1;
+            assert_eq!(other_meta.bounding_cube.min(), cube.min());
+            assert_eq!(other_meta.bounding_cube.edge_length(), cube.edge_length());
+        }
+    }
+    assert!(octree_a.nodes.len() > 1);
+    assert!(num_shared_nodes >= 1);
+
+    // Points outside of the root fail the build, unless they are dropped.
+    let mut points = points_a.clone();
+    points.push(Point3::new(-1.0, 0.0, 0.0));
+    let dir = TempDir::new("octree").unwrap();
+    assert!(build(dir.path(), &points, &root_a, &options).is_err());
+    let options = BuildOptions {
+        out_of_bounds: OutOfBounds::Drop,
+        ..options
+    };
+    build(dir.path(), &points, &root_a, &options).unwrap();
+    assert_eq!(open_test_octree(dir.path()).num_points(), points_a.len());
+}
+
+#[test]
+fn test_compute_extent() {
+    let points: Vec<Point3<f64>> = (0..100)
+        .map(|i| {
+            let i = f64::from(i);
+            Point3::new(
+                (i * 0.7).sin() * 10.0,
+                i * 0.1 - 3.0,
+                (i * 1.3).cos() + 100.0,
+            )
+        })
+        .collect();
+    let batches: Vec<PointsBatch> = points
+        .chunks(7)
+        .map(|chunk| blue_batch(chunk.to_vec()))
+        .chain(std::iter::once(blue_batch(Vec::new())))
+        .collect();
+    let extent = compute_extent(batches);
+    let min = points.iter().fold(points[0], |min, p| min.inf(p));
+    let max = points.iter().fold(points[0], |max, p| max.sup(p));
+    assert_eq!(extent, Aabb::new(min, max));
+    assert_eq!(compute_extent(Vec::new()), Aabb::zero());
+
+    assert!(recommend_root(&extent, &Point3::origin(), 0.5).is_err());
+    let grid_origin = Point3::new(-1000.0, -1000.0, -1000.0);
+    let root = recommend_root(&extent, &grid_origin, 0.5).unwrap();
+    let root_cube = Cube::bounding(&root);
+    assert!(
+        nalgebra::partial_le(&root_cube.min(), &min)
+            && nalgebra::partial_le(&max, &root_cube.max())
+    );
+    // The edge length is a power of two times the cell size, and the root is on the grid.
+    let num_cells = root_cube.edge_length() / 0.5;
+    assert_eq!(num_cells, 2f64.powi(num_cells.log2().round() as i32));
+    for coord in (root_cube.min() - grid_origin).iter() {
+        assert_eq!((coord / root_cube.edge_length()).fract(), 0.0);
+    }
+
+    // Would never reach an edge length that encloses the extent.
+    let not_finite = Aabb::new(min, Point3::new(max.x, std::f64::INFINITY, max.z));
+    assert!(recommend_root(&not_finite, &grid_origin, 0.5).is_err());
+    assert!(recommend_root(&extent, &grid_origin, std::f64::INFINITY).is_err());
+    assert!(recommend_root(&extent, &grid_origin, 0.0).is_err());
+}
+
+#[test]
+fn test_approx_memory_bytes_include_cache() {
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_test_octree_in(dir.path());
+    let uncached_bytes = octree.approx_memory_bytes();
+    assert!(uncached_bytes > 0);
+
+    let data_provider = OnDiskDataProvider {
+        directory: dir.path().to_owned(),
+    };
+    let cached_octree = Octree::from_data_provider(Box::new(CachingDataProvider::new(
+        Box::new(data_provider),
+        100_000_000,
+    )))
+    .unwrap();
+    let bytes_before_query = cached_octree.approx_memory_bytes();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    ParallelIterator::new(std::slice::from_ref(&cached_octree), &query, 7000, 2, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .unwrap();
+    // At least the positions and colors of all points are cached now.
+    assert!(cached_octree.approx_memory_bytes() >= bytes_before_query + NUM_POINTS * 4);
+}
+
+#[test]
+fn test_io_threads_return_the_same_points() {
+    let num_points = 20_000;
+    let dir = TempDir::new("octree").unwrap();
+    let (octree, _) = build_grid_octree(dir.path(), [40, 25, 20], 0.1, 500);
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let run = |num_io_threads: usize| {
+        let mut positions = Vec::new();
+        let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 1000, 2, 4)
+            .io_threads(num_io_threads)
+            .try_for_each_batch(|batch| {
+                positions.extend(batch.position.iter().map(|p| (p.x, p.y, p.z)));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(summary.points, num_points);
+        positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        positions
+    };
+    assert_eq!(run(0), run(16));
+}
+
+/// Counts the nodes read, with a delay like a data provider with a high latency.
+struct CountingDataProvider {
+    data_provider: OnDiskDataProvider,
+    num_reads: Arc<AtomicUsize>,
+}
+
+impl DataProvider for CountingDataProvider {
+    fn meta_proto(&self) -> Result<proto::Meta> {
+        self.data_provider.meta_proto()
+    }
+
+    fn data(
+        &self,
+        node_id: &str,
+        node_attributes: &[&str],
+    ) -> Result<HashMap<String, Box<dyn Read + Send>>> {
+        self.num_reads.fetch_add(1, AtomicOrdering::SeqCst);
+        std::thread::sleep(Duration::from_millis(5));
+        self.data_provider.data(node_id, node_attributes)
+    }
+}
+
+#[test]
+fn test_dropping_batches_stops_query() {
+    let num_points = 20_000;
+    let dir = TempDir::new("octree").unwrap();
+    build_grid_octree(dir.path(), [40, 25, 20], 0.1, 500);
+    let num_reads = Arc::new(AtomicUsize::new(0));
+    let octree = Octree::from_data_provider(Box::new(CountingDataProvider {
+        data_provider: OnDiskDataProvider {
+            directory: dir.path().to_owned(),
+        },
+        num_reads: Arc::clone(&num_reads),
+    }))
+    .unwrap();
+    let num_nodes = octree.nodes_in_location(&PointLocation::AllPoints).len();
+    let octrees: Arc<[Octree]> = Arc::from(vec![octree]);
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let pooled_iterator = PooledIterator::new(&thread_pool, octrees, &query, 100, 2, 1);
+
+    let points: usize = pooled_iterator
+        .batches()
+        .map(|batch| batch.unwrap().position.len())
+        .sum();
+    assert_eq!(points, num_points);
+    assert_eq!(num_reads.swap(0, AtomicOrdering::SeqCst), num_nodes);
+
+    let mut batches = pooled_iterator.batches();
+    assert!(!batches.next().unwrap().unwrap().position.is_empty());
+    drop(batches);
+    // The tasks have stopped once the iterator is dropped.
+    let num_reads_after_drop = num_reads.load(AtomicOrdering::SeqCst);
+    assert!(num_reads_after_drop < num_nodes / 2);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(num_reads.load(AtomicOrdering::SeqCst), num_reads_after_drop);
+}
+
+#[test]
+fn test_content_hash() {
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_test_octree_in(dir.path());
+    let hash = octree.content_hash().unwrap();
+    assert_eq!(hash, open_test_octree(dir.path()).content_hash().unwrap());
+    let other_dir = TempDir::new("octree").unwrap();
+    assert_eq!(
+        hash,
+        build_test_octree_in(other_dir.path())
+            .content_hash()
+            .unwrap()
+    );
+
+    // Append a point to the data of the root node.
+    let root_id = NodeId::from_level_index(0, 0);
+    let mut writer = RawNodeWriter::new(
+        dir.path().join(root_id.to_string()),
+        octree.encoding_for_node(root_id),
+        OpenMode::Append,
+    );
+    writer
+        .write(&PointsBatch {
+            position: vec![Point3::new(-1.0, -1.0, 1.0)],
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(0, 255, 0)]),
+            )]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        })
+        .unwrap();
+    drop(writer);
+    assert_ne!(hash, open_test_octree(dir.path()).content_hash().unwrap());
+
+    // Changing only the meta data changes the hash as well.
+    let local_from_ecef =
+        crate::math::local_frame_from_lat_lng(48.137_154, 11.576_124).to_homogeneous();
+    let changes: [&dyn Fn(&mut proto::OctreeMeta); 3] = [
+        &|meta| {
+            meta.set_coordinate_system(CoordinateSystem::LocalFromEcef(local_from_ecef).to_proto())
+        },
+        &|meta| meta.set_leaves_decimated(true),
+        &|meta| {
+            meta.mut_attribute_annotations().push(
+                AttributeAnnotation {
+                    unit: Some("W/m^2".to_string()),
+                    ..Default::default()
+                }
+                .to_proto("intensity"),
+            )
+        },
+    ];
+    let original_meta = open_test_octree(other_dir.path()).to_meta_proto();
+    for change in &changes {
+        let mut meta_proto = original_meta.clone();
+        change(meta_proto.mut_octree());
+        let mut meta_file = std::fs::File::create(other_dir.path().join(META_FILENAME)).unwrap();
+        protobuf::Message::write_to_writer(&meta_proto, &mut meta_file).unwrap();
+        drop(meta_file);
+        assert_ne!(
+            hash,
+            open_test_octree(other_dir.path()).content_hash().unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_diff_octrees() {
+    let dir_a = TempDir::new("octree").unwrap();
+    let dir_b = TempDir::new("octree").unwrap();
+    let octree_a = build_test_octree_in(dir_a.path());
+    assert!(diff_octrees(&octree_a, &build_test_octree_in(dir_b.path()))
+        .unwrap()
+        .is_empty());
+
+    // Recolor the points of the deepest node, keeping their number.
+    let (changed_id, _) = octree_a
+        .nodes
+        .iter()
+        .filter(|(_, meta)| meta.num_points > 0)
+        .max_by_key(|(id, _)| id.level())
+        .unwrap();
+    assert!(changed_id.level() > 0);
+    let color_path = dir_b.path().join(format!("{}.rgb", changed_id));
+    let recolored: Vec<u8> = std::fs::read(&color_path)
+        .unwrap()
+        .iter()
+        .map(|c| 255 - c)
+        .collect();
+    std::fs::write(&color_path, recolored).unwrap();
+    let octree_b = open_test_octree(dir_b.path());
+    assert_eq!(
+        diff_octrees(&octree_a, &octree_b).unwrap(),
+        vec![*changed_id]
+    );
+
+    let other_dir = TempDir::new("octree").unwrap();
+    let other_root = build_color_intensity_octree(other_dir.path(), 10);
+    assert!(diff_octrees(&octree_a, &other_root).is_err());
+}
+
+/// Builds an octree with points along the x axis, which are red and have an intensity of 1.
+/// Builds an octree of `num_points` points along x, y and z, on a grid with the `spacing` from the
+/// origin, into `directory`. Returns it with the positions of the points.
+fn build_grid_octree(
+    directory: &Path,
+    num_points: [u32; 3],
+    spacing: f64,
+    max_points_per_node: i64,
+) -> (Octree, Vec<Point3<f64>>) {
+    let [num_x, num_y, num_z] = num_points;
+    let positions: Vec<Point3<f64>> = (0..num_x * num_y * num_z)
+        .map(|i| {
+            Point3::new(
+                f64::from(i % num_x),
+                f64::from(i / num_x % num_y),
+                f64::from(i / (num_x * num_y)),
+            ) * spacing
+        })
+        .collect();
+    let options = BuildOptions {
+        max_points_per_node,
+        ..Default::default()
+    };
+    let max = Point3::new(f64::from(num_x), f64::from(num_y), f64::from(num_z)) * spacing;
+    build_octree_with_options(
+        directory,
+        0.001,
+        Aabb::new(Point3::origin(), max),
+        vec![blue_batch(positions.clone())].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    (open_test_octree(directory), positions)
+}
+
+fn build_color_intensity_octree(directory: &Path, num_points: usize) -> Octree {
+    let batch = PointsBatch {
+        position: (0..num_points)
+            .map(|i| Point3::new(i as f64, 0.0, 0.0))
+            .collect(),
+        attributes: vec![
+            (
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+            ),
+            (
+                "intensity".to_string(),
+                AttributeData::F32(vec![1.0; num_points]),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        bounding_box: None,
+    };
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(num_points as f64, 1.0, 1.0));
+    build_octree(
+        directory,
+        1.0,
+        bounding_box,
+        vec![batch].into_iter(),
+        &["color", "intensity"],
+    );
+    open_test_octree(directory)
+}
+
+#[test]
+fn test_rebuild_keeps_attribute_data_types() {
+    let num_points = 1000;
+    let batch = PointsBatch {
+        position: (0..num_points)
+            .map(|i| Point3::new(f64::from(i) * 0.1, 0.0, 0.0))
+            .collect(),
+        attributes: vec![
+            (
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points as usize]),
+            ),
+            (
+                "intensity".to_string(),
+                AttributeData::U16((0..num_points).map(|i| i as u16).collect()),
+            ),
+            (
+                "temperature".to_string(),
+                AttributeData::F64((0..num_points).map(f64::from).collect()),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        bounding_box: None,
+    };
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(100.0, 1.0, 1.0));
+    let attributes = &["color", "intensity", "temperature"];
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        attribute_data_types: vec![
+            ("intensity".to_string(), AttributeDataType::U16),
+            ("temperature".to_string(), AttributeDataType::F64),
+        ]
+        .into_iter()
+        .collect(),
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path().join("custom"),
+        0.01,
+        bounding_box,
+        vec![batch].into_iter(),
+        attributes,
+        &options,
+    )
+    .unwrap();
+    let custom = open_test_octree(&dir.path().join("custom"));
+
+    build_octree_from_point_clouds(
+        std::slice::from_ref(&custom),
+        dir.path().join("rebuilt"),
+        0.01,
+        attributes,
+        &BuildOptions::default(),
+    )
+    .unwrap();
+    let rebuilt = open_test_octree(&dir.path().join("rebuilt"));
+    assert_eq!(
+        rebuilt.attribute_data_types().get("intensity"),
+        Some(&AttributeDataType::U16)
+    );
+    let query = PointQuery {
+        attributes: attributes.to_vec(),
+        ..Default::default()
+    };
+    let mut temperatures = Vec::new();
+    ParallelIterator::new(std::slice::from_ref(&rebuilt), &query, 100, 1, 1)
+        .try_for_each_batch(|batch| {
+            let temperature: &Vec<f64> = batch.get_attribute_vec("temperature").unwrap();
+            temperatures.extend_from_slice(temperature);
+            Ok(())
+        })
+        .unwrap();
+    temperatures.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert!(temperatures.into_iter().eq((0..num_points).map(f64::from)));
+
+    // The inputs must agree on the data types.
+    let standard = build_color_intensity_octree(&dir.path().join("standard"), 10);
+    let result = build_octree_from_point_clouds(
+        &[custom, standard],
+        dir.path().join("mixed"),
+        0.01,
+        &["intensity"],
+        &BuildOptions::default(),
+    );
+    match result.unwrap_err().kind() {
+        ErrorKind::InvalidInput(message) => assert!(message.contains("intensity")),
+        kind => panic!("Unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_all_attributes_query() {
+    let num_points = 10;
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_color_intensity_octree(dir.path(), num_points);
+
+    let mut declared: Vec<&String> = octree.attribute_data_types().keys().collect();
+    declared.sort();
+    let query = PointQuery {
+        attributes: vec![ALL_ATTRIBUTES],
+        ..Default::default()
+    };
+    let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+        .try_for_each_batch(|batch| {
+            assert!(batch.attributes.keys().eq(declared.iter().copied()));
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(summary.points, num_points);
+}
+
+#[test]
+fn test_parallel_iterator_returns_thread_errors() {
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_color_intensity_octree(dir.path(), 10);
+    let queries = vec![
+        PointQuery {
+            attributes: vec!["normal"],
+            ..Default::default()
+        },
+        PointQuery {
+            attributes: vec!["-intensity"],
+            filter_intervals: vec![("intensity", ClosedInterval::new(0.0, 2.0))]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        },
+    ];
+    for query in &queries {
+        let result = ParallelIterator::new(std::slice::from_ref(&octree), query, 100, 2, 1)
+            .try_for_each_batch(|_| Ok(()));
+        assert!(result.is_err());
+    }
+}
+
+#[test]
+fn test_rgba_query() {
+    let num_points = 10;
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_color_intensity_octree(dir.path(), num_points);
+    let query = PointQuery {
+        attributes: vec![RGBA_ATTRIBUTE, "intensity"],
+        rgba_intensity_range: Some(ClosedInterval::new(0.0, 2.0)),
+        ..Default::default()
+    };
+    let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+        .try_for_each_batch(|batch| {
+            // Color was only read for packing.
+            assert!(batch.attributes.keys().eq(&["intensity", RGBA_ATTRIBUTE]));
+            let rgba: &Vec<u32> = batch.get_attribute_vec(RGBA_ATTRIBUTE).unwrap();
+            for value in rgba {
+                assert_eq!(value.to_le_bytes(), [255, 0, 0, 128]);
+            }
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(summary.points, num_points);
+
+    // Point clouds without color can not pack it. S2 cells are made from points on the earth.
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new(6_378_137.0 + i as f64, 0.0, 0.0))
+        .collect();
+    let batch = PointsBatch {
+        attributes: vec![
+            (
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+            ),
+            (
+                "intensity".to_string(),
+                AttributeData::F32(vec![1.0; num_points]),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        ..blue_batch(positions.clone())
+    };
+    let earth_dir = dir.path().join("earth");
+    build_octree(
+        &earth_dir,
+        0.01,
+        Aabb::from_points(&positions).unwrap(),
+        vec![batch].into_iter(),
+        &["color", "intensity"],
+    );
+    let s2_dir = dir.path().join("s2");
+    octree_to_s2_cells(&open_test_octree(&earth_dir), &s2_dir, 10, &["intensity"]).unwrap();
+    let s2_cells =
+        S2Cells::from_data_provider(Box::new(OnDiskDataProvider { directory: s2_dir })).unwrap();
+    let result = ParallelIterator::new(std::slice::from_ref(&s2_cells), &query, 100, 1, 1)
+        .try_for_each_batch(|_| Ok(()));
+    match result.unwrap_err().kind() {
+        ErrorKind::InvalidQuery(message) => assert!(message.contains("'color'")),
+        kind => panic!("Unexpected error: {:?}", kind),
+    }
+}
+
+#[test]
+fn test_query_weight_in_sphere() {
+    let num_points = 11;
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_color_intensity_octree(dir.path(), num_points);
+    let query = PointQuery {
+        attributes: vec![QUERY_WEIGHT_ATTRIBUTE],
+        location: PointLocation::Sphere(Sphere::new(Point3::new(5.0, 0.0, 0.0), 5.0)),
+        ..Default::default()
+    };
+    let mut weights = Vec::new();
+    ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+        .try_for_each_batch(|batch| {
+            assert!(batch.attributes.keys().eq(&[QUERY_WEIGHT_ATTRIBUTE]));
+            let batch_weights: &Vec<f32> = batch.get_attribute_vec(QUERY_WEIGHT_ATTRIBUTE).unwrap();
+            weights.extend(
+                batch
+                    .position
+                    .iter()
+                    .map(|p| p.x)
+                    .zip(batch_weights.iter().copied()),
+            );
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(weights.len(), num_points);
+    for (x, weight) in weights {
+        let expected = 1.0 - (x as f32 - 5.0).abs() / 5.0;
+        assert!((weight - expected).abs() < 1e-6, "{} at {}", weight, x);
+        if x == 5.0 {
+            assert_eq!(weight, 1.0);
+        }
+        if x == 0.0 || x == 10.0 {
+            assert!(weight.abs() < 1e-6);
+        }
+    }
+
+    let query = PointQuery {
+        attributes: vec![QUERY_WEIGHT_ATTRIBUTE],
+        ..Default::default()
+    };
+    // Errors in the parallel iterator's threads panic, so this streams a node directly.
+    let result = octree.stream_points_for_query_in_node(&query, NodeId::root(), 100, |_| Ok(()));
+    match result.unwrap_err().kind() {
+        ErrorKind::InvalidQuery(_) => (),
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
+
+#[test]
+fn test_json_queries() {
+    let num_points = 10;
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_color_intensity_octree(dir.path(), num_points);
+    // The points lie at x = 0, 1, ..., 9.
+    let locations_and_num_points = vec![
+        (r#""AllPoints""#.to_string(), num_points),
+        (r#"{"AllPointsInDepthRange": 20}"#.to_string(), num_points),
+        (
+            r#"{"Aabb": {"mins": [1.5, -1, -1], "maxs": [4.5, 1, 1]}}"#.to_string(),
+            3,
+        ),
+        (
+            // The identity matrix clips everything outside of [-1, 1] in all coordinates.
+            format!(
+                r#"{{"Frustum": {{"clip_from_query": {:?}}}}}"#,
+                nalgebra::Matrix4::<f64>::identity().as_slice()
+            ),
+            1,
+        ),
+        (
+            r#"{"Obb": {"query_from_obb": {"rotation": [0, 0, 0, 1], "translation": [5, 0, 0]},
+                "half_extent": [2.5, 1, 1]}}"#
+                .to_string(),
+            5,
+        ),
+        // The cell on the positive x axis contains all points but the origin.
+        (r#"{"S2Cells": [1152921504607895552]}"#.to_string(), 9),
+        (
+            r#"{"Sphere": {"center": [0, 0, 0], "radius": 2}}"#.to_string(),
+            3,
+        ),
+        (
+            r#"{"WebMercatorRect": {"north_west": {"normalized": [0.1, 0.1]},
+                "south_east": {"normalized": [0.100001, 0.100001]}}}"#
+                .to_string(),
+            0,
+        ),
+    ];
+    for (location, expected_num_points) in locations_and_num_points {
+        let json = format!(
+            r#"{{
+                "attributes": ["color", "intensity"],
+                "location": {},
+                "filter_intervals": {{"intensity": {{"lower_bound": 0.5, "upper_bound": 1.5}}}}
+            }}"#,
+            location
+        );
+        let query = PointQuery::from_json(&json).unwrap();
+        let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+            .try_for_each_batch(|batch| {
+                assert!(batch.attributes.keys().eq(&["color", "intensity"]));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(summary.points, expected_num_points, "{}", location);
+    }
+}
+
+#[test]
+fn test_snap_to_grid() {
+    let dir = TempDir::new("octree").unwrap();
+    let position: Vec<Point3<f64>> = (0..1000)
+        .map(|i| {
+            let i = f64::from(i);
+            Point3::new(0.0137 * i, 0.5 + 0.0071 * i, 2.0 - 0.0029 * i)
+        })
+        .collect();
+    let bounding_box = Aabb::from_points(&position).unwrap();
+    build_octree(
+        dir.path(),
+        0.001,
+        bounding_box,
+        vec![blue_batch(position)].into_iter(),
+        &["color"],
+    );
+    let octree = open_test_octree(dir.path());
+
+    let query = PointQuery::from_json(r#"{"output_transforms": [{"SnapToGrid": {"cell": 0.01}}]}"#)
+        .unwrap();
+    let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+        .try_for_each_batch(|batch| {
+            for p in &batch.position {
+                for c in p.iter() {
+                    let multiple = c / 0.01;
+                    assert!((multiple - multiple.round()).abs() < 1e-9, "{}", p);
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(summary.points, 1000);
+
+    assert!(
+        PointQuery::from_json(r#"{"output_transforms": [{"SnapToGrid": {"cell": 0}}]}"#).is_err()
+    );
+}
+
+#[test]
+fn test_open_incomplete_octree() {
+    let open = |directory: &Path| {
+        Octree::from_data_provider(Box::new(OnDiskDataProvider {
+            directory: directory.to_owned(),
+        }))
+        .map(|_| ())
+        .unwrap_err()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    let directory = dir.path().display().to_string();
+    match open(dir.path()).kind() {
+        ErrorKind::PointCloudNotFound(path) => assert_eq!(path, &directory),
+        other => panic!("Unexpected error {:?}", other),
+    }
+    match open(&dir.path().join("does_not_exist")).kind() {
+        ErrorKind::PointCloudNotFound(_) => (),
+        other => panic!("Unexpected error {:?}", other),
+    }
+
+    // Node data, but no meta data yet.
+    std::fs::write(dir.path().join("r.xyz"), b"").unwrap();
+    match open(dir.path()).kind() {
+        ErrorKind::IncompleteBuild(path) => assert_eq!(path, &directory),
+        other => panic!("Unexpected error {:?}", other),
+    }
+    std::fs::write(dir.path().join(META_FILENAME), b"").unwrap();
+    match open(dir.path()).kind() {
+        ErrorKind::IncompleteBuild(_) => (),
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
+
+#[test]
+fn test_morton_order() {
+    // The centers of a grid of cells in reverse order, tagged with their index in the intensity.
+    // Being at the centers, the points stay in their cells despite the position encoding.
+    let positions: Vec<Point3<f64>> = (0..16 * 16 * 16)
+        .rev()
+        .map(|i| Point3::new((i / 256) as f64, (i / 16 % 16) as f64, (i % 16) as f64))
+        .map(|p| p + Vector3::repeat(0.5))
+        .collect();
+    let num_points = positions.len();
+    let batch = PointsBatch {
+        position: positions.clone(),
+        attributes: vec![
+            (
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+            ),
+            (
+                "intensity".to_string(),
+                AttributeData::F32((0..num_points).map(|i| i as f32).collect()),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        bounding_box: None,
+    };
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        morton_order: true,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(16.0, 16.0, 16.0));
+    build_octree_with_options(
+        dir.path(),
+        1.0,
+        bounding_box,
+        vec![batch].into_iter(),
+        &["color", "intensity"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    assert!(octree.points_in_morton_order());
+    assert!(!build_test_octree().points_in_morton_order());
+
+    let root_cube = Cube::bounding(octree.bounding_box());
+    let node_ids: Vec<NodeId> = octree.nodes_in_location(&PointLocation::AllPoints);
+    assert!(node_ids.iter().any(|id| id.level() == 2));
+    for node_id in node_ids {
+        let bounding_cube = node_id.find_bounding_cube(&root_cube);
+        let mut num_points_in_node = 0;
+        let mut last_code = 0;
+        for batch in octree
+            .points_in_node(&["intensity"], node_id, 10, None)
+            .unwrap()
+        {
+            let indices: &Vec<f32> = batch.get_attribute_vec("intensity").unwrap();
+            for index in indices {
+                let code = bounding_cube.morton_code(&positions[*index as usize]);
+                assert!(code >= last_code, "{} is not sorted", node_id);
+                last_code = code;
+            }
+            num_points_in_node += indices.len();
+        }
+        assert_eq!(
+            num_points_in_node as u64,
+            octree.node_point_count(node_id).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_lossless_and_decimated_leaves() {
+    // All points fall into a single node of the resolution's size, which cannot be split further.
+    let num_points = 2000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new((i % 10) as f64, (i / 10 % 10) as f64, (i / 100) as f64) * 0.02)
+        .collect();
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0));
+    let count_points = |decimate_leaves: bool| {
+        let options = BuildOptions {
+            max_points_per_node: 100,
+            decimate_leaves,
+            ..Default::default()
+        };
+        let dir = TempDir::new("octree").unwrap();
+        build_octree_with_options(
+            dir.path(),
+            1.0,
+            bounding_box.clone(),
+            vec![blue_batch(positions.clone())].into_iter(),
+            &["color"],
+            &options,
+        )
+        .unwrap();
+        let octree = open_test_octree(dir.path());
+        assert_eq!(octree.leaves_decimated(), decimate_leaves);
+        let query = PointQuery {
+            attributes: vec!["color"],
+            location: PointLocation::AllPoints,
+            ..Default::default()
+        };
+        ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+            .try_for_each_batch(|_| Ok(()))
+            .unwrap()
+            .points
+    };
+    assert_eq!(count_points(false), num_points);
+    // Subsampling moves points of the leaf into its ancestors, but does not drop any.
+    assert_eq!(count_points(true), 100);
+
+    // A leaf that can not be decimated fails the build. A file in the place of the directory the
+    // leaf is moved into makes this fail.
+    let dir = TempDir::new("octree").unwrap();
+    std::fs::write(dir.path().join("spill"), b"").unwrap();
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        decimate_leaves: true,
+        ..Default::default()
+    };
+    assert!(build_octree_with_options(
+        dir.path(),
+        1.0,
+        bounding_box,
+        vec![blue_batch(positions)].into_iter(),
+        &["color"],
+        &options,
+    )
+    .is_err());
+}
+
+#[test]
+fn test_build_fails_if_meta_data_can_not_be_written() {
+    let dir = TempDir::new("octree").unwrap();
+    // A directory in the place of the meta data can not be opened for writing.
+    std::fs::create_dir(dir.path().join(META_FILENAME)).unwrap();
+    let positions: Vec<Point3<f64>> = (0..100)
+        .map(|i| Point3::new(f64::from(i), 0.0, 0.0))
+        .collect();
+    assert!(build_octree_with_options(
+        dir.path(),
+        1.0,
+        Aabb::new(Point3::origin(), Point3::new(100.0, 1.0, 1.0)),
+        vec![blue_batch(positions)].into_iter(),
+        &["color"],
+        &BuildOptions::default(),
+    )
+    .is_err());
+}
+
+#[test]
+fn test_attribute_codecs() {
+    let num_points = 8000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new((i % 20) as f64, (i / 20 % 20) as f64, (i / 400) as f64) * 0.2)
+        .collect();
+    let mut batch = blue_batch(positions);
+    // Few distinct values in large regions, like a classification.
+    let intensity = (0..num_points).map(|i| (i / 2000) as f32).collect();
+    batch
+        .attributes
+        .insert("intensity".to_string(), AttributeData::F32(intensity));
+    let build = |attribute_codecs: Vec<(&str, Vec<AttributeCodec>)>| {
+        let options = BuildOptions {
+            max_points_per_node: 500,
+            morton_order: true,
+            attribute_codecs: attribute_codecs
+                .into_iter()
+                .map(|(name, codecs)| (name.to_string(), codecs))
+                .collect(),
+            ..Default::default()
+        };
+        let dir = TempDir::new("octree").unwrap();
+        build_octree_with_options(
+            dir.path(),
+            0.001,
+            Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0)),
+            vec![batch.clone()].into_iter(),
+            &["color", "intensity"],
+            &options,
+        )
+        .map(|()| dir)
+    };
+    let num_bytes = |dir: &TempDir| -> u64 {
+        std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    // The points of every node, in the stored order.
+    type NodePoints = (Vec<Point3<f64>>, Vec<Vector3<u8>>, Vec<f32>);
+    let read_nodes = |dir: &TempDir| -> HashMap<NodeId, NodePoints> {
+        let octree = open_test_octree(dir.path());
+        octree
+            .nodes_in_location(&PointLocation::AllPoints)
+            .into_iter()
+            .map(|id| {
+                let mut points = NodePoints::default();
+                for batch in octree
+                    .points_in_node(&["color", "intensity"], id, 100, None)
+                    .unwrap()
+                {
+                    points.0.extend(&batch.position);
+                    points
+                        .1
+                        .extend(batch.get_attribute_vec::<Vector3<u8>>("color").unwrap());
+                    points
+                        .2
+                        .extend(batch.get_attribute_vec::<f32>("intensity").unwrap());
+                }
+                (id, points)
+            })
+            .collect()
+    };
+
+    let plain = build(Vec::new()).unwrap();
+    let deflate = vec![AttributeCodec::Deflate];
+    let single_codec = build(vec![
+        ("position", deflate.clone()),
+        ("color", deflate.clone()),
+        ("intensity", deflate),
+    ])
+    .unwrap();
+    let per_attribute = build(vec![
+        (
+            "position",
+            vec![AttributeCodec::Delta, AttributeCodec::Deflate],
+        ),
+        ("color", vec![AttributeCodec::RunLength]),
+        (
+            "intensity",
+            vec![AttributeCodec::RunLength, AttributeCodec::Deflate],
+        ),
+    ])
+    .unwrap();
+    let octree = open_test_octree(per_attribute.path());
+    assert_eq!(
+        octree.attribute_codecs()["position"],
+        vec![AttributeCodec::Delta, AttributeCodec::Deflate]
+    );
+    assert!(open_test_octree(plain.path()).attribute_codecs().is_empty());
+
+    let plain_nodes = read_nodes(&plain);
+    assert!(plain_nodes.len() > 8);
+    assert_eq!(read_nodes(&single_codec), plain_nodes);
+    assert_eq!(read_nodes(&per_attribute), plain_nodes);
+    assert!(num_bytes(&single_codec) < num_bytes(&plain));
+    assert!(num_bytes(&per_attribute) < num_bytes(&single_codec));
+
+    // Re-encoding writes the attribute without its codecs.
+    reencode_attribute(per_attribute.path(), "intensity", AttributeDataType::U8).unwrap();
+    let octree = open_test_octree(per_attribute.path());
+    assert!(!octree.attribute_codecs().contains_key("intensity"));
+    assert_eq!(octree.attribute_codecs().len(), 2);
+    for (id, (_, _, plain_intensities)) in &plain_nodes {
+        let intensities: Vec<f32> = octree
+            .points_in_node(&["intensity"], *id, 100, None)
+            .unwrap()
+            .flat_map(|batch| batch.get_attribute_vec::<u8>("intensity").unwrap().clone())
+            .map(f32::from)
+            .collect();
+        assert_eq!(&intensities, plain_intensities);
+    }
+
+    assert!(build(vec![("classification", vec![AttributeCodec::RunLength])]).is_err());
+}
+
+#[test]
+fn test_max_depth() {
+    let num_points = 8000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new((i % 20) as f64, (i / 20 % 20) as f64, (i / 400) as f64) * 0.2)
+        .collect();
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0));
+    let build = |max_depth: Option<u8>, decimate_leaves: bool| {
+        let options = BuildOptions {
+            max_points_per_node: 50,
+            decimate_leaves,
+            max_depth,
+            ..Default::default()
+        };
+        let dir = TempDir::new("octree").unwrap();
+        build_octree_with_options(
+            dir.path(),
+            0.001,
+            bounding_box.clone(),
+            vec![blue_batch(positions.clone())].into_iter(),
+            &["color"],
+            &options,
+        )
+        .map(|()| dir)
+    };
+    let depth_and_points = |dir: &TempDir| {
+        let octree = open_test_octree(dir.path());
+        let node_ids = octree.nodes_in_location(&PointLocation::AllPoints);
+        let depth = node_ids.iter().map(NodeId::level).max().unwrap();
+        let query = PointQuery {
+            attributes: vec!["color"],
+            ..Default::default()
+        };
+        let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 1000, 1, 1)
+            .try_for_each_batch(|_| Ok(()))
+            .unwrap();
+        (octree.max_depth(), depth, summary.points)
+    };
+
+    let uncapped = build(None, false).unwrap();
+    let (max_depth, depth, points) = depth_and_points(&uncapped);
+    assert_eq!(max_depth, None);
+    assert!(depth > 2);
+    assert_eq!(points, num_points);
+
+    // The leaves on the last level keep all of their points.
+    let capped = build(Some(2), false).unwrap();
+    assert_eq!(depth_and_points(&capped), (Some(2), 2, num_points));
+    let summary = open_test_octree(capped.path()).summary();
+    assert_eq!(summary.max_depth, Some(2));
+    assert_eq!(summary.levels.len(), 3);
+
+    let decimated = build(Some(2), true).unwrap();
+    let (_, depth, points) = depth_and_points(&decimated);
+    assert_eq!(depth, 2);
+    assert!(points < num_points);
+
+    assert!(build(Some(0), false).is_err());
+}
+
+#[test]
+fn test_original_index_recovers_input_order() {
+    // Consecutive input points are scattered over the bounding box, so that the nodes mix them.
+    let num_points = 3000;
+    let position = |i: usize| {
+        let cell = i * 7919 % 3375;
+        Point3::new(
+            (cell % 15) as f64,
+            (cell / 15 % 15) as f64,
+            (cell / 225) as f64,
+        ) * 0.25
+            + Vector3::repeat(0.125)
+    };
+    let input: Vec<PointsBatch> = (0..3)
+        .map(|b| blue_batch((b * 1000..(b + 1) * 1000).map(position).collect()))
+        .collect();
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        original_index: true,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0)),
+        input.into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    assert!(octree.has_original_index());
+    assert!(!build_test_octree().has_original_index());
+
+    let query = PointQuery {
+        attributes: vec![ORIGINAL_INDEX_ATTRIBUTE],
+        location: PointLocation::AllPoints,
+        output_transforms: vec![OutputTransform::SortByOriginalIndex],
+        ..Default::default()
+    };
+    assert!(PointQuery {
+        attributes: vec!["color"],
+        ..query.clone()
+    }
+    .validate()
+    .is_err());
+    // Transforms run on the batches read from a node, before they are merged for the caller.
+    let mut seen = vec![false; num_points];
+    for node_id in octree.nodes_in_location(&query.location) {
+        octree
+            .stream_points_for_query_in_node(&query, node_id, num_points, |batch| {
+                let original_indices = batch
+                    .get_attribute_vec::<u64>(ORIGINAL_INDEX_ATTRIBUTE)
+                    .unwrap();
+                assert!(original_indices.windows(2).all(|w| w[0] < w[1]));
+                for (p, original_index) in batch.position.iter().zip(original_indices) {
+                    let index = *original_index as usize;
+                    assert!(!seen[index]);
+                    seen[index] = true;
+                    assert!((p - position(index)).norm() < 0.01);
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+    assert!(seen.iter().all(|s| *s));
+}
+
+#[test]
+fn test_partitioner_matches_build() {
+    let num_points = 3000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| {
+            let cell = i * 7919 % 3375;
+            Point3::new(
+                (cell % 15) as f64,
+                (cell / 15 % 15) as f64,
+                (cell / 225) as f64,
+            ) * 0.25
+                + Vector3::repeat(0.125)
+        })
+        .collect();
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0));
+    let world_from_sensor = Similarity3::new(
+        Vector3::new(10.0, 20.0, 30.0),
+        Vector3::z() * std::f64::consts::FRAC_PI_2,
+        2.0,
+    );
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        original_index: true,
+        input_transform: Some(world_from_sensor),
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        bounding_box.clone(),
+        vec![blue_batch(positions.clone())].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let query = PointQuery {
+        attributes: vec![ORIGINAL_INDEX_ATTRIBUTE],
+        ..Default::default()
+    };
+    let mut built_nodes = vec![None; num_points];
+    for node_id in octree.nodes_in_location(&query.location) {
+        octree
+            .stream_points_for_query_in_node(&query, node_id, num_points, |batch| {
+                for index in batch.get_attribute_vec::<u64>(ORIGINAL_INDEX_ATTRIBUTE)? {
+                    built_nodes[*index as usize] = Some(node_id);
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    let partitioner = OctreePartitioner::new(bounding_box, 0.001, &options);
+    let leaves = partitioner.partition(&positions).unwrap();
+    // The build moved some points of every leaf into its ancestors.
+    for (index, (leaf, built_node)) in leaves.iter().zip(built_nodes).enumerate() {
+        let built_node = built_node.unwrap();
+        assert!(std::iter::successors(Some(*leaf), NodeId::parent).any(|id| id == built_node));
+        assert!(partitioner
+            .bounding_cube(*leaf)
+            .to_aabb()
+            .contains(&(world_from_sensor * positions[index])));
+    }
+    let mut partitioned_leaves: Vec<NodeId> = leaves.clone();
+    partitioned_leaves.sort_unstable_by_key(|id| (id.level(), id.index()));
+    partitioned_leaves.dedup();
+    let mut built_leaves: Vec<NodeId> = octree
+        .nodes
+        .keys()
+        .copied()
+        .filter(|id| octree.is_leaf(*id))
+        .collect();
+    built_leaves.sort_unstable_by_key(|id| (id.level(), id.index()));
+    assert!(partitioned_leaves.len() > 8);
+    assert_eq!(partitioned_leaves, built_leaves);
+
+    assert!(partitioner
+        .partition(&[Point3::new(1.0, 5.0, 1.0)])
+        .is_err());
+}
+
+#[test]
+fn test_json_summary() {
+    let num_points = 2000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new((i % 20) as f64, (i / 20 % 10) as f64, (i / 200) as f64) * 0.2)
+        .collect();
+    let build = |json_summary: bool| {
+        let options = BuildOptions {
+            max_points_per_node: 100,
+            coordinate_system: Some(CoordinateSystem::Epsg(4978)),
+            json_summary,
+            ..Default::default()
+        };
+        let dir = TempDir::new("octree").unwrap();
+        build_octree_with_options(
+            dir.path(),
+            0.001,
+            Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0)),
+            vec![blue_batch(positions.clone())].into_iter(),
+            &["color"],
+            &options,
+        )
+        .unwrap();
+        dir
+    };
+    assert!(!build(false).path().join(SUMMARY_FILENAME).exists());
+
+    let dir = build(true);
+    let summary = OctreeSummary::from_file(dir.path().join(SUMMARY_FILENAME)).unwrap();
+    let octree = open_test_octree(dir.path());
+    assert_eq!(summary, octree.summary());
+    assert_eq!(summary.num_points, num_points);
+    assert_eq!(summary.attributes, octree.attributes());
+    assert_eq!(summary.levels[0].num_nodes, 1);
+    assert!(summary.levels.len() > 1);
+}
+
+#[test]
+fn test_color16_round_trip() {
+    let num_points = 1000;
+    let position: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new((i % 10) as f64, (i / 10 % 10) as f64, (i / 100) as f64) * 0.3)
+        .collect();
+    let color16 = |p: &Point3<f64>| {
+        let channel = |c: f64| (c * 21_000.0) as u16 + 1;
+        Vector3::new(channel(p.x), channel(p.y), 65535 - channel(p.z))
+    };
+    let color16_data: Vec<Vector3<u16>> = position.iter().map(color16).collect();
+    let color = color16_data
+        .iter()
+        .map(|c| c.map(|c| (c >> 8) as u8))
+        .collect();
+    let batch = PointsBatch {
+        attributes: vec![
+            ("color".to_string(), AttributeData::U8Vec3(color)),
+            (
+                COLOR16_ATTRIBUTE.to_string(),
+                AttributeData::U16Vec3(color16_data),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        position,
+        bounding_box: None,
+    };
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0)),
+        vec![batch].into_iter(),
+        &["color", COLOR16_ATTRIBUTE],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let query = PointQuery {
+        attributes: vec![COLOR16_ATTRIBUTE],
+        ..Default::default()
+    };
+    let mut num_points_read = 0;
+    ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+        .try_for_each_batch(|batch| {
+            let color: &Vec<Vector3<u16>> = batch.get_attribute_vec(COLOR16_ATTRIBUTE)?;
+            for (p, c) in batch.position.iter().zip(color) {
+                // The positions are decoded to within the resolution.
+                let expected = color16(&Point3::from(p.coords.map(|c| (c / 0.3).round() * 0.3)));
+                assert_eq!(*c, expected);
+            }
+            num_points_read += batch.position.len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(num_points_read, num_points);
+
+    // An octree with only 8 bit color is upscaled on request.
+    let octree8 = build_test_octree();
+    let result = octree8.stream_points_for_query_in_node(&query, NodeId::root(), 100, |_| Ok(()));
+    assert!(matches!(
+        result.unwrap_err().kind(),
+        ErrorKind::InvalidQuery(_)
+    ));
+    let upscale_query = PointQuery {
+        upscale_color: true,
+        ..query.clone()
+    };
+    octree8
+        .stream_points_for_query_in_node(&upscale_query, NodeId::root(), 100, |batch| {
+            assert!(!batch.attributes.contains_key("color"));
+            let color: &Vec<Vector3<u16>> = batch.get_attribute_vec(COLOR16_ATTRIBUTE)?;
+            assert!(color.iter().all(|c| *c == Vector3::new(65535, 0, 0)));
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_attribute_annotations_round_trip() {
+    let batch = || PointsBatch {
+        position: vec![Point3::new(0.5, 0.5, 0.5); 10],
+        attributes: vec![
+            (
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); 10]),
+            ),
+            ("intensity".to_string(), AttributeData::F32(vec![0.5; 10])),
+        ]
+        .into_iter()
+        .collect(),
+        bounding_box: None,
+    };
+    let intensity_annotation = AttributeAnnotation {
+        unit: None,
+        semantic: Some("normalized".to_string()),
+    };
+    let mut options = BuildOptions::default();
+    options
+        .attribute_annotations
+        .insert("intensity".to_string(), intensity_annotation.clone());
+    let build = |directory: &Path, options: &BuildOptions| {
+        build_octree_with_options(
+            directory,
+            1.0,
+            Aabb::new(Point3::origin(), Point3::new(1.0, 1.0, 1.0)),
+            vec![batch()].into_iter(),
+            &["color", "intensity"],
+            options,
+        )
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build(dir.path(), &options).unwrap();
+    assert_eq!(
+        open_test_octree(dir.path()).attributes(),
+        vec![
+            AttributeDescription {
+                name: "color".to_string(),
+                data_type: AttributeDataType::U8Vec3,
+                annotation: AttributeAnnotation::default(),
+            },
+            AttributeDescription {
+                name: "intensity".to_string(),
+                data_type: AttributeDataType::F32,
+                annotation: intensity_annotation,
+            },
+        ]
+    );
+
+    // Only attributes of the octree can be annotated.
+    options.attribute_annotations.insert(
+        "gps_time".to_string(),
+        AttributeAnnotation {
+            unit: Some("seconds since GPS epoch".to_string()),
+            semantic: None,
+        },
+    );
+    let dir = TempDir::new("octree").unwrap();
+    match build(dir.path(), &options).unwrap_err().kind() {
+        ErrorKind::InvalidInput(_) => (),
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
+
+#[test]
+fn test_missing_vs_malformed_node() {
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_color_intensity_octree(dir.path(), 100);
+    let node_ids = octree.nodes_in_location(&PointLocation::AllPoints);
+    let node_path = |node_id: NodeId, attribute: &str| {
+        dir.path()
+            .join(node_id.to_string())
+            .with_extension(attribute_extension(attribute))
+    };
+
+    std::fs::remove_file(node_path(node_ids[0], "position")).unwrap();
+    let err = octree.get_node_data(&node_ids[0]).map(|_| ()).unwrap_err();
+    assert!(err.is_not_found());
+    match err.kind() {
+        ErrorKind::NodeNotFound => (),
+        other => panic!("Unexpected error {:?}", other),
+    }
+    // The IO error is kept as the cause.
+    let cause = std::error::Error::source(&err).unwrap();
+    assert_eq!(
+        cause.downcast_ref::<std::io::Error>().unwrap().kind(),
+        std::io::ErrorKind::NotFound
+    );
+
+    let position_path = node_path(node_ids[1], "position");
+    let mut position = std::fs::read(&position_path).unwrap();
+    position.pop();
+    std::fs::write(&position_path, position).unwrap();
+    let err = octree.get_node_data(&node_ids[1]).map(|_| ()).unwrap_err();
+    assert!(!err.is_not_found());
+    match err.kind() {
+        ErrorKind::Decode(_) => (),
+        other => panic!("Unexpected error {:?}", other),
+    }
+
+    // The files can't be listed, so the provider fails.
+    let data_provider = OnDiskDataProvider {
+        directory: node_path(node_ids[1], "color"),
+    };
+    let err = data_provider.meta_proto().unwrap_err();
+    assert!(!err.is_not_found());
+    match err.kind() {
+        ErrorKind::Provider(_) => (),
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
+
+#[test]
+fn test_frustum_sphere_culling_returns_same_points() {
+    let positions: Vec<Point3<f64>> = (0..16 * 16 * 16)
+        .map(|i| Point3::new((i / 256) as f64, (i / 16 % 16) as f64, (i % 16) as f64))
+        .collect();
+    let num_points = positions.len();
+    let batch = PointsBatch {
+        position: positions,
+        attributes: vec![(
+            "color".to_string(),
+            AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0); num_points]),
+        )]
+        .into_iter()
+        .collect(),
+        bounding_box: None,
+    };
+    let options = BuildOptions {
+        max_points_per_node: 10,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.1,
+        Aabb::new(Point3::origin(), Point3::new(16.0, 16.0, 16.0)),
+        vec![batch].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let num_nodes = octree.nodes.len();
+    let node_positions = |id: NodeId| -> Vec<Point3<f64>> {
+        octree
+            .points_in_node(&[], id, 1000, None)
+            .unwrap()
+            .flat_map(|batch| batch.position)
+            .collect()
+    };
+
+    // The spheres are stored in the meta data, and enclose the points of the node and of its
+    // descendants.
+    let data_provider = OnDiskDataProvider {
+        directory: dir.path().to_path_buf(),
+    };
+    let mut meta_proto = data_provider.meta_proto().unwrap();
+    let node_protos = meta_proto.mut_octree().mut_nodes();
+    assert!(node_protos.iter().all(|node| node.has_bounding_sphere()));
+    for id in octree.nodes.keys() {
+        for p in node_positions(*id) {
+            for ancestor in std::iter::successors(Some(*id), NodeId::parent) {
+                let sphere = &octree.nodes[&ancestor].bounding_sphere;
+                assert!((p - sphere.center()).norm() <= sphere.radius());
+            }
+        }
+    }
+    // The points only reach to 15, so the spheres of the nodes at the max are smaller than those
+    // around the bounding cubes.
+    let around_cube = |id: &NodeId| Sphere::enclosing_cube(&octree.nodes[id].bounding_cube);
+    assert!(octree
+        .nodes
+        .iter()
+        .any(|(id, node_meta)| node_meta.bounding_sphere.radius() < around_cube(id).radius()));
+
+    // Octrees without the spheres use those around the bounding cubes.
+    for node in node_protos.iter_mut() {
+        node.clear_bounding_sphere();
+    }
+    let old_dir = TempDir::new("octree").unwrap();
+    for entry in std::fs::read_dir(dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        std::fs::copy(&path, old_dir.path().join(path.file_name().unwrap())).unwrap();
+    }
+    let mut meta_file = std::fs::File::create(old_dir.path().join(META_FILENAME)).unwrap();
+    protobuf::Message::write_to_writer(&meta_proto, &mut meta_file).unwrap();
+    let old_octree = open_test_octree(old_dir.path());
+    assert!(old_octree
+        .nodes
+        .iter()
+        .all(|(id, node_meta)| node_meta.bounding_sphere == around_cube(id)));
+
+    for i in 0..20 {
+        // Eyes on a circle around the data, turned away from its center by up to 0.5 rad.
+        let angle = f64::from(i) * 0.7;
+        let eye = Point3::new(8.0 + 20.0 * angle.sin(), 8.0, 8.0 + 20.0 * angle.cos());
+        let rotation =
+            UnitQuaternion::from_axis_angle(&Vector3::y_axis(), angle + 0.05 * f64::from(i - 10));
+        let perspective =
+            Perspective3::new(1.0, 0.2 + 0.05 * f64::from(i), 1.0, 15.0 + f64::from(i));
+        let frustum = Frustum::new(
+            Isometry3::from_parts(Translation3::from(eye.coords), rotation),
+            perspective.into(),
+        );
+        let with_spheres = octree.nodes_in_frustum(&frustum);
+        let aabb_only = octree.nodes_in_location_impl(&frustum);
+        assert!(aabb_only.len() < num_nodes);
+        assert!(with_spheres.iter().all(|id| aabb_only.contains(id)));
+        let points_in_frustum = |nodes: &[NodeId]| {
+            let mut points: Vec<(f64, f64, f64)> = nodes
+                .iter()
+                .flat_map(|id| node_positions(*id))
+                .filter(|p| frustum.contains(p))
+                .map(|p| (p.x, p.y, p.z))
+                .collect();
+            points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            points
+        };
+        assert_eq!(
+            points_in_frustum(&with_spheres),
+            points_in_frustum(&aabb_only),
+            "{}",
+            i
+        );
+    }
+}
+
+#[test]
+fn test_overview_query() {
+    let dir = TempDir::new("octree").unwrap();
+    let (octree, _) = build_grid_octree(dir.path(), [20, 10, 10], 1.0, 50);
+    let overview = |location: PointLocation| {
+        let query = PointQuery {
+            attributes: vec!["color"],
+            location,
+            overview: true,
+            ..Default::default()
+        };
+        let mut positions = Vec::new();
+        ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 2, 2)
+            .try_for_each_batch(|batch| {
+                assert!(batch.attributes.contains_key("color"));
+                positions.extend(batch.position);
+                Ok(())
+            })
+            .unwrap();
+        positions
+    };
+
+    let num_nodes = octree.nodes_in_location(&PointLocation::AllPoints).len();
+    assert!(num_nodes > 10);
+    assert_eq!(overview(PointLocation::AllPoints).len(), num_nodes);
+
+    // Only the nodes with points inside of the box contribute one of them.
+    let aabb = Aabb::new(Point3::new(2.5, 2.5, 2.5), Point3::new(12.5, 6.5, 6.5));
+    let location = PointLocation::Aabb(aabb.clone());
+    let query = PointQuery {
+        location: location.clone(),
+        ..Default::default()
+    };
+    let mut num_nodes_with_matches = 0;
+    for node_id in octree.nodes_in_location(&location) {
+        let mut num_points = 0;
+        octree
+            .stream_points_for_query_in_node(&query, node_id, 100, |batch| {
+                num_points += batch.position.len();
+                Ok(())
+            })
+            .unwrap();
+        num_nodes_with_matches += (num_points > 0) as usize;
+    }
+    let positions = overview(location);
+    assert_eq!(positions.len(), num_nodes_with_matches);
+    assert!(positions.iter().all(|p| aabb.contains(p)));
+}
+
+#[test]
+fn test_iter_nodes() {
+    let dir = TempDir::new("octree").unwrap();
+    let (octree, _) = build_grid_octree(dir.path(), [20, 10, 10], 1.0, 50);
+
+    let nodes: Vec<NodeInfo> = octree.iter_nodes().collect();
+    assert_eq!(nodes.len(), octree.nodes.len());
+    let ids: HashSet<NodeId> = nodes.iter().map(|node| node.id).collect();
+    assert_eq!(ids.len(), nodes.len());
+    assert_eq!(nodes.iter().map(|node| node.num_points).sum::<u64>(), 2000);
+    assert_eq!(nodes[0].id, NodeId::root());
+    // Breadth first.
+    assert!(nodes.windows(2).all(|pair| pair[0].level <= pair[1].level));
+    for node in &nodes {
+        assert_eq!(node.level, node.id.level());
+        assert_eq!(
+            node.bounding_box,
+            octree.nodes[&node.id].bounding_cube.to_aabb()
+        );
+        for child_index in 0..8 {
+            let child_id = node.id.get_child_id(ChildIndex::from_u8(child_index));
+            assert_eq!(
+                node.child_mask & 1 << child_index != 0,
+                ids.contains(&child_id)
+            );
+        }
+        assert_eq!(node.child_mask == 0, octree.is_leaf(node.id));
+    }
+}
+
+#[test]
+fn test_pick_nearest() {
+    let dir = TempDir::new("octree").unwrap();
+    let (octree, _) = build_grid_octree(dir.path(), [20, 10, 10], 1.0, 50);
+    let target = Point3::new(13.3, 4.2, 5.1);
+    let exact_distance = (target - Point3::new(13.0, 4.0, 5.0)).norm();
+
+    let pick = octree
+        .pick_nearest(&target, std::f64::INFINITY, None)
+        .unwrap();
+    assert!(!pick.approximate);
+    let (position, distance) = pick.nearest.unwrap();
+    assert!((position - Point3::new(13.0, 4.0, 5.0)).norm() < 0.01);
+    assert!((distance - exact_distance).abs() < 0.01);
+    // A generous deadline does not change the result.
+    let deadline = Instant::now() + Duration::from_secs(60);
+    assert_eq!(
+        octree
+            .pick_nearest(&target, std::f64::INFINITY, Some(deadline))
+            .unwrap(),
+        pick
+    );
+
+    // An expired deadline still returns the closest point of the first node, the root, which
+    // holds a coarse sample of all points.
+    let pick = octree
+        .pick_nearest(&target, std::f64::INFINITY, Some(Instant::now()))
+        .unwrap();
+    assert!(pick.approximate);
+    let (_, distance) = pick.nearest.unwrap();
+    assert!(distance >= exact_distance - 0.01);
+    assert!(distance < 0.5 * octree.bounding_box().diag().norm());
+
+    // Nothing is within the maximum distance.
+    let far_away = Point3::new(100.0, 100.0, 100.0);
+    let pick = octree.pick_nearest(&far_away, 10.0, None).unwrap();
+    assert_eq!(pick.nearest, None);
+    assert!(!pick.approximate);
+}
+
+#[test]
+fn test_frustums_query() {
+    let dir = TempDir::new("octree").unwrap();
+    let (octree, _) = build_grid_octree(dir.path(), [20, 10, 10], 1.0, 50);
+    let positions = |query: &PointQuery| {
+        let mut points = Vec::new();
+        ParallelIterator::new(std::slice::from_ref(&octree), query, 100, 2, 2)
+            .try_for_each_batch(|batch| {
+                let masks = batch
+                    .get_attribute_vec::<u64>(FRUSTUM_MASK_ATTRIBUTE)
+                    .map(|masks| masks.clone())
+                    .unwrap_or_else(|_| vec![0; batch.position.len()]);
+                points.extend(batch.position.into_iter().zip(masks));
+                Ok(())
+            })
+            .unwrap();
+        points
+    };
+
+    // Two overlapping views from above, looking down the negative z axis.
+    let frustum_at = |x: f64| {
+        Frustum::new(
+            Isometry3::translation(x, 4.5, 30.0),
+            Perspective3::new(1.0, 0.3, 1.0, 40.0).into(),
+        )
+    };
+    let frustums = Frustums(vec![frustum_at(5.0), frustum_at(9.0)]);
+    let query = PointQuery {
+        attributes: vec![FRUSTUM_MASK_ATTRIBUTE],
+        location: PointLocation::Frustums(frustums.clone()),
+        ..Default::default()
+    };
+    query.validate().unwrap();
+    let visible = positions(&query);
+    let all = positions(&PointQuery::default());
+    assert_eq!(
+        visible.len(),
+        all.iter().filter(|(p, _)| frustums.mask(p) != 0).count()
+    );
+    let mut num_points_by_mask = [0; 4];
+    for (p, mask) in &visible {
+        for (i, frustum) in frustums.0.iter().enumerate() {
+            assert_eq!(mask & 1 << i != 0, frustum.contains(p), "{:?}", p);
+        }
+        num_points_by_mask[*mask as usize] += 1;
+    }
+    // Each view sees points that the other one doesn't, and both see some.
+    assert_eq!(num_points_by_mask[0], 0);
+    assert!(num_points_by_mask[1..].iter().all(|n| *n > 0));
+    assert!(visible.len() < all.len());
+
+    // The mask is only known for frustums.
+    let query = PointQuery {
+        attributes: vec![FRUSTUM_MASK_ATTRIBUTE],
+        ..Default::default()
+    };
+    assert!(octree
+        .stream_points_for_query_in_node(&query, NodeId::root(), 100, |_| Ok(()))
+        .is_err());
+    assert!(PointQuery {
+        location: PointLocation::Frustums(Frustums(Vec::new())),
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+}
+
+/// Hands out at most a few bytes per read, like a pipe whose writer is slow.
+struct Trickle<'a>(&'a [u8]);
+
+impl<'a> Read for Trickle<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.0.len()).min(7);
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_build_from_stream() {
+    let num_points = 1000;
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let ply_path = tmp_dir.path().join("points.ply");
+    {
+        let mut writer = PlyNodeWriter::new(&ply_path, Encoding::Plain, OpenMode::Truncate);
+        let batch = PointsBatch {
+            position: (0..num_points)
+                .map(|i| Point3::new(i as f64 * 0.1, 0.5, 0.5))
+                .collect(),
+            attributes: vec![
+                (
+                    "color".to_string(),
+                    AttributeData::U8Vec3(vec![Vector3::new(0, 255, 0); num_points]),
+                ),
+                (
+                    "intensity".to_string(),
+                    AttributeData::F32(vec![2.0; num_points]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        };
+        writer.write(&batch).unwrap();
+    }
+    let ply = std::fs::read(&ply_path).unwrap();
+
+    let octree_dir = tmp_dir.path().join("octree");
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(100.0, 1.0, 1.0));
+    build_octree_from_reader(
+        &octree_dir,
+        0.01,
+        bounding_box.clone(),
+        Trickle(&ply),
+        &["color", "intensity"],
+        &BuildOptions::default(),
+    )
+    .unwrap();
+
+    let octree = open_test_octree(&octree_dir);
+    let query = PointQuery {
+        attributes: vec!["color", "intensity"],
+        location: PointLocation::Aabb(Aabb::new(
+            Point3::new(9.95, 0.0, 0.0),
+            Point3::new(19.95, 1.0, 1.0),
+        )),
+        ..Default::default()
+    };
+    let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+        .try_for_each_batch(|batch| {
+            let color: &Vec<Vector3<u8>> = batch.get_attribute_vec("color").unwrap();
+            assert!(color.iter().all(|c| *c == Vector3::new(0, 255, 0)));
+            let intensity: &Vec<f32> = batch.get_attribute_vec("intensity").unwrap();
+            assert!(intensity.iter().all(|i| *i == 2.0));
+            Ok(())
+        })
+        .unwrap();
+    // The points at x = 10.0 to 19.9.
+    assert_eq!(summary.points, 100);
+
+    // Input that ends in the middle of a point fails the build.
+    assert!(build_octree_from_reader(
+        tmp_dir.path().join("truncated"),
+        0.01,
+        bounding_box,
+        Trickle(&ply[..ply.len() - 5]),
+        &["color", "intensity"],
+        &BuildOptions::default(),
+    )
+    .is_err());
+}
+
+#[test]
+fn test_estimate_octree() {
+    let tmp_dir = TempDir::new("octree").unwrap();
+    let ply_path = tmp_dir.path().join("points.ply");
+    // Denser towards the origin, so that the octree is unbalanced.
+    let positions: Vec<Point3<f64>> = (0..20_000)
+        .map(|i| {
+            let t = f64::from(i) / 20_000.0;
+            Point3::new(t * t * 100.0, (t * 7919.0) % 10.0, (t * 104_729.0) % 10.0)
+        })
+        .collect();
+    let bounding_box = Aabb::from_points(&positions).unwrap();
+    {
+        let mut writer = PlyNodeWriter::new(&ply_path, Encoding::Plain, OpenMode::Truncate);
+        writer.write(&blue_batch(positions)).unwrap();
+    }
+    let options = BuildOptions {
+        max_points_per_node: 500,
+        ..Default::default()
+    };
+    let estimate =
+        estimate_octree_from_file(0.01, &ply_path, &["color"], &options, usize::MAX).unwrap();
+    let files: Vec<_> = std::fs::read_dir(tmp_dir.path()).unwrap().collect();
+    assert_eq!(files.len(), 1);
+
+    // Sampling every point predicts the octree exactly.
+    let octree_dir = tmp_dir.path().join("octree");
+    build_octree_with_options(
+        &octree_dir,
+        0.01,
+        bounding_box.clone(),
+        PlyIterator::from_file(&ply_path, 1000).unwrap(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(&octree_dir);
+    assert_eq!(estimate.num_points, 20_000);
+    assert_eq!(estimate.num_nodes, octree.nodes.len());
+    assert_eq!(
+        estimate.depth,
+        octree.nodes.keys().map(|id| id.level()).max().unwrap()
+    );
+    let num_bytes: u64 = std::fs::read_dir(&octree_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_name() != META_FILENAME)
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert_eq!(estimate.num_bytes, num_bytes);
+
+    let sampled = estimate_octree_from_file(0.01, &ply_path, &["color"], &options, 2000).unwrap();
+    assert_eq!(sampled.num_points, 20_000);
+    assert!(sampled.num_nodes > 0 && sampled.num_nodes <= estimate.num_nodes);
+    assert!((sampled.num_bytes as f64 / num_bytes as f64 - 1.0).abs() < 0.25);
+
+    // Reading stops once there are enough samples, and they stand in for the rest of the input.
+    let mut stream = PlyIterator::from_file(&ply_path, 1000).unwrap();
+    let first =
+        estimate_octree(0.01, bounding_box, &mut stream, &["color"], &options, 2000).unwrap();
+    assert_eq!(stream.count(), 18);
+    assert_eq!(first.num_points, 20_000);
+}
+
+#[test]
+fn test_aabb_query_clamped_to_bounding_box() {
+    // The bounding box is flat, so most of the root cube is empty.
+    let positions: Vec<Point3<f64>> = (0..10_000)
+        .map(|i| Point3::new(f64::from(i) * 0.01, f64::from(i % 10), f64::from(i % 7)))
+        .collect();
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(100.0, 10.0, 10.0));
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.01,
+        bounding_box,
+        vec![blue_batch(positions)].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let aabb = |min: [f64; 3], max: [f64; 3]| Aabb::new(Point3::from(min), Point3::from(max));
+
+    // Inside the root cube, but outside of the bounding box.
+    let outside = aabb([0.0, 50.0, 0.0], [100.0, 100.0, 10.0]);
+    assert!(!octree.nodes_in_location_impl(&outside).is_empty());
+    assert!(octree
+        .nodes_in_location(&PointLocation::Aabb(outside))
+        .is_empty());
+
+    // Only the nodes of the part inside of the bounding box are scanned.
+    let partially_outside = aabb([50.0, -50.0, -50.0], [150.0, 50.0, 50.0]);
+    let inside = aabb([50.0, 0.0, 0.0], [100.0, 10.0, 10.0]);
+    let node_ids = octree.nodes_in_location(&PointLocation::Aabb(partially_outside.clone()));
+    assert_eq!(
+        node_ids,
+        octree.nodes_in_location(&PointLocation::Aabb(inside))
+    );
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: PointLocation::Aabb(partially_outside),
+        ..Default::default()
+    };
+    let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 1, 1)
+        .try_for_each_batch(|_| Ok(()))
+        .unwrap();
+    assert_eq!(summary.points, 5000);
+}
+
+#[test]
+fn test_boundary_epsilon() {
+    let positions: Vec<Point3<f64>> = (0..1000)
+        .map(|i| {
+            Point3::new(
+                f64::from(i % 10),
+                f64::from(i / 10 % 10),
+                f64::from(i / 100),
+            )
+        })
+        .collect();
+    let options = BuildOptions {
+        max_points_per_node: 50,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(9.0, 9.0, 9.0)),
+        vec![blue_batch(positions)].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let mut query = PointQuery::default();
+    let mut positions = Vec::new();
+    ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 2, 2)
+        .try_for_each_batch(|batch| {
+            positions.extend(batch.position);
+            Ok(())
+        })
+        .unwrap();
+    // The decoded position, which is exactly on the faces of the boxes.
+    let p = *positions
+        .iter()
+        .find(|p| (*p - Point3::new(2.0, 2.0, 2.0)).norm() < 0.01)
+        .unwrap();
+    let half = Vector3::repeat(0.5);
+    let num_points = |query: &PointQuery| {
+        ParallelIterator::new(std::slice::from_ref(&octree), query, 100, 2, 2)
+            .try_for_each_batch(|_| Ok(()))
+            .unwrap()
+            .points
+    };
+
+    // The max faces of a box exclude the point.
+    query.location = PointLocation::Aabb(Aabb::new(p - half, p));
+    assert_eq!(num_points(&query), 0);
+    query.boundary_epsilon = 1e-9;
+    query.validate().unwrap();
+    assert_eq!(num_points(&query), 1);
+
+    // The complement shrinks the box instead, so it includes the point on the min faces.
+    query.location =
+        PointLocation::Complement(Box::new(PointLocation::Aabb(Aabb::new(p, p + half))));
+    assert_eq!(num_points(&query), 1000);
+    query.boundary_epsilon = 0.0;
+    assert_eq!(num_points(&query), 1000 - 1);
+
+    query.boundary_epsilon = -1.0;
+    assert!(query.validate().is_err());
+}
+
+#[test]
+fn test_complement_query() {
+    let num_points = 2000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new((i % 20) as f64, (i / 20 % 10) as f64, (i / 200) as f64) * 0.5)
+        .collect();
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        original_index: true,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(10.0, 5.0, 5.0)),
+        vec![blue_batch(positions)].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let original_indices = |location: PointLocation| {
+        let query = PointQuery {
+            attributes: vec![ORIGINAL_INDEX_ATTRIBUTE],
+            location,
+            ..Default::default()
+        };
+        query.location.validate().unwrap();
+        let mut indices = Vec::new();
+        for node_id in octree.nodes_in_location(&query.location) {
+            octree
+                .stream_points_for_query_in_node(&query, node_id, num_points, |batch| {
+                    indices.extend(batch.get_attribute_vec::<u64>(ORIGINAL_INDEX_ATTRIBUTE)?);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        indices.sort_unstable();
+        indices
+    };
+
+    let aabb = Aabb::new(Point3::new(1.2, -1.0, 0.7), Point3::new(6.3, 2.1, 10.0));
+    let inside = original_indices(PointLocation::Aabb(aabb.clone()));
+    let outside = original_indices(PointLocation::Complement(Box::new(PointLocation::Aabb(
+        aabb,
+    ))));
+    assert!(!inside.is_empty() && !outside.is_empty());
+    let mut all: Vec<u64> = inside.iter().chain(&outside).copied().collect();
+    all.sort_unstable();
+    assert_eq!(all, (0..num_points as u64).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_difference_query() {
+    let dir = TempDir::new("octree").unwrap();
+    let (octree, positions) = build_grid_octree(dir.path(), [20, 20, 20], 0.5, 200);
+
+    // A box with a hole in its center.
+    let aabb = Aabb::new(Point3::new(1.2, 1.2, 1.2), Point3::new(8.3, 8.3, 8.3));
+    let sphere = Sphere::new(Point3::new(4.75, 4.75, 4.75), 3.0);
+    let is_in_shell = |p: &Point3<f64>| aabb.contains(p) && (p - sphere.center()).norm() > 3.0;
+    let query = PointQuery {
+        location: PointLocation::Difference(
+            Box::new(PointLocation::Aabb(aabb.clone())),
+            Box::new(PointLocation::Sphere(sphere)),
+        ),
+        ..Default::default()
+    };
+    query.location.validate().unwrap();
+    // The hole does not change which nodes are read.
+    assert_eq!(
+        octree.nodes_in_location(&query.location),
+        octree.nodes_in_location(&PointLocation::Aabb(aabb.clone()))
+    );
+    let mut num_points = 0;
+    for node_id in octree.nodes_in_location(&query.location) {
+        octree
+            .stream_points_for_query_in_node(&query, node_id, 1000, |batch| {
+                assert!(batch.position.iter().all(is_in_shell));
+                num_points += batch.position.len();
+                Ok(())
+            })
+            .unwrap();
+    }
+    let expected = positions.iter().filter(|p| is_in_shell(p)).count();
+    assert!(expected > 0 && expected < positions.len() / 2);
+    assert_eq!(num_points, expected);
+}
+
+#[test]
+fn test_query_by_original_index() {
+    // Like a scan, the input order is spatially coherent, so that nodes have narrow ranges of
+    // indices.
+    let positions: Vec<Point3<f64>> = (0..8000)
+        .map(|i| Point3::new(f64::from(i) * 0.001, f64::from(i % 10) * 0.5, 2.0))
+        .collect();
+    let build = |directory: &Path, original_index: bool| {
+        let options = BuildOptions {
+            max_points_per_node: 200,
+            original_index,
+            ..Default::default()
+        };
+        build_octree_with_options(
+            directory,
+            0.001,
+            Aabb::new(Point3::origin(), Point3::new(10.0, 10.0, 10.0)),
+            vec![blue_batch(positions.clone())].into_iter(),
+            &["color"],
+            &options,
+        )
+        .unwrap();
+        open_test_octree(directory)
+    };
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build(dir.path(), true);
+
+    // Indices beyond the input select nothing.
+    let selection: HashSet<u64> = [3, 4, 17, 402, 4000, 4001, 7999, 12_345]
+        .iter()
+        .copied()
+        .collect();
+    let location = PointLocation::ByOriginalIndex(selection.clone());
+    // Most nodes don't contain any of the indices.
+    let num_nodes = octree.nodes_in_location(&PointLocation::AllPoints).len();
+    assert!(octree.nodes_in_location(&location).len() < num_nodes / 2);
+    let select = |attributes: Vec<&str>| {
+        let query = PointQuery {
+            attributes,
+            location: location.clone(),
+            ..Default::default()
+        };
+        let mut batches = Vec::new();
+        for node_id in octree.nodes_in_location(&query.location) {
+            octree
+                .stream_points_for_query_in_node(&query, node_id, 1000, |batch| {
+                    batches.push(batch);
+                    Ok(())
+                })
+                .unwrap();
+        }
+        batches
+    };
+    let mut indices = Vec::new();
+    for batch in select(vec!["color", ORIGINAL_INDEX_ATTRIBUTE]) {
+        let original_index: &Vec<u64> = batch.get_attribute_vec(ORIGINAL_INDEX_ATTRIBUTE).unwrap();
+        for (p, index) in batch.position.iter().zip(original_index) {
+            assert!((p - positions[*index as usize]).norm() < 0.001);
+            indices.push(*index);
+        }
+    }
+    indices.sort_unstable();
+    assert_eq!(indices, vec![3, 4, 17, 402, 4000, 4001, 7999]);
+    // The index is only read for the selection if it is not requested.
+    let batches = select(vec!["color"]);
+    assert!(batches
+        .iter()
+        .all(|batch| !batch.attributes.contains_key(ORIGINAL_INDEX_ATTRIBUTE)));
+    assert_eq!(
+        batches
+            .iter()
+            .map(|batch| batch.position.len())
+            .sum::<usize>(),
+        7
+    );
+
+    assert!(PointLocation::Complement(Box::new(location.clone()))
+        .validate()
+        .is_err());
+    let dir = TempDir::new("octree").unwrap();
+    let without_index = build(dir.path(), false);
+    let query = PointQuery {
+        location,
+        ..Default::default()
+    };
+    assert!(without_index
+        .stream_points_for_query_in_node(&query, NodeId::root(), 1000, |_| Ok(()))
+        .is_err());
+}
+
+#[test]
+fn test_estimate_query() {
+    let dir = TempDir::new("octree").unwrap();
+    let (octree, _) = build_grid_octree(dir.path(), [20, 20, 20], 0.5, 200);
+
+    let query = PointQuery {
+        attributes: vec!["color"],
+        location: PointLocation::Aabb(Aabb::new(
+            Point3::new(1.2, 1.2, 1.2),
+            Point3::new(4.3, 8.3, 6.3),
+        )),
+        ..Default::default()
+    };
+    let estimate = octree.estimate_query(&query).unwrap();
+    let summary = ParallelIterator::new(std::slice::from_ref(&octree), &query, 1000, 2, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .unwrap();
+    assert_eq!(estimate.candidate_nodes, summary.nodes_visited);
+    assert!(estimate.approx_points >= summary.points as u64);
+    assert!(estimate.approx_points < 8000);
+
+    let all_points = octree
+        .estimate_query(&PointQuery {
+            attributes: vec!["color"],
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(all_points.approx_points, 8000);
+    // The color takes 3 bytes per point, in addition to the encoded position.
+    let position_bytes = octree.estimate_query(&PointQuery::default()).unwrap();
+    assert_eq!(
+        all_points.approx_bytes,
+        position_bytes.approx_bytes + 3 * 8000
+    );
+}
+
+#[test]
+fn test_skip_failed_nodes() {
+    let good_dir = TempDir::new("octree").unwrap();
+    let bad_dir = TempDir::new("octree").unwrap();
+    let octrees: Arc<[Octree]> = Arc::from(vec![
+        build_color_intensity_octree(good_dir.path(), 1000),
+        build_color_intensity_octree(bad_dir.path(), 500),
+    ]);
+    // Corrupt the second octree by removing the positions of all of its nodes.
+    let bad_nodes = octrees[1].nodes_in_location(&PointLocation::AllPoints);
+    for node_id in &bad_nodes {
+        let path = bad_dir
+            .path()
+            .join(node_id.to_string())
+            .with_extension(attribute_extension("position"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    let mut query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    PooledIterator::new(&thread_pool, Arc::clone(&octrees), &query, 100, 2, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .expect_err("The corrupt octree did not fail the query.");
+
+    query.skip_failed_nodes = true;
+    let pooled_summary = PooledIterator::new(&thread_pool, Arc::clone(&octrees), &query, 100, 2, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .unwrap();
+    let summary = ParallelIterator::new(&octrees, &query, 100, 2, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .unwrap();
+    for summary in &[summary, pooled_summary] {
+        assert_eq!(summary.points, 1000);
+        assert_eq!(summary.failures.len(), 1);
+        assert_eq!(summary.failures[0].point_cloud, 1);
+        assert_eq!(summary.failures[0].nodes_failed, bad_nodes.len());
+        assert!(summary.failures[0].error.starts_with("Node r"));
+        assert_eq!(summary.failures[0].node_ids.len(), bad_nodes.len());
+    }
+}
+
+#[test]
+fn test_skip_corrupt_node() {
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_color_intensity_octree(dir.path(), 1000);
+    // Truncate the colors of the node with the most points, so that it can't be decoded.
+    let (corrupt_id, corrupt_points) = octree
+        .nodes
+        .iter()
+        .map(|(id, node_meta)| (*id, node_meta.num_points as usize))
+        .max_by_key(|(_, num_points)| *num_points)
+        .unwrap();
+    assert!(corrupt_points < 1000);
+    let path = dir
+        .path()
+        .join(corrupt_id.to_string())
+        .with_extension(attribute_extension("color"));
+    std::fs::write(path, [0u8; 2]).unwrap();
+
+    let mut query = PointQuery {
+        attributes: vec!["color"],
+        ..Default::default()
+    };
+    let octrees: Arc<[Octree]> = Arc::from(vec![octree]);
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    PooledIterator::new(&thread_pool, Arc::clone(&octrees), &query, 100, 2, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .expect_err("The corrupt node did not fail the query.");
+
+    query.skip_failed_nodes = true;
+    let summary = ParallelIterator::new(&octrees, &query, 100, 2, 2)
+        .try_for_each_batch(|_| Ok(()))
+        .unwrap();
+    assert_eq!(summary.points, 1000 - corrupt_points);
+    assert_eq!(summary.failures.len(), 1);
+    assert_eq!(summary.failures[0].nodes_failed, 1);
+    assert_eq!(summary.failures[0].node_ids, vec![corrupt_id.to_string()]);
+    assert!(summary.failures[0]
+        .error
+        .starts_with(&format!("Node {}: ", corrupt_id)));
+}
+
+#[test]
+fn test_inspect_node() {
+    let octree = build_test_octree();
+    let (node_id, num_points) = octree
+        .nodes
+        .iter()
+        .map(|(id, node_meta)| (*id, node_meta.num_points))
+        .max_by_key(|(_, num_points)| *num_points)
+        .unwrap();
+    let mut out = Vec::new();
+    inspect_node(&octree, node_id, 3, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains(&format!("Node: {}\n", node_id)));
+    assert!(out.contains(&format!("Number of points: {}\n", num_points)));
+    assert!(out.contains("\n  color: U8Vec3\n"));
+    assert!(out.contains("\n  intensity: F32, not stored\n"));
+    assert_eq!(out.matches(" color: (255, 0, 0)\n").count(), 3);
+
+    let unknown = "r7777777".parse().unwrap();
+    let err = inspect_node(&octree, unknown, 3, &mut Vec::new()).unwrap_err();
+    match err.kind() {
+        ErrorKind::NodeNotFound => (),
+        other => panic!("Unexpected error {:?}", other),
+    }
+}
+
+#[test]
+fn test_leaf_only_attributes() {
+    let num_points = 2000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new((i % 20) as f64, (i / 20 % 10) as f64, (i / 200) as f64) * 0.2)
+        .collect();
+    let mut batch = blue_batch(positions);
+    let intensity = batch.position.iter().map(|p| p.x as f32).collect();
+    batch
+        .attributes
+        .insert("intensity".to_string(), AttributeData::F32(intensity));
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        leaf_only_attributes: vec!["intensity".to_string()],
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0)),
+        vec![batch].into_iter(),
+        &["color", "intensity"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    assert_eq!(octree.leaf_only_attributes(), ["intensity".to_string()]);
+    let node_ids = octree.nodes_in_location(&PointLocation::AllPoints);
+    let (leaves, inner): (Vec<NodeId>, Vec<NodeId>) =
+        node_ids.iter().partition(|id| octree.is_leaf(**id));
+    assert!(!leaves.is_empty() && !inner.is_empty());
+    for id in &inner {
+        let path = dir.path().join(id.to_string());
+        assert!(path.with_extension(attribute_extension("color")).exists());
+        assert!(!path
+            .with_extension(attribute_extension("intensity"))
+            .exists());
+    }
+    assert!(octree
+        .points_in_node(&["intensity"], inner[0], 1000, None)
+        .is_err());
+
+    let octrees: Arc<[Octree]> = Arc::from(vec![octree]);
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let run = |query: &PointQuery| {
+        let mut batches = Vec::new();
+        PooledIterator::new(&thread_pool, Arc::clone(&octrees), query, 1000, 2, 2)
+            .try_for_each_batch(|batch| {
+                batches.push(batch);
+                Ok(())
+            })
+            .map(|_| batches)
+    };
+
+    // Coarse levels are only available without the leaf only attribute.
+    let coarse = PointQuery {
+        attributes: vec![ALL_ATTRIBUTES],
+        location: PointLocation::AllPointsInDepthRange(1),
+        ..Default::default()
+    };
+    let batches = run(&coarse).unwrap();
+    assert!(!batches.is_empty());
+    for batch in &batches {
+        assert!(batch.attributes.contains_key("color"));
+        assert!(!batch.attributes.contains_key("intensity"));
+    }
+    let coarse_intensity = PointQuery {
+        attributes: vec!["intensity"],
+        ..coarse
+    };
+    assert!(run(&coarse_intensity).is_err());
+
+    // Requesting it explicitly returns exactly the points of the leaves.
+    let query = PointQuery {
+        attributes: vec!["intensity"],
+        ..Default::default()
+    };
+    let batches = run(&query).unwrap();
+    let num_leaf_points: u64 = leaves
+        .iter()
+        .map(|id| octrees[0].node_point_count(*id).unwrap())
+        .sum();
+    let num_returned: usize = batches.iter().map(|b| b.position.len()).sum();
+    assert_eq!(num_returned as u64, num_leaf_points);
+    assert!(num_leaf_points < num_points as u64);
+    for batch in &batches {
+        let intensity: &Vec<f32> = batch.get_attribute_vec("intensity").unwrap();
+        for (p, i) in batch.position.iter().zip(intensity) {
+            assert!((p.x as f32 - i).abs() < 0.01);
+        }
+    }
+}
+
+#[test]
+fn test_reencode_attribute_keeps_topology() {
+    let num_points = 2000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| Point3::new((i % 20) as f64, (i / 20 % 10) as f64, (i / 200) as f64) * 0.2)
+        .collect();
+    let mut batch = blue_batch(positions);
+    let intensity = (0..num_points).map(|i| (i * 7 % 1000) as f32).collect();
+    batch
+        .attributes
+        .insert("intensity".to_string(), AttributeData::F32(intensity));
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0)),
+        vec![batch].into_iter(),
+        &["color", "intensity"],
+        &options,
+    )
+    .unwrap();
+
+    // The positions and intensities of every node, in the stored order.
+    let read_nodes = |octree: &Octree| -> HashMap<NodeId, (Vec<Point3<f64>>, Vec<f64>)> {
+        octree
+            .nodes_in_location(&PointLocation::AllPoints)
+            .into_iter()
+            .map(|id| {
+                let mut positions = Vec::new();
+                let mut intensities = Vec::new();
+                for batch in octree
+                    .points_in_node(&["intensity"], id, 1000, None)
+                    .unwrap()
+                {
+                    positions.extend(batch.position);
+                    intensities.extend(match &batch.attributes["intensity"] {
+                        AttributeData::F32(data) => {
+                            data.iter().map(|i| f64::from(*i)).collect::<Vec<_>>()
+                        }
+                        AttributeData::U16(data) => {
+                            data.iter().map(|i| f64::from(*i)).collect::<Vec<_>>()
+                        }
+                        other => panic!("Unexpected data type {:?}.", other.data_type()),
+                    });
+                }
+                (id, (positions, intensities))
+            })
+            .collect()
+    };
+    let octree = open_test_octree(dir.path());
+    let before = read_nodes(&octree);
+    assert!(before.len() > 1);
+
+    // 999 does not fit into a u8, so nothing is changed.
+    assert!(reencode_attribute(dir.path(), "intensity", AttributeDataType::U8).is_err());
+    assert!(reencode_attribute(dir.path(), "color", AttributeDataType::U16).is_err());
+    let octree = open_test_octree(dir.path());
+    assert_eq!(
+        octree.attribute_data_types()["intensity"],
+        AttributeDataType::F32
+    );
+    // The data is written next to the files it replaces, which is cleaned up.
+    let no_temporary_files = || {
+        std::fs::read_dir(dir.path()).unwrap().all(|entry| {
+            let path = entry.unwrap().path().to_string_lossy().into_owned();
+            !path.ends_with(".reencoded") && !path.ends_with(".tmp")
+        })
+    };
+    assert!(no_temporary_files());
+
+    reencode_attribute(dir.path(), "intensity", AttributeDataType::U16).unwrap();
+    assert!(no_temporary_files());
+    let octree = open_test_octree(dir.path());
+    assert_eq!(
+        octree.attribute_data_types()["intensity"],
+        AttributeDataType::U16
+    );
+    for (id, (positions, _)) in &before {
+        assert_eq!(octree.node_point_count(*id), Some(positions.len() as u64));
+    }
+    assert_eq!(read_nodes(&octree), before);
+    let root_intensity = dir
+        .path()
+        .join("r")
+        .with_extension(attribute_extension("intensity"));
+    assert_eq!(
+        std::fs::metadata(root_intensity).unwrap().len(),
+        2 * octree.node_point_count(NodeId::root()).unwrap()
+    );
+}
+
+#[test]
+fn test_radial_decimation() {
+    // A plane of points 0.1 apart.
+    let positions: Vec<Point3<f64>> = (0..10_000)
+        .map(|i| Point3::new(0.1 * f64::from(i % 100), 0.1 * f64::from(i / 100), 0.0))
+        .collect();
+    let options = BuildOptions {
+        max_points_per_node: 500,
+        ..Default::default()
+    };
+    let dir = TempDir::new("octree").unwrap();
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        Aabb::new(Point3::origin(), Point3::new(9.9, 9.9, 1.0)),
+        vec![blue_batch(positions)].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let radial_decimation = RadialDecimation {
+        center: Point3::new(5.0, 5.0, 0.0),
+        full_radius: 1.0,
+        falloff: 2.0,
+    };
+    let query_positions = |radial_decimation: Option<RadialDecimation>| {
+        let query = PointQuery {
+            radial_decimation,
+            ..Default::default()
+        };
+        let mut positions = Vec::new();
+        ParallelIterator::new(std::slice::from_ref(&octree), &query, 100, 2, 2)
+            .try_for_each_batch(|batch| {
+                positions.extend(batch.position);
+                Ok(())
+            })
+            .unwrap();
+        positions.sort_by(|a, b| (a.x, a.y).partial_cmp(&(b.x, b.y)).unwrap());
+        positions
+    };
+    let all = query_positions(None);
+    let kept = query_positions(Some(radial_decimation));
+    // The same points on every query.
+    assert_eq!(kept, query_positions(Some(radial_decimation)));
+
+    let distance = |p: &Point3<f64>| (p - radial_decimation.center).norm();
+    let count = |positions: &[Point3<f64>], min: f64, max: f64| {
+        positions
+            .iter()
+            .filter(|p| distance(p) >= min && distance(p) < max)
+            .count()
+    };
+    // All points near the center are kept.
+    assert_eq!(count(&kept, 0.0, 1.0), count(&all, 0.0, 1.0));
+    // Near the edge, between 3 and 4 from the center, the expected fraction is kept.
+    let expected: f64 = all
+        .iter()
+        .filter(|p| distance(p) >= 3.0 && distance(p) < 4.0)
+        .map(|p| radial_decimation.keep_probability(p))
+        .sum();
+    let near_density = count(&kept, 0.0, 1.0) as f64 / count(&all, 0.0, 1.0) as f64;
+    let edge_density = count(&kept, 3.0, 4.0) as f64 / count(&all, 3.0, 4.0) as f64;
+    let expected_ratio = count(&all, 3.0, 4.0) as f64 / expected;
+    // About 1 / 3.5^2.
+    assert!(expected_ratio > 10.0 && expected_ratio < 14.0);
+    assert!((near_density / edge_density / expected_ratio - 1.0).abs() < 0.2);
+
+    let invalid_query = PointQuery {
+        radial_decimation: Some(RadialDecimation {
+            full_radius: 0.0,
+            ..radial_decimation
+        }),
+        ..Default::default()
+    };
+    assert!(invalid_query.validate().is_err());
+    let query = PointQuery::from_json(
+        r#"{"radial_decimation": {"center": [5, 5, 0], "full_radius": 1, "falloff": 2}}"#,
+    )
+    .unwrap();
+    assert_eq!(query.radial_decimation, Some(radial_decimation));
+}
+
+#[test]
+fn test_validate_query() {
+    let dir = TempDir::new("octree").unwrap();
+    let octree = build_color_intensity_octree(dir.path(), 10);
+    let valid_query = PointQuery {
+        attributes: vec!["color", "intensity", RGBA_ATTRIBUTE],
+        filter_intervals: vec![("intensity", ClosedInterval::new(0.0, 2.0))]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+    assert_eq!(octree.validate_query(&valid_query), Ok(()));
+
+    let query = PointQuery {
+        attributes: vec!["color", "unknown", QUERY_WEIGHT_ATTRIBUTE],
+        filter_intervals: vec![
+            ("color", ClosedInterval::new(0.0, 100.0)),
+            ("intensity", ClosedInterval::new(0.0, 2.0)),
+            ("missing", ClosedInterval::new(0.0, 1.0)),
+        ]
+        .into_iter()
+        .collect(),
+        ..Default::default()
+    };
+    let errors = octree.validate_query(&query).unwrap_err();
+    assert_eq!(
+        errors,
+        vec![
+            QueryValidationError::UnknownAttribute("unknown".to_string()),
+            QueryValidationError::UnsupportedAttribute {
+                attribute: QUERY_WEIGHT_ATTRIBUTE.to_string(),
+                reason: "The location has no weights.".to_string(),
+            },
+            QueryValidationError::FilterTypeMismatch {
+                attribute: "color".to_string(),
+                data_type: AttributeDataType::U8Vec3,
+            },
+            QueryValidationError::FilterAttributeNotRequested("intensity".to_string()),
+            QueryValidationError::UnknownFilterAttribute("missing".to_string()),
+        ]
+    );
+    assert!(errors[0].to_string().contains("'unknown'"));
+
+    // Problems of the query itself are reported as well.
+    let duplicate_query = PointQuery {
+        attributes: vec!["color", "color"],
+        ..Default::default()
+    };
+    assert!(matches!(
+        octree.validate_query(&duplicate_query).unwrap_err()[..],
+        [QueryValidationError::InvalidQuery(_)]
+    ));
+}
+
+/// Generates the points of a grid lazily, one batch at a time, like a reader of a large file.
+struct GridPoints {
+    num_batches: usize,
+    batch_size: usize,
+    next_batch: usize,
+}
+
+impl Iterator for GridPoints {
+    type Item = PointsBatch;
+
+    fn next(&mut self) -> Option<PointsBatch> {
+        if self.next_batch == self.num_batches {
+            return None;
+        }
+        let start = self.next_batch * self.batch_size;
+        self.next_batch += 1;
+        Some(blue_batch(
+            (start..start + self.batch_size)
+                .map(|i| {
+                    Point3::new((i % 40) as f64, (i / 40 % 40) as f64, (i / 1600) as f64) * 0.1
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl NumberOfPoints for GridPoints {
+    fn num_points(&self) -> usize {
+        (self.num_batches - self.next_batch) * self.batch_size
+    }
+}
+
+#[test]
+fn test_build_with_bounded_memory() {
+    // 32000 points on 20 levels of 40 x 40 points, in batches of 1000 points, which fill the lower
+    // half of the bounding box. With a maximum depth of 1, each of the 4 leaves there holds 8000
+    // points, more than the 500 points allowed in memory.
+    let num_points = 32_000;
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(4.0, 4.0, 4.0));
+    let build = |max_points_in_memory: Option<usize>, decimate_leaves: bool, morton_order: bool| {
+        let options = BuildOptions {
+            max_points_per_node: 1000,
+            max_depth: Some(1),
+            decimate_leaves,
+            morton_order,
+            max_points_in_memory,
+            ..Default::default()
+        };
+        let dir = TempDir::new("octree").unwrap();
+        let input = GridPoints {
+            num_batches: num_points / 1000,
+            batch_size: 1000,
+            next_batch: 0,
+        };
+        build_octree_with_options(
+            dir.path(),
+            0.001,
+            bounding_box.clone(),
+            input,
+            &["color"],
+            &options,
+        )
+        .map(|()| dir)
+    };
+    let max_points_in_memory = |dir: &TempDir| {
+        super::generation::MAX_POINTS_IN_MEMORY
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(directory, _)| directory == dir.path())
+            .map(|(_, max_points)| *max_points)
+            .unwrap()
+    };
+    let sorted_positions = |dir: &TempDir| {
+        assert!(!dir.path().join("spill").exists());
+        let octree = open_test_octree(dir.path());
+        let mut positions = Vec::new();
+        for id in octree.nodes_in_location(&PointLocation::AllPoints) {
+            for batch in octree.points_in_node(&[], id, 100, None).unwrap() {
+                positions.extend(
+                    batch
+                        .position
+                        .iter()
+                        .map(|p| (id.to_string(), p.x, p.y, p.z)),
+                );
+            }
+        }
+        positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        positions
+    };
+
+    // The nodes are streamed, so their points are the same as when reading them at once.
+    let bounded = build(Some(500), false, false).unwrap();
+    let unbounded = build(None, false, false).unwrap();
+    let positions = sorted_positions(&bounded);
+    assert_eq!(positions.len(), num_points);
+    assert_eq!(positions, sorted_positions(&unbounded));
+    assert!(max_points_in_memory(&bounded) <= 500);
+    assert!(max_points_in_memory(&unbounded) > 500);
+
+    let bounded = build(Some(500), true, false).unwrap();
+    let unbounded = build(None, true, false).unwrap();
+    let positions = sorted_positions(&bounded);
+    assert_eq!(positions.len(), 4 * 1000);
+    assert_eq!(positions, sorted_positions(&unbounded));
+    assert!(max_points_in_memory(&bounded) <= 500);
+
+    // Sorting a leaf in Morton order needs all of its points.
+    match build(Some(500), false, true) {
+        Err(crate::errors::Error(ErrorKind::InvalidInput(_), _)) => (),
+        _ => panic!("Expected a build error."),
+    }
+    let bounded = build(Some(20_000), false, true).unwrap();
+    assert!(max_points_in_memory(&bounded) <= 20_000);
+    assert!(build(Some(0), false, false).is_err());
+}
+
+#[test]
+fn test_tile_output() {
+    // 2000 points on a grid of 40 x 50 points covering 10 x 5, which are 4 x 2 tiles of 2.5.
+    let num_points = 2000;
+    let positions: Vec<Point3<f64>> = (0..num_points)
+        .map(|i| {
+            Point3::new(
+                (i % 40) as f64 * 0.25 + 0.125,
+                (i / 40) as f64 * 0.1 + 0.05,
+                0.5,
+            )
+        })
+        .collect();
+    let bounding_box = Aabb::new(Point3::origin(), Point3::new(10.0, 10.0, 10.0));
+    let dir = TempDir::new("octree").unwrap();
+    let options = BuildOptions {
+        max_points_per_node: 100,
+        ..Default::default()
+    };
+    build_octree_with_options(
+        dir.path(),
+        0.001,
+        bounding_box,
+        vec![blue_batch(positions)].into_iter(),
+        &["color"],
+        &options,
+    )
+    .unwrap();
+    let octree = open_test_octree(dir.path());
+    let query_tiles = |deterministic: bool, num_threads: usize| {
+        let query = PointQuery {
+            attributes: vec!["color"],
+            tile_output: Some(2.5),
+            deterministic,
+            ..Default::default()
+        };
+        let mut tiles = Vec::new();
+        ParallelIterator::new(std::slice::from_ref(&octree), &query, 37, num_threads, 2)
+            .coalesce_batches(500)
+            .try_for_each_batch(|batch| {
+                let tile_x: &Vec<i64> = batch.get_attribute_vec(TILE_X_ATTRIBUTE).unwrap();
+                let tile_y: &Vec<i64> = batch.get_attribute_vec(TILE_Y_ATTRIBUTE).unwrap();
+                let tile = (tile_x[0], tile_y[0]);
+                assert!(tile_x.iter().all(|x| *x == tile.0));
+                assert!(tile_y.iter().all(|y| *y == tile.1));
+                for p in &batch.position {
+                    assert_eq!(tile_coordinates(p, 2.5), tile);
+                    assert!(tile.0 as f64 * 2.5 <= p.x && p.x < (tile.0 + 1) as f64 * 2.5);
+                    assert!(tile.1 as f64 * 2.5 <= p.y && p.y < (tile.1 + 1) as f64 * 2.5);
+                }
+                tiles.push((tile, batch.position));
+                Ok(())
+            })
+            .unwrap();
+        tiles
+    };
+
+    // Every tile is returned once, with all of its points, even with coalescing.
+    let tiles = query_tiles(false, 4);
+    let coordinates: Vec<(i64, i64)> = tiles.iter().map(|(tile, _)| *tile).collect();
+    let expected: Vec<(i64, i64)> = (0..4).flat_map(|x| (0..2).map(move |y| (x, y))).collect();
+    assert_eq!(coordinates, expected);
+    assert!(tiles
+        .iter()
+        .all(|(_, position)| position.len() == num_points / 8));
+
+    // Deterministic queries return the same tiles regardless of the number of threads.
+    assert_eq!(query_tiles(true, 1), query_tiles(true, 4));
+
+    assert!(PointQuery::from_json(r#"{"location": "AllPoints", "tile_output": 2.5}"#).is_ok());
+    assert!(PointQuery::from_json(r#"{"location": "AllPoints", "tile_output": 0}"#).is_err());
 }
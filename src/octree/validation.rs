@@ -0,0 +1,143 @@
+use crate::color::{COLOR16_ATTRIBUTE, RGBA_ATTRIBUTE};
+use crate::iterator::{
+    PointCloud, PointLocation, PointQuery, FRUSTUM_MASK_ATTRIBUTE, QUERY_WEIGHT_ATTRIBUTE,
+};
+use crate::octree::reencode::is_scalar;
+use crate::octree::Octree;
+use crate::AttributeDataType;
+use nalgebra::Point3;
+use std::fmt;
+
+/// A problem of a query with the attributes of an octree, see `Octree::validate_query`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryValidationError {
+    /// The query is invalid regardless of the octree, see `PointQuery::validate`, or its
+    /// attributes can't be resolved, see `PointQuery::resolve_attributes`.
+    InvalidQuery(String),
+    /// A requested attribute that the octree neither stores nor can compute.
+    UnknownAttribute(String),
+    /// A requested attribute that the octree can only compute for other queries, e.g.
+    /// `QUERY_WEIGHT_ATTRIBUTE` for a location without weights.
+    UnsupportedAttribute { attribute: String, reason: String },
+    /// A filter interval of an attribute that the octree does not store.
+    UnknownFilterAttribute(String),
+    /// A filter interval of an attribute that is not scalar, e.g. "color".
+    FilterTypeMismatch {
+        attribute: String,
+        data_type: AttributeDataType,
+    },
+    /// A filter interval of an attribute that the query does not request, which filtering needs.
+    FilterAttributeNotRequested(String),
+}
+
+impl fmt::Display for QueryValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryValidationError::InvalidQuery(message) => write!(f, "{}", message),
+            QueryValidationError::UnknownAttribute(attribute) => {
+                write!(f, "The attribute '{}' is not available.", attribute)
+            }
+            QueryValidationError::UnsupportedAttribute { attribute, reason } => write!(
+                f,
+                "The attribute '{}' is not available for this query: {}",
+                attribute, reason
+            ),
+            QueryValidationError::UnknownFilterAttribute(attribute) => write!(
+                f,
+                "The filter attribute '{}' is not stored in the octree.",
+                attribute
+            ),
+            QueryValidationError::FilterTypeMismatch {
+                attribute,
+                data_type,
+            } => write!(
+                f,
+                "The filter attribute '{}' has type {:?}, but only scalar attributes can be \
+                 filtered.",
+                attribute, data_type
+            ),
+            QueryValidationError::FilterAttributeNotRequested(attribute) => write!(
+                f,
+                "The filter attribute '{}' needs to be requested as well.",
+                attribute
+            ),
+        }
+    }
+}
+
+impl Octree {
+    /// Checks the attributes and filters of the query against the attributes of the octree
+    /// without reading any points, e.g. to give immediate feedback in a UI. Unlike running the
+    /// query, which stops at the first problem, this reports all of them, in the order of the
+    /// attributes and then of the filters by name.
+    pub fn validate_query(&self, query: &PointQuery) -> Result<(), Vec<QueryValidationError>> {
+        let mut errors = Vec::new();
+        if let Err(e) = query.validate() {
+            errors.push(QueryValidationError::InvalidQuery(e.to_string()));
+        }
+        let available = self.attribute_data_types();
+        let attributes = match query.expand_attributes(available) {
+            Ok(attributes) => attributes,
+            Err(e) => {
+                errors.push(QueryValidationError::InvalidQuery(e.to_string()));
+                Vec::new()
+            }
+        };
+        for attribute in &attributes {
+            if available.contains_key(*attribute) {
+                continue;
+            }
+            let unsupported = |reason: &str| QueryValidationError::UnsupportedAttribute {
+                attribute: (*attribute).to_string(),
+                reason: reason.to_string(),
+            };
+            match *attribute {
+                RGBA_ATTRIBUTE => (),
+                COLOR16_ATTRIBUTE if available.contains_key("color") => {
+                    if !query.upscale_color {
+                        errors.push(unsupported(
+                            "Only 8 bit 'color' is stored, which is only upscaled on request.",
+                        ));
+                    }
+                }
+                QUERY_WEIGHT_ATTRIBUTE => {
+                    if query.location.query_weight(&Point3::origin()).is_none() {
+                        errors.push(unsupported("The location has no weights."));
+                    }
+                }
+                FRUSTUM_MASK_ATTRIBUTE => {
+                    if !matches!(query.location, PointLocation::Frustums(_)) {
+                        errors.push(unsupported("The location is not Frustums."));
+                    }
+                }
+                _ => errors.push(QueryValidationError::UnknownAttribute(
+                    (*attribute).to_string(),
+                )),
+            }
+        }
+        let mut filter_attributes: Vec<&str> = query.filter_intervals.keys().copied().collect();
+        filter_attributes.sort_unstable();
+        for attribute in filter_attributes {
+            match available.get(attribute) {
+                None => errors.push(QueryValidationError::UnknownFilterAttribute(
+                    attribute.to_string(),
+                )),
+                Some(data_type) if !is_scalar(*data_type) => {
+                    errors.push(QueryValidationError::FilterTypeMismatch {
+                        attribute: attribute.to_string(),
+                        data_type: *data_type,
+                    })
+                }
+                Some(_) if !attributes.contains(&attribute) => errors.push(
+                    QueryValidationError::FilterAttributeNotRequested(attribute.to_string()),
+                ),
+                Some(_) => (),
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Conversion between the columns of a `PointsBatch` and a struct per point, e.g. the vertex type
+//! of a renderer. The layout of the struct is described with `point_layout!`:
+//!
+//! ```
+//! use nalgebra::{Point3, Vector3};
+//! use point_viewer::point_layout;
+//! use point_viewer::point_layout::PointLayout;
+//!
+//! #[derive(Clone, Copy)]
+//! struct Vertex {
+//!     position: Point3<f64>,
+//!     color: Vector3<u8>,
+//!     intensity: f32,
+//! }
+//!
+//! point_layout!(Vertex {
+//!     position,
+//!     color: Vector3<u8> = "color",
+//!     intensity: f32 = "intensity",
+//! });
+//!
+//! let vertices = vec![Vertex {
+//!     position: Point3::new(1.0, 2.0, 3.0),
+//!     color: Vector3::new(255, 0, 0),
+//!     intensity: 0.5,
+//! }];
+//! let batch = Vertex::to_batch(&vertices);
+//! assert_eq!(Vertex::from_batch(&batch).unwrap()[0].intensity, 0.5);
+//! ```
+
+use crate::geometry::Aabb;
+use crate::{AttributeData, AttributeDataType, PointsBatch};
+use nalgebra::{Point3, Vector3};
+use std::convert::TryFrom;
+
+/// The Rust type of the values of an attribute.
+pub trait AttributeValue: Copy {
+    const DATA_TYPE: AttributeDataType;
+
+    /// `None` if the data has another type.
+    fn values(data: &AttributeData) -> Option<&[Self]>;
+
+    fn into_data(values: Vec<Self>) -> AttributeData;
+}
+
+macro_rules! impl_attribute_value {
+    ($dtype:ident, $value_type:ty) => {
+        impl AttributeValue for $value_type {
+            const DATA_TYPE: AttributeDataType = AttributeDataType::$dtype;
+
+            fn values(data: &AttributeData) -> Option<&[Self]> {
+                <&Vec<$value_type>>::try_from(data).ok().map(Vec::as_slice)
+            }
+
+            fn into_data(values: Vec<Self>) -> AttributeData {
+                AttributeData::$dtype(values)
+            }
+        }
+    };
+}
+
+impl_attribute_value!(U8, u8);
+impl_attribute_value!(U16, u16);
+impl_attribute_value!(U32, u32);
+impl_attribute_value!(U64, u64);
+impl_attribute_value!(I8, i8);
+impl_attribute_value!(I16, i16);
+impl_attribute_value!(I32, i32);
+impl_attribute_value!(I64, i64);
+impl_attribute_value!(F32, f32);
+impl_attribute_value!(F64, f64);
+impl_attribute_value!(U8Vec3, Vector3<u8>);
+impl_attribute_value!(U16Vec3, Vector3<u16>);
+impl_attribute_value!(F64Vec3, Vector3<f64>);
+
+/// A struct holding the position and some attributes of a point. Implement it with
+/// `point_layout!`.
+pub trait PointLayout: Sized {
+    /// The attributes of the struct besides the position, by name.
+    fn attributes() -> Vec<(&'static str, AttributeDataType)>;
+
+    /// Transposes the points of the batch into structs. Fails if the batch lacks one of the
+    /// `attributes`, has it with another data type or not for every point. Other attributes of
+    /// the batch are ignored.
+    fn from_batch(batch: &PointsBatch) -> std::result::Result<Vec<Self>, String>;
+
+    /// Transposes the structs back into a batch with the `attributes`.
+    fn to_batch(points: &[Self]) -> PointsBatch;
+}
+
+/// The values of the attribute for every point of the batch, for `PointLayout::from_batch`.
+pub fn values<'a, T: AttributeValue>(
+    batch: &'a PointsBatch,
+    attribute: &str,
+) -> std::result::Result<&'a [T], String> {
+    let data = batch
+        .attributes
+        .get(attribute)
+        .ok_or_else(|| format!("Attribute '{}' not found.", attribute))?;
+    let values = T::values(data).ok_or_else(|| {
+        format!(
+            "Attribute '{}' has data type '{:?}', but the layout needs '{:?}'.",
+            attribute,
+            data.data_type(),
+            T::DATA_TYPE
+        )
+    })?;
+    if values.len() != batch.position.len() {
+        return Err(format!(
+            "Attribute '{}' has {} values for {} points.",
+            attribute,
+            values.len(),
+            batch.position.len()
+        ));
+    }
+    Ok(values)
+}
+
+/// A batch of the positions, with the bounding box, for `PointLayout::to_batch`.
+pub fn batch_with_positions(position: Vec<Point3<f64>>) -> PointsBatch {
+    PointsBatch {
+        bounding_box: Aabb::from_points(&position),
+        position,
+        attributes: Default::default(),
+    }
+}
+
+/// Implements `PointLayout` for a struct with a `Point3<f64>` position field and fields of
+/// `AttributeValue` types, each of which is named after the attribute it holds:
+/// `point_layout!(Vertex { position, color: Vector3<u8> = "color" })`.
+#[macro_export]
+macro_rules! point_layout {
+    ($name:ident { $position:ident, $($field:ident: $value_type:ty = $attribute:expr),* $(,)? }) => {
+        impl $crate::point_layout::PointLayout for $name {
+            fn attributes() -> Vec<(&'static str, $crate::attributes::AttributeDataType)> {
+                vec![$((
+                    $attribute,
+                    <$value_type as $crate::point_layout::AttributeValue>::DATA_TYPE,
+                )),*]
+            }
+
+            fn from_batch(
+                batch: &$crate::PointsBatch,
+            ) -> std::result::Result<Vec<Self>, String> {
+                $(let $field: &[$value_type] =
+                    $crate::point_layout::values(batch, $attribute)?;)*
+                Ok(batch
+                    .position
+                    .iter()
+                    .enumerate()
+                    .map(|(index, position)| $name {
+                        $position: *position,
+                        $($field: $field[index],)*
+                    })
+                    .collect())
+            }
+
+            fn to_batch(points: &[Self]) -> $crate::PointsBatch {
+                let mut batch = $crate::point_layout::batch_with_positions(
+                    points.iter().map(|point| point.$position).collect(),
+                );
+                $(batch.attributes.insert(
+                    $attribute.to_string(),
+                    <$value_type as $crate::point_layout::AttributeValue>::into_data(
+                        points.iter().map(|point| point.$field).collect(),
+                    ),
+                );)*
+                batch
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Vertex {
+        position: Point3<f64>,
+        color: Vector3<u8>,
+        intensity: f32,
+        classification: u8,
+    }
+
+    point_layout!(Vertex {
+        position,
+        color: Vector3<u8> = "color",
+        intensity: f32 = "intensity",
+        classification: u8 = "classification",
+    });
+
+    #[test]
+    fn test_round_trip() {
+        let vertices: Vec<Vertex> = (0..10)
+            .map(|i| Vertex {
+                position: Point3::new(f64::from(i), -0.5 * f64::from(i), 2.0),
+                color: Vector3::new(i, 2 * i, 255 - i),
+                intensity: f32::from(i) * 0.25,
+                classification: i % 3,
+            })
+            .collect();
+        let batch = Vertex::to_batch(&vertices);
+        assert_eq!(
+            batch.bounding_box,
+            Some(Aabb::new(
+                Point3::new(0.0, -4.5, 2.0),
+                Point3::new(9.0, 0.0, 2.0)
+            ))
+        );
+        let intensity: &Vec<f32> = batch.get_attribute_vec("intensity").unwrap();
+        assert_eq!(intensity[3], 0.75);
+        assert_eq!(Vertex::from_batch(&batch).unwrap(), vertices);
+        assert_eq!(
+            Vertex::attributes(),
+            vec![
+                ("color", AttributeDataType::U8Vec3),
+                ("intensity", AttributeDataType::F32),
+                ("classification", AttributeDataType::U8),
+            ]
+        );
+
+        // The layout is checked against the batch.
+        let mut other_type = batch.clone();
+        other_type
+            .attributes
+            .insert("intensity".to_string(), AttributeData::F64(vec![0.0; 10]));
+        assert!(Vertex::from_batch(&other_type)
+            .unwrap_err()
+            .contains("needs 'F32'"));
+        let mut too_short = batch.clone();
+        too_short
+            .attributes
+            .insert("classification".to_string(), AttributeData::U8(vec![0; 9]));
+        assert!(Vertex::from_batch(&too_short).is_err());
+        let mut missing = batch;
+        missing.attributes.remove("color");
+        assert!(Vertex::from_batch(&missing).is_err());
+    }
+}
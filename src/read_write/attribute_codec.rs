@@ -0,0 +1,304 @@
+use crate::errors::*;
+use crate::proto;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// A step of the compression of the node data of an attribute, see
+/// `BuildOptions::attribute_codecs`. The steps are applied in order when writing, and in reverse
+/// when reading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttributeCodec {
+    /// Stores the difference of every value to the one of the previous point, e.g. for positions
+    /// in Morton order, which are close to each other. This only saves space when followed by
+    /// `Deflate`.
+    Delta,
+    /// Stores runs of points with equal values once, e.g. for the classification.
+    RunLength,
+    /// General purpose compression.
+    Deflate,
+}
+
+impl AttributeCodec {
+    pub fn from_proto(proto: proto::AttributeCodec) -> Result<Self> {
+        match proto {
+            proto::AttributeCodec::DELTA => Ok(AttributeCodec::Delta),
+            proto::AttributeCodec::RUN_LENGTH => Ok(AttributeCodec::RunLength),
+            proto::AttributeCodec::DEFLATE => Ok(AttributeCodec::Deflate),
+            proto::AttributeCodec::INVALID_CODEC => {
+                Err(ErrorKind::InvalidInput("Proto: AttributeCodec is invalid".to_string()).into())
+            }
+        }
+    }
+
+    pub fn to_proto(self) -> proto::AttributeCodec {
+        match self {
+            AttributeCodec::Delta => proto::AttributeCodec::DELTA,
+            AttributeCodec::RunLength => proto::AttributeCodec::RUN_LENGTH,
+            AttributeCodec::Deflate => proto::AttributeCodec::DEFLATE,
+        }
+    }
+}
+
+fn read_value(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .enumerate()
+        .fold(0, |value, (i, byte)| value | u64::from(*byte) << (8 * i))
+}
+
+fn write_value(value: u64, value_size: usize, out: &mut Vec<u8>) {
+    out.extend((0..value_size).map(|i| (value >> (8 * i)) as u8));
+}
+
+/// Values are subtracted as unsigned integers of their size, wrapping around, so that this is
+/// lossless for floats as well.
+fn delta_encode(data: &[u8], value_size: usize, components: usize) -> Result<Vec<u8>> {
+    if value_size == 0 || value_size > 8 || components == 0 || components > 255 {
+        return Err(ErrorKind::InvalidInput(format!(
+            "Delta coding needs values of 1 to 8 bytes, found {} bytes and {} components.",
+            value_size, components
+        ))
+        .into());
+    }
+    if data.len() % (value_size * components) != 0 {
+        return Err(ErrorKind::InvalidInput(format!(
+            "{} bytes are not a whole number of points of {} values of {} bytes.",
+            data.len(),
+            components,
+            value_size
+        ))
+        .into());
+    }
+    let mut out = Vec::with_capacity(data.len() + 2);
+    out.push(value_size as u8);
+    out.push(components as u8);
+    let values: Vec<u64> = data.chunks(value_size).map(read_value).collect();
+    for (i, value) in values.iter().enumerate() {
+        let previous = if i >= components {
+            values[i - components]
+        } else {
+            0
+        };
+        write_value(value.wrapping_sub(previous), value_size, &mut out);
+    }
+    Ok(out)
+}
+
+fn delta_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let (value_size, components) = match data {
+        [value_size, components, ..] => (usize::from(*value_size), usize::from(*components)),
+        _ => {
+            return Err(ErrorKind::Decode("Delta coded data lacks its header.".to_string()).into())
+        }
+    };
+    let data = &data[2..];
+    if value_size == 0 || value_size > 8 || components == 0 || data.len() % value_size != 0 {
+        return Err(ErrorKind::Decode(format!(
+            "{} bytes of delta coded data do not fit values of {} bytes.",
+            data.len(),
+            value_size
+        ))
+        .into());
+    }
+    let mut values: Vec<u64> = Vec::with_capacity(data.len() / value_size);
+    let mut out = Vec::with_capacity(data.len());
+    for (i, delta) in data.chunks(value_size).enumerate() {
+        let previous = if i >= components {
+            values[i - components]
+        } else {
+            0
+        };
+        let value = read_value(delta).wrapping_add(previous);
+        write_value(value, value_size, &mut out);
+        // Only the bytes of the value size are kept, like they are written.
+        values.push(read_value(&out[out.len() - value_size..]));
+    }
+    Ok(out)
+}
+
+fn run_length_encode(data: &[u8], point_size: usize) -> Result<Vec<u8>> {
+    if point_size == 0 || point_size > 255 || data.len() % point_size != 0 {
+        return Err(ErrorKind::InvalidInput(format!(
+            "{} bytes are not a whole number of points of {} bytes.",
+            data.len(),
+            point_size
+        ))
+        .into());
+    }
+    let mut out = vec![point_size as u8];
+    let mut points = data.chunks(point_size).peekable();
+    while let Some(point) = points.next() {
+        let mut run_length: u32 = 1;
+        while run_length < u32::MAX && points.peek() == Some(&point) {
+            points.next();
+            run_length += 1;
+        }
+        out.extend_from_slice(&run_length.to_le_bytes());
+        out.extend_from_slice(point);
+    }
+    Ok(out)
+}
+
+/// Fails as soon as the runs hold more than `max_points` points, so that corrupt run lengths do
+/// not exhaust the memory.
+fn run_length_decode(data: &[u8], max_points: usize) -> Result<Vec<u8>> {
+    let point_size = match data.first() {
+        Some(point_size) if *point_size > 0 => usize::from(*point_size),
+        _ => {
+            return Err(
+                ErrorKind::Decode("Run length coded data lacks its header.".to_string()).into(),
+            )
+        }
+    };
+    let runs = &data[1..];
+    if runs.len() % (4 + point_size) != 0 {
+        return Err(ErrorKind::Decode(format!(
+            "{} bytes of run length coded data are not whole runs of points of {} bytes.",
+            runs.len(),
+            point_size
+        ))
+        .into());
+    }
+    let mut out = Vec::with_capacity(max_points.saturating_mul(point_size));
+    let mut num_points = 0usize;
+    for run in runs.chunks(4 + point_size) {
+        let run_length = read_value(&run[..4]) as usize;
+        num_points = num_points.saturating_add(run_length);
+        if num_points > max_points {
+            return Err(ErrorKind::Decode(format!(
+                "Run length coded data holds more than the expected {} points.",
+                max_points
+            ))
+            .into());
+        }
+        for _ in 0..run_length {
+            out.extend_from_slice(&run[4..]);
+        }
+    }
+    Ok(out)
+}
+
+/// Compresses the node data of an attribute with the codecs, in order. The data consists of
+/// `components` values of `value_size` bytes per point, e.g. 2 and 3 for U16Vec3 colors. The
+/// codecs store what they need of this in their output, so that decoding only needs the codecs.
+pub fn encode_attribute(
+    data: Vec<u8>,
+    codecs: &[AttributeCodec],
+    value_size: usize,
+    components: usize,
+) -> Result<Vec<u8>> {
+    let (mut value_size, mut components) = (value_size, components);
+    let mut data = data;
+    for codec in codecs {
+        data = match codec {
+            AttributeCodec::Delta => delta_encode(&data, value_size, components)?,
+            AttributeCodec::RunLength => run_length_encode(&data, value_size * components)?,
+            AttributeCodec::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()?
+            }
+        };
+        // The output starts with a header, so later codecs see bytes.
+        value_size = 1;
+        components = 1;
+    }
+    Ok(data)
+}
+
+/// The largest points that delta coding accepts, 255 values of 8 bytes, bound the bytes per point
+/// of the data between two codecs, besides the headers.
+const MAX_CODED_BYTES_PER_POINT: usize = 8 * 255;
+
+/// Reverses `encode_attribute` with the same codecs, for the data of `num_points` points, e.g. the
+/// number of points of the node in the meta data.
+pub fn decode_attribute(
+    data: Vec<u8>,
+    codecs: &[AttributeCodec],
+    num_points: usize,
+) -> Result<Vec<u8>> {
+    let mut data = data;
+    for (i, codec) in codecs.iter().enumerate().rev() {
+        data = match codec {
+            AttributeCodec::Delta => delta_decode(&data)?,
+            AttributeCodec::RunLength => {
+                // Only the first codec sees the points, the later ones code the bytes of the
+                // output of the codecs before them, including their headers.
+                let max_points = if i == 0 {
+                    num_points
+                } else {
+                    num_points
+                        .saturating_mul(MAX_CODED_BYTES_PER_POINT)
+                        .saturating_add(2 * i)
+                };
+                run_length_decode(&data, max_points)?
+            }
+            AttributeCodec::Deflate => {
+                let mut decoded = Vec::new();
+                DeflateDecoder::new(&data[..])
+                    .read_to_end(&mut decoded)
+                    .map_err(|e| ErrorKind::Decode(format!("Could not inflate data: {}", e)))?;
+                decoded
+            }
+        };
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codecs_round_trip() {
+        let data: Vec<u8> = (0..300u16)
+            .flat_map(|i| {
+                let value = if i % 3 == 0 { 65_000 - i } else { i / 10 };
+                value.to_le_bytes().to_vec()
+            })
+            .collect();
+        let chains: [&[AttributeCodec]; 5] = [
+            &[],
+            &[AttributeCodec::Delta],
+            &[AttributeCodec::RunLength],
+            &[AttributeCodec::Delta, AttributeCodec::Deflate],
+            &[
+                AttributeCodec::Delta,
+                AttributeCodec::RunLength,
+                AttributeCodec::Deflate,
+            ],
+        ];
+        for codecs in &chains {
+            let encoded = encode_attribute(data.clone(), codecs, 2, 3).unwrap();
+            assert_eq!(decode_attribute(encoded, codecs, 300).unwrap(), data);
+        }
+
+        // Runs of equal points collapse.
+        let classes = [vec![2u8; 1000], vec![6u8; 1000]].concat();
+        let encoded =
+            encode_attribute(classes.clone(), &[AttributeCodec::RunLength], 1, 1).unwrap();
+        assert_eq!(encoded.len(), 1 + 2 * 5);
+        assert_eq!(
+            decode_attribute(encoded.clone(), &[AttributeCodec::RunLength], 2000).unwrap(),
+            classes
+        );
+        // More points than expected are rejected before they are written out.
+        assert!(decode_attribute(encoded, &[AttributeCodec::RunLength], 1999).is_err());
+        let mut huge_run = vec![1];
+        huge_run.extend_from_slice(&u32::MAX.to_le_bytes());
+        huge_run.push(7);
+        match decode_attribute(huge_run, &[AttributeCodec::RunLength], 1000)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::Decode(_) => (),
+            kind => panic!("Unexpected error: {:?}", kind),
+        }
+
+        assert!(encode_attribute(vec![0; 5], &[AttributeCodec::Delta], 2, 1).is_err());
+        assert!(decode_attribute(vec![2, 1, 0], &[AttributeCodec::Delta], 1).is_err());
+        assert!(decode_attribute(vec![1, 0, 0], &[AttributeCodec::RunLength], 1).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use crate::errors::*;
+use crate::iterator::CLASSIFICATION_ATTRIBUTE;
+use crate::read_write::{DataWriter, OpenMode};
+use crate::{AttributeData, PointsBatch};
+use byteorder::{LittleEndian, WriteBytesExt};
+use nalgebra::{Point3, Vector3};
+use std::convert::TryFrom;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// The positions are stored as integers in units of this scale, i.e. in millimeters.
+pub const LAS_SCALE: f64 = 0.001;
+
+/// The size of the header of LAS 1.2, after which the points begin.
+const HEADER_SIZE: u16 = 227;
+/// Point data record format 2 has the position, intensity, classification and color.
+const POINT_DATA_FORMAT: u8 = 2;
+const POINT_RECORD_LENGTH: u16 = 26;
+/// Where the counts and the bounds are in the header, which are filled in when the writer is
+/// dropped.
+const NUM_POINTS_OFFSET: u64 = 107;
+const BOUNDS_OFFSET: u64 = 179;
+
+/// Writes points into a LAS 1.2 file with point data record format 2, e.g. the results of a query.
+/// The colors are taken from a U8Vec3 "color" attribute, the intensities from an F32 "intensity"
+/// attribute, rounded and clamped to the range of u16, and the classes from a U8
+/// `CLASSIFICATION_ATTRIBUTE`. Missing attributes are stored as 0. The positions are stored in
+/// units of `LAS_SCALE` relative to the offset, see `with_offset`, which the header records. The
+/// file is only complete once the writer is dropped.
+pub struct LasNodeWriter {
+    writer: DataWriter,
+    offset: Vector3<f64>,
+    point_count: usize,
+    min: Point3<f64>,
+    max: Point3<f64>,
+}
+
+impl LasNodeWriter {
+    pub fn new(filename: impl Into<PathBuf>) -> Result<Self> {
+        Ok(LasNodeWriter {
+            writer: DataWriter::new(filename, OpenMode::Truncate)?,
+            offset: Vector3::zeros(),
+            point_count: 0,
+            min: Point3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: Point3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        })
+    }
+
+    /// Stores the positions relative to the offset, e.g. the minimum of the bounding box of a
+    /// far-from-origin point cloud. Without it, positions more than about 2000 km from the origin
+    /// do not fit.
+    pub fn with_offset(mut self, offset: Vector3<f64>) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn write(&mut self, p: &PointsBatch) -> Result<()> {
+        if p.position.is_empty() {
+            return Ok(());
+        }
+        let color = match p.attributes.get("color") {
+            Some(AttributeData::U8Vec3(color)) => Some(color),
+            None => None,
+            data => return Err(wrong_attribute("color", "U8Vec3", data)),
+        };
+        let intensity = match p.attributes.get("intensity") {
+            Some(AttributeData::F32(intensity)) => Some(intensity),
+            None => None,
+            data => return Err(wrong_attribute("intensity", "F32", data)),
+        };
+        let classification = match p.attributes.get(CLASSIFICATION_ATTRIBUTE) {
+            Some(AttributeData::U8(classification)) => Some(classification),
+            None => None,
+            data => return Err(wrong_attribute(CLASSIFICATION_ATTRIBUTE, "U8", data)),
+        };
+        if self.point_count == 0 {
+            self.create_header()?;
+        }
+
+        for (i, pos) in p.position.iter().enumerate() {
+            let local = (pos - self.offset).coords / LAS_SCALE;
+            for value in local.iter() {
+                let value = value.round();
+                if !(f64::from(i32::MIN)..=f64::from(i32::MAX)).contains(&value) {
+                    return Err(ErrorKind::InvalidInput(format!(
+                        "The position {} does not fit into a LAS file with offset {}.",
+                        pos, self.offset
+                    ))
+                    .into());
+                }
+                self.writer.write_i32::<LittleEndian>(value as i32)?;
+            }
+            let intensity = intensity.map_or(0, |intensity| {
+                intensity[i].round().max(0.0).min(f32::from(u16::MAX)) as u16
+            });
+            self.writer.write_u16::<LittleEndian>(intensity)?;
+            // Return number 1 of 1.
+            self.writer.write_u8(0b0000_1001)?;
+            self.writer
+                .write_u8(classification.map_or(0, |classification| classification[i]))?;
+            // The scan angle rank, user data and point source ID.
+            self.writer.write_all(&[0; 4])?;
+            // LAS colors are 16 bit.
+            let rgb = color.map_or(Vector3::zeros(), |color| color[i]);
+            for channel in rgb.iter() {
+                self.writer
+                    .write_u16::<LittleEndian>(u16::from(*channel) * 257)?;
+            }
+            self.min = self.min.inf(pos);
+            self.max = self.max.sup(pos);
+        }
+
+        self.point_count += p.position.len();
+        if u32::try_from(self.point_count).is_err() {
+            return Err(ErrorKind::InvalidInput(format!(
+                "LAS 1.2 files hold at most {} points.",
+                u32::MAX
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn create_header(&mut self) -> Result<()> {
+        let mut header = Vec::with_capacity(usize::from(HEADER_SIZE));
+        header.extend_from_slice(b"LASF");
+        // The file source ID, global encoding and project ID.
+        header.extend_from_slice(&[0; 20]);
+        header.extend_from_slice(&[1, 2]);
+        header.extend_from_slice(&padded(b"point_viewer"));
+        header.extend_from_slice(&padded(b"point_viewer"));
+        // The creation day and year are optional.
+        header.extend_from_slice(&[0; 4]);
+        header.write_u16::<LittleEndian>(HEADER_SIZE)?;
+        header.write_u32::<LittleEndian>(u32::from(HEADER_SIZE))?;
+        // No variable length records.
+        header.write_u32::<LittleEndian>(0)?;
+        header.write_u8(POINT_DATA_FORMAT)?;
+        header.write_u16::<LittleEndian>(POINT_RECORD_LENGTH)?;
+        // The counts of points, in total and by return.
+        header.extend_from_slice(&[0; 24]);
+        for _ in 0..3 {
+            header.write_f64::<LittleEndian>(LAS_SCALE)?;
+        }
+        for value in self.offset.iter() {
+            header.write_f64::<LittleEndian>(*value)?;
+        }
+        // The bounds.
+        header.extend_from_slice(&[0; 48]);
+        self.writer.write_all(&header)?;
+        Ok(())
+    }
+
+    fn finish_header(&mut self) -> Result<()> {
+        let point_count = self.point_count as u32;
+        self.writer.seek(SeekFrom::Start(NUM_POINTS_OFFSET))?;
+        // All points are first returns.
+        self.writer.write_u32::<LittleEndian>(point_count)?;
+        self.writer.write_u32::<LittleEndian>(point_count)?;
+        self.writer.seek(SeekFrom::Start(BOUNDS_OFFSET))?;
+        for axis in 0..3 {
+            self.writer.write_f64::<LittleEndian>(self.max[axis])?;
+            self.writer.write_f64::<LittleEndian>(self.min[axis])?;
+        }
+        Ok(())
+    }
+}
+
+/// The strings in the header are 32 bytes, padded with null bytes.
+fn padded(value: &[u8]) -> [u8; 32] {
+    let mut padded = [0; 32];
+    padded[..value.len()].copy_from_slice(value);
+    padded
+}
+
+fn wrong_attribute(name: &str, data_type: &str, data: Option<&AttributeData>) -> Error {
+    ErrorKind::InvalidInput(format!(
+        "The LAS file has the field of attribute '{}', which needs {} data, found {:?}.",
+        name,
+        data_type,
+        data.map(AttributeData::data_type)
+    ))
+    .into()
+}
+
+impl Drop for LasNodeWriter {
+    fn drop(&mut self) {
+        if self.point_count == 0 {
+            return;
+        }
+        let _res = self.finish_header();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tempdir::TempDir;
+
+    fn read_f64(bytes: &[u8], offset: usize) -> f64 {
+        f64::from_le_bytes(<[u8; 8]>::try_from(&bytes[offset..offset + 8]).unwrap())
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(<[u8; 4]>::try_from(&bytes[offset..offset + 4]).unwrap())
+    }
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(<[u8; 2]>::try_from(&bytes[offset..offset + 2]).unwrap())
+    }
+
+    #[test]
+    fn test_las_write() {
+        let tmp_dir = TempDir::new("test_las_write").unwrap();
+        let path = tmp_dir.path().join("points.las");
+        let offset = Vector3::new(4_100_000.0, 500_000.0, 4_800_000.0);
+        let position: Vec<Point3<f64>> = (0..5)
+            .map(|i| Point3::new(0.25 * i as f64, -1.5, 2.0 + i as f64) + offset)
+            .collect();
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "color".to_string(),
+            AttributeData::U8Vec3((0..5).map(|i| Vector3::new(i, 128, 255)).collect()),
+        );
+        attributes.insert(
+            "intensity".to_string(),
+            AttributeData::F32(vec![-1.0, 0.4, 12.6, 1e6, 3.0]),
+        );
+        let batch = PointsBatch {
+            position: position.clone(),
+            attributes,
+            bounding_box: None,
+        };
+        {
+            let mut writer = LasNodeWriter::new(&path).unwrap().with_offset(offset);
+            writer.write(&batch).unwrap();
+        }
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], b"LASF");
+        assert_eq!(&bytes[24..26], &[1, 2]);
+        assert_eq!(read_u32(&bytes, 96), u32::from(HEADER_SIZE));
+        assert_eq!(bytes[104], POINT_DATA_FORMAT);
+        assert_eq!(read_u16(&bytes, 105), POINT_RECORD_LENGTH);
+        assert_eq!(read_u32(&bytes, 107), 5);
+        assert_eq!(read_u32(&bytes, 111), 5);
+        assert_eq!(read_f64(&bytes, 131), LAS_SCALE);
+        assert_eq!(read_f64(&bytes, 155), offset.x);
+        // The maximum and minimum of x.
+        assert_eq!(read_f64(&bytes, 179), offset.x + 1.0);
+        assert_eq!(read_f64(&bytes, 187), offset.x);
+        assert_eq!(
+            bytes.len(),
+            usize::from(HEADER_SIZE) + 5 * usize::from(POINT_RECORD_LENGTH)
+        );
+
+        let records: Vec<&[u8]> = bytes[usize::from(HEADER_SIZE)..]
+            .chunks(usize::from(POINT_RECORD_LENGTH))
+            .collect();
+        for (i, (record, original)) in records.iter().zip(&position).enumerate() {
+            let read = Point3::new(
+                f64::from(read_u32(record, 0) as i32),
+                f64::from(read_u32(record, 4) as i32),
+                f64::from(read_u32(record, 8) as i32),
+            ) * LAS_SCALE
+                + offset;
+            assert!((read - original).norm() < 1e-9);
+            assert_eq!(read_u16(record, 20), i as u16 * 257);
+            assert_eq!(read_u16(record, 24), u16::MAX);
+        }
+        let intensities: Vec<u16> = records.iter().map(|record| read_u16(record, 12)).collect();
+        assert_eq!(intensities, vec![0, 0, 13, u16::MAX, 3]);
+    }
+
+    #[test]
+    fn test_las_rejects_positions_out_of_range() {
+        let tmp_dir = TempDir::new("test_las_rejects_positions_out_of_range").unwrap();
+        let batch = PointsBatch {
+            position: vec![Point3::new(4_100_000.0, 0.0, 0.0)],
+            attributes: BTreeMap::new(),
+            bounding_box: None,
+        };
+        let mut writer = LasNodeWriter::new(tmp_dir.path().join("points.las")).unwrap();
+        assert!(writer.write(&batch).is_err());
+    }
+}
@@ -12,18 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod attribute_codec;
+pub use self::attribute_codec::{decode_attribute, encode_attribute, AttributeCodec};
+
 mod codec;
 pub use self::codec::{
     decode, fixpoint_decode, fixpoint_encode, vec3_encode, vec3_fixpoint_encode, Encoding,
     PositionEncoding,
 };
 
+mod las;
+pub use self::las::{LasNodeWriter, LAS_SCALE};
+
 mod node_iterator;
 pub use self::node_iterator::NodeIterator;
 
 mod node_writer;
 pub use self::node_writer::{DataWriter, NodeWriter, OpenMode, WriteEncoded, WriteLE, WriteLEPos};
 
+mod pcd;
+pub use self::pcd::{PcdDataFormat, PcdNodeWriter};
+
 mod ply;
 pub use self::ply::{PlyIterator, PlyNodeWriter};
 
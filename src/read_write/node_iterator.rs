@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::data_provider::DataProvider;
+use crate::data_provider::{CancellationToken, DataProvider};
 use crate::errors::*;
 use crate::read_write::{AttributeReader, Encoding, RawNodeReader};
 use crate::{AttributeDataType, NumberOfPoints, PointsBatch};
@@ -21,22 +21,15 @@ use std::collections::HashMap;
 use std::io::BufReader;
 
 /// Streams points from our data provider representation.
+#[derive(Default)]
 pub struct NodeIterator {
     reader: Option<RawNodeReader>,
     num_points: usize,
     point_count: usize,
     batch_size: usize,
-}
-
-impl Default for NodeIterator {
-    fn default() -> Self {
-        NodeIterator {
-            reader: None,
-            num_points: 0,
-            point_count: 0,
-            batch_size: 0,
-        }
-    }
+    /// Once cancelled, the failing reads return `ErrorKind::Cancelled`, and the iterator ends
+    /// early instead of panicking.
+    cancellation: Option<CancellationToken>,
 }
 
 impl NodeIterator {
@@ -48,8 +41,8 @@ impl NodeIterator {
         NodeIterator {
             reader: Some(reader),
             num_points,
-            point_count: 0,
             batch_size,
+            ..Default::default()
         }
     }
 
@@ -60,14 +53,20 @@ impl NodeIterator {
         id: &Id,
         num_points: usize,
         batch_size: usize,
+        cancellation: Option<&CancellationToken>,
     ) -> Result<Self> {
         if num_points == 0 {
             return Ok(NodeIterator::default());
         }
 
         let attributes: Vec<&str> = attribute_data_types.keys().map(String::as_str).collect();
-        let mut all_reads =
-            data_provider.data(&id.to_string(), &[&["position"], &attributes[..]].concat())?;
+        let node_attributes = [&["position"], &attributes[..]].concat();
+        let mut all_reads = match cancellation {
+            Some(cancellation) => {
+                data_provider.data_cancellable(&id.to_string(), &node_attributes, cancellation)?
+            }
+            None => data_provider.data(&id.to_string(), &node_attributes)?,
+        };
         // Unwrapping all following removals is safe,
         // as the data provider would already have errored on unavailability.
         let position_reader = all_reads.remove("position").unwrap();
@@ -81,11 +80,56 @@ impl NodeIterator {
             })
             .collect();
 
-        Ok(Self::new(
+        let mut node_iterator = Self::new(
             RawNodeReader::new(position_reader, attribute_readers, encoding)?,
             num_points,
             batch_size,
-        ))
+        );
+        node_iterator.cancellation = cancellation.cloned();
+        Ok(node_iterator)
+    }
+
+    /// Reads the remaining data of the node into memory, see `RawNodeReader::prefetch`.
+    pub fn prefetch(&mut self) -> Result<()> {
+        if let Some(reader) = &mut self.reader {
+            if let Err(e) = reader.prefetch() {
+                // Once cancelled, the reads fail.
+                return Err(if self.is_cancelled() {
+                    ErrorKind::Cancelled.into()
+                } else {
+                    e.into()
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn is_cancelled(&self) -> bool {
+        matches!(&self.cancellation, Some(cancellation) if cancellation.is_cancelled())
+    }
+
+    /// Like `next`, but returns an `ErrorKind::Decode` if the node data is corrupt instead of
+    /// panicking, e.g. to skip the node, and an `ErrorKind::Cancelled` if a read fails once the
+    /// node is cancelled, so that a cut off node is not taken for a complete one.
+    pub fn try_next(&mut self) -> Result<Option<PointsBatch>> {
+        if let Some(reader) = &mut self.reader {
+            if self.point_count < self.num_points {
+                let num_points_to_read =
+                    std::cmp::min(self.batch_size, self.num_points - self.point_count);
+                let res = match reader.read_batch(num_points_to_read) {
+                    Ok(res) => res,
+                    Err(_) if self.is_cancelled() => return Err(ErrorKind::Cancelled.into()),
+                    Err(e) => {
+                        return Err(
+                            ErrorKind::Decode(format!("Couldn't read from node: {}", e)).into()
+                        )
+                    }
+                };
+                self.point_count += num_points_to_read;
+                return Ok(Some(res));
+            }
+        }
+        Ok(None)
     }
 }
 
@@ -103,17 +147,12 @@ impl Iterator for NodeIterator {
         (num_batches, Some(num_batches))
     }
     fn next(&mut self) -> Option<PointsBatch> {
-        if let Some(reader) = &mut self.reader {
-            if self.point_count < self.num_points {
-                let num_points_to_read =
-                    std::cmp::min(self.batch_size, self.num_points - self.point_count);
-                let res = reader
-                    .read_batch(num_points_to_read)
-                    .expect("Couldn't read from node.");
-                self.point_count += num_points_to_read;
-                return Some(res);
-            }
+        match self.try_next() {
+            Ok(batch) => batch,
+            Err(e) => match e.kind() {
+                ErrorKind::Cancelled => None,
+                _ => panic!("{}", e),
+            },
         }
-        None
     }
 }
@@ -204,6 +204,15 @@ impl WriteLE for Vec<Vector3<u8>> {
     }
 }
 
+impl WriteLE for Vec<Vector3<u16>> {
+    fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
+        for elem in self {
+            elem.write_le(writer)?;
+        }
+        Ok(())
+    }
+}
+
 impl WriteLE for Vec<Vector3<f64>> {
     fn write_le(&self, writer: &mut DataWriter) -> Result<()> {
         for elem in self {
@@ -0,0 +1,381 @@
+use crate::errors::*;
+use crate::read_write::{DataWriter, OpenMode};
+use crate::{AttributeData, PointsBatch};
+use byteorder::{LittleEndian, WriteBytesExt};
+use nalgebra::Vector3;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// The counts in the header have a fixed width, so that they can be filled in when the writer is
+/// dropped. PCD counts are 32 bit.
+const HEADER_NUM_POINTS: &str = "0000000000";
+
+/// How the points are stored after the header of a PCD file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PcdDataFormat {
+    /// One line of text per point.
+    Ascii,
+    /// Little endian values, point after point.
+    Binary,
+}
+
+/// Writes points into a PCD file of the Point Cloud Library, e.g. the results of a query. Besides
+/// `x y z`, the file has an `rgb` field if the points have a U8Vec3 "color" attribute, packed into
+/// a float as PCL does, and an `intensity` field if they have an F32 "intensity" attribute. The
+/// fields are fixed by the first batch. Positions are stored as F32 like in the point types of PCL,
+/// so large coordinates lose precision, unless they are stored relative to an offset, see
+/// `with_offset`. The file is only complete once the writer is dropped.
+pub struct PcdNodeWriter {
+    writer: DataWriter,
+    path: PathBuf,
+    data_format: PcdDataFormat,
+    offset: Option<Vector3<f64>>,
+    point_count: usize,
+    has_color: bool,
+    has_intensity: bool,
+    // Where the counts of points are in the header.
+    count_offsets: Vec<u64>,
+}
+
+impl PcdNodeWriter {
+    pub fn new(filename: impl Into<PathBuf>, data_format: PcdDataFormat) -> Result<Self> {
+        let path = filename.into();
+        Ok(PcdNodeWriter {
+            writer: DataWriter::new(&path, OpenMode::Truncate)?,
+            path,
+            data_format,
+            offset: None,
+            point_count: 0,
+            has_color: false,
+            has_intensity: false,
+            count_offsets: Vec::new(),
+        })
+    }
+
+    /// Stores the positions relative to the offset, e.g. the minimum of the bounding box of a
+    /// far-from-origin point cloud, so that they keep their precision as F32. PCD has no field for
+    /// this, so the offset is written to a sidecar file at `offset_path`, and the absolute
+    /// positions are the stored ones plus the offset, see `read_offset`.
+    pub fn with_offset(mut self, offset: Vector3<f64>) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Where the offset of the PCD file at `pcd_path` is stored, see `with_offset`.
+    pub fn offset_path(pcd_path: impl AsRef<Path>) -> PathBuf {
+        pcd_path.as_ref().with_extension("offset.json")
+    }
+
+    /// Reads the offset of the PCD file at `pcd_path`, see `with_offset`.
+    pub fn read_offset(pcd_path: impl AsRef<Path>) -> Result<Vector3<f64>> {
+        let file =
+            File::open(Self::offset_path(pcd_path)).chain_err(|| "Could not open offset.")?;
+        let sidecar: serde_json::Value =
+            serde_json::from_reader(file).chain_err(|| "Could not parse offset.")?;
+        serde_json::from_value(sidecar["offset"].clone()).chain_err(|| "Could not parse offset.")
+    }
+
+    pub fn write(&mut self, p: &PointsBatch) -> Result<()> {
+        if p.position.is_empty() {
+            return Ok(());
+        }
+        if self.point_count == 0 {
+            self.has_color = p.attributes.contains_key("color");
+            self.has_intensity = p.attributes.contains_key("intensity");
+            self.create_header()?;
+        }
+        let color = if self.has_color {
+            match p.attributes.get("color") {
+                Some(AttributeData::U8Vec3(color)) => Some(color),
+                data => return Err(wrong_attribute("color", "U8Vec3", data)),
+            }
+        } else {
+            None
+        };
+        let intensity = if self.has_intensity {
+            match p.attributes.get("intensity") {
+                Some(AttributeData::F32(intensity)) => Some(intensity),
+                data => return Err(wrong_attribute("intensity", "F32", data)),
+            }
+        } else {
+            None
+        };
+
+        for (i, pos) in p.position.iter().enumerate() {
+            let pos = match self.offset {
+                Some(offset) => pos - offset,
+                None => *pos,
+            };
+            let xyz = [pos.x as f32, pos.y as f32, pos.z as f32];
+            // PCL packs the color into the bits of a float, see pcl::PointXYZRGB.
+            let rgb = color.map(|color| {
+                let c = color[i];
+                u32::from(c.x) << 16 | u32::from(c.y) << 8 | u32::from(c.z)
+            });
+            let intensity = intensity.map(|intensity| intensity[i]);
+            match self.data_format {
+                PcdDataFormat::Ascii => {
+                    write!(self.writer, "{} {} {}", xyz[0], xyz[1], xyz[2])?;
+                    // PCL writes the packed color as an integer in text, since some colors are
+                    // NaN as floats.
+                    if let Some(rgb) = rgb {
+                        write!(self.writer, " {}", rgb)?;
+                    }
+                    if let Some(intensity) = intensity {
+                        write!(self.writer, " {}", intensity)?;
+                    }
+                    self.writer.write_all(b"\n")?;
+                }
+                PcdDataFormat::Binary => {
+                    for value in &xyz {
+                        self.writer.write_f32::<LittleEndian>(*value)?;
+                    }
+                    if let Some(rgb) = rgb {
+                        self.writer.write_u32::<LittleEndian>(rgb)?;
+                    }
+                    if let Some(intensity) = intensity {
+                        self.writer.write_f32::<LittleEndian>(intensity)?;
+                    }
+                }
+            }
+        }
+
+        self.point_count += p.position.len();
+        if self.point_count > u32::MAX as usize {
+            return Err(ErrorKind::InvalidInput(format!(
+                "PCD files hold at most {} points.",
+                u32::MAX
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn create_header(&mut self) -> Result<()> {
+        if let Some(offset) = self.offset {
+            let sidecar_writer = BufWriter::new(File::create(Self::offset_path(&self.path))?);
+            serde_json::to_writer_pretty(sidecar_writer, &serde_json::json!({ "offset": offset }))
+                .chain_err(|| "Could not write offset.")?;
+        }
+        let mut fields = vec!["x", "y", "z"];
+        if self.has_color {
+            fields.push("rgb");
+        }
+        if self.has_intensity {
+            fields.push("intensity");
+        }
+        let repeat = |value: &str| vec![value; fields.len()].join(" ");
+        let mut header = format!(
+            "# .PCD v0.7 - Point Cloud Data file format\nVERSION 0.7\nFIELDS {}\nSIZE {}\nTYPE {}\n\
+             COUNT {}\nWIDTH ",
+            fields.join(" "),
+            repeat("4"),
+            // The packed color is a float in PCL, even though it is not meant as one.
+            repeat("F"),
+            repeat("1"),
+        );
+        self.count_offsets.push(header.len() as u64);
+        header.push_str(HEADER_NUM_POINTS);
+        header.push_str("\nHEIGHT 1\nVIEWPOINT 0 0 0 1 0 0 0\nPOINTS ");
+        self.count_offsets.push(header.len() as u64);
+        header.push_str(HEADER_NUM_POINTS);
+        header.push_str(match self.data_format {
+            PcdDataFormat::Ascii => "\nDATA ascii\n",
+            PcdDataFormat::Binary => "\nDATA binary\n",
+        });
+        self.writer.write_all(header.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn wrong_attribute(name: &str, data_type: &str, data: Option<&AttributeData>) -> Error {
+    ErrorKind::InvalidInput(format!(
+        "The PCD file has the field of attribute '{}', which needs {} data, found {:?}.",
+        name,
+        data_type,
+        data.map(AttributeData::data_type)
+    ))
+    .into()
+}
+
+impl Drop for PcdNodeWriter {
+    fn drop(&mut self) {
+        if self.point_count == 0 {
+            return;
+        }
+        for offset in &self.count_offsets {
+            if self.writer.seek(SeekFrom::Start(*offset)).is_ok() {
+                let _res = write!(
+                    &mut self.writer,
+                    "{:0width$}",
+                    self.point_count,
+                    width = HEADER_NUM_POINTS.len()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Point3, Vector3};
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    /// Splits the file into the header, by keyword, and the data.
+    fn read_pcd(path: &std::path::Path) -> (HashMap<String, String>, Vec<u8>) {
+        let bytes = std::fs::read(path).unwrap();
+        let mut header = HashMap::new();
+        let mut rest = &bytes[..];
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            let line = std::str::from_utf8(&rest[..end]).unwrap().to_string();
+            rest = &rest[end + 1..];
+            if line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ' ');
+            let keyword = parts.next().unwrap().to_string();
+            header.insert(keyword.clone(), parts.next().unwrap().to_string());
+            if keyword == "DATA" {
+                break;
+            }
+        }
+        (header, rest.to_vec())
+    }
+
+    fn batch(num_points: usize) -> PointsBatch {
+        let position = (0..num_points)
+            .map(|i| Point3::new(i as f64, 0.5 * i as f64, -2.0))
+            .collect();
+        let mut attributes = std::collections::BTreeMap::new();
+        attributes.insert(
+            "color".to_string(),
+            AttributeData::U8Vec3(
+                (0..num_points)
+                    .map(|i| Vector3::new(i as u8, 128, 255))
+                    .collect(),
+            ),
+        );
+        attributes.insert(
+            "intensity".to_string(),
+            AttributeData::F32((0..num_points).map(|i| 0.25 * i as f32).collect()),
+        );
+        PointsBatch {
+            position,
+            attributes,
+            bounding_box: None,
+        }
+    }
+
+    #[test]
+    fn test_pcd_write() {
+        let tmp_dir = TempDir::new("test_pcd_write").unwrap();
+        let binary_path = tmp_dir.path().join("binary.pcd");
+        let ascii_path = tmp_dir.path().join("ascii.pcd");
+        for (path, data_format) in &[
+            (&binary_path, PcdDataFormat::Binary),
+            (&ascii_path, PcdDataFormat::Ascii),
+        ] {
+            let mut writer = PcdNodeWriter::new(*path, *data_format).unwrap();
+            writer.write(&batch(3)).unwrap();
+            writer.write(&batch(2)).unwrap();
+        }
+
+        let (header, data) = read_pcd(&binary_path);
+        assert_eq!(header["FIELDS"], "x y z rgb intensity");
+        assert_eq!(header["SIZE"], "4 4 4 4 4");
+        assert_eq!(header["TYPE"], "F F F F F");
+        assert_eq!(header["COUNT"], "1 1 1 1 1");
+        assert_eq!(header["WIDTH"].parse::<usize>().unwrap(), 5);
+        assert_eq!(header["HEIGHT"], "1");
+        assert_eq!(header["POINTS"].parse::<usize>().unwrap(), 5);
+        assert_eq!(header["DATA"], "binary");
+        assert_eq!(data.len(), 5 * 20);
+        let values: Vec<[u8; 4]> = data.chunks(4).map(|c| [c[0], c[1], c[2], c[3]]).collect();
+        // The second point of the first batch.
+        let point = &values[5..10];
+        assert_eq!(f32::from_le_bytes(point[0]), 1.0);
+        assert_eq!(f32::from_le_bytes(point[1]), 0.5);
+        assert_eq!(f32::from_le_bytes(point[2]), -2.0);
+        assert_eq!(u32::from_le_bytes(point[3]), 0x01_80_ff);
+        assert_eq!(f32::from_le_bytes(point[4]), 0.25);
+
+        let (header, data) = read_pcd(&ascii_path);
+        assert_eq!(header["FIELDS"], "x y z rgb intensity");
+        assert_eq!(header["POINTS"].parse::<usize>().unwrap(), 5);
+        assert_eq!(header["DATA"], "ascii");
+        let lines: Vec<&str> = std::str::from_utf8(&data).unwrap().lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2], format!("2 1 -2 {} 0.5", 0x02_80_ff));
+        assert_eq!(lines[4], format!("1 0.5 -2 {} 0.25", 0x01_80_ff));
+    }
+
+    #[test]
+    fn test_pcd_omits_missing_attributes() {
+        let tmp_dir = TempDir::new("test_pcd_omits_missing_attributes").unwrap();
+        let path = tmp_dir.path().join("xyz.pcd");
+        let mut points = batch(2);
+        points.attributes.remove("color");
+        {
+            let mut writer = PcdNodeWriter::new(&path, PcdDataFormat::Ascii).unwrap();
+            writer.write(&points).unwrap();
+            // The fields are fixed by the first batch.
+            points.attributes.remove("intensity");
+            assert!(writer.write(&points).is_err());
+        }
+        let (header, data) = read_pcd(&path);
+        assert_eq!(header["FIELDS"], "x y z intensity");
+        assert_eq!(header["SIZE"], "4 4 4 4");
+        assert_eq!(header["POINTS"].parse::<usize>().unwrap(), 2);
+        assert_eq!(
+            std::str::from_utf8(&data).unwrap(),
+            "0 0 -2 0\n1 0.5 -2 0.25\n"
+        );
+
+        // Nothing is left behind without points.
+        let empty_path = tmp_dir.path().join("empty.pcd");
+        drop(PcdNodeWriter::new(&empty_path, PcdDataFormat::Binary).unwrap());
+        assert!(!empty_path.exists());
+    }
+
+    #[test]
+    fn test_pcd_write_with_offset() {
+        let tmp_dir = TempDir::new("test_pcd_write_with_offset").unwrap();
+        let path = tmp_dir.path().join("offset.pcd");
+        let mut points = batch(4);
+        // Far from the origin, like ECEF coordinates, where F32 has a precision of meters.
+        let far = Vector3::new(4_100_000.125, -500_000.0, 4_800_000.0);
+        for p in &mut points.position {
+            *p += far;
+        }
+        {
+            let mut writer = PcdNodeWriter::new(&path, PcdDataFormat::Binary)
+                .unwrap()
+                .with_offset(far);
+            writer.write(&points).unwrap();
+        }
+        let offset = PcdNodeWriter::read_offset(&path).unwrap();
+        assert_eq!(offset, far);
+
+        let (_, data) = read_pcd(&path);
+        let values: Vec<f32> = data
+            .chunks(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        for (i, original) in points.position.iter().enumerate() {
+            let local = Vector3::new(values[5 * i], values[5 * i + 1], values[5 * i + 2]);
+            let absolute = local.map(f64::from) + offset;
+            assert!((absolute - original.coords).norm() < 1e-5);
+        }
+
+        // Without an offset, there is no sidecar.
+        let plain_path = tmp_dir.path().join("plain.pcd");
+        PcdNodeWriter::new(&plain_path, PcdDataFormat::Binary)
+            .unwrap()
+            .write(&points)
+            .unwrap();
+        assert!(!PcdNodeWriter::offset_path(&plain_path).exists());
+    }
+}
@@ -314,16 +314,27 @@ struct PropertyReader {
     func: ReadingFn,
 }
 
-/// Abstraction to read binary points from ply files into points.
-pub struct PlyIterator {
-    reader: BufReader<File>,
+/// Abstraction to read binary points from ply files into points. If the input cannot be read,
+/// e.g. because it ends early, the iteration ends, see `into_result`.
+pub struct PlyIterator<R = File> {
+    reader: BufReader<R>,
     readers: Vec<PropertyReader>,
     pub num_total_points: i64,
     batch_size: usize,
     offset: Vector3<f64>,
     point_count: usize,
+    /// Holds a point that is split across reads of the underlying reader.
+    point_buf: Vec<u8>,
+    error: Option<Error>,
+    /// Only every n-th point is read, and the ones in between are skipped with the function, see
+    /// `with_stride`.
+    stride: usize,
+    skip: Option<SkipBytes<R>>,
 }
 
+/// Advances the reader by the number of bytes.
+type SkipBytes<R> = fn(&mut BufReader<R>, i64) -> io::Result<()>;
+
 impl PlyIterator {
     pub fn from_file<P: AsRef<Path>>(ply_file: P, batch_size: usize) -> Result<Self> {
         let mut file = File::open(ply_file).chain_err(|| "Could not open input file.")?;
@@ -332,126 +343,192 @@ impl PlyIterator {
         file = reader.into_inner();
         file.seek(SeekFrom::Start(header_len as u64))?;
 
-        if !header.has_element("vertex") {
-            panic!("Header does not have element 'vertex'");
-        }
+        let (readers, num_bytes_per_point) = property_readers(&header, batch_size);
+        // We align the buffer of this 'BufReader' to points, so that it almost always contains
+        // full points to parse.
+        let reader = BufReader::with_capacity(num_bytes_per_point * 1024, file);
+        Ok(PlyIterator::new(
+            header,
+            reader,
+            readers,
+            num_bytes_per_point,
+            batch_size,
+        ))
+    }
+}
+
+impl<R: Read + Seek> PlyIterator<R> {
+    /// Only reads every `stride`-th point, beginning with the first, and seeks over the ones in
+    /// between, e.g. to sample a large file evenly without reading all of it.
+    pub fn with_stride(mut self, stride: usize) -> Self {
+        self.stride = std::cmp::max(stride, 1);
+        self.skip = Some(BufReader::seek_relative);
+        self
+    }
+}
+
+impl<R: Read> PlyIterator<R> {
+    /// Reads a binary PLY from a stream that cannot seek, e.g. stdin. The points are read as the
+    /// iterator advances, so a slow consumer holds back the writer of the stream.
+    pub fn from_reader(reader: R, batch_size: usize) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let (header, _) = parse_header(&mut reader)?;
+        let (readers, num_bytes_per_point) = property_readers(&header, batch_size);
+        Ok(PlyIterator::new(
+            header,
+            reader,
+            readers,
+            num_bytes_per_point,
+            batch_size,
+        ))
+    }
 
-        if header.format != Format::BinaryLittleEndianV1 {
-            panic!("Unsupported PLY format: {:?}", header.format);
+    fn new(
+        header: Header,
+        reader: BufReader<R>,
+        readers: Vec<PropertyReader>,
+        num_bytes_per_point: usize,
+        batch_size: usize,
+    ) -> Self {
+        PlyIterator {
+            reader,
+            readers,
+            num_total_points: header["vertex"].count,
+            batch_size,
+            offset: header.offset,
+            point_count: 0,
+            point_buf: vec![0; num_bytes_per_point],
+            error: None,
+            stride: 1,
+            skip: None,
         }
+    }
 
-        let vertex = &header["vertex"];
-        let mut seen_x = false;
-        let mut seen_y = false;
-        let mut seen_z = false;
+    /// The number of points the iterator yields, which is less than `num_total_points` if it
+    /// skips points.
+    fn num_read_points(&self) -> usize {
+        div_ceil(self.num_total_points as usize, self.stride)
+    }
+
+    /// Fails with the error that ended the iteration early, if any. The points of the batch that
+    /// could not be read completely are dropped.
+    pub fn into_result(self) -> Result<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+}
 
-        let mut readers: Vec<PropertyReader> = Vec::new();
-        let mut num_bytes_per_point = 0;
+/// Creates the readers for the properties of the vertices, and returns them with the number of
+/// bytes per point.
+fn property_readers(header: &Header, batch_size: usize) -> (Vec<PropertyReader>, usize) {
+    if !header.has_element("vertex") {
+        panic!("Header does not have element 'vertex'");
+    }
 
-        for prop in &vertex.properties {
-            match &prop.name as &str {
-                "x" => {
-                    push_reader!(
+    if header.format != Format::BinaryLittleEndianV1 {
+        panic!("Unsupported PLY format: {:?}", header.format);
+    }
+
+    let vertex = &header["vertex"];
+    let mut seen_x = false;
+    let mut seen_y = false;
+    let mut seen_z = false;
+
+    let mut readers: Vec<PropertyReader> = Vec::new();
+    let mut num_bytes_per_point = 0;
+
+    for prop in &vertex.properties {
+        match &prop.name as &str {
+            "x" => {
+                push_reader!(
+                    readers,
+                    prop,
+                    AttributeData::F64(Vec::with_capacity(batch_size)),
+                    &mut num_bytes_per_point,
+                    f64
+                );
+                seen_x = true;
+            }
+            "y" => {
+                push_reader!(
+                    readers,
+                    prop,
+                    AttributeData::F64(Vec::with_capacity(batch_size)),
+                    &mut num_bytes_per_point,
+                    f64
+                );
+                seen_y = true;
+            }
+            "z" => {
+                push_reader!(
+                    readers,
+                    prop,
+                    AttributeData::F64(Vec::with_capacity(batch_size)),
+                    &mut num_bytes_per_point,
+                    f64
+                );
+                seen_z = true;
+            }
+            "a" | "alpha" => {
+                readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 1));
+            }
+            other => {
+                // TODO(feuerste): We may need to support multidimensional attributes.
+                assert!(!other.chars().last().unwrap().is_ascii_digit(),
+                "Multidimensional attributes other than position and color are currently unsupported.");
+                use self::DataType::*;
+                match prop.data_type {
+                    Uint8 => push_reader!(
                         readers,
                         prop,
-                        AttributeData::F64(Vec::with_capacity(batch_size)),
+                        AttributeData::U8(Vec::with_capacity(batch_size)),
                         &mut num_bytes_per_point,
-                        f64
-                    );
-                    seen_x = true;
-                }
-                "y" => {
-                    push_reader!(
+                        u8
+                    ),
+                    Uint64 => push_reader!(
                         readers,
                         prop,
-                        AttributeData::F64(Vec::with_capacity(batch_size)),
+                        AttributeData::U64(Vec::with_capacity(batch_size)),
                         &mut num_bytes_per_point,
-                        f64
-                    );
-                    seen_y = true;
-                }
-                "z" => {
-                    push_reader!(
+                        u64
+                    ),
+                    Int64 => push_reader!(
+                        readers,
+                        prop,
+                        AttributeData::I64(Vec::with_capacity(batch_size)),
+                        &mut num_bytes_per_point,
+                        i64
+                    ),
+                    Float32 => push_reader!(
+                        readers,
+                        prop,
+                        AttributeData::F32(Vec::with_capacity(batch_size)),
+                        &mut num_bytes_per_point,
+                        f32
+                    ),
+                    Float64 => push_reader!(
                         readers,
                         prop,
                         AttributeData::F64(Vec::with_capacity(batch_size)),
                         &mut num_bytes_per_point,
                         f64
-                    );
-                    seen_z = true;
-                }
-                "a" | "alpha" => {
-                    readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 1));
-                }
-                other => {
-                    // TODO(feuerste): We may need to support multidimensional attributes.
-                    assert!(!other.chars().last().unwrap().is_ascii_digit(),
-                    "Multidimensional attributes other than position and color are currently unsupported.");
-                    use self::DataType::*;
-                    match prop.data_type {
-                        Uint8 => push_reader!(
-                            readers,
-                            prop,
-                            AttributeData::U8(Vec::with_capacity(batch_size)),
-                            &mut num_bytes_per_point,
-                            u8
-                        ),
-                        Uint64 => push_reader!(
-                            readers,
-                            prop,
-                            AttributeData::U64(Vec::with_capacity(batch_size)),
-                            &mut num_bytes_per_point,
-                            u64
-                        ),
-                        Int64 => push_reader!(
-                            readers,
-                            prop,
-                            AttributeData::I64(Vec::with_capacity(batch_size)),
-                            &mut num_bytes_per_point,
-                            i64
-                        ),
-                        Float32 => push_reader!(
-                            readers,
-                            prop,
-                            AttributeData::F32(Vec::with_capacity(batch_size)),
-                            &mut num_bytes_per_point,
-                            f32
-                        ),
-                        Float64 => push_reader!(
-                            readers,
-                            prop,
-                            AttributeData::F64(Vec::with_capacity(batch_size)),
-                            &mut num_bytes_per_point,
-                            f64
-                        ),
-                        Int8 => readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 1)),
-                        Uint16 | Int16 => {
-                            readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 2))
-                        }
+                    ),
+                    Int8 => readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 1)),
+                    Uint16 | Int16 => {
+                        readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 2))
+                    }
 
-                        Uint32 | Int32 => {
-                            readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 4))
-                        }
+                    Uint32 | Int32 => {
+                        readers.push(push_skip_reader!(prop, &mut num_bytes_per_point, 4))
                     }
                 }
             }
         }
+    }
 
-        if !seen_x || !seen_y || !seen_z {
-            panic!("PLY must contain properties 'x', 'y', 'z' for 'vertex'.");
-        }
-
-        // We align the buffer of this 'BufReader' to points, so that we can index this buffer and know
-        // that it will always contain full points to parse.
-        Ok(PlyIterator {
-            reader: BufReader::with_capacity(num_bytes_per_point * 1024, file),
-            readers,
-            num_total_points: header["vertex"].count,
-            batch_size,
-            offset: header.offset,
-            point_count: 0,
-        })
+    if !seen_x || !seen_y || !seen_z {
+        panic!("PLY must contain properties 'x', 'y', 'z' for 'vertex'.");
     }
+    (readers, num_bytes_per_point)
 }
 
 fn batch_from_readers(readers: &mut [PropertyReader], offset: &Vector3<f64>) -> PointsBatch {
@@ -507,47 +584,76 @@ fn batch_from_readers(readers: &mut [PropertyReader], offset: &Vector3<f64>) ->
     PointsBatch {
         position,
         attributes,
+        bounding_box: None,
     }
 }
 
-impl NumberOfPoints for PlyIterator {
+impl<R: Read> NumberOfPoints for PlyIterator<R> {
     fn num_points(&self) -> usize {
-        self.num_total_points as usize
+        self.num_read_points()
     }
 }
 
-impl Iterator for PlyIterator {
+impl<R: Read> NumberOfPoints for &mut PlyIterator<R> {
+    fn num_points(&self) -> usize {
+        self.num_read_points()
+    }
+}
+
+impl<R: Read> Iterator for PlyIterator<R> {
     type Item = PointsBatch;
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let num_batches = div_ceil(self.num_total_points as usize, self.batch_size);
+        let num_batches = div_ceil(self.num_read_points(), self.batch_size);
         (num_batches, Some(num_batches))
     }
 
     fn next(&mut self) -> Option<PointsBatch> {
-        if self.point_count == self.num_total_points as usize {
+        let num_read_points = self.num_read_points();
+        if self.point_count == num_read_points || self.error.is_some() {
             return None;
         }
 
-        let cur_batch_size = std::cmp::min(
-            self.batch_size,
-            self.num_total_points as usize - self.point_count,
-        );
+        let cur_batch_size = std::cmp::min(self.batch_size, num_read_points - self.point_count);
 
-        for _ in 0..cur_batch_size {
+        let num_bytes_per_point = self.point_buf.len();
+        for i in 0..cur_batch_size {
             let mut nread = 0;
 
-            // We made sure before that the internal buffer of 'reader' is aligned to the number of
-            // bytes for a single point, therefore we can access it here and know that we can always
-            // read into it and are sure that it contains at least a full point.
-            {
-                let buf = self.reader.fill_buf().unwrap();
+            // The internal buffer of 'reader' usually contains at least a full point, so we parse
+            // it in place. Only if a read came up short, e.g. from a pipe, we copy the point out.
+            let buf = match self.reader.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) => {
+                    self.error = Some(Error::with_chain(e, "Could not read PLY input."));
+                    return None;
+                }
+            };
+            if buf.len() >= num_bytes_per_point {
                 for r in self.readers.iter_mut() {
                     let cnread = nread;
                     (r.func)(&mut nread, &buf[cnread..], &mut r.data);
                 }
+                self.reader.consume(nread);
+            } else {
+                if let Err(e) = self.reader.read_exact(&mut self.point_buf) {
+                    self.error = Some(Error::with_chain(e, "Unexpected end of PLY input."));
+                    return None;
+                }
+                for r in self.readers.iter_mut() {
+                    let cnread = nread;
+                    (r.func)(&mut nread, &self.point_buf[cnread..], &mut r.data);
+                }
+            }
+            if let Some(skip) = self.skip {
+                if self.point_count + i + 1 < num_read_points {
+                    let num_bytes = (self.stride - 1) * num_bytes_per_point;
+                    if let Err(e) = skip(&mut self.reader, num_bytes as i64) {
+                        self.error = Some(Error::with_chain(e, "Could not read PLY input."));
+                        return None;
+                    }
+                }
             }
-            self.reader.consume(nread);
         }
         self.point_count += cur_batch_size;
 
@@ -560,6 +666,7 @@ pub struct PlyNodeWriter {
     writer: DataWriter,
     point_count: usize,
     encoding: Encoding,
+    offset: Option<Vector3<f64>>,
 }
 
 impl NodeWriter<PointsBatch> for PlyNodeWriter {
@@ -590,6 +697,7 @@ impl NodeWriter<PointsBatch> for PlyNodeWriter {
                                 AttributeData::F32(_) => "float",
                                 AttributeData::F64(_) => "double",
                                 AttributeData::U8Vec3(_) => "uchar",
+                                AttributeData::U16Vec3(_) => "ushort",
                                 AttributeData::F64Vec3(_) => "double",
                             },
                             data.dim(),
@@ -600,7 +708,7 @@ impl NodeWriter<PointsBatch> for PlyNodeWriter {
         }
 
         for (i, pos) in p.position.iter().enumerate() {
-            pos.write_encoded(&self.encoding, &mut self.writer)?;
+            self.write_position(pos)?;
             for data in p.attributes.values() {
                 data.write_le_pos(i, &mut self.writer)?;
             }
@@ -626,7 +734,7 @@ impl NodeWriter<Point> for PlyNodeWriter {
             self.create_header(&attributes)?;
         }
 
-        p.position.write_encoded(&self.encoding, &mut self.writer)?;
+        self.write_position(&p.position)?;
         p.color.write_le(&mut self.writer)?;
         if let Some(i) = p.intensity {
             i.write_le(&mut self.writer)?;
@@ -685,6 +793,28 @@ impl PlyNodeWriter {
             writer,
             point_count,
             encoding,
+            offset: None,
+        }
+    }
+
+    /// Stores the positions as floats relative to the offset, e.g. the minimum of the bounding box
+    /// of a far-from-origin point cloud, instead of as doubles. The offset is written to the header
+    /// as `comment offset: x y z`, which `PlyIterator` adds back to the positions. This only
+    /// applies to `Encoding::Plain`, since the other encodings are relative to their cube already.
+    /// When appending, the offset has to be the one the file was created with.
+    pub fn with_offset(mut self, offset: Vector3<f64>) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    fn write_position(&mut self, pos: &Point3<f64>) -> io::Result<()> {
+        match (&self.encoding, self.offset) {
+            (Encoding::Plain, Some(offset)) => {
+                let local = pos - offset;
+                Vector3::new(local.x as f32, local.y as f32, local.z as f32)
+                    .write_le(&mut self.writer)
+            }
+            _ => pos.write_encoded(&self.encoding, &mut self.writer),
         }
     }
 
@@ -693,6 +823,7 @@ impl PlyNodeWriter {
         self.writer.write_all(HEADER_NUM_VERTICES)?;
         self.writer.write_all(b"\n")?;
         let pos_data_str = match &self.encoding {
+            Encoding::Plain if self.offset.is_some() => "float",
             Encoding::Plain => "double",
             Encoding::ScaledToCube(_, _, pos_enc) => match pos_enc {
                 PositionEncoding::Uint8 => "uchar",
@@ -701,6 +832,10 @@ impl PlyNodeWriter {
                 PositionEncoding::Float64 => "double",
             },
         };
+        if let (Encoding::Plain, Some(offset)) = (&self.encoding, self.offset) {
+            let comment = format!("comment offset: {} {} {}\n", offset.x, offset.y, offset.z);
+            self.writer.write_all(comment.as_bytes())?;
+        }
         for pos in &["x", "y", "z"] {
             let prop = &["property", " ", pos_data_str, " ", pos, "\n"].concat();
             self.writer.write_all(&prop.as_bytes())?;
@@ -793,6 +928,21 @@ mod tests {
         assert_eq!(color_last.last().unwrap().x, 234);
     }
 
+    #[test]
+    fn test_ply_read_with_stride() {
+        let path = "src/test_data/xyz_f32_rgb_u8_le.ply";
+        let all: Vec<Point3<f64>> = batches_from_file(path)
+            .into_iter()
+            .flat_map(|batch| batch.position)
+            .collect();
+        let iterator = PlyIterator::from_file(path, BATCH_SIZE)
+            .unwrap()
+            .with_stride(3);
+        assert_eq!(iterator.num_points(), 3);
+        let sampled: Vec<Point3<f64>> = iterator.flat_map(|batch| batch.position).collect();
+        assert_eq!(sampled, vec![all[0], all[3], all[6]]);
+    }
+
     #[test]
     fn test_ply_read_write() {
         let tmp_dir = TempDir::new("test_ply_read_write").unwrap();
@@ -834,4 +984,49 @@ mod tests {
                 assert!(test_intensity.iter().all(|i| i.is_nan()));
             });
     }
+
+    #[test]
+    fn test_ply_write_with_offset() {
+        let tmp_dir = TempDir::new("test_ply_write_with_offset").unwrap();
+        let path = tmp_dir.path().join("offset.ply");
+        // Far from the origin, like ECEF coordinates, where floats have a precision of meters.
+        let position: Vec<Point3<f64>> = (0..10)
+            .map(|i| {
+                Point3::new(
+                    4_100_000.0 + 0.123 * i as f64,
+                    500_000.25,
+                    4_800_000.5 - i as f64,
+                )
+            })
+            .collect();
+        let offset = Vector3::new(4_100_000.0, 500_000.25, 4_800_000.5 - 9.0);
+        let mut attributes = BTreeMap::new();
+        attributes.insert(
+            "intensity".to_string(),
+            AttributeData::F32((0..10).map(|i| i as f32).collect()),
+        );
+        let batch = PointsBatch {
+            position: position.clone(),
+            attributes,
+            bounding_box: None,
+        };
+        {
+            let mut ply_writer =
+                PlyNodeWriter::new(&path, Encoding::Plain, OpenMode::Truncate).with_offset(offset);
+            ply_writer.write(&batch).unwrap();
+        }
+        let header = std::fs::read(&path).unwrap();
+        let header = String::from_utf8_lossy(&header);
+        assert!(header.contains("comment offset: 4100000 500000.25 4799991.5\n"));
+        assert!(header.contains("property float x\n"));
+
+        let read: Vec<Point3<f64>> = PlyIterator::from_file(&path, BATCH_SIZE)
+            .unwrap()
+            .flat_map(|batch| batch.position)
+            .collect();
+        assert_eq!(read.len(), position.len());
+        for (read, original) in read.iter().zip(&position) {
+            assert!((read - original).norm() < 1e-5);
+        }
+    }
 }
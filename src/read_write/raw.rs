@@ -14,6 +14,7 @@
 
 use crate::color;
 use crate::errors::*;
+use crate::geometry::Aabb;
 use crate::read_write::{
     decode, fixpoint_decode, AttributeReader, DataWriter, Encoding, NodeWriter, OpenMode,
     PositionEncoding, WriteEncoded, WriteLE,
@@ -22,7 +23,7 @@ use crate::{attribute_extension, AttributeData, AttributeDataType, Point, Points
 use byteorder::{LittleEndian, ReadBytesExt};
 use nalgebra::{Point3, Vector3};
 use std::collections::{BTreeMap, HashMap};
-use std::io::{self, BufReader, ErrorKind, Read};
+use std::io::{self, BufReader, Cursor, ErrorKind, Read};
 use std::path::PathBuf;
 
 pub struct RawNodeReader {
@@ -128,6 +129,7 @@ impl RawNodeReader {
         let mut batch = PointsBatch {
             position: vec![],
             attributes: BTreeMap::new(),
+            bounding_box: None,
         };
 
         match self.encoding {
@@ -214,6 +216,8 @@ impl RawNodeReader {
                 }
             },
         };
+        // The positions were just written, so this is cheap.
+        batch.bounding_box = Aabb::from_points(&batch.position);
 
         // TODO(nnmm): Implement ReadLE trait and rewrite this section with a macro
         self.attribute_readers.iter_mut().try_for_each(
@@ -306,6 +310,21 @@ impl RawNodeReader {
                             .attributes
                             .insert(key.to_owned(), AttributeData::U8Vec3(attr));
                     }
+                    AttributeDataType::U16Vec3 => {
+                        let mut attr = Vec::with_capacity(num_points);
+                        let mut buffer = vec![0; 3 * num_points];
+                        reader.read_u16_into::<LittleEndian>(&mut buffer)?;
+                        for i in 0..num_points {
+                            attr.push(Vector3::new(
+                                buffer[3 * i],
+                                buffer[3 * i + 1],
+                                buffer[3 * i + 2],
+                            ));
+                        }
+                        batch
+                            .attributes
+                            .insert(key.to_owned(), AttributeData::U16Vec3(attr));
+                    }
                     AttributeDataType::F64Vec3 => {
                         let mut attr = Vec::with_capacity(num_points);
                         let mut buffer = vec![0.0; 3 * num_points];
@@ -356,6 +375,21 @@ impl RawNodeReader {
             encoding,
         })
     }
+
+    /// Reads the remaining data into memory, so that reading points does no more IO.
+    pub fn prefetch(&mut self) -> io::Result<()> {
+        fn read_into_memory(reader: &mut BufReader<Box<dyn Read + Send>>) -> io::Result<()> {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf)?;
+            *reader = BufReader::new(Box::new(Cursor::new(buf)));
+            Ok(())
+        }
+        read_into_memory(&mut self.xyz_reader)?;
+        for attribute_reader in self.attribute_readers.values_mut() {
+            read_into_memory(&mut attribute_reader.reader)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct RawNodeWriter {
@@ -80,6 +80,7 @@ where
             let s2_cell_batch = batches_by_s2_cell.entry(s2_cell_id).or_insert(PointsBatch {
                 position: Vec::new(),
                 attributes: BTreeMap::new(),
+                bounding_box: None,
             });
             s2_cell_batch.position.push(*pos);
             for (in_key, in_data) in &points_batch.attributes {
@@ -100,6 +101,7 @@ where
                         (F32(in_vec), F32(out_vec)) => out_vec.push(in_vec[i]),
                         (F64(in_vec), F64(out_vec)) => out_vec.push(in_vec[i]),
                         (U8Vec3(in_vec), U8Vec3(out_vec)) => out_vec.push(in_vec[i]),
+                        (U16Vec3(in_vec), U16Vec3(out_vec)) => out_vec.push(in_vec[i]),
                         (F64Vec3(in_vec), F64Vec3(out_vec)) => out_vec.push(in_vec[i]),
                         _ => panic!("Input data type unequal output data type."),
                     })
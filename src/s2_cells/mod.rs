@@ -1,8 +1,9 @@
-use crate::data_provider::DataProvider;
+use crate::data_provider::{CancellationToken, DataProvider};
 use crate::errors::*;
 use crate::geometry::Aabb;
 use crate::iterator::{PointCloud, PointLocation};
 use crate::math::{ConvexPolyhedron, FromPoint3};
+use crate::octree::{CoordinateSystem, ECEF_EPSG_CODE};
 use crate::proto;
 use crate::read_write::{Encoding, NodeIterator};
 use crate::{AttributeDataType, PointCloudMeta, CURRENT_VERSION};
@@ -11,13 +12,16 @@ use s2::cell::Cell;
 use s2::cellid::CellID;
 use s2::cellunion::CellUnion;
 use s2::region::Region;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter;
+use std::sync::Arc;
 
+/// Cheap to clone, like `Octree`.
+#[derive(Clone)]
 pub struct S2Cells {
-    data_provider: Box<dyn DataProvider>,
-    cells: FnvHashMap<CellID, Cell>,
-    meta: S2Meta,
+    data_provider: Arc<dyn DataProvider>,
+    cells: Arc<FnvHashMap<CellID, Cell>>,
+    meta: Arc<S2Meta>,
 }
 
 #[derive(Copy, Clone)]
@@ -159,12 +163,32 @@ impl PointCloud for S2Cells {
 
     fn nodes_in_location(&self, location: &PointLocation) -> Vec<Self::Id> {
         match location {
-            PointLocation::AllPoints => self.cells.keys().cloned().collect(),
+            PointLocation::AllPoints
+            | PointLocation::AllPointsInDepthRange(_)
+            | PointLocation::Complement(_)
+            | PointLocation::ByOriginalIndex(_) => self.cells.keys().cloned().collect(),
             PointLocation::Aabb(aabb) => self.cells_in_convex_polyhedron(aabb),
             PointLocation::Obb(obb) => self.cells_in_convex_polyhedron(obb),
             PointLocation::Frustum(frustum) => self.cells_in_convex_polyhedron(frustum),
-            PointLocation::S2Cells(cell_union) => self.cells_intersecting_region(cell_union),
+            PointLocation::Frustums(frustums) => frustums
+                .0
+                .iter()
+                .flat_map(|frustum| self.cells_in_convex_polyhedron(frustum))
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect(),
+            PointLocation::S2Cells(cell_union) => {
+                // Intersecting a union assumes that it is normalized, which a union of cells of
+                // mixed levels need not be.
+                let mut cell_union = cell_union.clone();
+                cell_union.normalize();
+                self.cells_intersecting_region(&cell_union)
+            }
+            PointLocation::Sphere(sphere) => {
+                self.cells_in_convex_polyhedron(&sphere.bounding_box())
+            }
             PointLocation::WebMercatorRect(wmr) => self.cells_in_convex_polyhedron(wmr),
+            PointLocation::Difference(minuend, _) => self.nodes_in_location(minuend),
         }
     }
 
@@ -172,11 +196,16 @@ impl PointCloud for S2Cells {
         Encoding::Plain
     }
 
+    fn node_point_count(&self, id: Self::Id) -> Option<u64> {
+        self.meta.cells.get(&id).map(|cell| cell.num_points)
+    }
+
     fn points_in_node(
         &self,
         attributes: &[&str],
         node_id: Self::Id,
         batch_size: usize,
+        cancellation: Option<&CancellationToken>,
     ) -> Result<NodeIterator> {
         let num_points = self.meta.cells[&node_id].num_points as usize;
         let node_iterator = NodeIterator::from_data_provider(
@@ -186,6 +215,7 @@ impl PointCloud for S2Cells {
             &node_id,
             num_points,
             batch_size,
+            cancellation,
         )?;
         Ok(node_iterator)
     }
@@ -193,6 +223,22 @@ impl PointCloud for S2Cells {
     fn bounding_box(&self) -> &Aabb {
         &self.meta.bounding_box
     }
+
+    fn num_points(&self) -> usize {
+        self.meta
+            .cells
+            .values()
+            .map(|c| c.num_points as usize)
+            .sum()
+    }
+
+    fn attribute_data_types(&self) -> &HashMap<String, AttributeDataType> {
+        self.meta.attribute_data_types()
+    }
+
+    fn coordinate_system(&self) -> Option<&CoordinateSystem> {
+        Some(&CoordinateSystem::Epsg(ECEF_EPSG_CODE))
+    }
 }
 
 impl S2Cells {
@@ -205,9 +251,9 @@ impl S2Cells {
             .map(|id| (*id, Cell::from(id)))
             .collect();
         Ok(S2Cells {
-            data_provider,
-            cells,
-            meta,
+            data_provider: Arc::from(data_provider),
+            cells: Arc::new(cells),
+            meta: Arc::new(meta),
         })
     }
 
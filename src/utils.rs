@@ -1,6 +1,8 @@
 use pbr::ProgressBar;
 use std::error::Error;
+use std::fs;
 use std::io;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -20,6 +22,14 @@ where
     Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
 }
 
+/// Removes the file, if it exists.
+pub fn remove_file_if_exists(path: impl AsRef<Path>) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
 pub fn create_progress_bar(total: usize, message: &str) -> ProgressBar<io::Stderr> {
     let mut progress_bar = ProgressBar::on(io::stderr(), total as u64);
     progress_bar.set_max_refresh_rate(Some(PROGRESS_REFRESH_RATE));
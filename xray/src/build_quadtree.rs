@@ -41,6 +41,15 @@ fn parse_arguments<T: Extension>() -> clap::ArgMatches {
                 .about("Size of finest X-Ray level tile in pixels. Must be a power of two.")
                 .long("tile-size")
                 .default_value("256"),
+            clap::Arg::with_name("splat_radius")
+                .about(
+                    "Radius in pixels of the disc that every point covers on the finest X-Ray \
+                     level, which reduces aliasing for sparse points. With 0, every point \
+                     colors a single pixel.",
+                )
+                .long("splat-radius")
+                .takes_value(true)
+                .default_value("0"),
             clap::Arg::with_name("coloring_strategy")
                 .long("coloring-strategy")
                 .takes_value(true)
@@ -137,6 +146,11 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
     if !tile_size_px.is_power_of_two() {
         panic!("tile_size is not a power of two.");
     }
+    let splat_radius_px = args
+        .value_of("splat_radius")
+        .unwrap()
+        .parse::<f64>()
+        .expect("splat_radius could not be parsed.");
 
     let binning = args.value_of("binning").map(|f| parse_key_val(f).unwrap());
     let coloring_strategy_kind = {
@@ -214,6 +228,7 @@ pub fn run<T: Extension>(data_provider_factory: DataProviderFactory) {
         tile_background_color,
         tile_size_px,
         pixel_size_m,
+        splat_radius_px,
         root_node_id,
     };
     build_xray_quadtree(&coloring_strategy_kind, &parameters)
@@ -24,6 +24,7 @@ use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // The number of Z-buckets we subdivide our bounding cube into along the z-direction. This affects
 // the saturation of a point in x-rays: the more buckets contain a point, the darker the pixel
@@ -96,34 +97,96 @@ impl ColoringStrategyKind {
     }
 }
 
+// The number of samples per pixel and axis used to approximate how much of a pixel a splat covers.
+const NUM_COVERAGE_SAMPLES: u32 = 8;
+
+/// A pixel (x, y) and z column that a point landed in.
+pub struct DiscretizedPoint {
+    /// The index of the point in its batch.
+    pub index: usize,
+    pub location: Point3<u32>,
+    /// The fraction of the pixel covered by the point's splat, in (0, 1].
+    pub coverage: f32,
+}
+
+/// The pixels of an image of `image_size` that are covered by a disc of `radius` pixels around
+/// `center`, which is given in continuous pixel coordinates, together with the covered fraction of
+/// each pixel. A radius of 0 covers exactly the pixel containing `center`.
+pub fn pixel_coverages(
+    center: Point2<f64>,
+    radius: f64,
+    image_size: Vector2<u32>,
+) -> Vec<((u32, u32), f32)> {
+    if radius <= 0. {
+        return vec![((center.x as u32, center.y as u32), 1.)];
+    }
+    let pixel_range = |c: f64, size: u32| {
+        let min = (c - radius).floor().max(0.) as u32;
+        let max = ((c + radius).floor().max(0.) as u32).min(size.saturating_sub(1));
+        min..=max
+    };
+    let num_samples = NUM_COVERAGE_SAMPLES * NUM_COVERAGE_SAMPLES;
+    let mut coverages = Vec::new();
+    for y in pixel_range(center.y, image_size.y) {
+        for x in pixel_range(center.x, image_size.x) {
+            let num_covered = (0..num_samples)
+                .filter(|i| {
+                    let sample = |pixel: u32, j: u32| {
+                        f64::from(pixel) + (f64::from(j) + 0.5) / f64::from(NUM_COVERAGE_SAMPLES)
+                    };
+                    let offset = Point2::new(
+                        sample(x, i % NUM_COVERAGE_SAMPLES),
+                        sample(y, i / NUM_COVERAGE_SAMPLES),
+                    ) - center;
+                    offset.norm_squared() <= radius * radius
+                })
+                .count();
+            if num_covered > 0 {
+                coverages.push(((x, y), num_covered as f32 / num_samples as f32));
+            }
+        }
+    }
+    coverages
+}
+
 pub trait ColoringStrategy: Send {
     // Processes points that have been discretized into the pixels (x, y) and the z columns according
-    // to NUM_Z_BUCKETS.
+    // to NUM_Z_BUCKETS. A point can land in several pixels if it is splatted.
     fn process_discretized_point_data(
         &mut self,
         points_batch: &PointsBatch,
-        discretized_locations: Vec<Point3<u32>>,
+        discretized_points: Vec<DiscretizedPoint>,
     );
 
+    // Every point covers a disc of `splat_radius_px` pixels, which reduces aliasing when the
+    // points are sparse compared to the pixels. With a radius of 0, every point lands in one pixel.
     fn process_point_data(
         &mut self,
         points_batch: &PointsBatch,
         bbox: &Aabb,
         image_size: Vector2<u32>,
+        splat_radius_px: f64,
     ) {
-        let mut discretized_locations = Vec::with_capacity(points_batch.position.len());
-        for pos in &points_batch.position {
+        let mut discretized_points = Vec::with_capacity(points_batch.position.len());
+        for (index, pos) in points_batch.position.iter().enumerate() {
             // We want a right handed coordinate system with the x-axis of world and images aligning.
             // This means that the y-axis aligns too, but the origin of the image space must be at the
             // bottom left. Since images have their origin at the top left, we need actually have to
             // invert y and go from the bottom of the image.
-            let x = (((pos.x - bbox.min().x) / bbox.diag().x) * f64::from(image_size.x)) as u32;
-            let y =
-                ((1. - ((pos.y - bbox.min().y) / bbox.diag().y)) * f64::from(image_size.y)) as u32;
+            let x = ((pos.x - bbox.min().x) / bbox.diag().x) * f64::from(image_size.x);
+            let y = (1. - ((pos.y - bbox.min().y) / bbox.diag().y)) * f64::from(image_size.y);
             let z = (((pos.z - bbox.min().z) / bbox.diag().z) * NUM_Z_BUCKETS) as u32;
-            discretized_locations.push(Point3::new(x, y, z));
+            for ((x, y), coverage) in
+                pixel_coverages(Point2::new(x, y), splat_radius_px, image_size)
+            {
+                discretized_points.push(DiscretizedPoint {
+                    index,
+                    location: Point3::new(x, y, z),
+                    coverage,
+                });
+            }
         }
-        self.process_discretized_point_data(points_batch, discretized_locations)
+        self.process_discretized_point_data(points_batch, discretized_points)
     }
 
     // After all points are processed, this is used to query the color that should be assigned to
@@ -175,11 +238,14 @@ impl ColoringStrategy for XRayColoringStrategy {
     fn process_discretized_point_data(
         &mut self,
         _: &PointsBatch,
-        discretized_locations: Vec<Point3<u32>>,
+        discretized_points: Vec<DiscretizedPoint>,
     ) {
-        for d_loc in discretized_locations {
-            let z_buckets = self.z_buckets.entry((d_loc.x, d_loc.y)).or_default();
-            z_buckets.insert(d_loc.z);
+        for point in discretized_points {
+            let z_buckets = self
+                .z_buckets
+                .entry((point.location.x, point.location.y))
+                .or_default();
+            z_buckets.insert(point.location.z);
         }
     }
 
@@ -199,10 +265,11 @@ impl ColoringStrategy for XRayColoringStrategy {
 
 #[derive(Default)]
 struct PerColumnData<T> {
-    // The sum of all seen values.
+    // The sum of all seen values, weighted by their coverage of the pixel.
     sum: T,
-    // The number of all points that landed in this column.
-    count: usize,
+    // The summed coverage of all points that landed in this column, i.e. their number if they are
+    // not splatted.
+    weight: f32,
 }
 
 type IntensityPerColumnData = FnvHashMap<(u32, u32), FnvHashMap<i64, PerColumnData<f32>>>;
@@ -235,7 +302,7 @@ impl ColoringStrategy for IntensityColoringStrategy {
     fn process_discretized_point_data(
         &mut self,
         points_batch: &PointsBatch,
-        discretized_locations: Vec<Point3<u32>>,
+        discretized_points: Vec<DiscretizedPoint>,
     ) {
         let bins = self.bins(points_batch);
         let intensity_attribute = points_batch
@@ -243,18 +310,18 @@ impl ColoringStrategy for IntensityColoringStrategy {
             .get("intensity")
             .expect("Coloring by intensity was requested, but point data without intensity found.");
         if let AttributeData::F32(intensity_vec) = intensity_attribute {
-            for i in 0..intensity_vec.len() {
-                let intensity = intensity_vec[i];
+            for point in discretized_points {
+                let intensity = intensity_vec[point.index];
                 if intensity < 0. {
                     return;
                 }
                 let per_column_data = self
                     .per_column_data
-                    .entry((discretized_locations[i].x, discretized_locations[i].y))
+                    .entry((point.location.x, point.location.y))
                     .or_default();
-                let bin_data = per_column_data.entry(bins[i]).or_default();
-                bin_data.sum += intensity;
-                bin_data.count += 1;
+                let bin_data = per_column_data.entry(bins[point.index]).or_default();
+                bin_data.sum += intensity * point.coverage;
+                bin_data.weight += point.coverage;
             }
         }
     }
@@ -263,7 +330,7 @@ impl ColoringStrategy for IntensityColoringStrategy {
         self.per_column_data.get(&(x, y)).map(|c| {
             let mean = (c
                 .values()
-                .map(|bin_data| bin_data.sum / bin_data.count as f32)
+                .map(|bin_data| bin_data.sum / bin_data.weight)
                 .sum::<f32>()
                 / c.len() as f32)
                 .max(self.min)
@@ -315,7 +382,7 @@ impl ColoringStrategy for PointColorColoringStrategy {
     fn process_discretized_point_data(
         &mut self,
         points_batch: &PointsBatch,
-        discretized_locations: Vec<Point3<u32>>,
+        discretized_points: Vec<DiscretizedPoint>,
     ) {
         let bins = self.bins(points_batch);
         let color_attribute = points_batch
@@ -323,21 +390,21 @@ impl ColoringStrategy for PointColorColoringStrategy {
             .get("color")
             .expect("Coloring was requested, but point data without color found.");
         if let AttributeData::U8Vec3(color_vec) = color_attribute {
-            for i in 0..color_vec.len() {
+            for point in discretized_points {
                 let color = Color::<u8> {
-                    red: color_vec[i][0],
-                    green: color_vec[i][1],
-                    blue: color_vec[i][2],
+                    red: color_vec[point.index][0],
+                    green: color_vec[point.index][1],
+                    blue: color_vec[point.index][2],
                     alpha: 255,
                 }
                 .to_f32();
                 let per_column_data = self
                     .per_column_data
-                    .entry((discretized_locations[i].x, discretized_locations[i].y))
+                    .entry((point.location.x, point.location.y))
                     .or_default();
-                let bin_data = per_column_data.entry(bins[i]).or_default();
-                bin_data.sum += color;
-                bin_data.count += 1;
+                let bin_data = per_column_data.entry(bins[point.index]).or_default();
+                bin_data.sum += color * point.coverage;
+                bin_data.weight += point.coverage;
             }
         }
     }
@@ -345,7 +412,7 @@ impl ColoringStrategy for PointColorColoringStrategy {
     fn get_pixel_color(&self, x: u32, y: u32) -> Option<Color<u8>> {
         self.per_column_data.get(&(x, y)).map(|c| {
             (c.values()
-                .map(|bin_data| bin_data.sum / bin_data.count as f32)
+                .map(|bin_data| bin_data.sum / bin_data.weight)
                 .sum::<Color<f32>>()
                 / c.len() as f32)
                 .to_u8()
@@ -382,17 +449,14 @@ impl<C: Colormap> ColoringStrategy for HeightStddevColoringStrategy<C> {
     fn process_discretized_point_data(
         &mut self,
         points_batch: &PointsBatch,
-        discretized_locations: Vec<Point3<u32>>,
+        discretized_points: Vec<DiscretizedPoint>,
     ) {
-        for (i, d_loc) in discretized_locations
-            .iter()
-            .enumerate()
-            .take(discretized_locations.len())
-        {
+        // The statistics are not weighted, splatted points count fully in every pixel they cover.
+        for point in discretized_points {
             self.per_column_data
-                .entry((d_loc.x, d_loc.y))
+                .entry((point.location.x, point.location.y))
                 .or_insert_with(OnlineStats::new)
-                .add(points_batch.position[i].z);
+                .add(points_batch.position[point.index].z);
         }
     }
 
@@ -458,6 +522,8 @@ pub struct XrayParameters {
     pub tile_background_color: Color<u8>,
     pub tile_size_px: u32,
     pub pixel_size_m: f64,
+    /// The radius of the disc that every point covers in the leaf tiles, in pixels.
+    pub splat_radius_px: f64,
     pub root_node_id: NodeId,
 }
 
@@ -485,6 +551,7 @@ pub fn xray_from_points(
             .iter()
             .map(|(k, v)| (&k[..], *v))
             .collect(),
+        ..Default::default()
     };
     let _ = parameters
         .point_cloud_client
@@ -495,7 +562,12 @@ pub fn xray_from_points(
                     *p = query_from_global.transform_point(p);
                 }
             }
-            coloring_strategy.process_point_data(&points_batch, bbox, image_size);
+            coloring_strategy.process_point_data(
+                &points_batch,
+                bbox,
+                image_size,
+                parameters.splat_radius_px,
+            );
             Ok(())
         });
 
@@ -554,13 +626,9 @@ pub fn get_bounding_box(bounding_box: &Aabb, query_from_global: &Option<Isometry
     }
 }
 
-pub fn build_xray_quadtree(
-    coloring_strategy_kind: &ColoringStrategyKind,
-    parameters: &XrayParameters,
-) -> Result<(), Box<dyn Error>> {
-    // Ignore errors, maybe directory is already there.
-    let _ = fs::create_dir(&parameters.output_directory);
-
+/// The bounding box of the point cloud in query coordinates, the root node of the quadtree and
+/// the level of its leaves.
+fn quadtree_layout(parameters: &XrayParameters) -> (Aabb, Node, u8) {
     let bounding_box = get_bounding_box(
         &parameters.point_cloud_client.bounding_box(),
         &parameters.query_from_global,
@@ -570,14 +638,25 @@ pub fn build_xray_quadtree(
         parameters.tile_size_px,
         parameters.pixel_size_m,
     );
-
-    let root_node_id = parameters.root_node_id;
-    let root_level = root_node_id.level();
     assert!(
-        root_level <= deepest_level,
+        parameters.root_node_id.level() <= deepest_level,
         "Specified root node id is outside quadtree."
     );
-    let root_node = Node::from_node_id_and_root_bounding_rect(root_node_id, bounding_rect);
+    let root_node =
+        Node::from_node_id_and_root_bounding_rect(parameters.root_node_id, bounding_rect);
+    (bounding_box, root_node, deepest_level)
+}
+
+pub fn build_xray_quadtree(
+    coloring_strategy_kind: &ColoringStrategyKind,
+    parameters: &XrayParameters,
+) -> Result<(), Box<dyn Error>> {
+    // Ignore errors, maybe directory is already there.
+    let _ = fs::create_dir(&parameters.output_directory);
+
+    let (bounding_box, root_node, deepest_level) = quadtree_layout(parameters);
+    let root_node_id = root_node.id;
+    let root_level = root_node_id.level();
     let leaf_nodes = get_nodes_at_level(&root_node, deepest_level);
 
     let created_leaf_node_ids = create_leaf_nodes(
@@ -622,12 +701,41 @@ pub fn create_leaf_nodes(
     coloring_strategy_kind: &ColoringStrategyKind,
     parameters: &XrayParameters,
 ) -> ImageResult<FnvHashSet<NodeId>> {
-    let (created_leaf_node_ids_tx, created_leaf_node_ids_rx) = crossbeam::channel::unbounded();
     let progress_bar = create_syncable_progress_bar(
         leaf_nodes.len(),
         &format!("Building level {}", deepest_level),
     );
-    leaf_nodes
+    let created_leaf_node_ids = rasterize_tiles(
+        leaf_nodes,
+        bounding_box,
+        coloring_strategy_kind,
+        parameters,
+        |_, _| {
+            progress_bar.lock().unwrap().inc();
+        },
+    )?;
+    progress_bar.lock().unwrap().finish_println("");
+    Ok(created_leaf_node_ids)
+}
+
+/// Rasterizes the tiles in parallel on the rayon thread pool, each from a query for the part of
+/// `bounding_box` below it, and writes those with points into the output directory as soon as
+/// they are done. Tiles without points are not written. After every tile, `progress` is called
+/// with the number of tiles done so far and the total. Returns the ids of the written tiles.
+pub fn rasterize_tiles<F>(
+    nodes: Vec<Node>,
+    bounding_box: &Aabb,
+    coloring_strategy_kind: &ColoringStrategyKind,
+    parameters: &XrayParameters,
+    progress: F,
+) -> ImageResult<FnvHashSet<NodeId>>
+where
+    F: Fn(usize, usize) + Sync,
+{
+    let (created_node_ids_tx, created_node_ids_rx) = crossbeam::channel::unbounded();
+    let num_nodes = nodes.len();
+    let num_done = AtomicUsize::new(0);
+    nodes
         .into_par_iter()
         .try_for_each(|node| -> ImageResult<()> {
             let strategy: Box<dyn ColoringStrategy> = coloring_strategy_kind.new_strategy();
@@ -643,14 +751,42 @@ pub fn create_leaf_nodes(
                 parameters,
             ) {
                 image.save(&get_image_path(&parameters.output_directory, node.id))?;
-                created_leaf_node_ids_tx.send(node.id).unwrap();
+                created_node_ids_tx.send(node.id).unwrap();
             }
-            progress_bar.lock().unwrap().inc();
+            progress(num_done.fetch_add(1, Ordering::Relaxed) + 1, num_nodes);
             Ok(())
         })?;
-    progress_bar.lock().unwrap().finish_println("");
-    drop(created_leaf_node_ids_tx);
-    Ok(created_leaf_node_ids_rx.into_iter().collect())
+    drop(created_node_ids_tx);
+    Ok(created_node_ids_rx.into_iter().collect())
+}
+
+/// Rasterizes all tiles of a level of the quadtree below the root node of the parameters from
+/// the points, see `rasterize_tiles`, e.g. to render a whole level at once instead of building
+/// the quadtree. The tiles of levels above the leaves have a coarser resolution than
+/// `pixel_size_m`.
+pub fn rasterize_level<F>(
+    level: u8,
+    coloring_strategy_kind: &ColoringStrategyKind,
+    parameters: &XrayParameters,
+    progress: F,
+) -> ImageResult<FnvHashSet<NodeId>>
+where
+    F: Fn(usize, usize) + Sync,
+{
+    let (bounding_box, root_node, _) = quadtree_layout(parameters);
+    assert!(
+        root_node.level() <= level,
+        "The level is above the specified root node."
+    );
+    // Ignore errors, maybe directory is already there.
+    let _ = fs::create_dir(&parameters.output_directory);
+    rasterize_tiles(
+        get_nodes_at_level(&root_node, level),
+        &bounding_box,
+        coloring_strategy_kind,
+        parameters,
+        progress,
+    )
 }
 
 pub fn create_non_leaf_nodes(
@@ -757,3 +893,133 @@ fn build_node(
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+    use point_cloud_client::PointCloudClientBuilder;
+    use point_viewer::octree::build_octree;
+    use std::sync::Mutex;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_rasterize_level() {
+        // The points cover the lower half of a 4 m x 4 m quadtree with tiles of 2 m.
+        let position: Vec<Point3<f64>> = (0..400)
+            .map(|i| Point3::new(f64::from(i % 40) * 0.1, f64::from(i / 40) * 0.1, 0.5))
+            .collect();
+        let color = vec![Vector3::new(255, 0, 0); position.len()];
+        let points_batch = PointsBatch {
+            bounding_box: Aabb::from_points(&position),
+            position,
+            attributes: vec![("color".to_string(), AttributeData::U8Vec3(color))]
+                .into_iter()
+                .collect(),
+        };
+        let tmp_dir = TempDir::new("xray").unwrap();
+        let octree_directory = tmp_dir.path().join("octree");
+        build_octree(
+            &octree_directory,
+            0.001,
+            points_batch.bounding_box.clone().unwrap(),
+            vec![points_batch].into_iter(),
+            &["color"],
+        );
+        let locations = [octree_directory.to_string_lossy().into_owned()];
+        let parameters = XrayParameters {
+            output_directory: tmp_dir.path().join("xray"),
+            point_cloud_client: PointCloudClientBuilder::new(&locations).build().unwrap(),
+            query_from_global: None,
+            filter_intervals: HashMap::new(),
+            tile_background_color: WHITE.to_u8(),
+            tile_size_px: 8,
+            pixel_size_m: 0.25,
+            splat_radius_px: 0.,
+            root_node_id: NodeId::root(),
+        };
+        let (_, _, deepest_level) = quadtree_layout(&parameters);
+        assert_eq!(deepest_level, 1);
+
+        let calls = Mutex::new(Vec::new());
+        let created_node_ids = rasterize_level(
+            1,
+            &ColoringStrategyKind::Colored(None),
+            &parameters,
+            |done, total| calls.lock().unwrap().push((done, total)),
+        )
+        .unwrap();
+        let mut calls = calls.into_inner().unwrap();
+        calls.sort_unstable();
+        assert_eq!(calls, vec![(1, 4), (2, 4), (3, 4), (4, 4)]);
+
+        assert_eq!(created_node_ids.len(), 2);
+        for node in get_nodes_at_level(&quadtree_layout(&parameters).1, 1) {
+            let image_path = get_image_path(&parameters.output_directory, node.id);
+            let is_covered = node.bounding_rect.min().y < 2.;
+            assert_eq!(created_node_ids.contains(&node.id), is_covered);
+            assert_eq!(image_path.exists(), is_covered);
+            if is_covered {
+                let image = image::open(&image_path).unwrap().to_rgba();
+                assert_eq!(image.dimensions(), (8, 8));
+                assert!(image.pixels().any(|p| p[3] == 255 && p[0] == 255));
+            }
+        }
+    }
+
+    #[test]
+    fn test_pixel_coverages_of_unit_radius() {
+        let image_size = Vector2::new(5, 5);
+        let coverages: FnvHashMap<(u32, u32), f32> =
+            pixel_coverages(Point2::new(2.5, 2.5), 1., image_size)
+                .into_iter()
+                .collect();
+        assert_eq!(coverages.len(), 9);
+        assert_eq!(coverages[&(2, 2)], 1.);
+        let edge = coverages[&(1, 2)];
+        let corner = coverages[&(1, 1)];
+        for pixel in &[(3, 2), (2, 1), (2, 3)] {
+            assert_eq!(coverages[pixel], edge);
+        }
+        for pixel in &[(3, 1), (1, 3), (3, 3)] {
+            assert_eq!(coverages[pixel], corner);
+        }
+        assert!(0. < corner && corner < edge && edge < 1.);
+
+        assert_eq!(
+            pixel_coverages(Point2::new(2.5, 2.5), 0., image_size),
+            vec![((2, 2), 1.)]
+        );
+    }
+
+    #[test]
+    fn test_splatted_point_colors_neighbors() {
+        let points_batch = PointsBatch {
+            position: vec![Point3::new(2.5, 2.5, 0.5)],
+            attributes: vec![(
+                "color".to_string(),
+                AttributeData::U8Vec3(vec![Vector3::new(255, 0, 0)]),
+            )]
+            .into_iter()
+            .collect(),
+            bounding_box: None,
+        };
+        let bbox = Aabb::new(Point3::origin(), Point3::new(5., 5., 1.));
+        for (radius, covered) in vec![(0., 2..=2), (1., 1..=3)] {
+            let mut strategy = PointColorColoringStrategy::new(None);
+            strategy.process_point_data(&points_batch, &bbox, Vector2::new(5, 5), radius);
+            for x in 0..5 {
+                for y in 0..5 {
+                    let color = strategy.get_pixel_color(x, y);
+                    if covered.contains(&x) && covered.contains(&y) {
+                        // The coverage weights cancel out, up to rounding.
+                        let color = color.unwrap();
+                        assert!(color.red >= 254 && color.green == 0 && color.blue == 0);
+                    } else {
+                        assert_eq!(color, None);
+                    }
+                }
+            }
+        }
+    }
+}